@@ -1,17 +1,61 @@
 // file: server9/export.cpp
+//
+// Before each upload the engine reads the account's storage quota (mega-df) and refuses to upload
+// a file that won't fit, reporting "MEGA storage quota exhausted" so the generator keeps it pending
+// locally. With REMOTE_PRUNE_KEEP=N it first deletes the oldest remote cycles in the target
+// directory: every metrics_<stamp>* file of a cycle (its export or shards and manifest, delta,
+// sidecar and extra formats) goes together, one cycle at a time and re-reading the quota after
+// each, but it never leaves fewer than N cycles, nor touches the cycle being uploaded. N must be a
+// positive integer; anything else exits with EXIT_CONFIG before touching MEGA. Engines run in
+// parallel (the generator retries pending exports concurrently), so pruning holds an exclusive lock
+// on PRUNE_LOCK_PATH and re-reads the quota once it has it. Every run prints
+// "Export Engine: MEGA storage used=<bytes> total=<bytes>" for the generator to log.
+//
+// A `<file>.sha256` checksum written next to the local file is uploaded right after it, as
+// `<remote>.sha256`, and deleted with it; pruning removes a remote export's checksum too.
 
+#include <algorithm>
 #include <iostream>
+#include <map>
 #include <fstream>
+#include <cctype>
+#include <cerrno>
 #include <cstdlib>
 #include <cstdio>
 #include <sstream>
 #include <stdexcept>
 #include <string>
 #include <vector>
+#include <fcntl.h>
+#include <sys/file.h>
 #include <sys/wait.h>
+#include <unistd.h>
 
 using namespace std;
 
+// Exit codes, matching the metrics generator's contract (see src/exit.rs)
+const int EXIT_CONFIG = 2;
+const int EXIT_AUTH = 3;
+// MEGAcmd exits with this when there is no logged-in session (MCMD_NOTLOGGEDIN)
+const int MEGA_NOT_LOGGED_IN = 57;
+
+// A command that ran but exited with a code it wasn't allowed to
+struct CommandFailed : runtime_error {
+    int exit_code;
+    CommandFailed(const string& message, int code) : runtime_error(message), exit_code(code) {}
+};
+
+// The file doesn't fit in what is left of the storage quota, even after any pruning
+struct QuotaExhausted : runtime_error {
+    QuotaExhausted(const string& message) : runtime_error(message) {}
+};
+
+struct StorageQuota {
+    bool known = false;
+    long long used = 0;
+    long long total = 0;
+};
+
 // Executes a shell command and captures its output. This function is unchanged.
 string safeSystem(const string& cmd, bool checkError = true, const vector<int>& allowedExitCodes = {}) {
     string effective_cmd = "env HOME=/home/appuser " + cmd;
@@ -67,7 +111,7 @@ string safeSystem(const string& cmd, bool checkError = true, const vector<int>&
         if(!cmd_output_str.empty()) {
              error_msg += ". Output was: " + cmd_output_str;
         }
-        throw runtime_error(error_msg);
+        throw CommandFailed(error_msg, exit_code);
     }
     return cmd_output_str;
 }
@@ -93,13 +137,176 @@ void ensureRemoteParentDirExists(const string& full_remote_path) {
     }
 }
 
+// Parses the "USED STORAGE:  <used>  <percent>% of <total>" line of mega-df
+StorageQuota readStorageQuota() {
+    StorageQuota quota;
+    string output;
+    try {
+        output = safeSystem("mega-df");
+    } catch (const CommandFailed& e) {
+        cerr << "Export Engine Warning: could not read the storage quota: " << e.what() << endl;
+        return quota;
+    }
+    istringstream lines(output);
+    string line;
+    const string label = "USED STORAGE:";
+    while (getline(lines, line)) {
+        size_t at = line.find(label);
+        if (at == string::npos) {
+            continue;
+        }
+        istringstream fields(line.substr(at + label.size()));
+        string percent, of;
+        if (fields >> quota.used >> percent >> of >> quota.total && of == "of" && quota.total > 0) {
+            quota.known = true;
+        }
+        break;
+    }
+    if (!quota.known) {
+        cerr << "Export Engine Warning: no USED STORAGE line in the mega-df output" << endl;
+    }
+    return quota;
+}
+
+void printStorageQuota(const StorageQuota& quota) {
+    if (quota.known) {
+        cout << "Export Engine: MEGA storage used=" << quota.used << " total=" << quota.total << endl;
+    }
+}
+
+// The cycle stamp in an export's name: `20250101000000` for metrics_20250101000000.json,
+// metrics_20250101000000_003.json, metrics_20250101000000_manifest.json or metrics_20250101000000.csv
+string cycleStamp(const string& name) {
+    if (name.rfind("metrics_", 0) != 0) {
+        return name;
+    }
+    string rest = name.substr(string("metrics_").size());
+    return rest.substr(0, rest.find_first_of("_."));
+}
+
+bool endsWith(const string& name, const string& suffix) {
+    return name.size() >= suffix.size() && name.compare(name.size() - suffix.size(), suffix.size(), suffix) == 0;
+}
+
+// Remote exports in `remote_dir` by cycle stamp, oldest cycle first (the stamps sort
+// chronologically). Checksums are left out; each goes with its file.
+map<string, vector<string>> listRemoteCycles(const string& remote_dir) {
+    istringstream lines(safeSystem("mega-ls \"" + remote_dir + "\""));
+    map<string, vector<string>> cycles;
+    string name;
+    while (getline(lines, name)) {
+        if (name.rfind("metrics_", 0) == 0 && !endsWith(name, ".sha256")) {
+            cycles[cycleStamp(name)].push_back(name);
+        }
+    }
+    return cycles;
+}
+
+// Held by whichever engine is pruning, so two never pick the same oldest cycles
+const char* PRUNE_LOCK_PATH = "/tmp/export_engine_prune.lock";
+
+// Reads REMOTE_PRUNE_KEEP into `keep`, 0 when it is unset or blank. False when it isn't a positive
+// integer: strtoul alone reads a typo as 0 and wraps a negative value. Surrounding whitespace is
+// allowed, as the generator's own check (src/config.rs) trims it.
+bool readPruneKeep(size_t& keep) {
+    keep = 0;
+    const char* keep_env = getenv("REMOTE_PRUNE_KEEP");
+    if (keep_env == nullptr) {
+        return true;
+    }
+    while (isspace(static_cast<unsigned char>(*keep_env))) {
+        keep_env++;
+    }
+    if (*keep_env == '\0') {
+        return true;
+    }
+    if (!isdigit(static_cast<unsigned char>(*keep_env))) {
+        return false;
+    }
+    char* end = nullptr;
+    errno = 0;
+    unsigned long parsed = strtoul(keep_env, &end, 10);
+    while (isspace(static_cast<unsigned char>(*end))) {
+        end++;
+    }
+    if (errno != 0 || *end != '\0' || parsed == 0) {
+        return false;
+    }
+    keep = parsed;
+    return true;
+}
+
+// An exclusive flock on PRUNE_LOCK_PATH for as long as it lives
+class PruneLock {
+public:
+    PruneLock() : fd_(open(PRUNE_LOCK_PATH, O_RDWR | O_CREAT | O_CLOEXEC, 0666)) {
+        if (fd_ < 0 || flock(fd_, LOCK_EX) != 0) {
+            throw runtime_error(string("Cannot lock ") + PRUNE_LOCK_PATH + " for pruning");
+        }
+    }
+    ~PruneLock() { close(fd_); }
+    PruneLock(const PruneLock&) = delete;
+    PruneLock& operator=(const PruneLock&) = delete;
+
+private:
+    int fd_;
+};
+
+// Makes sure `bytes` fit in the quota, pruning old remote exports when `keep` (REMOTE_PRUNE_KEEP)
+// allows it. An unreadable quota doesn't block the upload; mega-put still fails on its own if it
+// doesn't fit.
+StorageQuota ensureRoom(long long bytes, const string& full_remote_path, size_t keep) {
+    StorageQuota quota = readStorageQuota();
+    printStorageQuota(quota);
+    if (!quota.known || quota.used + bytes <= quota.total) {
+        return quota;
+    }
+
+    size_t last_slash_idx = full_remote_path.find_last_of('/');
+    if (keep > 0 && last_slash_idx != string::npos) {
+        PruneLock lock;
+        // Another engine may have pruned while this one waited for the lock
+        quota = readStorageQuota();
+        if (!quota.known || quota.used + bytes <= quota.total) {
+            return quota;
+        }
+        string remote_dir = last_slash_idx > 0 ? full_remote_path.substr(0, last_slash_idx) : "/";
+        string target = full_remote_path.substr(last_slash_idx + 1);
+        map<string, vector<string>> cycles = listRemoteCycles(remote_dir);
+        cycles.erase(cycleStamp(target));
+        for (auto cycle = cycles.begin(); cycles.size() > keep && quota.used + bytes > quota.total; cycle = cycles.erase(cycle)) {
+            cout << "Export Engine: Pruning cycle " << cycle->first << " (" << cycle->second.size() << " files) to make room" << endl;
+            for (const string& file : cycle->second) {
+                string path = (remote_dir == "/" ? "" : remote_dir) + "/" + file;
+                safeSystem("mega-rm -f \"" + path + "\"");
+                safeSystem("mega-rm -f \"" + path + ".sha256\"", false);
+            }
+            quota = readStorageQuota();
+            printStorageQuota(quota);
+            if (!quota.known) {
+                return quota;
+            }
+        }
+    }
+    if (quota.used + bytes > quota.total) {
+        throw QuotaExhausted("MEGA storage quota exhausted: " + to_string(bytes) + " bytes needed, "
+            + to_string(max(0LL, quota.total - quota.used)) + " of " + to_string(quota.total) + " free");
+    }
+    return quota;
+}
+
 int main(int argc, char* argv[]) {
     if (argc < 3) {
         cerr << "Export Engine Usage: " << argv[0] << " <local_metrics_filepath> <full_mega_remote_path>\n";
-        return EXIT_FAILURE;
+        return EXIT_CONFIG;
     }
     string local_filepath = argv[1];
     string remote_mega_path = argv[2];
+    size_t keep;
+    if (!readPruneKeep(keep)) {
+        cerr << "Export Engine: REMOTE_PRUNE_KEEP must be a positive number of cycles, got \"" << getenv("REMOTE_PRUNE_KEEP") << "\"\n";
+        return EXIT_CONFIG;
+    }
 
     cout << "Export Engine started." << endl;
     cout << "Local file to upload: " << local_filepath << endl;
@@ -109,19 +316,54 @@ int main(int argc, char* argv[]) {
         // 1. Ensure the parent directory exists on MEGA before trying to upload.
         ensureRemoteParentDirExists(remote_mega_path);
 
-        // 2. Upload the file to the full path. This works because the parent dir is now guaranteed to exist.
+        // 2. Check the file fits in the storage quota, pruning old exports if allowed.
+        ifstream local_file(local_filepath, ios::binary | ios::ate);
+        if (!local_file) {
+            throw runtime_error("Cannot open local file " + local_filepath);
+        }
+        long long bytes = local_file.tellg();
+        string local_checksum = local_filepath + ".sha256";
+        ifstream checksum_file(local_checksum, ios::binary | ios::ate);
+        bool has_checksum = static_cast<bool>(checksum_file);
+        if (has_checksum) {
+            bytes += checksum_file.tellg();
+        }
+        StorageQuota quota = ensureRoom(bytes, remote_mega_path, keep);
+
+        // 3. Upload the file to the full path. This works because the parent dir is now guaranteed to exist.
         string uploadCmd = "mega-put -v \"" + local_filepath + "\" \"" + remote_mega_path + "\"";
         safeSystem(uploadCmd);
         cout << "Export Engine: Successfully uploaded " << local_filepath << " to " << remote_mega_path << endl;
+        if (has_checksum) {
+            safeSystem("mega-put -v \"" + local_checksum + "\" \"" + remote_mega_path + ".sha256\"");
+        }
+        // The upload's own usage, without another mega-df round trip
+        if (quota.known) {
+            quota.used += bytes;
+            printStorageQuota(quota);
+        }
 
-        // 3. Delete the local file after the successful upload.
+        // 4. Delete the local file after the successful upload.
         cout << "Export Engine: Attempting to delete local file: " << local_filepath << endl;
         if (remove(local_filepath.c_str()) != 0) {
             perror(("Export Engine Warning: Could not delete local file " + local_filepath).c_str());
         } else {
             cout << "Export Engine: Successfully deleted local file: " << local_filepath << endl;
         }
+        if (has_checksum && remove(local_checksum.c_str()) != 0) {
+            perror(("Export Engine Warning: Could not delete local file " + local_checksum).c_str());
+        }
 
+    } catch (const QuotaExhausted& e) {
+        cerr << "Export Engine: " << e.what() << endl;
+        return EXIT_FAILURE;
+    } catch (const CommandFailed& e) {
+        cerr << "Export Engine: FATAL ERROR: " << e.what() << endl;
+        if (e.exit_code == MEGA_NOT_LOGGED_IN) {
+            cerr << "Export Engine: not logged in to MEGA" << endl;
+            return EXIT_AUTH;
+        }
+        return EXIT_FAILURE;
     } catch (const exception& e) {
         cerr << "Export Engine: FATAL ERROR: " << e.what() << endl;
         return EXIT_FAILURE;
@@ -0,0 +1,174 @@
+// One side's active windows (positive moving-week deltas), kept in window and velocity order as
+// windows are added and evicted, so the velocity and rhythm detectors never rescan the buffer.
+// `from_deltas` builds the same index from exported sequences (`--recompute`, `--explain`).
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeSet, VecDeque};
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+struct ActiveWindow {
+    window: u64,
+    start: u64,
+    delta: i64,
+    // Bits of its velocity, when it has one
+    velocity: Option<u64>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ActivityIndex {
+    // Ids of the oldest window still buffered and of the next one; positions count from the former
+    first_window: u64,
+    next_window: u64,
+    active: VecDeque<ActiveWindow>,
+    // (velocity bits, window, start, delta) for the active windows shorter than an hour. Velocities
+    // are positive and finite, so their bit patterns sort like the values.
+    by_velocity: BTreeSet<(u64, u64, u64, i64)>,
+    active_delta_sum: i64,
+}
+
+impl ActivityIndex {
+    // Window i runs from timestamps[i] to timestamps[i + 1]; deltas without both are left out
+    pub fn from_deltas(deltas: &[i64], timestamps: &[u64]) -> Self {
+        let mut index = Self::default();
+        for (&delta, span) in deltas.iter().zip(timestamps.windows(2)) {
+            index.push(delta, span[0], span[1]);
+        }
+        index
+    }
+
+    pub fn push(&mut self, delta: i64, start: u64, end: u64) {
+        let window = self.next_window;
+        self.next_window += 1;
+        if delta <= 0 {
+            return;
+        }
+        let minutes = end.saturating_sub(start) as f64 / 60.0;
+        let velocity = (minutes > 0.0 && minutes < 60.0).then(|| (delta as f64 / minutes).to_bits());
+        if let Some(bits) = velocity {
+            self.by_velocity.insert((bits, window, start, delta));
+        }
+        self.active.push_back(ActiveWindow { window, start, delta, velocity });
+        self.active_delta_sum += delta;
+    }
+
+    pub fn evict_oldest(&mut self) {
+        if self.first_window == self.next_window {
+            return;
+        }
+        if let Some(oldest) = self.active.front().copied().filter(|oldest| oldest.window == self.first_window) {
+            self.active.pop_front();
+            self.active_delta_sum -= oldest.delta;
+            if let Some(bits) = oldest.velocity {
+                self.by_velocity.remove(&(bits, oldest.window, oldest.start, oldest.delta));
+            }
+        }
+        self.first_window += 1;
+    }
+
+    // (position, velocity, delta, start timestamp), slowest first
+    pub fn by_velocity(&self) -> impl Iterator<Item = (usize, f64, i64, u64)> + '_ {
+        self.by_velocity.iter()
+            .map(|&(bits, window, start, delta)| (self.position(window), f64::from_bits(bits), delta, start))
+    }
+
+    // (position, start timestamp, delta), oldest first
+    pub fn active(&self) -> impl Iterator<Item = (usize, u64, i64)> + '_ {
+        self.active.iter().map(|w| (self.position(w.window), w.start, w.delta))
+    }
+
+    // None while there are no active windows
+    pub fn mean_active_delta(&self) -> Option<f64> {
+        (!self.active.is_empty()).then(|| self.active_delta_sum as f64 / self.active.len() as f64)
+    }
+
+    fn position(&self, window: u64) -> usize {
+        (window - self.first_window) as usize
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::DetectorConfig;
+    use crate::rng::SplitMix64;
+    use crate::{FuzzyPattern, ProductMetricsState};
+    use std::time::{Duration, Instant};
+
+    const WINDOWS: usize = 180;
+    const EMIT_EVERY: usize = 10;
+
+    // Fills of about 1,000 in a third of the windows, with jittered spacing and an hour-long gap now
+    // and then, which leaves that window without a velocity
+    fn jittered_fills(count: usize) -> (Vec<i64>, Vec<u64>) {
+        let mut rng = SplitMix64(11);
+        let mut timestamps = vec![1_700_000_000];
+        let mut deltas = Vec::new();
+        for i in 0..count {
+            let gap = if i % 97 == 0 { 4_000 } else { 0 };
+            timestamps.push(timestamps[i] + rng.range(15, 25) as u64 + gap);
+            deltas.push(if rng.range(0, 2) == 0 { 1_000 + rng.range(-100, 100) } else { rng.range(-5, 0) });
+        }
+        (deltas, timestamps)
+    }
+
+    fn detect(index: &ActivityIndex, detector: &DetectorConfig) -> (Vec<FuzzyPattern>, Vec<FuzzyPattern>) {
+        (ProductMetricsState::detect_velocity_patterns(index, detector), ProductMetricsState::detect_rhythm_patterns(index, detector))
+    }
+
+    #[test]
+    fn sliding_index_matches_a_rebuild_at_every_emit() {
+        let (deltas, timestamps) = jittered_fills(3_000);
+        let detector = DetectorConfig::default();
+        let mut index = ActivityIndex::default();
+        let mut emits_with_patterns = 0;
+        for end in 1..=deltas.len() {
+            index.push(deltas[end - 1], timestamps[end - 1], timestamps[end]);
+            if end > WINDOWS {
+                index.evict_oldest();
+            }
+            if end < WINDOWS || end % EMIT_EVERY != 0 {
+                continue;
+            }
+            let patterns = detect(&index, &detector);
+            let expected = detect(&ActivityIndex::from_deltas(&deltas[end - WINDOWS..end], &timestamps[end - WINDOWS..=end]), &detector);
+            assert_eq!(patterns, expected, "emit ending at window {}", end);
+            emits_with_patterns += usize::from(!patterns.0.is_empty() || !patterns.1.is_empty());
+        }
+        assert!(emits_with_patterns > 0);
+    }
+
+    #[test]
+    fn an_empty_index_has_no_mean_delta() {
+        let mut index = ActivityIndex::from_deltas(&[0, -3], &[0, 20, 40]);
+        assert_eq!(index.mean_active_delta(), None);
+        index.push(500, 40, 60);
+        index.push(700, 60, 80);
+        assert_eq!(index.mean_active_delta(), Some(600.0));
+    }
+
+    // Timing comparison only: cargo test --release -- --ignored --nocapture incremental_vs_rebuilt
+    #[test]
+    #[ignore]
+    fn incremental_vs_rebuilt_timing() {
+        let (deltas, timestamps) = jittered_fills(3_000);
+        let detector = DetectorConfig::default();
+        let mut index = ActivityIndex::default();
+        let (mut incremental, mut rebuilt) = (Duration::ZERO, Duration::ZERO);
+        for end in 1..=deltas.len() {
+            let started = Instant::now();
+            index.push(deltas[end - 1], timestamps[end - 1], timestamps[end]);
+            if end > WINDOWS {
+                index.evict_oldest();
+            }
+            if end >= WINDOWS && end % EMIT_EVERY == 0 {
+                detect(&index, &detector);
+                incremental += started.elapsed();
+                let started = Instant::now();
+                detect(&ActivityIndex::from_deltas(&deltas[end - WINDOWS..end], &timestamps[end - WINDOWS..=end]), &detector);
+                rebuilt += started.elapsed();
+            } else {
+                incremental += started.elapsed();
+            }
+        }
+        println!("{} windows, emit every {}: incremental {:?}, rebuilt per emit {:?}", WINDOWS, EMIT_EVERY, incremental, rebuilt);
+    }
+}
@@ -0,0 +1,181 @@
+// Optional alert webhook (ALERT_WEBHOOK_URL): after every exported cycle the rules in
+// `AlertRules` are evaluated and whatever fires is POSTed as one JSON message. The body carries
+// `content` (Discord) and `text` (Slack) with a readable summary, plus the structured `events`.
+//
+// Rules, each disabled by setting its threshold to 0:
+//   export_failures  this many exports in a row ended Failed or DiskFull
+//   manipulation     a side's spike magnitude (largest window over the median window) reached
+//                    `spike_magnitude`
+//   wide_spread      (instabuy avg - instasell avg) / instasell avg reached `spread_fraction`
+//
+// Every event has a key (the rule, plus the product for product rules). A key that was delivered
+// less than `cooldown_secs` ago is held back; a failed POST records nothing, so its events go out
+// with the next cycle instead.
+use crate::config::AlertRules;
+use crate::AnalysisResult;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::error::Error;
+use std::time::Duration;
+
+// Events spelled out in `content`/`text`; the rest are only counted there but stay in `events`
+const MAX_LISTED: usize = 10;
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct AlertEvent {
+    pub event: &'static str,
+    pub key: String,
+    pub message: String,
+    pub metrics: Option<AlertMetrics>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct AlertMetrics {
+    pub product_id: String,
+    pub instabuy_price_average: f64,
+    pub instasell_price_average: f64,
+    pub instabuy_moving_week_volume: f64,
+    pub instasell_moving_week_volume: f64,
+    pub buy_spike_magnitude: Option<f64>,
+    pub sell_spike_magnitude: Option<f64>,
+}
+
+impl AlertMetrics {
+    fn of(result: &AnalysisResult) -> Self {
+        Self {
+            product_id: result.product_id.clone(),
+            instabuy_price_average: result.instabuy_price_average,
+            instasell_price_average: result.instasell_price_average,
+            instabuy_moving_week_volume: result.instabuy_moving_week_volume,
+            instasell_moving_week_volume: result.instasell_moving_week_volume,
+            buy_spike_magnitude: result.buy_spike_magnitude,
+            sell_spike_magnitude: result.sell_spike_magnitude,
+        }
+    }
+}
+
+pub fn evaluate(rules: &AlertRules, results: &[AnalysisResult], consecutive_export_failures: u32) -> Vec<AlertEvent> {
+    let mut events = Vec::new();
+    if rules.export_failures > 0 && consecutive_export_failures >= rules.export_failures {
+        events.push(AlertEvent {
+            event: "export_failures",
+            key: "export_failures".to_string(),
+            message: format!("{} exports in a row have failed", consecutive_export_failures),
+            metrics: None,
+        });
+    }
+    for result in results {
+        let spike = [("buy", result.buy_spike_magnitude), ("sell", result.sell_spike_magnitude)].into_iter()
+            .filter_map(|(side, magnitude)| magnitude.map(|m| (side, m)))
+            .filter(|(_, magnitude)| rules.spike_magnitude > 0.0 && *magnitude >= rules.spike_magnitude)
+            .max_by(|a, b| a.1.total_cmp(&b.1));
+        if let Some((side, magnitude)) = spike {
+            events.push(AlertEvent {
+                event: "manipulation",
+                key: format!("manipulation:{}", result.product_id),
+                message: format!("{}: {} spike of {:.1}x the median window", result.product_id, side, magnitude),
+                metrics: Some(AlertMetrics::of(result)),
+            });
+        }
+
+        let spread = (result.instabuy_price_average - result.instasell_price_average) / result.instasell_price_average;
+        if rules.spread_fraction > 0.0 && result.instasell_price_average > 0.0 && spread >= rules.spread_fraction {
+            events.push(AlertEvent {
+                event: "wide_spread",
+                key: format!("wide_spread:{}", result.product_id),
+                message: format!("{}: spread {:.0}% of the instasell price ({:.1} vs {:.1})", result.product_id,
+                    spread * 100.0, result.instabuy_price_average, result.instasell_price_average),
+                metrics: Some(AlertMetrics::of(result)),
+            });
+        }
+    }
+    events
+}
+
+pub struct Notifier {
+    pub url: String,
+    pub cooldown_secs: u64,
+    pub client: reqwest::Client,
+    // When each key was last delivered (unix seconds)
+    last_sent: HashMap<String, u64>,
+}
+
+impl Notifier {
+    pub fn new(url: String, cooldown_secs: u64, client: reqwest::Client) -> Self {
+        Self { url, cooldown_secs, client, last_sent: HashMap::new() }
+    }
+
+    // The events whose key is out of its cooldown at `now`
+    fn due(&self, events: Vec<AlertEvent>, now: u64) -> Vec<AlertEvent> {
+        events.into_iter()
+            .filter(|event| self.last_sent.get(&event.key).is_none_or(|&sent| now.saturating_sub(sent) >= self.cooldown_secs))
+            .collect()
+    }
+
+    // POSTs the due events as one message; returns how many went out
+    pub async fn notify(&mut self, events: Vec<AlertEvent>, now: u64) -> Result<usize, Box<dyn Error>> {
+        let due = self.due(events, now);
+        if due.is_empty() {
+            return Ok(0);
+        }
+        let body = payload(&due);
+        self.client.post(&self.url).json(&body).timeout(Duration::from_secs(10)).send().await?.error_for_status()?;
+        for event in &due {
+            self.last_sent.insert(event.key.clone(), now);
+        }
+        Ok(due.len())
+    }
+}
+
+fn payload(events: &[AlertEvent]) -> serde_json::Value {
+    let mut lines: Vec<String> = events.iter().take(MAX_LISTED).map(|e| format!("[{}] {}", e.event, e.message)).collect();
+    if events.len() > MAX_LISTED {
+        lines.push(format!("... and {} more", events.len() - MAX_LISTED));
+    }
+    let summary = lines.join("\n");
+    serde_json::json!({ "content": summary, "text": summary, "events": events })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::replay::replay_cycle;
+
+    #[tokio::test]
+    async fn rules_fire_on_failures_spikes_and_wide_spreads() {
+        // DEAD_ITEM trades at 3.0 / 1.0; WHEAT spikes 8.6x on the buy side and 17.5x on the sell side
+        let results = replay_cycle().await;
+        let rules = AlertRules { export_failures: 3, spike_magnitude: 10.0, spread_fraction: 1.0, cooldown_secs: 3600 };
+        let events = evaluate(&rules, &results, 2);
+        let keys = |events: &[AlertEvent]| events.iter().map(|e| e.key.clone()).collect::<Vec<_>>();
+        assert_eq!(keys(&events), ["wide_spread:DEAD_ITEM", "manipulation:WHEAT"]);
+        assert_eq!(events[1].message, "WHEAT: sell spike of 17.5x the median window");
+        assert_eq!(events[1].metrics.as_ref().unwrap().sell_spike_magnitude, Some(17.5));
+        assert_eq!(keys(&evaluate(&rules, &results, 3))[0], "export_failures");
+
+        // Thresholds out of reach or at 0 keep every rule quiet
+        let quiet = AlertRules { export_failures: 0, spike_magnitude: 20.0, spread_fraction: 0.0, ..rules };
+        assert!(evaluate(&quiet, &results, 10).is_empty());
+    }
+
+    #[tokio::test]
+    async fn repeated_keys_wait_out_the_cooldown_and_failed_posts_retry() {
+        let event = |key: &str| AlertEvent { event: "manipulation", key: key.to_string(), message: key.to_string(), metrics: None };
+        let unreachable = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap().local_addr().unwrap();
+        let mut notifier = Notifier::new(format!("http://{}/hook", unreachable), 600, reqwest::Client::new());
+
+        // Nothing was delivered, so nothing is held back
+        assert!(notifier.notify(vec![event("a")], 1_000).await.is_err());
+        assert_eq!(notifier.due(vec![event("a")], 1_000).len(), 1);
+
+        notifier.last_sent.insert("a".into(), 1_000);
+        let due = |at| notifier.due(vec![event("a"), event("b")], at).into_iter().map(|e| e.key).collect::<Vec<_>>();
+        assert_eq!(due(1_599), ["b"]);
+        assert_eq!(due(1_600), ["a", "b"]);
+
+        let many: Vec<AlertEvent> = (0..12).map(|i| event(&i.to_string())).collect();
+        let body = payload(&many);
+        assert!(body["content"].as_str().unwrap().ends_with("... and 2 more"));
+        assert_eq!(body["events"].as_array().unwrap().len(), 12);
+    }
+}
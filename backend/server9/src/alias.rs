@@ -0,0 +1,81 @@
+// PRODUCT_ALIASES_PATH: friendlier names and known-duplicate ids, loaded from a JSON file like
+//   { "display_names": { "INK_SACK:3": "Cocoa Beans" },
+//     "canonical": { "ENCHANTED_CARROT_ON_A_STICK": "ENCHANTED_CARROT_STICK" } }
+//
+// Every exported product gets a `display_name`: its entry in `display_names`, or else the id in
+// title case (`ENCHANTED_LAPIS_BLOCK` → `Enchanted Lapis Block`). `product_id` stays authoritative.
+//
+// `canonical` renames ids as snapshots are collected, before the product filter, so a product the
+// API lists under a second id keeps one history. When both ids appear in the same snapshot the
+// second entry is dropped as a duplicate.
+use crate::{AnalysisResult, BazaarInfo};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ProductAliases {
+    pub display_names: BTreeMap<String, String>,
+    pub canonical: BTreeMap<String, String>,
+}
+
+impl ProductAliases {
+    pub fn load(path: &str) -> Result<Self, String> {
+        let text = std::fs::read_to_string(path).map_err(|e| format!("PRODUCT_ALIASES_PATH {}: {}", path, e))?;
+        let aliases: Self = serde_json::from_str(&text).map_err(|e| format!("PRODUCT_ALIASES_PATH {}: {}", path, e))?;
+        if let Some((from, to)) = aliases.canonical.iter().find(|(_, to)| aliases.canonical.contains_key(*to)) {
+            return Err(format!("PRODUCT_ALIASES_PATH {}: {} maps to {}, which is itself an alias", path, from, to));
+        }
+        Ok(aliases)
+    }
+
+    pub fn display_name(&self, product_id: &str) -> String {
+        self.display_names.get(product_id).cloned().unwrap_or_else(|| default_display_name(product_id))
+    }
+
+    pub fn canonicalize(&self, info: &mut BazaarInfo) {
+        if let Some(canonical) = self.canonical.get(&info.product_id) {
+            info.product_id = canonical.clone();
+        }
+    }
+
+    pub fn apply(&self, results: &mut [AnalysisResult]) {
+        for result in results {
+            result.display_name = self.display_name(&result.product_id);
+        }
+    }
+}
+
+pub fn default_display_name(product_id: &str) -> String {
+    product_id.split('_')
+        .filter(|word| !word.is_empty())
+        .map(|word| {
+            let lower = word.to_lowercase();
+            let mut chars = lower.chars();
+            chars.next().map(|first| first.to_uppercase().chain(chars).collect()).unwrap_or_default()
+        })
+        .collect::<Vec<String>>()
+        .join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mapped_ids_use_their_alias_and_unmapped_ids_are_title_cased() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("aliases.json");
+        std::fs::write(&path, r#"{ "display_names": { "INK_SACK:3": "Cocoa Beans" }, "canonical": { "OLD_WHEAT": "WHEAT" } }"#).unwrap();
+        let aliases = ProductAliases::load(path.to_str().unwrap()).unwrap();
+
+        assert_eq!(aliases.display_name("INK_SACK:3"), "Cocoa Beans");
+        assert_eq!(aliases.display_name("ENCHANTED_LAPIS_BLOCK"), "Enchanted Lapis Block");
+        assert_eq!(ProductAliases::default().display_name("INK_SACK:3"), "Ink Sack:3");
+
+        std::fs::write(&path, r#"{ "canonical": { "A": "B", "B": "C" } }"#).unwrap();
+        assert!(ProductAliases::load(path.to_str().unwrap()).unwrap_err().contains("itself an alias"));
+        std::fs::write(&path, r#"{ "names": {} }"#).unwrap();
+        assert!(ProductAliases::load(path.to_str().unwrap()).is_err());
+    }
+}
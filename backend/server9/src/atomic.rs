@@ -0,0 +1,80 @@
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+pub fn write_atomic(path: &Path, contents: &[u8]) -> io::Result<()> {
+    write_atomic_with(path, |writer| writer.write_all(contents))
+}
+
+// Writes into a temp file in the same directory and renames it over `path`, so readers (and the
+// export engine) only ever see the previous file or the complete new one. On any error the temp
+// file is removed and `path` is left untouched.
+pub fn write_atomic_with<F>(path: &Path, write: F) -> io::Result<()>
+where
+    F: FnOnce(&mut BufWriter<File>) -> io::Result<()>,
+{
+    let tmp = temp_path(path);
+    let result = (|| {
+        let mut writer = BufWriter::new(File::create(&tmp)?);
+        write(&mut writer)?;
+        let file = writer.into_inner().map_err(|e| e.into_error())?;
+        file.sync_all()?;
+        fs::rename(&tmp, path)
+    })();
+    if result.is_err() {
+        let _ = fs::remove_file(&tmp);
+    }
+    result
+}
+
+// As `write_atomic`, without the fsync: for many small files derived from one that is synced,
+// where a crash may cost the newest copies but never leaves a torn one
+pub fn write_replace(path: &Path, contents: &[u8]) -> io::Result<()> {
+    let tmp = temp_path(path);
+    let result = fs::write(&tmp, contents).and_then(|()| fs::rename(&tmp, path));
+    if result.is_err() {
+        let _ = fs::remove_file(&tmp);
+    }
+    result
+}
+
+// Hidden, and not ending in `.json`, so capture listings and uploaders never pick it up
+fn temp_path(path: &Path) -> PathBuf {
+    let name = path.file_name().map(|n| n.to_string_lossy().into_owned()).unwrap_or_default();
+    path.with_file_name(format!(".{}.{}.tmp", name, std::process::id()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn interrupted_write_leaves_previous_file_and_no_temp() {
+        let dir = tempfile::tempdir().unwrap();
+        let target = dir.path().join("metrics_1.json");
+        write_atomic(&target, b"[{\"complete\":true}]").unwrap();
+
+        let result = write_atomic_with(&target, |writer| {
+            writer.write_all(b"[{\"comp")?;
+            Err(io::Error::other("simulated crash mid-write"))
+        });
+
+        assert!(result.is_err());
+        assert_eq!(fs::read(&target).unwrap(), b"[{\"complete\":true}]");
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn interrupted_first_write_creates_nothing() {
+        let dir = tempfile::tempdir().unwrap();
+        let target = dir.path().join("metrics_2.json");
+
+        let _ = write_atomic_with(&target, |writer| {
+            writer.write_all(b"[")?;
+            Err(io::Error::other("simulated crash mid-write"))
+        });
+
+        assert!(!target.exists());
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 0);
+    }
+}
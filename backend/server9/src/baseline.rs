@@ -0,0 +1,285 @@
+// Optional per-product baselines (BASELINE_PATH) for spotting unusual cycles. Each exported cycle is
+// scored against the product's trailing norm before it is folded into that norm:
+//   volume = instabuy_moving_week_volume + instasell_moving_week_volume
+//   spread = instabuy_price_average - instasell_price_average
+//   <metric>_vs_baseline = (x - mean) / stddev
+//
+// The baseline is an exponentially weighted mean and variance, updated once per cycle with
+// d = x - mean:
+//   mean     += alpha * d
+//   variance  = (1 - alpha) * (variance + alpha * d * d)
+// so a cycle's weight halves roughly every 0.69 / alpha cycles (about 7 at the default 0.1). A
+// product's first cycle seeds the mean with zero variance. Scores stay None until a product has
+// MIN_CYCLES cycles behind it, and whenever the stddev is zero. A cycle is folded in and the store
+// rewritten only once the cycle is committed, so one kept for a retry after a failed export isn't
+// counted twice; products that stop appearing keep their last baseline.
+//
+// The store also keeps each product's last detected pattern, and `detection_stability` compares
+// this cycle's pattern against it: the mean of five similarities in [0, 1], namely whether the
+// detection method matched, and min/max of the instabuy and instasell modal size and pattern rate
+// (1 when both are 0). A pattern that keeps repeating scores near 1; one that jumps around from
+// hour to hour scores low and deserves less trust. It is None for a product's first detected
+// cycle and whenever detection didn't run, which also leaves the stored pattern alone.
+use crate::atomic::write_atomic;
+use crate::AnalysisResult;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+const MIN_CYCLES: usize = 3;
+
+// (volume, spread) of a cycle, or None when either isn't finite
+fn cycle_metrics(result: &AnalysisResult) -> Option<(f64, f64)> {
+    let volume = result.instabuy_moving_week_volume + result.instasell_moving_week_volume;
+    let spread = result.instabuy_price_average - result.instasell_price_average;
+    (volume.is_finite() && spread.is_finite()).then_some((volume, spread))
+}
+
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+struct Moments {
+    mean: f64,
+    variance: f64,
+}
+
+impl Moments {
+    fn score(&self, x: f64) -> Option<f64> {
+        let stddev = self.variance.sqrt();
+        (stddev > 0.0).then(|| (x - self.mean) / stddev).filter(|z| z.is_finite())
+    }
+
+    fn update(&mut self, x: f64, alpha: f64) {
+        let d = x - self.mean;
+        self.mean += alpha * d;
+        self.variance = (1.0 - alpha) * (self.variance + alpha * d * d);
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct PatternFingerprint {
+    method: String,
+    buy_size: f64,
+    buy_per_hour: f64,
+    sell_size: f64,
+    sell_per_hour: f64,
+}
+
+impl PatternFingerprint {
+    // None when detection didn't run for the product this cycle
+    fn of(result: &AnalysisResult) -> Option<Self> {
+        let method = &result.pattern_details.detection_method;
+        (!method.starts_with("skipped") && method != "below_pattern_threshold").then(|| Self {
+            method: method.clone(),
+            buy_size: result.instabuy_modal_size,
+            buy_per_hour: result.instabuy_pattern_per_hour,
+            sell_size: result.instasell_modal_size,
+            sell_per_hour: result.instasell_pattern_per_hour,
+        })
+    }
+
+    fn stability(&self, previous: &Self) -> f64 {
+        let similarity = |a: f64, b: f64| {
+            let (low, high) = (a.abs().min(b.abs()), a.abs().max(b.abs()));
+            if high == 0.0 { 1.0 } else { low / high }
+        };
+        let scores = [
+            if self.method == previous.method { 1.0 } else { 0.0 },
+            similarity(self.buy_size, previous.buy_size),
+            similarity(self.buy_per_hour, previous.buy_per_hour),
+            similarity(self.sell_size, previous.sell_size),
+            similarity(self.sell_per_hour, previous.sell_per_hour),
+        ];
+        scores.iter().sum::<f64>() / scores.len() as f64
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+struct ProductBaseline {
+    cycles: usize,
+    volume: Moments,
+    spread: Moments,
+    #[serde(default)]
+    last_pattern: Option<PatternFingerprint>,
+}
+
+pub struct BaselineStore {
+    path: PathBuf,
+    alpha: f64,
+    products: BTreeMap<String, ProductBaseline>,
+}
+
+impl BaselineStore {
+    // A missing file starts every product from scratch
+    pub fn load(path: &Path, alpha: f64) -> Result<Self, Box<dyn Error>> {
+        let products = match fs::read(path) {
+            Ok(bytes) => serde_json::from_slice(&bytes)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => BTreeMap::new(),
+            Err(e) => return Err(e.into()),
+        };
+        Ok(Self { path: path.to_path_buf(), alpha, products })
+    }
+
+    // Fills the *_vs_baseline fields and `detection_stability` without touching the store, so a
+    // cycle that is kept for a retry can be scored again
+    pub fn score(&self, results: &mut [AnalysisResult]) {
+        for result in results {
+            let Some(baseline) = self.products.get(&result.product_id) else { continue };
+            if let Some(pattern) = PatternFingerprint::of(result) {
+                result.detection_stability = baseline.last_pattern.as_ref().map(|previous| pattern.stability(previous));
+            }
+            if let Some((volume, spread)) = cycle_metrics(result).filter(|_| baseline.cycles >= MIN_CYCLES) {
+                result.volume_vs_baseline = baseline.volume.score(volume);
+                result.spread_vs_baseline = baseline.spread.score(spread);
+            }
+        }
+    }
+
+    // Folds a committed cycle into each product's baseline; call it once per cycle, after `score`
+    pub fn fold(&mut self, results: &[AnalysisResult]) {
+        for result in results {
+            if let Some(pattern) = PatternFingerprint::of(result) {
+                self.products.entry(result.product_id.clone()).or_default().last_pattern = Some(pattern);
+            }
+            let Some((volume, spread)) = cycle_metrics(result) else { continue };
+            let baseline = self.products.entry(result.product_id.clone()).or_default();
+            if baseline.cycles == 0 {
+                baseline.volume = Moments { mean: volume, variance: 0.0 };
+                baseline.spread = Moments { mean: spread, variance: 0.0 };
+            } else {
+                baseline.volume.update(volume, self.alpha);
+                baseline.spread.update(spread, self.alpha);
+            }
+            baseline.cycles += 1;
+        }
+    }
+
+    pub fn save(&self) -> Result<(), Box<dyn Error>> {
+        write_atomic(&self.path, &serde_json::to_vec(&self.products)?)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::replay::replay_cycle;
+
+    // A cycle with its volume and spread scaled, to simulate other hours
+    fn scaled(mut results: Vec<AnalysisResult>, volume: f64, spread: f64) -> Vec<AnalysisResult> {
+        for result in &mut results {
+            result.instabuy_moving_week_volume *= volume;
+            result.instasell_moving_week_volume *= volume;
+            let mid = (result.instabuy_price_average + result.instasell_price_average) / 2.0;
+            let half = (result.instabuy_price_average - result.instasell_price_average) / 2.0 * spread;
+            result.instabuy_price_average = mid + half;
+            result.instasell_price_average = mid - half;
+        }
+        results
+    }
+
+    // A committed cycle, as the processor handles it
+    fn apply(store: &mut BaselineStore, results: &mut [AnalysisResult]) {
+        store.score(results);
+        store.fold(results);
+    }
+
+    #[tokio::test]
+    async fn scores_normal_and_anomalous_cycles_against_the_trailing_baseline() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("baseline.json");
+        let mut store = BaselineStore::load(&path, 0.1).unwrap();
+        let diamond = |results: &[AnalysisResult]| {
+            let r = results.iter().find(|r| r.product_id == "ENCHANTED_DIAMOND").unwrap();
+            (r.volume_vs_baseline, r.spread_vs_baseline)
+        };
+
+        // No baseline yet: the first MIN_CYCLES cycles only build it
+        for (i, factor) in [1.0, 1.1, 0.9].into_iter().enumerate() {
+            let mut results = scaled(replay_cycle().await, factor, 2.0 - factor);
+            apply(&mut store, &mut results);
+            assert_eq!(diamond(&results), (None, None), "cycle {}", i);
+        }
+        store.save().unwrap();
+
+        // A reloaded store keeps scoring where it left off
+        let mut store = BaselineStore::load(&path, 0.1).unwrap();
+        let mut normal = scaled(replay_cycle().await, 1.0, 1.0);
+        apply(&mut store, &mut normal);
+        let (volume, spread) = diamond(&normal);
+        assert!(volume.unwrap().abs() < 1.0 && spread.unwrap().abs() < 1.0, "{:?}", diamond(&normal));
+
+        let mut anomalous = scaled(replay_cycle().await, 10.0, -1.0);
+        apply(&mut store, &mut anomalous);
+        let (volume, spread) = diamond(&anomalous);
+        assert!(volume.unwrap() > 10.0 && spread.unwrap() < -10.0, "{:?}", diamond(&anomalous));
+    }
+
+    #[tokio::test]
+    async fn a_repeating_pattern_is_stable() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut store = BaselineStore::load(&dir.path().join("baseline.json"), 0.1).unwrap();
+        let stability = |results: &[AnalysisResult], product: &str| results.iter().find(|r| r.product_id == product).unwrap().detection_stability;
+
+        let mut first = replay_cycle().await;
+        apply(&mut store, &mut first);
+        assert_eq!(stability(&first, "WHEAT"), None);
+        let mut second = replay_cycle().await;
+        apply(&mut store, &mut second);
+        assert_eq!(stability(&second, "WHEAT"), Some(1.0));
+        // Detection never runs for an inactive product, so there's nothing to compare
+        assert_eq!(stability(&second, "DEAD_ITEM"), None);
+    }
+
+    #[tokio::test]
+    async fn an_erratic_pattern_scores_low() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut store = BaselineStore::load(&dir.path().join("baseline.json"), 0.1).unwrap();
+        apply(&mut store, &mut replay_cycle().await);
+
+        // Same product an hour later: another method, sizes and rate far from the last pattern
+        let mut erratic = replay_cycle().await;
+        let wheat = erratic.iter().position(|r| r.product_id == "WHEAT").unwrap();
+        erratic[wheat].pattern_details.detection_method = "legacy_clustering".into();
+        erratic[wheat].instabuy_modal_size *= 10.0;
+        erratic[wheat].instabuy_pattern_per_hour /= 4.0;
+        erratic[wheat].instasell_modal_size *= 5.0;
+        apply(&mut store, &mut erratic);
+        let stability = erratic[wheat].detection_stability.unwrap();
+        assert!(stability < 0.5, "{}", stability);
+    }
+
+    #[tokio::test]
+    async fn scoring_a_retried_cycle_leaves_the_baseline_alone() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut store = BaselineStore::load(&dir.path().join("baseline.json"), 0.1).unwrap();
+        for factor in [1.0, 1.1, 0.9] {
+            apply(&mut store, &mut scaled(replay_cycle().await, factor, 2.0 - factor));
+        }
+        let wheat = |results: &[AnalysisResult]| {
+            let r = results.iter().find(|r| r.product_id == "WHEAT").unwrap();
+            (r.volume_vs_baseline, r.spread_vs_baseline, r.detection_stability)
+        };
+
+        // An export kept for a retry is scored again, against the same baseline, before it's folded
+        let mut attempt = scaled(replay_cycle().await, 3.0, 1.0);
+        store.score(&mut attempt);
+        let mut retry = scaled(replay_cycle().await, 3.0, 1.0);
+        store.score(&mut retry);
+        assert_eq!(wheat(&attempt), wheat(&retry));
+        assert!(wheat(&retry).0.is_some());
+        assert_eq!(store.products["WHEAT"].cycles, 3);
+        store.fold(&retry);
+        assert_eq!(store.products["WHEAT"].cycles, 4);
+    }
+
+    #[test]
+    fn update_rule_tracks_an_exponentially_weighted_mean_and_variance() {
+        let mut moments = Moments { mean: 10.0, variance: 0.0 };
+        moments.update(20.0, 0.5);
+        assert_eq!((moments.mean, moments.variance), (15.0, 25.0));
+        assert_eq!(moments.score(20.0), Some(1.0));
+        assert_eq!(Moments { mean: 3.0, variance: 0.0 }.score(4.0), None);
+    }
+}
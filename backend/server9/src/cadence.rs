@@ -0,0 +1,130 @@
+// Learns how often the bazaar API actually publishes, from the spacing of distinct `Last-Modified`
+// values. Polling faster than that only yields unchanged responses, and polling slower skips
+// updates. With AUTO_POLL_INTERVAL=1 the poll interval follows the learned cadence.
+//
+// POLL_FAST_INTERVAL_SECONDS instead polls at a fixed interval well under the cadence. A window is
+// still only committed when `Last-Modified` changes, so deltas are timed to within one fast poll
+// of the actual update. The unchanged polls skipped before each window are counted and reported
+// per cycle as a `SkipReport`.
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+pub type SharedCadence = Arc<Mutex<CadenceTracker>>;
+
+// Intervals kept for the median; about ten minutes at the usual ~20 s cadence
+const MAX_INTERVALS: usize = 30;
+// Too few intervals and a single irregular update would set the cadence
+const MIN_INTERVALS: usize = 3;
+// Poll this fraction of the cadence, so a slightly early update is still caught on the next poll
+const POLL_FRACTION: f64 = 0.9;
+
+#[derive(Debug, Default)]
+pub struct CadenceTracker {
+    last_update: Option<u64>,
+    intervals: VecDeque<u64>,
+}
+
+#[derive(Debug, Clone, Serialize, PartialEq)]
+pub struct CadenceReport {
+    pub cadence_secs: Option<f64>,
+    pub samples: usize,
+}
+
+impl CadenceTracker {
+    // Records a snapshot's `Last-Modified` (unix seconds); repeats and older values are ignored
+    pub fn observe(&mut self, last_modified: u64) {
+        match self.last_update {
+            Some(last) if last_modified <= last => return,
+            Some(last) => {
+                if self.intervals.len() == MAX_INTERVALS {
+                    self.intervals.pop_front();
+                }
+                self.intervals.push_back(last_modified - last);
+            }
+            None => {}
+        }
+        self.last_update = Some(last_modified);
+    }
+
+    // Median spacing of recent updates; the median so one missed update (a double interval) doesn't skew it
+    pub fn cadence_secs(&self) -> Option<f64> {
+        if self.intervals.len() < MIN_INTERVALS {
+            return None;
+        }
+        let mut sorted: Vec<u64> = self.intervals.iter().copied().collect();
+        sorted.sort_unstable();
+        let mid = sorted.len() / 2;
+        Some(if sorted.len().is_multiple_of(2) { (sorted[mid - 1] + sorted[mid]) as f64 / 2.0 } else { sorted[mid] as f64 })
+    }
+
+    // Just faster than the learned cadence, never under a second; `configured` until one is learned
+    pub fn poll_interval(&self, configured: Duration) -> Duration {
+        self.cadence_secs()
+            .map_or(configured, |cadence| Duration::from_secs_f64((cadence * POLL_FRACTION).max(1.0)))
+    }
+
+    pub fn report(&self) -> CadenceReport {
+        CadenceReport { cadence_secs: self.cadence_secs(), samples: self.intervals.len() }
+    }
+}
+
+// Unchanged polls since the last committed window
+#[derive(Debug, Default)]
+pub struct PollSkips {
+    pending: u32,
+}
+
+impl PollSkips {
+    pub fn skip(&mut self) {
+        self.pending += 1;
+    }
+
+    // A window was committed; returns the polls skipped before it
+    pub fn commit(&mut self) -> u32 {
+        std::mem::take(&mut self.pending)
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct SkipReport {
+    pub windows: usize,
+    pub polls_skipped: u64,
+    pub max_per_window: u32,
+}
+
+impl SkipReport {
+    pub fn add(&mut self, skipped: u32) {
+        self.windows += 1;
+        self.polls_skipped += u64::from(skipped);
+        self.max_per_window = self.max_per_window.max(skipped);
+    }
+
+    pub fn mean_per_window(&self) -> f64 {
+        self.polls_skipped as f64 / self.windows.max(1) as f64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cadence_is_the_median_spacing_of_distinct_updates() {
+        let mut tracker = CadenceTracker::default();
+        let configured = Duration::from_secs(5);
+        for t in [1_000, 1_020, 1_020, 1_040] {
+            tracker.observe(t);
+        }
+        assert_eq!(tracker.cadence_secs(), None);
+        assert_eq!(tracker.poll_interval(configured), configured);
+
+        // A missed update (40 s) and a late one (23 s) don't move the median off 20 s
+        for t in [1_060, 1_100, 1_123, 1_143, 1_110] {
+            tracker.observe(t);
+        }
+        assert_eq!(tracker.report(), CadenceReport { cadence_secs: Some(20.0), samples: 6 });
+        assert_eq!(tracker.poll_interval(configured), Duration::from_secs(18));
+    }
+}
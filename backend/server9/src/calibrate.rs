@@ -0,0 +1,144 @@
+// Calibration of `pattern_detection_confidence` against labelled data: a corpus whose products
+// have known modal sizes (synthetic corpora with embedded patterns, or hand-validated captures).
+// Products are binned by reported confidence and each bin reports how often the detection was
+// actually right, so "70%" can be checked against the observed hit rate.
+use crate::{AnalysisResult, ProductMetricsState};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt::Write;
+
+// Expected modal size per side; a missing or null side means "no pattern should be detected"
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct TruthLabel {
+    #[serde(default)]
+    pub instabuy_modal_size: Option<f64>,
+    #[serde(default)]
+    pub instasell_modal_size: Option<f64>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct CalibrationBin {
+    pub confidence_low: f64,
+    pub confidence_high: f64,
+    pub count: usize,
+    pub correct: usize,
+    pub mean_confidence: Option<f64>,
+    pub accuracy: Option<f64>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct CalibrationReport {
+    pub products_scored: usize,
+    // Count-weighted gap between accuracy and mean confidence (as a fraction); 0 is perfectly calibrated
+    pub expected_calibration_error: Option<f64>,
+    pub bins: Vec<CalibrationBin>,
+}
+
+pub fn load_truth(bytes: &[u8]) -> Result<HashMap<String, TruthLabel>, Box<dyn Error>> {
+    Ok(serde_json::from_slice(bytes)?)
+}
+
+fn side_correct(expected: Option<f64>, detected: f64, tolerance: f64) -> bool {
+    match expected {
+        Some(size) => detected > 0.0 && ProductMetricsState::relative_diff(size, detected) <= tolerance,
+        None => detected == 0.0,
+    }
+}
+
+// One (confidence, correct) pair per labelled product; products without a label are ignored
+pub fn score_results(results: &[AnalysisResult], truth: &HashMap<String, TruthLabel>, tolerance: f64) -> Vec<(f64, bool)> {
+    results.iter()
+        .filter_map(|result| {
+            let label = truth.get(&result.product_id)?;
+            let correct = side_correct(label.instabuy_modal_size, result.instabuy_modal_size, tolerance)
+                && side_correct(label.instasell_modal_size, result.instasell_modal_size, tolerance);
+            Some((result.pattern_detection_confidence, correct))
+        })
+        .collect()
+}
+
+// Equal-width bins over the 0-100 confidence range; a confidence of exactly 100 lands in the last bin
+pub fn calibration_curve(observations: &[(f64, bool)], bins: usize) -> CalibrationReport {
+    let bins = bins.max(1);
+    let width = 100.0 / bins as f64;
+    let mut sums = vec![(0usize, 0usize, 0.0f64); bins];
+
+    for &(confidence, correct) in observations.iter().filter(|(c, _)| c.is_finite()) {
+        let index = ((confidence.clamp(0.0, 100.0) / width) as usize).min(bins - 1);
+        let (count, hits, total) = &mut sums[index];
+        *count += 1;
+        *hits += correct as usize;
+        *total += confidence;
+    }
+
+    let scored: usize = sums.iter().map(|(count, _, _)| count).sum();
+    let bins: Vec<CalibrationBin> = sums.into_iter().enumerate()
+        .map(|(i, (count, correct, total))| CalibrationBin {
+            confidence_low: i as f64 * width,
+            confidence_high: (i + 1) as f64 * width,
+            count,
+            correct,
+            mean_confidence: (count > 0).then(|| total / count as f64),
+            accuracy: (count > 0).then(|| correct as f64 / count as f64),
+        })
+        .collect();
+
+    let expected_calibration_error = (scored > 0).then(|| {
+        bins.iter()
+            .filter_map(|bin| Some((bin.count, bin.accuracy?, bin.mean_confidence?)))
+            .map(|(count, accuracy, confidence)| count as f64 / scored as f64 * (accuracy - confidence / 100.0).abs())
+            .sum()
+    });
+
+    CalibrationReport { products_scored: scored, expected_calibration_error, bins }
+}
+
+pub fn to_csv(report: &CalibrationReport) -> String {
+    let mut csv = String::from("confidence_low,confidence_high,count,correct,mean_confidence,accuracy\n");
+    let optional = |v: Option<f64>| v.map(|v| v.to_string()).unwrap_or_default();
+    for bin in &report.bins {
+        let _ = writeln!(csv, "{},{},{},{},{},{}", bin.confidence_low, bin.confidence_high, bin.count, bin.correct,
+            optional(bin.mean_confidence), optional(bin.accuracy));
+    }
+    csv
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::replay::replay_cycle;
+
+    #[test]
+    fn curve_bins_by_confidence_and_reports_accuracy() {
+        let observations = [(5.0, false), (15.0, false), (85.0, true), (95.0, true), (100.0, false), (f64::NAN, true)];
+        let report = calibration_curve(&observations, 2);
+
+        assert_eq!(report.products_scored, 5);
+        assert_eq!(report.bins[0], CalibrationBin {
+            confidence_low: 0.0, confidence_high: 50.0, count: 2, correct: 0, mean_confidence: Some(10.0), accuracy: Some(0.0),
+        });
+        assert_eq!(report.bins[1].count, 3);
+        assert_eq!(report.bins[1].mean_confidence, Some(280.0 / 3.0));
+        // 2/5 * |0 - 0.1| + 3/5 * |2/3 - 280/300|
+        let expected = 0.4 * 0.1 + 0.6 * (2.0 / 3.0 - 280.0 / 300.0_f64).abs();
+        assert!((report.expected_calibration_error.unwrap() - expected).abs() < 1e-12);
+
+        let csv = to_csv(&calibration_curve(&[], 2));
+        assert_eq!(csv.lines().nth(1), Some("0,50,0,0,,"));
+    }
+
+    #[tokio::test]
+    async fn replayed_corpus_is_scored_against_labels() {
+        let results = replay_cycle().await;
+        let truth = load_truth(br#"{
+            "ENCHANTED_DIAMOND": { "instabuy_modal_size": 155, "instasell_modal_size": 25 },
+            "DEAD_ITEM": {},
+            "LATE_ITEM": { "instabuy_modal_size": 64 }
+        }"#).unwrap();
+
+        let mut scored = score_results(&results, &truth, 0.1);
+        scored.sort_by(|a, b| a.0.total_cmp(&b.0));
+        assert_eq!(scored, vec![(0.0, true), (50.0, false), (100.0, true)]);
+    }
+}
@@ -0,0 +1,123 @@
+use crate::atomic::write_atomic;
+use crate::config::{Config, BAZAAR_URL};
+use crate::{fetch_raw_snapshot, Endpoint, RawSnapshot};
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::time::sleep;
+
+// Sidecar written next to every captured response as `snapshot_<ts>.meta.json`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CaptureMeta {
+    pub captured_at: u64,
+    pub last_modified: Option<String>,
+}
+
+// Records raw bazaar responses without parsing or analysis, for replay corpora and debugging
+pub async fn run(dir: PathBuf, config: &Config, client: &reqwest::Client) -> Result<(), Box<dyn Error>> {
+    fs::create_dir_all(&dir)?;
+    let mut endpoint = Endpoint::new(BAZAAR_URL.to_string());
+
+    println!("[GiantWizard] Capture mode: writing raw snapshots to {} every {} seconds (keeping {} files).",
+        dir.display(), config.api_poll_interval_secs, config.capture_max_files);
+
+    loop {
+        match fetch_raw_snapshot(client, &mut endpoint).await {
+            Ok(Some(raw)) => match write_capture(&dir, &raw) {
+                Ok(path) => println!("[GiantWizard] Captured {}", path.display()),
+                Err(e) => eprintln!("[GiantWizard] ❌ Capture write error: {}", e),
+            },
+            Ok(None) => {} // Unchanged since last capture
+            Err(e) => eprintln!("[GiantWizard] Capture fetch error: {}", e),
+        }
+
+        match rotate_captures(&dir, config.capture_max_files) {
+            Ok(0) => {}
+            Ok(removed) => println!("[GiantWizard] Rotated out {} old captures", removed),
+            Err(e) => eprintln!("[GiantWizard] Capture rotation error: {}", e),
+        }
+
+        sleep(Duration::from_secs(config.api_poll_interval_secs)).await;
+    }
+}
+
+fn write_capture(dir: &Path, raw: &RawSnapshot) -> io::Result<PathBuf> {
+    let ts = Utc::now().format("%Y%m%d%H%M%S%3f").to_string();
+    let path = dir.join(format!("snapshot_{}.json", ts));
+    let meta = CaptureMeta {
+        captured_at: SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs(),
+        last_modified: raw.last_modified.clone(),
+    };
+
+    // Sidecar first: replay only lists snapshot files, and each one must already have its meta
+    write_atomic(&meta_path(&path), &serde_json::to_vec_pretty(&meta)?)?;
+    write_atomic(&path, &raw.body)?;
+    Ok(path)
+}
+
+pub fn meta_path(snapshot_path: &Path) -> PathBuf {
+    snapshot_path.with_extension("meta.json")
+}
+
+// Captured snapshots in chronological order (the timestamped names sort lexically)
+pub fn list_captures(dir: &Path) -> io::Result<Vec<PathBuf>> {
+    let mut captures: Vec<PathBuf> = fs::read_dir(dir)?
+        .filter_map(|entry| entry.ok().map(|e| e.path()))
+        .filter(|path| {
+            path.file_name()
+                .and_then(|n| n.to_str())
+                .is_some_and(|n| n.starts_with("snapshot_") && n.ends_with(".json") && !n.ends_with(".meta.json"))
+        })
+        .collect();
+    captures.sort();
+    Ok(captures)
+}
+
+// Deletes the oldest captures (and their sidecars) beyond `max_files`
+fn rotate_captures(dir: &Path, max_files: usize) -> io::Result<usize> {
+    let captures = list_captures(dir)?;
+    let excess = captures.len().saturating_sub(max_files);
+    for path in &captures[..excess] {
+        fs::remove_file(path)?;
+        let _ = fs::remove_file(meta_path(path));
+    }
+    Ok(excess)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rotation_keeps_newest_captures_and_their_sidecars() {
+        let dir = tempfile::tempdir().unwrap();
+        for i in 0..5 {
+            let raw = RawSnapshot { body: format!("{{\"n\":{}}}", i).into(), last_modified: None };
+            let path = dir.path().join(format!("snapshot_2025010100000{}000.json", i));
+            fs::write(&path, &raw.body).unwrap();
+            fs::write(meta_path(&path), "{}").unwrap();
+        }
+
+        assert_eq!(rotate_captures(dir.path(), 3).unwrap(), 2);
+        let remaining = list_captures(dir.path()).unwrap();
+        assert_eq!(remaining.len(), 3);
+        assert!(remaining[0].ends_with("snapshot_20250101000002000.json"));
+        assert!(!meta_path(&dir.path().join("snapshot_20250101000000000.json")).exists());
+        assert!(meta_path(&remaining[0]).exists());
+    }
+
+    #[test]
+    fn write_capture_records_last_modified_sidecar() {
+        let dir = tempfile::tempdir().unwrap();
+        let raw = RawSnapshot { body: bytes::Bytes::from_static(b"{\"products\":{}}"), last_modified: Some("Tue, 01 Jan 2025 00:00:00 GMT".into()) };
+
+        let path = write_capture(dir.path(), &raw).unwrap();
+        assert_eq!(fs::read(&path).unwrap(), raw.body);
+        let meta: CaptureMeta = serde_json::from_slice(&fs::read(meta_path(&path)).unwrap()).unwrap();
+        assert_eq!(meta.last_modified.as_deref(), Some("Tue, 01 Jan 2025 00:00:00 GMT"));
+    }
+}
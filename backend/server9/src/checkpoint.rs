@@ -0,0 +1,126 @@
+// Crash recovery for the in-progress cycle (CHECKPOINT_DIR). The processor writes every product's
+// ProductMetricsState, with the deltas, histories and running totals collected so far, to
+// `state_checkpoint.json` every CHECKPOINT_EVERY snapshots and after each cycle reset. On startup
+// it resumes from that file, so a restart 50 minutes in loses at most CHECKPOINT_EVERY snapshots
+// rather than the whole cycle. Each save serializes the whole state, so the default keeps it to
+// one every 15 snapshots; the processor copies the states under the lock and writes them off it.
+// The floats come back bit-identical (serde_json's float_roundtrip), so a resumed cycle finalizes
+// exactly like one that never stopped.
+//
+// A checkpoint is rejected, and the processor starts fresh, when:
+//   - it was written by a build with another CHECKPOINT_VERSION (the state's layout changed)
+//   - it was collected under another window layout: a different `target_windows`, or fixed vs
+//     sliding mode, whose cycles would be cut at the wrong length
+//   - its last snapshot is older than CHECKPOINT_MAX_AGE_SECONDS: the first window after the
+//     restart would span the whole outage
+use crate::atomic::write_atomic;
+use crate::ProductMetricsState;
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+const CHECKPOINT_FILE: &str = "state_checkpoint.json";
+// Bump whenever ProductMetricsState or anything it holds changes shape
+const CHECKPOINT_VERSION: u32 = 2;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct WindowLayout {
+    pub target_windows: usize,
+    pub sliding: bool,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Checkpoint<'a> {
+    version: u32,
+    layout: WindowLayout,
+    // captured_at of the last snapshot applied
+    saved_at: u64,
+    windows_since_emit: usize,
+    products: Cow<'a, HashMap<String, ProductMetricsState>>,
+}
+
+pub struct Restored {
+    pub products: HashMap<String, ProductMetricsState>,
+    pub saved_at: u64,
+    pub windows_since_emit: usize,
+}
+
+pub fn save(dir: &Path, layout: WindowLayout, saved_at: u64, windows_since_emit: usize, products: &HashMap<String, ProductMetricsState>) -> Result<(), Box<dyn Error>> {
+    let checkpoint = Checkpoint { version: CHECKPOINT_VERSION, layout, saved_at, windows_since_emit, products: Cow::Borrowed(products) };
+    fs::create_dir_all(dir)?;
+    write_atomic(&dir.join(CHECKPOINT_FILE), &serde_json::to_vec(&checkpoint)?)?;
+    Ok(())
+}
+
+// None when there is no checkpoint yet
+pub fn load(dir: &Path, layout: WindowLayout, now: u64, max_age_secs: Option<u64>) -> Result<Option<Restored>, Box<dyn Error>> {
+    let bytes = match fs::read(dir.join(CHECKPOINT_FILE)) {
+        Ok(bytes) => bytes,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    // Checked before the products, which an older layout may not even parse as
+    #[derive(Deserialize)]
+    struct Header {
+        version: u32,
+    }
+    let header: Header = serde_json::from_slice(&bytes)?;
+    if header.version != CHECKPOINT_VERSION {
+        return Err(format!("checkpoint version {} is not supported (this build writes {})", header.version, CHECKPOINT_VERSION).into());
+    }
+    let checkpoint: Checkpoint = serde_json::from_slice(&bytes)?;
+    if checkpoint.layout != layout {
+        return Err(format!("collected with {} windows ({}), configured for {} ({})",
+            checkpoint.layout.target_windows, mode(checkpoint.layout), layout.target_windows, mode(layout)).into());
+    }
+    let age = now.saturating_sub(checkpoint.saved_at);
+    if let Some(max_age) = max_age_secs.filter(|&max_age| age > max_age) {
+        return Err(format!("last snapshot is {}s old, more than {}s", age, max_age).into());
+    }
+    Ok(Some(Restored {
+        products: checkpoint.products.into_owned(),
+        saved_at: checkpoint.saved_at,
+        windows_since_emit: checkpoint.windows_since_emit,
+    }))
+}
+
+fn mode(layout: WindowLayout) -> &'static str {
+    if layout.sliding { "sliding" } else { "fixed" }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::AnalysisConfig;
+    use crate::finalize_all;
+    use crate::replay::replay_states;
+
+    #[tokio::test]
+    async fn restored_states_finalize_identically_and_mismatches_are_rejected() {
+        let states = replay_states(&AnalysisConfig::default()).await;
+        let dir = tempfile::tempdir().unwrap();
+        let layout = WindowLayout { target_windows: 180, sliding: false };
+        assert!(load(dir.path(), layout, 1_000, None).unwrap().is_none());
+
+        save(dir.path(), layout, 1_000, 0, &states).unwrap();
+        let restored = load(dir.path(), layout, 1_300, Some(600)).unwrap().unwrap();
+        let analysis = AnalysisConfig::default();
+        let (expected, actual) = (finalize_all(&states, &analysis), finalize_all(&restored.products, &analysis));
+        assert_eq!(serde_json::to_value(&actual).unwrap(), serde_json::to_value(&expected).unwrap());
+
+        let error = |layout, now| load(dir.path(), layout, now, Some(600)).err().unwrap().to_string();
+        assert_eq!(error(WindowLayout { target_windows: 90, sliding: false }, 1_000), "collected with 180 windows (fixed), configured for 90 (fixed)");
+        assert_eq!(error(WindowLayout { sliding: true, ..layout }, 1_000), "collected with 180 windows (fixed), configured for 180 (sliding)");
+        assert_eq!(error(layout, 2_000), "last snapshot is 1000s old, more than 600s");
+
+        let path = dir.path().join(CHECKPOINT_FILE);
+        let mut checkpoint: serde_json::Value = serde_json::from_slice(&fs::read(&path).unwrap()).unwrap();
+        checkpoint["version"] = 0.into();
+        fs::write(&path, checkpoint.to_string()).unwrap();
+        assert!(error(layout, 1_000).starts_with("checkpoint version 0 is not supported"));
+    }
+}
@@ -0,0 +1,67 @@
+// Every export file gets a `<file>.sha256` sidecar in `sha256sum` format ("<hex>  <name>\n"), so
+// `sha256sum -c` can check it as well as `--verify`. The hash is taken from the file as it landed
+// on disk, i.e. the exact bytes the engine uploads. The engine uploads the sidecar right after
+// its file (see export.cpp), so a consumer can compare what it downloaded against what was written.
+use crate::atomic::write_atomic;
+use sha2::{Digest, Sha256};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+pub fn sha256_hex(bytes: &[u8]) -> String {
+    Sha256::digest(bytes).iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+pub fn sidecar_path(path: &Path) -> PathBuf {
+    let mut sidecar = path.as_os_str().to_owned();
+    sidecar.push(".sha256");
+    PathBuf::from(sidecar)
+}
+
+// Hashes `path` as it is now on disk and writes its sidecar, returning the hash
+pub fn record(path: &Path) -> io::Result<String> {
+    let hash = sha256_hex(&fs::read(path)?);
+    let name = path.file_name().map(|n| n.to_string_lossy().into_owned()).unwrap_or_default();
+    write_atomic(&sidecar_path(path), format!("{}  {}\n", hash, name).as_bytes())?;
+    Ok(hash)
+}
+
+// Compares `bytes` (the contents of `path`) with its sidecar: Ok(None) when there is no sidecar,
+// Ok(Some(hash)) when it matches
+pub fn check(path: &Path, bytes: &[u8]) -> Result<Option<String>, String> {
+    let sidecar = sidecar_path(path);
+    let recorded = match fs::read_to_string(&sidecar) {
+        Ok(recorded) => recorded,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(format!("{}: {}", sidecar.display(), e)),
+    };
+    let expected = recorded.split_whitespace().next().unwrap_or_default();
+    let actual = sha256_hex(bytes);
+    if expected.eq_ignore_ascii_case(&actual) {
+        Ok(Some(actual))
+    } else {
+        Err(format!("sha256 is {} but {} records {}", actual, sidecar.display(), expected))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn a_one_byte_change_no_longer_matches_the_recorded_hash() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("metrics_20250101000000.json");
+        assert_eq!(check(&path, b"[]"), Ok(None));
+
+        fs::write(&path, b"[{\"product_id\":\"WHEAT\"}]").unwrap();
+        let hash = record(&path).unwrap();
+        assert_eq!(fs::read_to_string(sidecar_path(&path)).unwrap(), format!("{}  metrics_20250101000000.json\n", hash));
+        assert_eq!(sha256_hex(b""), "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855");
+
+        let mut bytes = fs::read(&path).unwrap();
+        assert_eq!(check(&path, &bytes), Ok(Some(hash)));
+        bytes[3] ^= 1;
+        assert!(check(&path, &bytes).unwrap_err().contains("records"));
+    }
+}
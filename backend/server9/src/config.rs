@@ -0,0 +1,819 @@
+use crate::alias::ProductAliases;
+use crate::filter::{glob_match, ProductFilter, StackSizes};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::fmt::Display;
+use std::str::FromStr;
+
+pub const BAZAAR_URL: &str = "https://api.hypixel.net/v2/skyblock/bazaar";
+const DEFAULT_USER_AGENT: &str = concat!("wiz/", env!("CARGO_PKG_VERSION"), " (+https://github.com/GiantWizard/wiz)");
+
+// Thresholds shared by the velocity, rhythm and legacy detectors
+#[derive(Debug, Clone, Serialize)]
+pub struct DetectorConfig {
+    // Smallest group of similar observations that counts as a pattern, used by every detector
+    pub min_cluster_size: usize,
+    // Relative tolerances tried by the rhythm detector; each must lie in (0, 1)
+    pub rhythm_tolerances: Vec<f64>,
+    // Hand-tuned settings for specific products, loaded from DETECTOR_OVERRIDES_PATH (see
+    // `for_product`)
+    pub overrides: BTreeMap<String, DetectorOverride>,
+}
+
+// Any subset of the detector settings, merged over the global ones for matching products
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DetectorOverride {
+    pub min_cluster_size: Option<usize>,
+    pub rhythm_tolerances: Option<Vec<f64>>,
+}
+
+impl Default for DetectorConfig {
+    fn default() -> Self {
+        Self {
+            min_cluster_size: 3,
+            rhythm_tolerances: vec![0.25, 0.5],
+            overrides: BTreeMap::new(),
+        }
+    }
+}
+
+impl DetectorConfig {
+    // The overrides file is a JSON object keyed by product id or `*` category pattern, e.g.
+    //   { "ENCHANTED_*": { "min_cluster_size": 4 }, "ENCHANTED_DIAMOND": { "rhythm_tolerances": [0.1] } }
+    pub fn load_overrides(path: &str) -> Result<BTreeMap<String, DetectorOverride>, String> {
+        let text = std::fs::read_to_string(path).map_err(|e| format!("DETECTOR_OVERRIDES_PATH {}: {}", path, e))?;
+        let overrides: BTreeMap<String, DetectorOverride> = serde_json::from_str(&text)
+            .map_err(|e| format!("DETECTOR_OVERRIDES_PATH {}: {}", path, e))?;
+        Ok(overrides.into_iter().map(|(key, value)| (key.to_uppercase(), value)).collect())
+    }
+
+    // The settings for one product. Each field comes from the first of: the product's own entry,
+    // the most specific (longest) matching category pattern, then the global setting.
+    pub fn for_product(&self, product_id: &str) -> Cow<'_, DetectorConfig> {
+        let item = self.overrides.get(product_id);
+        let category = self.overrides.iter()
+            .filter(|(pattern, _)| pattern.contains('*') && glob_match(pattern, product_id))
+            .max_by_key(|(pattern, _)| pattern.len())
+            .map(|(_, value)| value);
+        if item.is_none() && category.is_none() {
+            return Cow::Borrowed(self);
+        }
+        Cow::Owned(DetectorConfig {
+            min_cluster_size: item.and_then(|o| o.min_cluster_size)
+                .or_else(|| category.and_then(|o| o.min_cluster_size))
+                .unwrap_or(self.min_cluster_size),
+            rhythm_tolerances: item.and_then(|o| o.rhythm_tolerances.clone())
+                .or_else(|| category.and_then(|o| o.rhythm_tolerances.clone()))
+                .unwrap_or_else(|| self.rhythm_tolerances.clone()),
+            overrides: BTreeMap::new(),
+        })
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        self.validate_settings()?;
+        for key in self.overrides.keys() {
+            self.for_product(key).validate_settings().map_err(|e| format!("{} (detector override for {})", e, key))?;
+        }
+        Ok(())
+    }
+
+    fn validate_settings(&self) -> Result<(), String> {
+        if self.min_cluster_size < 2 {
+            return Err(format!("DETECTOR_MIN_CLUSTER_SIZE must be at least 2, got {}", self.min_cluster_size));
+        }
+        if let Some(bad) = self.rhythm_tolerances.iter().find(|t| !(**t > 0.0 && **t < 1.0)) {
+            return Err(format!("DETECTOR_RHYTHM_TOLERANCES must all lie in (0, 1), got {}", bad));
+        }
+        Ok(())
+    }
+}
+
+// When the alert webhook fires (see alert.rs); a threshold of 0 disables its rule
+#[derive(Debug, Clone, Serialize)]
+pub struct AlertRules {
+    pub export_failures: u32,
+    pub spike_magnitude: f64,
+    pub spread_fraction: f64,
+    // How long an event key stays quiet after it was delivered
+    pub cooldown_secs: u64,
+}
+
+impl Default for AlertRules {
+    fn default() -> Self {
+        Self {
+            export_failures: 3,
+            spike_magnitude: 20.0,
+            spread_fraction: 1.0,
+            cooldown_secs: 3600,
+        }
+    }
+}
+
+// How `delta_sequences.timestamps` are written: unix seconds (compact, the default) or RFC3339 strings
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize)]
+pub enum TimestampFormat {
+    #[default]
+    Epoch,
+    Rfc3339,
+}
+
+impl FromStr for TimestampFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "epoch" => Ok(Self::Epoch),
+            "rfc3339" => Ok(Self::Rfc3339),
+            other => Err(format!("Unknown timestamp format: {}", other)),
+        }
+    }
+}
+
+// How much of `delta_sequences` is written: all of it, none (`null`), or every Kth element. Detection
+// always runs on the full sequences; only the output shrinks. `--recompute` needs full sequences
+// and skips products exported with either of the reduced forms.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize)]
+pub enum DeltaSequenceOutput {
+    #[default]
+    Full,
+    Omit,
+    Every(usize),
+}
+
+impl FromStr for DeltaSequenceOutput {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "full" => Ok(Self::Full),
+            "omit" => Ok(Self::Omit),
+            other => match other.strip_prefix("every:").map(str::parse) {
+                Some(Ok(1)) => Ok(Self::Full),
+                Some(Ok(k)) if k > 1 => Ok(Self::Every(k)),
+                _ => Err(format!("Unknown delta sequence output: {} (expected full, omit or every:K)", other)),
+            },
+        }
+    }
+}
+
+// How player transactions are counted from book diffs at levels whose amount fell (see
+// `EventCounts` for the ambiguous cases): one per level, by the drop in order count, or by
+// order count with a partial fill counting once. Inferred volume is the amount drop in every mode.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize)]
+pub enum EventInference {
+    #[default]
+    Amount,
+    Orders,
+    Combined,
+}
+
+impl FromStr for EventInference {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "amount" => Ok(Self::Amount),
+            "orders" => Ok(Self::Orders),
+            "combined" => Ok(Self::Combined),
+            other => Err(format!("Unknown event inference: {}", other)),
+        }
+    }
+}
+
+// How an hourly export is split into files: one file (the default), `hash:N` shards by product-id
+// hash, or `size:BYTES` shards of at most about that many bytes. Each shard is pushed on its own,
+// so a failed push only leaves that shard pending, and a manifest lists every shard.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize)]
+pub enum ExportSharding {
+    #[default]
+    Single,
+    Hash(usize),
+    MaxBytes(u64),
+}
+
+impl FromStr for ExportSharding {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let lower = s.to_lowercase();
+        if lower == "single" {
+            return Ok(Self::Single);
+        }
+        if let Some(count) = lower.strip_prefix("hash:") {
+            return match count.parse() {
+                Ok(1) => Ok(Self::Single),
+                Ok(n) if n > 1 => Ok(Self::Hash(n)),
+                _ => Err(format!("Shard count must be a positive integer, got {}", count)),
+            };
+        }
+        if let Some(bytes) = lower.strip_prefix("size:") {
+            return match bytes.parse() {
+                Ok(n) if n > 0 => Ok(Self::MaxBytes(n)),
+                _ => Err(format!("Shard size must be a positive byte count, got {}", bytes)),
+            };
+        }
+        Err(format!("Unknown export sharding: {} (expected single, hash:N or size:BYTES)", s))
+    }
+}
+
+// Settings that shape how a cycle's state is turned into an `AnalysisResult`
+#[derive(Debug, Clone, Serialize)]
+pub struct AnalysisConfig {
+    // Windows per cycle (and, in sliding mode, in the trailing buffer). TARGET_WINDOWS, or
+    // ANALYSIS_WINDOW_SECONDS divided by API_POLL_INTERVAL_SECONDS; 180 is an hour at 20-second polls.
+    pub target_windows: usize,
+    // Scale volume totals of a short cycle up to `target_windows`, assuming activity was uniform
+    pub extrapolate_partial_windows: bool,
+    // A side counts as active for `market_activity` only with at least this much moving-week
+    // volume and this many inferred player transactions over the cycle
+    pub activity_min_moving_week_volume: i64,
+    pub activity_min_inferred_events: usize,
+    pub event_inference: EventInference,
+    // A window whose inferred volume exceeds this multiple of the median window is flagged as a spike
+    pub spike_median_multiple: f64,
+    // Product whose average prices the *_price_relative fields are expressed against
+    pub reference_product: Option<String>,
+    pub timestamp_format: TimestampFormat,
+    pub delta_sequence_output: DeltaSequenceOutput,
+    // Largest price range (in coins) over a cycle that still counts as a pinned price
+    pub price_pin_epsilon: f64,
+    // Products with fewer windows than this in the cycle are held back from results as warming up
+    pub min_observations_before_export: usize,
+    // A moving-week counter falling by more than this fraction of itself between two snapshots is a
+    // reset (COUNTER_RESET_FRACTION, in (0, 1]): the window's delta is recorded as 0 and counted in
+    // *_counter_resets rather than kept as a large negative. Smaller drops are the rolling week
+    // ageing out and stay as they are.
+    pub counter_reset_fraction: f64,
+    // Detection runs only for products with a share of active windows above the first, or
+    // moving-week volume above the second; the rest report "skipped_inactive" unless forced
+    pub prefilter_min_active_fraction: f64,
+    pub prefilter_min_moving_week_activity: i64,
+    // Past the prefilter, detection also needs an average spread (instabuy - instasell) above the
+    // first and moving-week volume above the second on the busier side; the rest report
+    // "below_pattern_threshold" unless forced
+    pub pattern_min_spread: f64,
+    pub pattern_min_moving_week_activity: i64,
+    pub force_full_detection: bool,
+    // Report the average standing order volume (quick_status buyVolume/sellVolume)
+    pub standing_volume: bool,
+    pub detector: DetectorConfig,
+}
+
+impl Default for AnalysisConfig {
+    fn default() -> Self {
+        Self {
+            target_windows: 180,
+            extrapolate_partial_windows: false,
+            activity_min_moving_week_volume: 1,
+            activity_min_inferred_events: 1,
+            event_inference: EventInference::Amount,
+            spike_median_multiple: 5.0,
+            reference_product: None,
+            timestamp_format: TimestampFormat::Epoch,
+            delta_sequence_output: DeltaSequenceOutput::Full,
+            price_pin_epsilon: 1e-6,
+            min_observations_before_export: 0,
+            counter_reset_fraction: 0.5,
+            prefilter_min_active_fraction: 0.0,
+            prefilter_min_moving_week_activity: 0,
+            pattern_min_spread: 0.0,
+            pattern_min_moving_week_activity: 0,
+            force_full_detection: false,
+            standing_volume: true,
+            detector: DetectorConfig::default(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum RuntimeFlavor {
+    MultiThread,
+    CurrentThread,
+}
+
+impl FromStr for RuntimeFlavor {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().replace('-', "_").as_str() {
+            "multi_thread" => Ok(Self::MultiThread),
+            "current_thread" => Ok(Self::CurrentThread),
+            other => Err(format!("Unknown runtime flavor: {}", other)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct RuntimeConfig {
+    pub flavor: RuntimeFlavor,
+    // Worker threads for the multi-thread flavor; None keeps tokio's default of one per core
+    pub worker_threads: Option<usize>,
+}
+
+// How JSON outputs are serialized; `Auto` pretty-prints only small outputs
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum OutputFormat {
+    Auto,
+    Pretty,
+    Compact,
+}
+
+impl FromStr for OutputFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "auto" => Ok(Self::Auto),
+            "pretty" => Ok(Self::Pretty),
+            "compact" => Ok(Self::Compact),
+            other => Err(format!("Unknown output format: {}", other)),
+        }
+    }
+}
+
+impl OutputFormat {
+    pub fn is_pretty(self, result_count: usize, pretty_max_results: usize) -> bool {
+        match self {
+            Self::Auto => result_count < pretty_max_results,
+            Self::Pretty => true,
+            Self::Compact => false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Config {
+    pub analysis: AnalysisConfig,
+    pub runtime: RuntimeConfig,
+    pub api_poll_interval_secs: u64,
+    // Bazaar-format endpoints polled concurrently each tick and merged into one snapshot (FETCH_ENDPOINTS,
+    // comma-separated); just the bazaar by default
+    pub fetch_endpoints: Vec<String>,
+    // Follow the learned API update cadence instead of polling at a fixed interval
+    pub auto_poll_interval: bool,
+    // Poll this often instead, committing a window only when the API has updated (see cadence.rs);
+    // overrides API_POLL_INTERVAL_SECONDS and AUTO_POLL_INTERVAL
+    pub poll_fast_interval_secs: Option<f64>,
+    // Parsed snapshots that may wait for processing before fetching blocks
+    pub snapshot_queue_capacity: usize,
+    // Sliding mode: instead of resetting every `target_windows`, keep the trailing `target_windows`
+    // windows per product and emit every this many windows. Memory grows with the window count, at
+    // roughly 260 bytes per window per product (about 47 KB per product, or 65 MB for the ~1,400
+    // bazaar products, at the default 180); fixed mode peaks at the same size just before its reset.
+    pub sliding_emit_every: Option<usize>,
+    // Windows observed since startup before the first export may go out. A cold start otherwise
+    // exports as soon as a cycle is due; a value above `target_windows` makes the first fixed-mode
+    // cycle that much longer. 0 disables the gate.
+    pub warmup_windows: usize,
+    pub export_engine_path: String,
+    pub export_retry_concurrency: usize,
+    pub export_sharding: ExportSharding,
+    // When the metrics volume fills up, prune all but this many already-pushed export cycles
+    pub export_keep_files: usize,
+    // When the remote quota runs out, the export engine prunes all but this many remote cycles. The
+    // engine reads REMOTE_PRUNE_KEEP itself; it is parsed here so a bad value fails at startup
+    pub remote_prune_keep: Option<usize>,
+    // GET /ready fails once more exports than this are waiting for their remote push
+    pub ready_max_pending_exports: usize,
+    // Also insert every exported cycle into this SQLite database, optionally with the sequences
+    pub sqlite_path: Option<String>,
+    pub sqlite_sequences: bool,
+    // Score every cycle against a per-product baseline persisted here (see baseline.rs); alpha is
+    // the weight of each new cycle. In sliding mode every emit counts as a cycle.
+    pub baseline_path: Option<String>,
+    pub baseline_alpha: f64,
+    // Also publish every exported cycle to this NATS server, one message per product on
+    // `<nats_subject>.<product_id>` (see stream.rs)
+    pub nats_url: Option<String>,
+    pub nats_subject: String,
+    pub nats_token: Option<String>,
+    // POST alerts here when `alert_rules` fire
+    pub alert_webhook_url: Option<String>,
+    pub alert_rules: AlertRules,
+    pub http_addr: Option<String>,
+    pub product_filter: ProductFilter,
+    // Order-book amounts counted in stacks are scaled to units (see filter.rs)
+    pub stack_sizes: StackSizes,
+    pub export_query_max_products: usize,
+    pub capture_max_files: usize,
+    // OUTPUT_FORMAT, a comma-separated list (see formatter.rs): the JSON export's style, and every
+    // name in the list for the formats written alongside it. EXPORT_CSV (on unless set to 0) adds
+    // `csv` to the list; turning it off doesn't remove a `csv` listed in OUTPUT_FORMAT.
+    pub output_format: OutputFormat,
+    pub output_formats: Vec<String>,
+    // Wrap exports in {generated_at, meta, results} instead of a bare array
+    pub output_meta: bool,
+    // Under `Auto`, outputs with fewer results than this are pretty-printed
+    pub pretty_max_results: usize,
+    pub user_agent: String,
+    // Optional contact address sent as the `From` header
+    pub http_from: Option<String>,
+    // WIZ_SEED: seed for everything random (see rng.rs); None keeps each consumer's default
+    pub seed: Option<u64>,
+    // JSON file of per-product detector overrides, loaded into `analysis.detector` at startup
+    pub detector_overrides_path: Option<String>,
+    // JSON file of display names and canonical ids (see alias.rs), loaded into `aliases` at startup
+    pub product_aliases_path: Option<String>,
+    pub aliases: ProductAliases,
+    // Expose GET /config (the redacted effective config) and GET /snapshots
+    pub debug_endpoints: bool,
+    // Raw snapshots kept per product for GET /snapshots; each costs about one copy of the product's
+    // order book, so it is off (0) by default
+    pub recent_snapshots: usize,
+    // PROFILE: time the finalize step per product and report it with each export
+    pub profile: bool,
+    // FINALIZE_CONCURRENCY: finalize the live loop's cycles on at most this many blocking-pool
+    // tasks instead of inline on the processor task. Inline is the least total CPU and suits
+    // one-shot modes and quiet runtimes; the pool keeps the HTTP API and fetching responsive
+    // through the end-of-cycle crunch, at the cost of briefly copying each chunk's state.
+    pub finalize_concurrency: Option<usize>,
+    // DELTA_EXPORT_THRESHOLD: write only the products whose fields moved by more than this
+    // fraction since the consumer's copy (see delta.rs), with a full export every DELTA_FULL_EVERY
+    // cycles. Unset writes every cycle in full.
+    pub delta_threshold: Option<f64>,
+    pub delta_full_every: usize,
+    // EXPORT_TIMEOUT_SECONDS: a cycle's finalize + export that runs longer than this is cancelled,
+    // its file left pending for the next export's retries, so a stuck push can't hold up polling
+    // (see process_snapshots). 0 disables it.
+    pub export_timeout_secs: Option<u64>,
+    // PER_PRODUCT_EXPORT: also write `by_product/<product_id>_<stamp>.json` for each product matching
+    // these patterns (comma-separated, `*` for every product); unset writes none. PER_PRODUCT_PUSH
+    // pushes them too, one engine run per file, so it suits a short list. The newest
+    // PER_PRODUCT_KEEP cycles of them are kept on disk.
+    pub per_product_export: Option<ProductFilter>,
+    pub per_product_push: bool,
+    pub per_product_keep: usize,
+    // CHECKPOINT_DIR: save every product's in-progress state here every CHECKPOINT_EVERY snapshots
+    // (15 by default, five minutes at the default poll interval) and resume from it on startup
+    // (see checkpoint.rs). A checkpoint older than
+    // CHECKPOINT_MAX_AGE_SECONDS (0 for no limit) is discarded rather than bridged.
+    pub checkpoint_dir: Option<String>,
+    pub checkpoint_every: usize,
+    pub checkpoint_max_age_secs: Option<u64>,
+    // Settings that were set but don't parse or are out of range, where falling back to the default
+    // would quietly run something else (e.g. an EXPORT_SHARDING this build doesn't know);
+    // check_config refuses them
+    #[serde(skip)]
+    pub invalid: Vec<String>,
+}
+
+impl Config {
+    pub fn from_env() -> Self {
+        Self::from_vars(&Vars::new(&|key| std::env::var(key).ok()))
+    }
+
+    fn from_vars(vars: &Vars) -> Self {
+        let defaults = AnalysisConfig::default();
+        let alert_defaults = AlertRules::default();
+        let mut output_formats: Vec<String> = vars.get("OUTPUT_FORMAT").unwrap_or_default()
+            .split(',')
+            .map(|name| name.trim().to_lowercase())
+            .filter(|name| !name.is_empty())
+            .collect();
+        let json_styles: Vec<&str> = output_formats.iter().map(String::as_str).filter(|name| name.parse::<OutputFormat>().is_ok()).collect();
+        if json_styles.len() > 1 {
+            vars.reject("OUTPUT_FORMAT", &output_formats.join(","), format_args!("lists more than one JSON style ({})", json_styles.join(", ")));
+        }
+        if vars.flag("EXPORT_CSV").unwrap_or(true) && !output_formats.iter().any(|name| name == "csv") {
+            output_formats.push("csv".to_string());
+        }
+        let api_poll_interval_secs = vars.parse("API_POLL_INTERVAL_SECONDS").unwrap_or(20);
+        Self {
+            analysis: AnalysisConfig {
+                target_windows: vars.parse_where("TARGET_WINDOWS", |n| *n > 0, "a positive number of windows")
+                    .or_else(|| vars.parse("ANALYSIS_WINDOW_SECONDS").and_then(|secs| windows_in(secs, api_poll_interval_secs)))
+                    .unwrap_or(defaults.target_windows),
+                extrapolate_partial_windows: vars.flag("EXTRAPOLATE_PARTIAL_WINDOWS")
+                    .unwrap_or(defaults.extrapolate_partial_windows),
+                activity_min_moving_week_volume: vars.parse("ACTIVITY_MIN_MOVING_WEEK_VOLUME")
+                    .unwrap_or(defaults.activity_min_moving_week_volume),
+                activity_min_inferred_events: vars.parse("ACTIVITY_MIN_INFERRED_EVENTS")
+                    .unwrap_or(defaults.activity_min_inferred_events),
+                event_inference: vars.parse("EVENT_INFERENCE").unwrap_or(defaults.event_inference),
+                spike_median_multiple: vars.parse("SPIKE_MEDIAN_MULTIPLE")
+                    .unwrap_or(defaults.spike_median_multiple),
+                reference_product: vars.get("REFERENCE_PRODUCT").filter(|s| !s.is_empty()),
+                timestamp_format: vars.parse("TIMESTAMP_FORMAT").unwrap_or(defaults.timestamp_format),
+                delta_sequence_output: vars.parse("DELTA_SEQUENCES").unwrap_or(defaults.delta_sequence_output),
+                price_pin_epsilon: vars.parse("PRICE_PIN_EPSILON").unwrap_or(defaults.price_pin_epsilon),
+                min_observations_before_export: vars.parse("MIN_OBSERVATIONS_BEFORE_EXPORT")
+                    .unwrap_or(defaults.min_observations_before_export),
+                counter_reset_fraction: vars.parse_where("COUNTER_RESET_FRACTION", |f: &f64| *f > 0.0 && *f <= 1.0, "a fraction in (0, 1]")
+                    .unwrap_or(defaults.counter_reset_fraction),
+                prefilter_min_active_fraction: vars.parse("PREFILTER_MIN_ACTIVE_FRACTION")
+                    .unwrap_or(defaults.prefilter_min_active_fraction),
+                prefilter_min_moving_week_activity: vars.parse("PREFILTER_MIN_MOVING_WEEK_ACTIVITY")
+                    .unwrap_or(defaults.prefilter_min_moving_week_activity),
+                pattern_min_spread: vars.parse("PATTERN_MIN_SPREAD").unwrap_or(defaults.pattern_min_spread),
+                pattern_min_moving_week_activity: vars.parse("PATTERN_MIN_MOVING_WEEK_ACTIVITY")
+                    .unwrap_or(defaults.pattern_min_moving_week_activity),
+                force_full_detection: vars.flag("FORCE_FULL_DETECTION").unwrap_or(defaults.force_full_detection),
+                standing_volume: vars.flag("STANDING_VOLUME").unwrap_or(defaults.standing_volume),
+                detector: DetectorConfig {
+                    min_cluster_size: vars.parse("DETECTOR_MIN_CLUSTER_SIZE")
+                        .unwrap_or(defaults.detector.min_cluster_size),
+                    rhythm_tolerances: vars.list("DETECTOR_RHYTHM_TOLERANCES")
+                        .unwrap_or_else(|| defaults.detector.rhythm_tolerances.clone()),
+                    overrides: BTreeMap::new(),
+                },
+            },
+            runtime: RuntimeConfig {
+                flavor: vars.parse("TOKIO_FLAVOR").unwrap_or(RuntimeFlavor::MultiThread),
+                worker_threads: vars.parse("TOKIO_WORKERS").filter(|n| *n > 0),
+            },
+            api_poll_interval_secs,
+            fetch_endpoints: vars.get("FETCH_ENDPOINTS")
+                .map(|list| list.split(',').map(|url| url.trim().to_string()).filter(|url| !url.is_empty()).collect::<Vec<_>>())
+                .filter(|urls| !urls.is_empty())
+                .unwrap_or_else(|| vec![BAZAAR_URL.to_string()]),
+            auto_poll_interval: vars.flag("AUTO_POLL_INTERVAL").unwrap_or(false),
+            poll_fast_interval_secs: vars.parse("POLL_FAST_INTERVAL_SECONDS").filter(|secs: &f64| *secs > 0.0),
+            snapshot_queue_capacity: vars.parse_where("SNAPSHOT_QUEUE_CAPACITY", |n| *n > 0, "a positive capacity").unwrap_or(4),
+            sliding_emit_every: vars.parse("SLIDING_EMIT_EVERY").filter(|n| *n > 0),
+            warmup_windows: vars.parse("WARMUP_WINDOWS").unwrap_or(0),
+            export_engine_path: vars.get("EXPORT_ENGINE_PATH")
+                .unwrap_or_else(|| "export_engine".to_string()),
+            export_retry_concurrency: vars.parse_where("EXPORT_RETRY_CONCURRENCY", |n| *n > 0, "a positive number of pushes").unwrap_or(4),
+            export_sharding: vars.parse("EXPORT_SHARDING").unwrap_or_default(),
+            export_keep_files: vars.parse_where("EXPORT_KEEP_FILES", |n| *n > 0, "a positive number of cycles").unwrap_or(168),
+            remote_prune_keep: vars.parse_where("REMOTE_PRUNE_KEEP", |n| *n > 0, "a positive number of cycles"),
+            ready_max_pending_exports: vars.parse("READY_MAX_PENDING_EXPORTS").unwrap_or(6),
+            sqlite_path: vars.get("SQLITE_PATH").filter(|s| !s.is_empty()),
+            sqlite_sequences: vars.flag("SQLITE_SEQUENCES").unwrap_or(false),
+            baseline_path: vars.get("BASELINE_PATH").filter(|s| !s.is_empty()),
+            baseline_alpha: vars.parse_where("BASELINE_ALPHA", |a: &f64| *a > 0.0 && *a <= 1.0, "a weight in (0, 1]").unwrap_or(0.1),
+            nats_url: vars.get("NATS_URL").filter(|s| !s.is_empty()),
+            nats_subject: vars.get("NATS_SUBJECT").filter(|s| !s.is_empty()).unwrap_or_else(|| "wiz.metrics".to_string()),
+            nats_token: vars.get("NATS_TOKEN").filter(|s| !s.is_empty()),
+            alert_webhook_url: vars.get("ALERT_WEBHOOK_URL").filter(|s| !s.is_empty()),
+            alert_rules: AlertRules {
+                export_failures: vars.parse("ALERT_EXPORT_FAILURES").unwrap_or(alert_defaults.export_failures),
+                spike_magnitude: vars.parse_where("ALERT_SPIKE_MAGNITUDE", |m: &f64| *m >= 0.0, "a non-negative magnitude")
+                    .unwrap_or(alert_defaults.spike_magnitude),
+                spread_fraction: vars.parse_where("ALERT_SPREAD_FRACTION", |f: &f64| *f >= 0.0, "a non-negative fraction")
+                    .unwrap_or(alert_defaults.spread_fraction),
+                cooldown_secs: vars.parse("ALERT_COOLDOWN_SECS").unwrap_or(alert_defaults.cooldown_secs),
+            },
+            http_addr: vars.get("HTTP_ADDR").filter(|s| !s.is_empty()),
+            product_filter: ProductFilter::from_lists(
+                &vars.get("PRODUCT_INCLUDE").unwrap_or_default(),
+                &vars.get("PRODUCT_EXCLUDE").unwrap_or_default(),
+            ),
+            stack_sizes: StackSizes::from_list(&vars.get("AMOUNT_STACK_SIZES").unwrap_or_default()),
+            export_query_max_products: vars.parse("EXPORT_QUERY_MAX_PRODUCTS").unwrap_or(2000),
+            capture_max_files: vars.parse("CAPTURE_MAX_FILES").unwrap_or(2000),
+            output_format: output_formats.iter().find_map(|name| name.parse().ok()).unwrap_or(OutputFormat::Auto),
+            output_formats,
+            output_meta: vars.flag("OUTPUT_META").unwrap_or(false),
+            pretty_max_results: vars.parse("PRETTY_MAX_RESULTS").unwrap_or(100),
+            user_agent: vars.get("USER_AGENT").filter(|s| !s.is_empty())
+                .unwrap_or_else(|| DEFAULT_USER_AGENT.to_string()),
+            http_from: vars.get("HTTP_FROM").filter(|s| !s.is_empty()),
+            seed: vars.parse("WIZ_SEED"),
+            detector_overrides_path: vars.get("DETECTOR_OVERRIDES_PATH").filter(|s| !s.is_empty()),
+            product_aliases_path: vars.get("PRODUCT_ALIASES_PATH").filter(|s| !s.is_empty()),
+            aliases: ProductAliases::default(),
+            debug_endpoints: vars.flag("DEBUG_ENDPOINTS").unwrap_or(false),
+            recent_snapshots: vars.parse("RECENT_SNAPSHOTS").unwrap_or(0),
+            profile: vars.flag("PROFILE").unwrap_or(false),
+            finalize_concurrency: vars.parse("FINALIZE_CONCURRENCY").filter(|n| *n > 0),
+            delta_threshold: vars.parse_where("DELTA_EXPORT_THRESHOLD", |t: &f64| t.is_finite() && *t >= 0.0, "a non-negative fraction"),
+            delta_full_every: vars.parse_where("DELTA_FULL_EVERY", |n| *n > 0, "a positive number of exports").unwrap_or(24),
+            export_timeout_secs: Some(vars.parse("EXPORT_TIMEOUT_SECONDS").unwrap_or(900)).filter(|secs| *secs > 0),
+            per_product_export: vars.get("PER_PRODUCT_EXPORT").filter(|s| !s.trim().is_empty())
+                .map(|list| ProductFilter::from_lists(&list, "")),
+            per_product_push: vars.flag("PER_PRODUCT_PUSH").unwrap_or(false),
+            per_product_keep: vars.parse_where("PER_PRODUCT_KEEP", |n| *n > 0, "a positive number of cycles").unwrap_or(24),
+            checkpoint_dir: vars.get("CHECKPOINT_DIR").filter(|s| !s.is_empty()),
+            checkpoint_every: vars.parse_where("CHECKPOINT_EVERY", |n| *n > 0, "a positive number of snapshots").unwrap_or(15),
+            checkpoint_max_age_secs: Some(vars.parse("CHECKPOINT_MAX_AGE_SECONDS").unwrap_or(600)).filter(|secs| *secs > 0),
+            // Last, once every setting above has been read
+            invalid: vars.invalid.take(),
+        }
+    }
+}
+
+// `Config::default()` is what an empty environment gives: every setting at its default
+impl Default for Config {
+    fn default() -> Self {
+        Self::from_vars(&Vars::new(&|_| None))
+    }
+}
+
+// Keys whose values never leave the process, matched anywhere in the config tree
+const REDACTED_KEYS: &[&str] = &["http_from", "password", "api_key", "token", "secret", "webhook_url"];
+
+impl Config {
+    // The effective config as JSON with secrets and contact details replaced by "***"
+    pub fn redacted_json(&self) -> Value {
+        let mut value = serde_json::to_value(self).unwrap_or_default();
+        redact(&mut value);
+        value
+    }
+}
+
+fn redact(value: &mut Value) {
+    match value {
+        Value::Object(fields) => {
+            for (key, field) in fields.iter_mut() {
+                if REDACTED_KEYS.iter().any(|secret| key.to_lowercase().contains(secret)) {
+                    if !field.is_null() {
+                        *field = Value::from("***");
+                    }
+                } else {
+                    redact(field);
+                }
+            }
+        }
+        Value::Array(items) => items.iter_mut().for_each(redact),
+        _ => {}
+    }
+}
+
+// Windows of `poll_interval_secs` needed to cover `secs`, rounding up; None for a zero span
+fn windows_in(secs: u64, poll_interval_secs: u64) -> Option<usize> {
+    (secs > 0).then(|| secs.div_ceil(poll_interval_secs.max(1)) as usize)
+}
+
+// Where `Config` reads its settings: the process environment, or nothing for the defaults. A
+// setting that is blank reads as unset; one that doesn't parse, or is out of range, is recorded in
+// `invalid` (see `Config::invalid`) and reads as unset too.
+struct Vars<'a> {
+    lookup: &'a dyn Fn(&str) -> Option<String>,
+    invalid: RefCell<Vec<String>>,
+}
+
+impl<'a> Vars<'a> {
+    fn new(lookup: &'a dyn Fn(&str) -> Option<String>) -> Self {
+        Self { lookup, invalid: RefCell::new(Vec::new()) }
+    }
+
+    fn get(&self, key: &str) -> Option<String> {
+        (self.lookup)(key)
+    }
+
+    fn reject(&self, key: &str, value: &str, reason: impl Display) {
+        self.invalid.borrow_mut().push(format!("{}={:?}: {}", key, value, reason));
+    }
+
+    fn parse<T: FromStr>(&self, key: &str) -> Option<T> where T::Err: Display {
+        let value = self.get(key).filter(|s| !s.trim().is_empty())?;
+        value.trim().parse().map_err(|e| self.reject(key, &value, e)).ok()
+    }
+
+    // `parse`, also rejecting a value `valid` doesn't allow; `expected` says what it does
+    fn parse_where<T: FromStr>(&self, key: &str, valid: impl Fn(&T) -> bool, expected: &str) -> Option<T> where T::Err: Display {
+        let parsed = self.parse(key)?;
+        if valid(&parsed) {
+            return Some(parsed);
+        }
+        self.reject(key, &self.get(key).unwrap_or_default(), format_args!("expected {}", expected));
+        None
+    }
+
+    // A comma-separated list; one bad entry rejects the whole list
+    fn list<T: FromStr>(&self, key: &str) -> Option<Vec<T>> where T::Err: Display {
+        let value = self.get(key).filter(|s| !s.trim().is_empty())?;
+        value.split(',').map(str::trim).filter(|entry| !entry.is_empty())
+            .map(|entry| entry.parse().map_err(|e| self.reject(key, &value, format_args!("{}: {}", entry, e))))
+            .collect::<Result<_, _>>().ok()
+    }
+
+    fn flag(&self, key: &str) -> Option<bool> {
+        let value = self.get(key).filter(|s| !s.trim().is_empty())?;
+        match value.trim().to_lowercase().as_str() {
+            "1" | "true" | "yes" | "on" => Some(true),
+            "0" | "false" | "no" | "off" => Some(false),
+            _ => {
+                self.reject(key, &value, "expected true or false");
+                None
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn auto_output_format_pretty_prints_only_below_threshold() {
+        assert!(OutputFormat::Auto.is_pretty(99, 100));
+        assert!(!OutputFormat::Auto.is_pretty(100, 100));
+        assert!(OutputFormat::Pretty.is_pretty(5_000, 100));
+        assert!(!OutputFormat::Compact.is_pretty(1, 100));
+        assert_eq!("COMPACT".parse(), Ok(OutputFormat::Compact));
+    }
+
+    #[test]
+    fn analysis_window_seconds_round_up_to_whole_polls() {
+        assert_eq!(windows_in(3_600, 20), Some(180));
+        assert_eq!(windows_in(6 * 3_600, 20), Some(1_080));
+        assert_eq!(windows_in(1_800, 45), Some(40));
+        assert_eq!(windows_in(50, 20), Some(3));
+        assert_eq!(windows_in(10, 0), Some(10));
+        assert_eq!(windows_in(0, 20), None);
+    }
+
+    #[test]
+    fn export_sharding_parses_modes_and_rejects_nonsense() {
+        assert_eq!("single".parse(), Ok(ExportSharding::Single));
+        assert_eq!("HASH:8".parse(), Ok(ExportSharding::Hash(8)));
+        assert_eq!("hash:1".parse(), Ok(ExportSharding::Single));
+        assert_eq!("size:5000000".parse(), Ok(ExportSharding::MaxBytes(5_000_000)));
+        for bad in ["hash:0", "size:0", "size:big", "category"] {
+            assert!(bad.parse::<ExportSharding>().is_err(), "{} parsed", bad);
+        }
+    }
+
+    // Every enum and numeric setting, set to something that doesn't parse or is out of range, is
+    // recorded rather than quietly replaced by its default
+    #[test]
+    fn unparsable_or_out_of_range_settings_are_recorded_as_invalid() {
+        let cases = [
+            ("EXPORT_SHARDING", "category"),
+            ("TIMESTAMP_FORMAT", "epoch-ms"),
+            ("DELTA_SEQUENCES", "every:1x"),
+            ("EVENT_INFERENCE", "amounts"),
+            ("TOKIO_FLAVOR", "single"),
+            ("TOKIO_WORKERS", "four"),
+            ("COUNTER_RESET_FRACTION", "1.5"),
+            ("COUNTER_RESET_FRACTION", "half"),
+            ("OUTPUT_FORMAT", "pretty,compact"),
+            ("DETECTOR_RHYTHM_TOLERANCES", "0.1,O.2"),
+            ("DETECTOR_MIN_CLUSTER_SIZE", "-3"),
+            ("TARGET_WINDOWS", "0"),
+            ("API_POLL_INTERVAL_SECONDS", "20s"),
+            ("SPIKE_MEDIAN_MULTIPLE", "x"),
+            ("BASELINE_ALPHA", "0"),
+            ("ALERT_SPIKE_MAGNITUDE", "-1"),
+            ("DELTA_EXPORT_THRESHOLD", "NaN"),
+            ("EXPORT_KEEP_FILES", "0"),
+            ("REMOTE_PRUNE_KEEP", "0"),
+            ("REMOTE_PRUNE_KEEP", "-1"),
+            ("REMOTE_PRUNE_KEEP", "3x"),
+            ("CHECKPOINT_EVERY", "often"),
+            ("WIZ_SEED", "0x2a"),
+            ("STANDING_VOLUME", "enabled"),
+        ];
+        for (key, value) in cases {
+            let config = Config::from_vars(&Vars::new(&|k| (k == key).then(|| value.to_string())));
+            assert_eq!(config.invalid.len(), 1, "{}={}: {:?}", key, value, config.invalid);
+            assert!(config.invalid[0].starts_with(&format!("{}=", key)), "{:?}", config.invalid);
+        }
+
+        let config = Config::from_vars(&Vars::new(&|key| (key == "EXPORT_SHARDING").then(|| "category".into())));
+        assert_eq!(config.export_sharding, ExportSharding::Single);
+        let config = Config::from_vars(&Vars::new(&|key| (key == "DETECTOR_RHYTHM_TOLERANCES").then(|| "0.1,O.2".into())));
+        assert_eq!(config.analysis.detector.rhythm_tolerances, AnalysisConfig::default().detector.rhythm_tolerances);
+        // Blank reads as unset
+        assert!(Config::from_vars(&Vars::new(&|_| Some(" ".into()))).invalid.is_empty());
+        assert!(Config::default().invalid.is_empty());
+    }
+
+    #[test]
+    fn redacted_config_hides_secrets_but_keeps_settings() {
+        let mut config = Config {
+            http_from: Some("ops@example.com".into()),
+            nats_token: Some("s3cret-token".into()),
+            alert_webhook_url: Some("https://discord.com/api/webhooks/1/s3cret".into()),
+            ..Config::default()
+        };
+        config.analysis.spike_median_multiple = 7.5;
+        let json = config.redacted_json();
+        assert_eq!(json["http_from"], "***");
+        assert_eq!(json["analysis"]["spike_median_multiple"], 7.5);
+        assert!(!json.to_string().contains("ops@example.com"));
+        assert_eq!(json["nats_token"], "***");
+        assert_eq!(json["alert_webhook_url"], "***");
+
+        let mut nested = serde_json::json!({ "mega": { "MEGA_PASSWORD": "hunter2", "user": "wiz" }, "api_key": null });
+        redact(&mut nested);
+        assert_eq!(nested, serde_json::json!({ "mega": { "MEGA_PASSWORD": "***", "user": "wiz" }, "api_key": null }));
+    }
+
+    #[test]
+    fn detector_overrides_prefer_item_then_longest_category() {
+        let mut detector = DetectorConfig::default();
+        let tolerances = |t: &[f64]| DetectorOverride { min_cluster_size: None, rhythm_tolerances: Some(t.to_vec()) };
+        detector.overrides.insert("ENCHANTED_*".into(), DetectorOverride { min_cluster_size: Some(4), rhythm_tolerances: Some(vec![0.3]) });
+        detector.overrides.insert("ENCHANTED_D*".into(), tolerances(&[0.2]));
+        detector.overrides.insert("ENCHANTED_DIAMOND".into(), tolerances(&[0.1]));
+
+        let diamond = detector.for_product("ENCHANTED_DIAMOND");
+        assert_eq!((diamond.min_cluster_size, diamond.rhythm_tolerances.clone()), (3, vec![0.1]));
+        let dirt = detector.for_product("ENCHANTED_DIRT");
+        assert_eq!((dirt.min_cluster_size, dirt.rhythm_tolerances.clone()), (3, vec![0.2]));
+        let gold = detector.for_product("ENCHANTED_GOLD");
+        assert_eq!((gold.min_cluster_size, gold.rhythm_tolerances.clone()), (4, vec![0.3]));
+        assert!(matches!(detector.for_product("WHEAT"), Cow::Borrowed(_)));
+        assert!(detector.validate().is_ok());
+
+        detector.overrides.insert("WHEAT".into(), DetectorOverride { min_cluster_size: Some(1), rhythm_tolerances: None });
+        assert!(detector.validate().unwrap_err().contains("WHEAT"));
+    }
+}
@@ -0,0 +1,152 @@
+// `--correlate <metrics.json>`: products whose moving-week counters move together, such as an item
+// and its enchanted form or a recipe and its inputs. Offline only, from an export's
+// `delta_sequences`. Products exported without sequences are skipped.
+//
+// Each product's series is its buy + sell moving-week delta per window, keyed by the window's start
+// timestamp. A pair is correlated (Pearson) over the windows both products have, and only when
+// they share at least MIN_OVERLAP of them.
+//
+// Comparing every pair is O(n²). `--max-products` (default 200) bounds it to the products with the
+// most moving-week activity. The output is the sparse upper triangle of the correlation matrix:
+// only pairs at or above `--threshold` (default 0.8), strongest first, at most `--top` (default 100).
+use crate::{pearson, DeltaSequences};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::BTreeMap;
+
+const MIN_OVERLAP: usize = 10;
+
+#[derive(Debug, Clone, Copy)]
+pub struct CorrelateOptions {
+    pub threshold: f64,
+    pub max_products: usize,
+    pub top: usize,
+}
+
+impl Default for CorrelateOptions {
+    fn default() -> Self {
+        Self { threshold: 0.8, max_products: 200, top: 100 }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct CorrelationReport {
+    pub threshold: f64,
+    pub products_compared: usize,
+    // Without sequences, or left out by `max_products`
+    pub products_skipped: usize,
+    pub pairs: Vec<CorrelatedPair>,
+}
+
+#[derive(Debug, Clone, Serialize, PartialEq)]
+pub struct CorrelatedPair {
+    pub a: String,
+    pub b: String,
+    pub correlation: f64,
+    pub windows: usize,
+}
+
+struct Series {
+    product_id: String,
+    activity: i64,
+    deltas: BTreeMap<u64, f64>,
+}
+
+pub fn correlate(export: &Value, options: CorrelateOptions) -> Result<CorrelationReport, String> {
+    // A bare array, or the OUTPUT_META wrapper with the array under `results`
+    let products = match export {
+        Value::Object(document) => document.get("results").and_then(Value::as_array),
+        other => other.as_array(),
+    }
+    .ok_or("Export is not a JSON array of products")?;
+
+    let mut series: Vec<Series> = products.iter()
+        .filter_map(|product| {
+            let sequences = DeltaSequences::deserialize(&product["delta_sequences"]).ok()?;
+            let deltas: BTreeMap<u64, f64> = sequences.timestamps.iter()
+                .zip(sequences.buy_moving_week.iter().zip(&sequences.sell_moving_week))
+                .map(|(&timestamp, (&buy, &sell))| (timestamp, (buy + sell) as f64))
+                .collect();
+            Some(Series {
+                product_id: product["product_id"].as_str()?.to_string(),
+                activity: sequences.buy_moving_week.iter().chain(&sequences.sell_moving_week).map(|delta| delta.abs()).sum(),
+                deltas,
+            })
+        })
+        .collect();
+    series.sort_by(|a, b| b.activity.cmp(&a.activity).then_with(|| a.product_id.cmp(&b.product_id)));
+    series.truncate(options.max_products);
+
+    let mut pairs = Vec::new();
+    for (i, a) in series.iter().enumerate() {
+        for b in &series[i + 1..] {
+            let shared: Vec<(f64, f64)> = a.deltas.iter()
+                .filter_map(|(timestamp, &x)| b.deltas.get(timestamp).map(|&y| (x, y)))
+                .collect();
+            if shared.len() < MIN_OVERLAP {
+                continue;
+            }
+            if let Some(correlation) = pearson(&shared).filter(|r| *r >= options.threshold) {
+                let (a, b) = if a.product_id <= b.product_id { (a, b) } else { (b, a) };
+                pairs.push(CorrelatedPair { a: a.product_id.clone(), b: b.product_id.clone(), correlation, windows: shared.len() });
+            }
+        }
+    }
+    pairs.sort_by(|x, y| y.correlation.total_cmp(&x.correlation).then_with(|| (&x.a, &x.b).cmp(&(&y.a, &y.b))));
+    pairs.truncate(options.top);
+
+    Ok(CorrelationReport {
+        threshold: options.threshold,
+        products_compared: series.len(),
+        products_skipped: products.len() - series.len(),
+        pairs,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rng::SplitMix64;
+    use serde_json::json;
+
+    fn product(product_id: &str, buy: &[i64]) -> Value {
+        let windows = buy.len();
+        json!({
+            "product_id": product_id,
+            "delta_sequences": {
+                "buy_moving_week": buy, "sell_moving_week": vec![0; windows],
+                "buy_orders": vec![0; windows], "sell_orders": vec![0; windows],
+                "buy_amount": vec![0; windows], "sell_amount": vec![0; windows],
+                "timestamps": (0..=windows as u64).map(|i| 1_700_000_000 + 20 * i).collect::<Vec<_>>(),
+            },
+        })
+    }
+
+    #[test]
+    fn synthetically_correlated_series_are_paired_and_noise_is_not() {
+        let mut rng = SplitMix64(3);
+        let base: Vec<i64> = (0..60).map(|_| rng.range(0, 1_000)).collect();
+        // Nine enchanted per raw item, give or take a few, and an unrelated random series
+        let enchanted: Vec<i64> = base.iter().map(|v| 9 * v + rng.range(-50, 50)).collect();
+        let unrelated: Vec<i64> = (0..60).map(|_| rng.range(0, 1_000)).collect();
+        let export = json!([
+            product("WHEAT", &base),
+            product("ENCHANTED_WHEAT", &enchanted),
+            product("CARROT", &unrelated),
+            { "product_id": "NO_SEQUENCES", "delta_sequences": null },
+        ]);
+
+        let report = correlate(&export, CorrelateOptions::default()).unwrap();
+        assert_eq!((report.products_compared, report.products_skipped), (3, 1));
+        assert_eq!(report.pairs.len(), 1, "{:?}", report.pairs);
+        let pair = &report.pairs[0];
+        assert_eq!((pair.a.as_str(), pair.b.as_str(), pair.windows), ("ENCHANTED_WHEAT", "WHEAT", 60));
+        assert!(pair.correlation > 0.99);
+
+        // Bounded to the single most active product there is nothing left to pair
+        let bounded = correlate(&export, CorrelateOptions { max_products: 1, ..CorrelateOptions::default() }).unwrap();
+        assert_eq!((bounded.products_compared, bounded.products_skipped, bounded.pairs.len()), (1, 3, 0));
+        let everything = correlate(&export, CorrelateOptions { threshold: -1.0, ..CorrelateOptions::default() }).unwrap();
+        assert_eq!(everything.pairs.len(), 3);
+    }
+}
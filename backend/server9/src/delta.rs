@@ -0,0 +1,289 @@
+// Optional delta exports (DELTA_EXPORT_THRESHOLD) for bandwidth-constrained uploads. Every
+// DELTA_FULL_EVERY-th export is a normal full export; the ones in between are written as
+// `metrics_<stamp>_delta.json` holding only the products that changed:
+//   { "kind": "delta", "cycle": n, "base_cycle": n - 1, "threshold": t,
+//     "changed": [full rows], "unchanged": [product ids], "removed": [product ids] }
+// A consumer keeps the rows of the last full export and applies each delta in cycle order (see
+// `apply`): changed rows replace or add, removed ids are dropped, unchanged ids keep their row. A
+// gap in `cycle` means a delta was missed; wait for the next full export to resync.
+//
+// A row has changed when, compared with the row the consumer currently holds, any field was
+// added or removed, any non-numeric value differs, or any number moved by more than `threshold`
+// relative to the larger magnitude of the two (|new - old| / max(|old|, |new|)); nested objects
+// are compared the same way, and arrays element by element (one that changed length has changed). `delta_sequences` is left out of the comparison, since its windows
+// differ every cycle; it is only refreshed with a changed row. Comparing against what the consumer
+// holds, rather than the previous cycle, keeps slow drifts from accumulating unseen.
+//
+// What the consumer holds is persisted next to the exports (`delta_base.json`) so deltas continue
+// across restarts. It only advances once an export was written locally.
+use crate::atomic::write_atomic;
+use crate::{AnalysisResult, SCHEMA_VERSION};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::BTreeMap;
+#[cfg(test)]
+use std::collections::BTreeSet;
+use std::error::Error;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+const IGNORED_FIELDS: [&str; 1] = ["delta_sequences"];
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct DeltaBase {
+    cycle: u64,
+    deltas_since_full: usize,
+    products: BTreeMap<String, Value>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DeltaDocument {
+    pub schema_version: u32,
+    pub generated_at: u64,
+    pub kind: String,
+    pub cycle: u64,
+    pub base_cycle: u64,
+    pub threshold: f64,
+    pub changed: Vec<Value>,
+    pub unchanged: Vec<String>,
+    pub removed: Vec<String>,
+}
+
+// One cycle's export decision: `document` is None for a full export
+pub struct DeltaPlan {
+    pub document: Option<DeltaDocument>,
+    current: BTreeMap<String, Value>,
+}
+
+pub struct DeltaTracker {
+    path: PathBuf,
+    threshold: f64,
+    full_every: usize,
+    base: DeltaBase,
+}
+
+impl DeltaTracker {
+    pub fn load(path: &Path, threshold: f64, full_every: usize) -> Result<Self, Box<dyn Error>> {
+        let base = match fs::read(path) {
+            Ok(bytes) => serde_json::from_slice(&bytes)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => DeltaBase::default(),
+            Err(e) => return Err(e.into()),
+        };
+        Ok(Self { path: path.to_path_buf(), threshold, full_every: full_every.max(1), base })
+    }
+
+    // Full when nothing was exported yet or the full export is due, a delta otherwise
+    pub fn plan(&self, results: &[AnalysisResult], generated_at: u64) -> serde_json::Result<DeltaPlan> {
+        let current = results.iter()
+            .map(|result| Ok((result.product_id.clone(), serde_json::to_value(result)?)))
+            .collect::<serde_json::Result<BTreeMap<String, Value>>>()?;
+        if self.base.products.is_empty() || self.base.deltas_since_full + 1 >= self.full_every {
+            return Ok(DeltaPlan { document: None, current });
+        }
+
+        let mut changed = Vec::new();
+        let mut unchanged = Vec::new();
+        for (product_id, row) in &current {
+            match self.base.products.get(product_id) {
+                Some(held) if !row_changed(held, row, self.threshold) => unchanged.push(product_id.clone()),
+                _ => changed.push(row.clone()),
+            }
+        }
+        let removed = self.base.products.keys().filter(|id| !current.contains_key(*id)).cloned().collect();
+        let document = DeltaDocument {
+            schema_version: SCHEMA_VERSION,
+            generated_at,
+            kind: "delta".to_string(),
+            cycle: self.base.cycle + 1,
+            base_cycle: self.base.cycle,
+            threshold: self.threshold,
+            changed,
+            unchanged,
+            removed,
+        };
+        Ok(DeltaPlan { document: Some(document), current })
+    }
+
+    // Records that `plan` was exported, so the next cycle is compared against it
+    pub fn commit(&mut self, plan: DeltaPlan) -> Result<(), Box<dyn Error>> {
+        let DeltaPlan { document, mut current } = plan;
+        match document {
+            None => {
+                self.base.products = current;
+                self.base.deltas_since_full = 0;
+            }
+            Some(document) => {
+                for row in &document.changed {
+                    if let Some((id, row)) = row["product_id"].as_str().and_then(|id| current.remove_entry(id)) {
+                        self.base.products.insert(id, row);
+                    }
+                }
+                for id in &document.removed {
+                    self.base.products.remove(id);
+                }
+                self.base.deltas_since_full += 1;
+            }
+        }
+        self.base.cycle += 1;
+        write_atomic(&self.path, &serde_json::to_vec(&self.base)?)?;
+        Ok(())
+    }
+}
+
+fn row_changed(held: &Value, row: &Value, threshold: f64) -> bool {
+    match (held, row) {
+        (Value::Object(held), Value::Object(row)) => {
+            held.len() != row.len()
+                || row.iter().any(|(key, value)| {
+                    !IGNORED_FIELDS.contains(&key.as_str()) && held.get(key).is_none_or(|old| value_changed(old, value, threshold))
+                })
+        }
+        _ => held != row,
+    }
+}
+
+fn value_changed(old: &Value, new: &Value, threshold: f64) -> bool {
+    match (old, new) {
+        (Value::Number(old), Value::Number(new)) => {
+            let (old, new) = (old.as_f64().unwrap_or_default(), new.as_f64().unwrap_or_default());
+            let scale = old.abs().max(new.abs());
+            scale > 0.0 && (new - old).abs() / scale > threshold
+        }
+        (Value::Object(old), Value::Object(new)) => {
+            old.len() != new.len() || new.iter().any(|(key, value)| old.get(key).is_none_or(|o| value_changed(o, value, threshold)))
+        }
+        (Value::Array(old), Value::Array(new)) => {
+            old.len() != new.len() || old.iter().zip(new).any(|(o, value)| value_changed(o, value, threshold))
+        }
+        _ => old != new,
+    }
+}
+
+// Brings a consumer's rows (keyed by product id) up to date with one export: a full export (a bare
+// array, or the OUTPUT_META document) replaces them, a delta is applied on top. A delta that
+// doesn't line up with the rows held is an error; the consumer should wait for a full export.
+// Consumers live outside this crate; this is the reference the tests hold the exports to.
+#[cfg(test)]
+fn apply(rows: &mut BTreeMap<String, Value>, export: &Value) -> Result<(), String> {
+    if export["kind"] != "delta" {
+        let results = export.get("results").unwrap_or(export).as_array().ok_or("Export is neither a delta nor a full export")?;
+        *rows = results.iter()
+            .map(|row| row["product_id"].as_str().map(|id| (id.to_string(), row.clone())).ok_or("Row without a product_id"))
+            .collect::<Result<_, _>>()?;
+        return Ok(());
+    }
+
+    let document: DeltaDocument = serde_json::from_value(export.clone()).map_err(|e| e.to_string())?;
+    let changed: BTreeSet<&str> = document.changed.iter().filter_map(|row| row["product_id"].as_str()).collect();
+    let expected: BTreeSet<&str> = document.unchanged.iter().chain(&document.removed).map(String::as_str)
+        .chain(changed.iter().copied().filter(|id| rows.contains_key(*id)))
+        .collect();
+    if rows.keys().map(String::as_str).collect::<BTreeSet<_>>() != expected {
+        return Err(format!("Delta for cycle {} doesn't match the rows held; wait for the next full export", document.cycle));
+    }
+    for id in &document.removed {
+        rows.remove(id);
+    }
+    for row in document.changed {
+        if let Some(id) = row["product_id"].as_str() {
+            rows.insert(id.to_string(), row);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::replay::replay_cycle;
+
+    // Exports `results` through the tracker and feeds the document to the consumer's rows
+    fn export(tracker: &mut DeltaTracker, rows: &mut BTreeMap<String, Value>, results: &[AnalysisResult]) -> Value {
+        let plan = tracker.plan(results, 1_700_000_000).unwrap();
+        let document = match &plan.document {
+            Some(delta) => serde_json::to_value(delta).unwrap(),
+            None => serde_json::to_value(results).unwrap(),
+        };
+        apply(rows, &document).unwrap();
+        tracker.commit(plan).unwrap();
+        document
+    }
+
+    #[tokio::test]
+    async fn consumers_rebuild_the_full_state_from_deltas() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("delta_base.json");
+        let mut tracker = DeltaTracker::load(&path, 0.05, 3).unwrap();
+        let mut rows = BTreeMap::new();
+        let first = replay_cycle().await;
+        assert!(export(&mut tracker, &mut rows, &first).is_array());
+
+        // WHEAT's price jumps 50%, ENCHANTED_DIAMOND's drifts 1%, LATE_ITEM disappears
+        let mut second = replay_cycle().await;
+        second.retain(|r| r.product_id != "LATE_ITEM");
+        for result in &mut second {
+            match result.product_id.as_str() {
+                "WHEAT" => result.instabuy_price_average *= 1.5,
+                "ENCHANTED_DIAMOND" => result.instabuy_price_average *= 1.01,
+                _ => {}
+            }
+        }
+        let delta = export(&mut tracker, &mut rows, &second);
+        assert_eq!(delta["changed"].as_array().unwrap().len(), 1);
+        assert_eq!(delta["changed"][0]["product_id"], "WHEAT");
+        assert_eq!(delta["unchanged"], serde_json::json!(["DEAD_ITEM", "ENCHANTED_DIAMOND"]));
+        assert_eq!(delta["removed"], serde_json::json!(["LATE_ITEM"]));
+
+        // Every row held is within the threshold of the true state
+        let truth = |results: &[AnalysisResult]| -> BTreeMap<String, Value> {
+            results.iter().map(|r| (r.product_id.clone(), serde_json::to_value(r).unwrap())).collect()
+        };
+        let held_matches = |rows: &BTreeMap<String, Value>, truth: &BTreeMap<String, Value>| {
+            rows.len() == truth.len() && truth.iter().all(|(id, row)| !row_changed(&rows[id], row, 0.05))
+        };
+        assert!(held_matches(&rows, &truth(&second)));
+
+        // Another 1% drift on ENCHANTED_DIAMOND is 2% against the row the consumer holds: still
+        // unchanged. The tracker survives a reload, and the third export is a full resync.
+        for result in &mut second {
+            if result.product_id == "ENCHANTED_DIAMOND" {
+                result.instabuy_price_average *= 1.01;
+            }
+        }
+        let mut tracker = DeltaTracker::load(&path, 0.05, 3).unwrap();
+        let delta = export(&mut tracker, &mut rows, &second);
+        assert_eq!((delta["cycle"].as_u64(), delta["changed"].as_array().unwrap().len()), (Some(3), 0));
+        assert!(held_matches(&rows, &truth(&second)));
+        assert!(export(&mut tracker, &mut rows, &second).is_array());
+        assert_eq!(rows, truth(&second));
+    }
+
+    #[test]
+    fn deltas_that_do_not_line_up_are_rejected() {
+        let mut rows = BTreeMap::from([("WHEAT".to_string(), serde_json::json!({ "product_id": "WHEAT" }))]);
+        let delta = |unchanged: &[&str]| serde_json::json!({
+            "schema_version": SCHEMA_VERSION, "generated_at": 0, "kind": "delta", "cycle": 5, "base_cycle": 4,
+            "threshold": 0.0, "changed": [], "unchanged": unchanged, "removed": [],
+        });
+        assert!(apply(&mut rows, &delta(&["WHEAT", "CARROT"])).is_err());
+        assert!(apply(&mut rows, &delta(&[])).is_err());
+        assert!(apply(&mut rows, &delta(&["WHEAT"])).is_ok());
+
+        let old = serde_json::json!({ "a": 100.0, "b": "x", "delta_sequences": [1] });
+        assert!(!row_changed(&old, &serde_json::json!({ "a": 104.0, "b": "x", "delta_sequences": [2] }), 0.05));
+        assert!(row_changed(&old, &serde_json::json!({ "a": 106.0, "b": "x", "delta_sequences": [1] }), 0.05));
+        assert!(row_changed(&old, &serde_json::json!({ "a": 100.0, "b": "y", "delta_sequences": [1] }), 0.05));
+        assert!(row_changed(&old, &serde_json::json!({ "a": 100.0, "delta_sequences": [1] }), 0.05));
+    }
+
+    #[test]
+    fn array_elements_are_held_to_the_same_threshold_as_scalars() {
+        let old = serde_json::json!({ "patterns": [{ "size": 100.0, "kind": "flip" }], "history": [10, 20] });
+        let jittered = serde_json::json!({ "patterns": [{ "size": 103.0, "kind": "flip" }], "history": [10, 21] });
+        assert!(!row_changed(&old, &jittered, 0.05));
+        assert!(row_changed(&old, &serde_json::json!({ "patterns": [{ "size": 100.0, "kind": "flip" }], "history": [10, 30] }), 0.05));
+        assert!(row_changed(&old, &serde_json::json!({ "patterns": [{ "size": 100.0, "kind": "flip" }], "history": [10, 20, 30] }), 0.05));
+        assert!(row_changed(&old, &serde_json::json!({ "patterns": [{ "size": 100.0, "kind": "dump" }], "history": [10, 20] }), 0.05));
+    }
+}
@@ -0,0 +1,82 @@
+// Exit-code contract, shared with the export engine (export.cpp), so a supervisor can tell
+// "restart me" from "fix me first":
+//   0  clean shutdown (Ctrl-C after draining, or a one-shot mode finished)
+//   1  anything unexpected; restarting is reasonable
+//   2  config error: bad environment, flags or export engine path; restarting won't help
+//   3  auth failure: MEGA reports it is not logged in
+//   4  unrecoverable fetch failure: every endpoint answers with a client error (4xx other than
+//      408 and 429) for MAX_REJECTED_TICKS polls in a row, e.g. a wrong FETCH_ENDPOINTS URL
+//   5  disk failure: the local disk is full or read-only, or the metrics dir can't be created
+//
+// Errors stay `Box<dyn Error>` everywhere; the ones that matter for the contract are raised as an
+// `ExitError` carrying their code, and `exit_code` classifies whatever reaches `main`.
+use crate::export::is_disk_full;
+use std::error::Error;
+use std::fmt;
+use std::io;
+
+pub const CONFIG: u8 = 2;
+pub const AUTH: u8 = 3;
+pub const FETCH: u8 = 4;
+pub const DISK: u8 = 5;
+
+#[derive(Debug)]
+pub struct ExitError {
+    pub code: u8,
+    pub message: String,
+}
+
+impl ExitError {
+    pub fn config(message: impl Into<String>) -> Self {
+        Self { code: CONFIG, message: message.into() }
+    }
+
+    pub fn auth(message: impl Into<String>) -> Self {
+        Self { code: AUTH, message: message.into() }
+    }
+
+    pub fn fetch(message: impl Into<String>) -> Self {
+        Self { code: FETCH, message: message.into() }
+    }
+
+    pub fn disk(message: impl Into<String>) -> Self {
+        Self { code: DISK, message: message.into() }
+    }
+}
+
+impl fmt::Display for ExitError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl Error for ExitError {}
+
+pub fn exit_code(error: &(dyn Error + 'static)) -> u8 {
+    if let Some(exit) = error.downcast_ref::<ExitError>() {
+        exit.code
+    } else if error.downcast_ref::<io::Error>().is_some_and(is_disk_failure) {
+        DISK
+    } else {
+        1
+    }
+}
+
+fn is_disk_failure(error: &io::Error) -> bool {
+    is_disk_full(error) || error.kind() == io::ErrorKind::ReadOnlyFilesystem
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn errors_map_to_their_exit_codes() {
+        let code = |error: Box<dyn Error>| exit_code(error.as_ref());
+        assert_eq!(code(Box::new(ExitError::auth("not logged in"))), AUTH);
+        assert_eq!(code(Box::new(ExitError::fetch("404"))), FETCH);
+        assert_eq!(code(Box::new(io::Error::from(io::ErrorKind::StorageFull))), DISK);
+        assert_eq!(code(Box::new(io::Error::from(io::ErrorKind::NotFound))), 1);
+        assert_eq!(code("anything else".into()), 1);
+    }
+}
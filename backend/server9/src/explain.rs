@@ -0,0 +1,231 @@
+// `--explain <product_id> --export <metrics.json>`: how one product's headline metrics in an
+// export came about, for auditing a number by hand.
+//
+// Headline fields, the volume reconciliation and the flags are read straight from the export. The
+// detector candidates (winner first, then the runners-up in the detectors' own ranking) and the
+// windows each was built from are re-derived from the exported `delta_sequences` with the current
+// detector settings, exactly as `--recompute` would. They are left empty when the sequences were
+// omitted or downsampled, or when detection didn't run for the product.
+use crate::activity::ActivityIndex;
+use crate::config::DetectorConfig;
+use crate::{DeltaSequences, FuzzyPattern, ProductMetricsState};
+use serde::Serialize;
+use serde_json::Value;
+use std::fmt::Write;
+
+#[derive(Debug, Serialize)]
+pub struct Explanation {
+    pub product_id: String,
+    pub detection_method: String,
+    // Why `candidates` is empty, when it is
+    pub candidates_note: Option<String>,
+    pub buy: SideExplanation,
+    pub sell: SideExplanation,
+    pub flags: Vec<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct SideExplanation {
+    pub modal_size: f64,
+    pub pattern_per_hour: f64,
+    pub modal_ratio: Option<f64>,
+    pub candidates: Vec<Candidate>,
+    pub moving_week_volume: f64,
+    pub inferred_volume: f64,
+    pub inferred_coverage: Option<f64>,
+    pub inference_ratio: Option<f64>,
+    pub inference_correlation: Option<f64>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Candidate {
+    pub method: String,
+    pub size: f64,
+    pub interval_minutes: f64,
+    pub confidence: f64,
+    pub occurrences: usize,
+    pub positions: Vec<usize>,
+}
+
+impl From<FuzzyPattern> for Candidate {
+    fn from(pattern: FuzzyPattern) -> Self {
+        Self {
+            method: pattern.pattern_type,
+            size: pattern.size,
+            interval_minutes: pattern.interval_minutes,
+            confidence: pattern.confidence,
+            occurrences: pattern.occurrences,
+            positions: pattern.positions,
+        }
+    }
+}
+
+pub fn explain(export: &Value, product_id: &str, detector: &DetectorConfig) -> Result<Explanation, String> {
+    // A bare array, or the OUTPUT_META wrapper with the array under `results`
+    let products = match export {
+        Value::Object(document) => document.get("results").and_then(Value::as_array),
+        other => other.as_array(),
+    }
+    .ok_or("Export is not a JSON array of products")?;
+    let product = products.iter().find(|p| p["product_id"] == product_id)
+        .ok_or_else(|| format!("{} is not in this export", product_id))?;
+
+    let detection_method = product["pattern_details"]["detection_method"].as_str().unwrap_or_default().to_string();
+    let sequences = serde_json::from_value::<DeltaSequences>(product["delta_sequences"].clone()).ok()
+        .filter(|sequences| sequences.downsampled_every.is_none());
+    let detected = !detection_method.starts_with("skipped") && detection_method != "below_pattern_threshold";
+    let candidates_note = match (&sequences, detected) {
+        (_, false) => Some(format!("detection did not run ({})", detection_method)),
+        (None, true) => Some("delta_sequences omitted or downsampled in this export".to_string()),
+        (Some(_), true) => None,
+    };
+    let candidates = |moving_week: fn(&DeltaSequences) -> &[i64], inferred: fn(&DeltaSequences) -> &[i64]| {
+        sequences.as_ref().filter(|_| detected)
+            .map(|s| side_candidates(moving_week(s), inferred(s), &s.timestamps, &detector.for_product(product_id)))
+            .unwrap_or_default()
+    };
+
+    Ok(Explanation {
+        product_id: product_id.to_string(),
+        detection_method,
+        candidates_note,
+        buy: side(product, "instabuy", candidates(|s| &s.buy_moving_week, |s| &s.buy_inferred_volume)),
+        sell: side(product, "instasell", candidates(|s| &s.sell_moving_week, |s| &s.sell_inferred_volume)),
+        flags: flags(product),
+    })
+}
+
+// Candidates in the order `detect_fuzzy_modal_pattern` considers them, so the first one won.
+// Legacy clustering only runs when no fuzzy pattern was found and records no positions.
+fn side_candidates(moving_week: &[i64], inferred: &[i64], timestamps: &[u64], detector: &DetectorConfig) -> Vec<Candidate> {
+    let activity = ActivityIndex::from_deltas(moving_week, timestamps);
+    let mut candidates: Vec<Candidate> = ProductMetricsState::detect_velocity_patterns(&activity, detector).into_iter()
+        .chain(ProductMetricsState::detect_rhythm_patterns(&activity, detector))
+        .map(Candidate::from)
+        .collect();
+    if candidates.is_empty() {
+        let periods = ProductMetricsState::find_patterns_from_deltas(moving_week, inferred, timestamps);
+        if let Some(legacy) = ProductMetricsState::detect_modal_pattern_legacy(&periods, detector) {
+            candidates.push(Candidate {
+                method: "legacy_clustering".to_string(),
+                size: legacy.size,
+                interval_minutes: legacy.interval_minutes,
+                confidence: legacy.confidence,
+                occurrences: legacy.occurrence_count,
+                positions: Vec::new(),
+            });
+        }
+    }
+    candidates
+}
+
+fn side(product: &Value, prefix: &str, candidates: Vec<Candidate>) -> SideExplanation {
+    let field = |name: &str| product[format!("{}_{}", prefix, name)].as_f64();
+    SideExplanation {
+        modal_size: field("modal_size").unwrap_or_default(),
+        pattern_per_hour: field("pattern_per_hour").unwrap_or_default(),
+        modal_ratio: field("modal_ratio"),
+        candidates,
+        moving_week_volume: field("moving_week_volume").unwrap_or_default(),
+        inferred_volume: field("inferred_volume").unwrap_or_default(),
+        inferred_coverage: field("inferred_coverage"),
+        inference_ratio: field("inference_ratio"),
+        inference_correlation: field("inference_correlation"),
+    }
+}
+
+fn flags(product: &Value) -> Vec<String> {
+    let mut flags = Vec::new();
+    for (side, price) in [("buy", "instabuy"), ("sell", "instasell")] {
+        if product[format!("{}_price_pinned", side)] == true {
+            flags.push(format!("{} price pinned at {}", side, product[format!("{}_price_average", price)]));
+        }
+        if let Some(resets) = product[format!("{}_counter_resets", side)].as_u64().filter(|&n| n > 0) {
+            flags.push(format!("{} moving-week counter reset in {} windows, recorded as no movement", side, resets));
+        }
+        if product[format!("{}_spike_detected", side)] == true {
+            flags.push(format!("{} spike of {:.1}x the median window at window {}", side,
+                product[format!("{}_spike_magnitude", side)].as_f64().unwrap_or_default(), product[format!("{}_spike_window", side)]));
+        }
+    }
+    match product["market_activity"].as_str() {
+        Some("BuyOnly") => flags.push("one-sided: only the buy side traded".to_string()),
+        Some("SellOnly") => flags.push("one-sided: only the sell side traded".to_string()),
+        Some("Inactive") => flags.push("inactive: neither side traded".to_string()),
+        _ => {}
+    }
+    if let Some(flash) = product["flash_order_count"].as_u64().filter(|&n| n > 0) {
+        flags.push(format!("{} flash orders excluded from inferred volume", flash));
+    }
+    if product["extrapolated"] == true {
+        flags.push(format!("volumes extrapolated from {:.0}% window coverage",
+            product["window_coverage"].as_f64().unwrap_or_default() * 100.0));
+    }
+    flags
+}
+
+impl Explanation {
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        let _ = writeln!(out, "{} ({})", self.product_id, self.detection_method);
+        for (name, side) in [("Instabuy", &self.buy), ("Instasell", &self.sell)] {
+            let _ = writeln!(out, "\n{}: modal size {} at {:.1}/hour, ratio {}", name, side.modal_size, side.pattern_per_hour, optional(side.modal_ratio));
+            match side.candidates.split_first() {
+                Some((winner, runners_up)) => {
+                    let _ = writeln!(out, "  won by {}", describe(winner));
+                    for candidate in runners_up {
+                        let _ = writeln!(out, "  runner-up {}", describe(candidate));
+                    }
+                }
+                None => {
+                    let _ = writeln!(out, "  no candidates{}", self.candidates_note.as_ref().map(|n| format!(": {}", n)).unwrap_or_default());
+                }
+            }
+            let _ = writeln!(out, "  volume: {} moving-week vs {} inferred (coverage {}, per-window ratio {}, correlation {})",
+                side.moving_week_volume, side.inferred_volume, optional(side.inferred_coverage),
+                optional(side.inference_ratio), optional(side.inference_correlation));
+        }
+        let _ = writeln!(out, "\nFlags: {}", if self.flags.is_empty() { "none".to_string() } else { self.flags.join("; ") });
+        out
+    }
+}
+
+fn describe(candidate: &Candidate) -> String {
+    format!("{} (confidence {:.2}): size {:.1} every {:.1} min, {} occurrences at windows {:?}",
+        candidate.method, candidate.confidence, candidate.size, candidate.interval_minutes, candidate.occurrences, candidate.positions)
+}
+
+fn optional(value: Option<f64>) -> String {
+    value.map_or_else(|| "n/a".to_string(), |v| format!("{:.3}", v))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+
+    fn golden() -> Value {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/replay_golden.json");
+        serde_json::from_slice(&std::fs::read(path).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn explains_the_winning_candidate_and_flags() {
+        let export = golden();
+        let wheat = explain(&export, "WHEAT", &DetectorConfig::default()).unwrap();
+        let exported = export.as_array().unwrap().iter().find(|p| p["product_id"] == "WHEAT").unwrap();
+
+        let winner = &wheat.buy.candidates[0];
+        assert_eq!(winner.size, exported["instabuy_modal_size"]);
+        assert_eq!(winner.occurrences, winner.positions.len());
+        assert_eq!(wheat.buy.moving_week_volume, exported["instabuy_moving_week_volume"]);
+        assert!(wheat.flags.iter().any(|f| f.starts_with("buy spike")));
+        assert!(wheat.render().contains("won by"));
+
+        let dead = explain(&export, "DEAD_ITEM", &DetectorConfig::default()).unwrap();
+        assert!(dead.buy.candidates.is_empty());
+        assert_eq!(dead.candidates_note.as_deref(), Some("detection did not run (skipped_inactive)"));
+        assert!(dead.flags.iter().any(|f| f.starts_with("inactive")));
+        assert!(explain(&export, "MISSING", &DetectorConfig::default()).is_err());
+    }
+}
@@ -0,0 +1,1242 @@
+// Writes a finished cycle locally and pushes it through the export engine as one step. A local
+// file whose remote push failed keeps a `.pending` marker next to it (holding the remote path), and
+// every later export (and startup) drains those pushes before its own, several at a time.
+//
+// With EXPORT_SHARDING the cycle is written as `metrics_<stamp>_NNN.json` shards, each pushed (and
+// retried) on its own, followed by `metrics_<stamp>_manifest.json` listing every shard file and
+// its product count, and any products dead-lettered by a failed finalize (see `DeadLetter`).
+//
+// With DELTA_EXPORT_THRESHOLD most cycles are written as `metrics_<stamp>_delta.json` instead (see
+// delta.rs); those are pushed and retried like any other export.
+//
+// Exports without a manifest (single files and deltas) get `metrics_<stamp>_cycle.json` instead
+// when the cycle has dead letters or a PROFILE aggregate to record, pushed like the export itself.
+//
+// Every file is written with a `.sha256` sidecar (see checksum.rs), which the engine uploads
+// alongside it; shard manifests also list each shard's hash.
+//
+// The engine checks the MEGA storage quota before each upload (see export.cpp) and prints the
+// account's usage, which is kept as `remote_quota` on the report. A file that doesn't fit fails
+// with "quota exhausted" and stays pending like any other failed push.
+//
+// With PER_PRODUCT_EXPORT each selected product of a cycle is also written on its own as
+// `by_product/<product_id>_<stamp>.json`, the bare result object, for consumers after one product.
+// Characters other than letters, digits, `_` and `-` in the id are %-encoded (`INK_SACK:3` becomes
+// `INK_SACK%3A3`). Being thousands of small copies of the combined file, they are not fsynced one
+// by one and only get a sidecar and a push with PER_PRODUCT_PUSH; only the newest
+// PER_PRODUCT_KEEP cycles are kept.
+//
+// While any cancellation is outstanding (`cancelled`, EXPORT_TIMEOUT_SECONDS, see process_snapshots)
+// the running engine is killed and every push fails, so a stuck export unwinds quickly and leaves
+// its files pending.
+use crate::atomic::{write_atomic, write_atomic_with, write_replace};
+use crate::cadence::SkipReport;
+use crate::checksum;
+use crate::config::{Config, ExportSharding};
+use crate::delta::DeltaDocument;
+use crate::exit;
+use crate::filter::ProductFilter;
+use crate::formatter::OutputFormatter;
+use crate::resources::ResourceUsage;
+use crate::{unix_now, AnalysisResult, DeadLetter, FinalizeProfile, SCHEMA_VERSION};
+use serde::Serialize;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+pub type SharedExportReport = Arc<Mutex<Option<ExportReport>>>;
+
+// A local write and its push: the status, the error if any, and the written file's sha256
+type WriteOutcome = (ExportStatus, Option<String>, Option<String>);
+
+const BY_PRODUCT_DIR: &str = "by_product";
+
+// How often a running engine is checked for cancellation
+const ENGINE_POLL_INTERVAL: Duration = Duration::from_millis(10);
+// How long the output readers get to finish once the engine has exited; past it they are detached
+const ENGINE_DRAIN_GRACE: Duration = Duration::from_secs(2);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum ExportStatus {
+    Both,
+    LocalOnly,
+    Failed,
+    // The local write ran out of space (ENOSPC or a disk quota); the cycle is kept for a retry
+    DiskFull,
+    // Timed out and still unwinding after being cancelled, so whether anything was written is
+    // unknown; the cycle is kept for a retry like DiskFull
+    Abandoned,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ExportReport {
+    pub status: ExportStatus,
+    pub local_path: String,
+    pub remote_path: String,
+    pub error: Option<String>,
+    // Of the file at `local_path` as written; None when the write (or hashing it) failed
+    pub sha256: Option<String>,
+    // Retries of earlier local files on this attempt, and how many are still waiting afterwards
+    pub retry: RetryReport,
+    pub pending_remote: usize,
+    pub finished_at: u64,
+    pub detection: DetectionSummary,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub profile: Option<FinalizeProfile>,
+    // Old exports deleted to make room after a `DiskFull` write
+    pub pruned: usize,
+    // Sharded exports only; `local_path` and `remote_path` are then the manifest's
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub shards: Vec<ShardReport>,
+    // The latest storage usage the engine reported; None until an engine run has reported one
+    pub remote_quota: Option<RemoteQuota>,
+    // Products left out of the cycle because their finalize failed
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub dead_letters: Vec<DeadLetter>,
+    // The process's memory and the CPU time of the cycle, sampled once the export is done
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub resources: Option<ResourceUsage>,
+    // The extra OUTPUT_FORMAT files written alongside, each pushed and tracked on its own
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub formats: Vec<FormatReport>,
+    // Unchanged polls skipped before each window committed since the previous export
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub poll_skips: Option<SkipReport>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub per_product: Option<PerProductReport>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct RemoteQuota {
+    pub used_bytes: u64,
+    pub total_bytes: u64,
+}
+
+impl RemoteQuota {
+    // The last "MEGA storage used=<bytes> total=<bytes>" line of an engine run's output
+    fn from_engine_output(stdout: &str) -> Option<Self> {
+        stdout.lines().rev().find_map(|line| {
+            let (used, total) = line.split_once("MEGA storage used=")?.1.split_once(" total=")?;
+            Some(Self { used_bytes: used.trim().parse().ok()?, total_bytes: total.trim().parse().ok()? })
+        })
+    }
+
+    pub fn used_fraction(&self) -> f64 {
+        self.used_bytes as f64 / self.total_bytes.max(1) as f64
+    }
+}
+
+// Local files still waiting for their remote push; a growing count means pushes keep failing
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct PendingBacklog {
+    pub count: usize,
+    pub oldest_age_secs: Option<u64>,
+}
+
+// How detection went across the catalog in one cycle. `by_method` counts each exact
+// `pattern_details.detection_method` ("buy:<method>, sell:<method>", or "skipped_inactive" /
+// "skipped_misaligned" when detection didn't run), so its counts sum to `products`.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct DetectionSummary {
+    pub products: usize,
+    // Products where the velocity or rhythm detectors found a pattern on either side
+    pub fuzzy_detected: usize,
+    // Products where either side fell back to legacy clustering and found one
+    pub legacy_detected: usize,
+    pub skipped_inactive: usize,
+    pub by_method: BTreeMap<String, usize>,
+}
+
+impl DetectionSummary {
+    pub fn of(results: &[AnalysisResult]) -> Self {
+        let mut summary = Self { products: results.len(), ..Default::default() };
+        for result in results {
+            let details = &result.pattern_details;
+            if details.velocity_patterns_found + details.rhythm_patterns_found > 0 {
+                summary.fuzzy_detected += 1;
+            }
+            if details.detection_method.contains("legacy") {
+                summary.legacy_detected += 1;
+            }
+            if details.detection_method == "skipped_inactive" {
+                summary.skipped_inactive += 1;
+            }
+            *summary.by_method.entry(details.detection_method.clone()).or_default() += 1;
+        }
+        summary
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ShardReport {
+    pub file: String,
+    pub products: usize,
+    pub status: ExportStatus,
+    pub error: Option<String>,
+    pub sha256: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct FormatReport {
+    pub format: String,
+    pub file: String,
+    pub status: ExportStatus,
+    pub error: Option<String>,
+    pub sha256: Option<String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct PerProductExport {
+    pub filter: ProductFilter,
+    pub push: bool,
+    pub keep_cycles: usize,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct PerProductReport {
+    pub written: usize,
+    pub pushed: usize,
+    // Written, but the push failed and was left pending
+    pub pending: usize,
+    pub failed: usize,
+    // Files of older cycles deleted under PER_PRODUCT_KEEP
+    pub pruned: usize,
+    // The first error of the cycle
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct RetryReport {
+    pub uploaded: usize,
+    pub failed: usize,
+    pub bytes: u64,
+    // Set when the remote reported its quota exhausted, or the engine wasn't logged in, and the rest
+    // of the batch was left pending
+    pub stopped: Option<String>,
+}
+
+// Provenance of an export: which build wrote it, and a hash of the effective analysis settings, so
+// two files with the same `config_hash` were computed the same way. Only `AnalysisConfig` is hashed,
+// as JSON with sorted keys; paths, credentials and other runtime settings don't change the results.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ExportMeta {
+    pub crate_version: String,
+    pub build: String,
+    pub config_hash: String,
+}
+
+impl ExportMeta {
+    pub fn for_config(config: &Config) -> Self {
+        Self {
+            crate_version: env!("CARGO_PKG_VERSION").to_string(),
+            build: env!("GIT_HASH").to_string(),
+            config_hash: format!("{:016x}", fnv1a(&serde_json::to_vec(&serde_json::to_value(&config.analysis).unwrap_or_default()).unwrap_or_default())),
+        }
+    }
+}
+
+// With OUTPUT_META=1 the results are wrapped in this instead of written as a bare array. Every row
+// carries its own schema_version, so the document doesn't repeat it.
+#[derive(Serialize)]
+struct ExportDocument<'a, R> {
+    generated_at: u64,
+    meta: &'a ExportMeta,
+    results: &'a [R],
+}
+
+#[derive(Serialize)]
+struct ShardManifest<'a> {
+    schema_version: u32,
+    generated_at: u64,
+    sharding: ExportSharding,
+    // "pretty" or "compact", as chosen by OUTPUT_FORMAT for this cycle's result count
+    json_style: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    meta: Option<&'a ExportMeta>,
+    detection: &'a DetectionSummary,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    profile: Option<&'a FinalizeProfile>,
+    #[serde(skip_serializing_if = "<[_]>::is_empty")]
+    dead_letters: &'a [DeadLetter],
+    shards: Vec<ManifestEntry>,
+}
+
+// What a manifest records beyond its shards, for the exports that have no manifest
+#[derive(Serialize)]
+struct CycleSidecar<'a> {
+    schema_version: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    profile: Option<&'a FinalizeProfile>,
+    #[serde(skip_serializing_if = "<[_]>::is_empty")]
+    dead_letters: &'a [DeadLetter],
+}
+
+#[derive(Serialize)]
+struct ManifestEntry {
+    file: String,
+    products: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    sha256: Option<String>,
+}
+
+pub struct Exporter {
+    pub local_dir: PathBuf,
+    pub remote_dir: String,
+    pub engine_path: String,
+    // Pending pushes run at most this many at a time
+    pub retry_concurrency: usize,
+    pub meta: Option<ExportMeta>,
+    pub sharding: ExportSharding,
+    // Retention on a full disk: already-pushed export cycles beyond the newest this many are deleted
+    pub keep_files: usize,
+    // Refreshed by every engine run that reports the storage quota
+    pub remote_quota: Mutex<Option<RemoteQuota>>,
+    // Extra OUTPUT_FORMAT formats written next to each full export
+    pub formats: Vec<Box<dyn OutputFormatter>>,
+    // Timed-out exports being cancelled that haven't finished unwinding yet; each clears only its own
+    pub cancelled: AtomicUsize,
+    pub per_product: Option<PerProductExport>,
+}
+
+#[cfg(test)]
+impl Exporter {
+    // Writes into `local_dir` and pushes with `engine`; every other setting at its default
+    pub(crate) fn for_tests(local_dir: &Path, engine: &str) -> Self {
+        Self { local_dir: local_dir.to_path_buf(), remote_dir: "/remote_metrics".into(), engine_path: engine.into(), retry_concurrency: 2, meta: None,
+            sharding: ExportSharding::Single, keep_files: 168, remote_quota: Mutex::new(None), formats: Vec::new(), cancelled: Default::default(), per_product: None }
+    }
+}
+
+impl Exporter {
+    // The exporter `config` describes, writing into `local_dir`; the one construction shared by the
+    // processor and --serve-fixture
+    pub fn from_config(config: &Config, local_dir: &Path) -> Result<Self, String> {
+        Ok(Self {
+            local_dir: local_dir.to_path_buf(),
+            remote_dir: "/remote_metrics".into(),
+            engine_path: config.export_engine_path.clone(),
+            retry_concurrency: config.export_retry_concurrency,
+            meta: config.output_meta.then(|| ExportMeta::for_config(config)),
+            sharding: config.export_sharding,
+            keep_files: config.export_keep_files,
+            remote_quota: Default::default(),
+            formats: crate::formatter::build(&config.output_formats)?,
+            cancelled: Default::default(),
+            per_product: config.per_product_export.clone().map(|filter| PerProductExport {
+                filter,
+                push: config.per_product_push,
+                keep_cycles: config.per_product_keep,
+            }),
+        })
+    }
+
+    pub fn export(&self, stamp: &str, results: &[AnalysisResult], pretty: bool, profile: Option<FinalizeProfile>,
+        dead_letters: Vec<DeadLetter>) -> ExportReport {
+        let retry = self.retry_pending();
+        let detection = DetectionSummary::of(results);
+        let (name, outcome, shards) = match self.sharding {
+            ExportSharding::Single => {
+                let name = format!("metrics_{}.json", stamp);
+                let outcome = self.write_and_push(&name, |writer| self.write_results(writer, results, pretty));
+                self.export_sidecar(stamp, profile.as_ref(), &dead_letters, pretty);
+                (name, outcome, Vec::new())
+            }
+            _ => self.export_shards(stamp, results, pretty, &detection, profile.as_ref(), &dead_letters),
+        };
+        let formats = self.export_formats(stamp, results);
+        let per_product = self.export_per_product(stamp, results);
+        ExportReport { dead_letters, formats, per_product, ..self.report(name, outcome, retry, detection, profile, shards) }
+    }
+
+    // None without PER_PRODUCT_EXPORT
+    pub fn export_per_product(&self, stamp: &str, results: &[AnalysisResult]) -> Option<PerProductReport> {
+        let settings = self.per_product.as_ref()?;
+        let dir = self.local_dir.join(BY_PRODUCT_DIR);
+        let mut report = PerProductReport::default();
+        let selected: Vec<&AnalysisResult> = results.iter().filter(|r| settings.filter.matches_id(&r.product_id)).collect();
+        if let Err(e) = fs::create_dir_all(&dir) {
+            report.failed = selected.len();
+            report.error = Some(format!("{}: {}", dir.display(), e));
+            return Some(report);
+        }
+        for result in selected {
+            let name = product_file_name(&result.product_id, stamp);
+            let local_path = dir.join(&name);
+            let written = serde_json::to_vec(result).map_err(io::Error::from).and_then(|json| write_replace(&local_path, &json));
+            if let Err(e) = written {
+                report.failed += 1;
+                report.error.get_or_insert_with(|| format!("{}: {}", name, e));
+                continue;
+            }
+            report.written += 1;
+            if settings.push {
+                match self.push_written(&local_path, &format!("{}/{}", BY_PRODUCT_DIR, name)) {
+                    (ExportStatus::Both, _, _) => report.pushed += 1,
+                    (_, error, _) => {
+                        report.pending += 1;
+                        report.error = report.error.take().or(error);
+                    }
+                }
+            }
+        }
+        report.pruned = self.prune_by_product(settings.keep_cycles).unwrap_or_else(|e| {
+            eprintln!("[GiantWizard] ❌ Could not prune per-product files: {}", e);
+            0
+        });
+        Some(report)
+    }
+
+    // Each format is written and pushed even when another one failed; a failure only shows in its
+    // own `FormatReport`
+    pub fn export_formats(&self, stamp: &str, results: &[AnalysisResult]) -> Vec<FormatReport> {
+        self.formats.iter()
+            .map(|formatter| {
+                let file = format!("metrics_{}.{}", stamp, formatter.extension());
+                let local_path = self.local_dir.join(&file);
+                let (status, error, sha256) = match formatter.write(results, &local_path) {
+                    Ok(()) => self.push_written(&local_path, &file),
+                    Err(e) if e.downcast_ref::<io::Error>().is_some_and(is_disk_full) =>
+                        (ExportStatus::DiskFull, Some(format!("local write failed, disk full: {}", e)), None),
+                    Err(e) => (ExportStatus::Failed, Some(format!("local write failed: {}", e)), None),
+                };
+                FormatReport { format: formatter.name().to_string(), file, status, error, sha256 }
+            })
+            .collect()
+    }
+
+    // A delta cycle is one file whatever EXPORT_SHARDING says; `detection` covers the whole cycle
+    pub fn export_delta(&self, stamp: &str, delta: &DeltaDocument, detection: DetectionSummary, pretty: bool,
+        profile: Option<FinalizeProfile>, dead_letters: Vec<DeadLetter>) -> ExportReport {
+        let retry = self.retry_pending();
+        let name = format!("metrics_{}_delta.json", stamp);
+        let outcome = self.write_and_push(&name, |writer| write_json(writer, delta, pretty));
+        self.export_sidecar(stamp, profile.as_ref(), &dead_letters, pretty);
+        ExportReport { dead_letters, ..self.report(name, outcome, retry, detection, profile, Vec::new()) }
+    }
+
+    // Only written when there is something to record; a failure is logged and, once written, the
+    // push is retried like any other
+    fn export_sidecar(&self, stamp: &str, profile: Option<&FinalizeProfile>, dead_letters: &[DeadLetter], pretty: bool) {
+        if profile.is_none() && dead_letters.is_empty() {
+            return;
+        }
+        let sidecar = CycleSidecar { schema_version: SCHEMA_VERSION, profile, dead_letters };
+        let name = format!("metrics_{}_cycle.json", stamp);
+        if let (ExportStatus::Failed | ExportStatus::DiskFull, Some(e), _) = self.write_and_push(&name, |writer| write_json(writer, &sidecar, pretty)) {
+            eprintln!("[GiantWizard] ❌ Could not write {}: {}", name, e);
+        }
+    }
+
+    // For an export cancelled by the timeout that never got to report back
+    pub fn abandoned(&self, stamp: &str, detection: DetectionSummary) -> ExportReport {
+        let outcome = (ExportStatus::Abandoned, Some("timed out and still unwinding after being cancelled".to_string()), None);
+        self.report(format!("metrics_{}.json", stamp), outcome, RetryReport::default(), detection, None, Vec::new())
+    }
+
+    fn report(&self, name: String, (status, error, sha256): WriteOutcome, retry: RetryReport, detection: DetectionSummary,
+        profile: Option<FinalizeProfile>, shards: Vec<ShardReport>) -> ExportReport {
+        let pruned = if status == ExportStatus::DiskFull {
+            self.prune(self.keep_files).unwrap_or_else(|e| {
+                eprintln!("[GiantWizard] ❌ Could not prune old exports: {}", e);
+                0
+            })
+        } else {
+            0
+        };
+
+        ExportReport {
+            status,
+            local_path: self.local_dir.join(&name).display().to_string(),
+            remote_path: format!("{}/{}", self.remote_dir, name),
+            error,
+            sha256,
+            retry,
+            pending_remote: self.pending().map(|p| p.len()).unwrap_or(0),
+            finished_at: unix_now(),
+            detection,
+            profile,
+            pruned,
+            shards,
+            remote_quota: self.remote_quota.lock().ok().and_then(|quota| *quota),
+            dead_letters: Vec::new(),
+            formats: Vec::new(),
+            resources: None,
+            poll_skips: None,
+            per_product: None,
+        }
+    }
+
+    // Every shard is written and pushed even when an earlier one failed; the manifest goes last
+    fn export_shards(&self, stamp: &str, results: &[AnalysisResult], pretty: bool, detection: &DetectionSummary,
+        profile: Option<&FinalizeProfile>, dead_letters: &[DeadLetter])
+        -> (String, WriteOutcome, Vec<ShardReport>) {
+        let shards: Vec<ShardReport> = split_shards(results, self.sharding, pretty).into_iter().enumerate()
+            .map(|(i, shard)| {
+                let file = format!("metrics_{}_{:03}.json", stamp, i);
+                let (status, error, sha256) = self.write_and_push(&file, |writer| self.write_results(writer, &shard, pretty));
+                ShardReport { file, products: shard.len(), status, error, sha256 }
+            })
+            .collect();
+
+        let manifest = ShardManifest {
+            schema_version: SCHEMA_VERSION,
+            generated_at: unix_now(),
+            sharding: self.sharding,
+            json_style: if pretty { "pretty" } else { "compact" },
+            meta: self.meta.as_ref(),
+            detection,
+            profile,
+            dead_letters,
+            shards: shards.iter().map(|shard| ManifestEntry { file: shard.file.clone(), products: shard.products, sha256: shard.sha256.clone() }).collect(),
+        };
+        let name = format!("metrics_{}_manifest.json", stamp);
+        let (manifest_status, manifest_error, manifest_sha256) = self.write_and_push(&name, |writer| write_json(writer, &manifest, pretty));
+
+        let statuses = shards.iter().map(|shard| shard.status).chain([manifest_status]);
+        let status = if statuses.clone().any(|s| s == ExportStatus::DiskFull) {
+            ExportStatus::DiskFull
+        } else if statuses.clone().any(|s| s == ExportStatus::Failed) {
+            ExportStatus::Failed
+        } else if statuses.clone().any(|s| s == ExportStatus::LocalOnly) {
+            ExportStatus::LocalOnly
+        } else {
+            ExportStatus::Both
+        };
+        let errors: Vec<String> = shards.iter()
+            .filter_map(|shard| shard.error.as_ref().map(|e| format!("{}: {}", shard.file, e)))
+            .chain(manifest_error.map(|e| format!("{}: {}", name, e)))
+            .collect();
+        (name, (status, (!errors.is_empty()).then(|| errors.join("; ")), manifest_sha256), shards)
+    }
+
+    // `R` is `AnalysisResult` for a single file and `&AnalysisResult` for a shard
+    fn write_results<W: io::Write, R: Serialize>(&self, writer: W, results: &[R], pretty: bool) -> serde_json::Result<()> {
+        match &self.meta {
+            Some(meta) => {
+                let document = ExportDocument { generated_at: unix_now(), meta, results };
+                write_json(writer, &document, pretty)
+            }
+            None => write_json(writer, &results, pretty),
+        }
+    }
+
+    // Writes `name` and its checksum sidecar into the local dir and pushes it, leaving a pending
+    // marker if the push fails
+    fn write_and_push<F>(&self, name: &str, write: F) -> WriteOutcome
+    where
+        F: FnOnce(&mut io::BufWriter<fs::File>) -> serde_json::Result<()>,
+    {
+        let local_path = self.local_dir.join(name);
+        match write_atomic_with(&local_path, |writer| write(writer).map_err(io::Error::from)) {
+            Err(e) if is_disk_full(&e) => (ExportStatus::DiskFull, Some(format!("local write failed, disk full: {}", e)), None),
+            Err(e) => (ExportStatus::Failed, Some(format!("local write failed: {}", e)), None),
+            Ok(()) => self.push_written(&local_path, name),
+        }
+    }
+
+    // Records the checksum of a freshly written local file and pushes it
+    fn push_written(&self, local_path: &Path, name: &str) -> WriteOutcome {
+        let remote_path = format!("{}/{}", self.remote_dir, name);
+        let sha256 = checksum::record(local_path)
+            .map_err(|e| eprintln!("[GiantWizard] ⚠️ Could not record the checksum of {}: {}", local_path.display(), e))
+            .ok();
+        match self.push_remote(local_path, &remote_path) {
+            Ok(()) => (ExportStatus::Both, None, sha256),
+            Err(e) => {
+                if let Err(marker_error) = write_atomic(&pending_marker(local_path), remote_path.as_bytes()) {
+                    eprintln!("[GiantWizard] ❌ Could not record pending remote push for {}: {}",
+                        local_path.display(), marker_error);
+                }
+                (ExportStatus::LocalOnly, Some(format!("remote push failed: {}", e)), sha256)
+            }
+        }
+    }
+
+    // Startup check, so a bad EXPORT_ENGINE_PATH fails the deploy instead of the first hourly push
+    pub fn validate_engine(&self) -> Result<(), String> {
+        let path = resolve_executable(&self.engine_path).ok_or_else(|| self.not_found())?;
+        if !is_executable(&path) {
+            return Err(self.not_executable());
+        }
+        Ok(())
+    }
+
+    fn not_found(&self) -> String {
+        format!("export engine {:?} not found; set EXPORT_ENGINE_PATH to the export_engine binary", self.engine_path)
+    }
+
+    fn not_executable(&self) -> String {
+        format!("export engine {:?} is not executable; check its permissions", self.engine_path)
+    }
+
+    fn push_remote(&self, local_path: &Path, remote_path: &str) -> Result<(), String> {
+        let cancelled = || format!("{} cancelled: the export timed out", self.engine_path);
+        let is_cancelled = || self.cancelled.load(Ordering::Relaxed) > 0;
+        if is_cancelled() {
+            return Err(cancelled());
+        }
+        let mut child = Command::new(&self.engine_path)
+            .arg(local_path)
+            .arg(remote_path)
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .map_err(|e| match e.kind() {
+                io::ErrorKind::NotFound => self.not_found(),
+                io::ErrorKind::PermissionDenied => self.not_executable(),
+                _ => format!("could not run {}: {}", self.engine_path, e),
+            })?;
+        // The engine echoes every command's output (whole `mega-ls` listings included), so both pipes
+        // are drained on their own threads; a full pipe would block it. The exit is polled rather
+        // than waited on, so a cancelled export can kill it. A reader still held open by something
+        // the engine left running (a stuck `mega-*` helper) is abandoned on cancellation, and
+        // ENGINE_DRAIN_GRACE after the engine exited, so it can't hang an export without a timeout.
+        let (stdout, stderr) = (drain(child.stdout.take()), drain(child.stderr.take()));
+        let waited = |e: io::Error| format!("could not wait for {}: {}", self.engine_path, e);
+        let mut exited_at = None;
+        let status = loop {
+            if let Some(status) = child.try_wait().map_err(waited)? {
+                let exited_at = *exited_at.get_or_insert_with(Instant::now);
+                if stdout.is_finished() && stderr.is_finished() {
+                    break status;
+                }
+                if exited_at.elapsed() >= ENGINE_DRAIN_GRACE {
+                    eprintln!("[GiantWizard] ⚠️ {} exited but something it started still holds its output open; not waiting for it",
+                        self.engine_path);
+                    break status;
+                }
+            }
+            if is_cancelled() {
+                let _ = child.kill();
+                let _ = child.wait();
+                return Err(cancelled());
+            }
+            thread::sleep(ENGINE_POLL_INTERVAL);
+        };
+        // An unfinished reader is detached by dropping its handle; its output is lost
+        let joined = |reader: thread::JoinHandle<Vec<u8>>| if reader.is_finished() { reader.join().unwrap_or_default() } else { Vec::new() };
+        let output = std::process::Output { status, stdout: joined(stdout), stderr: joined(stderr) };
+        if let Some(quota) = RemoteQuota::from_engine_output(&String::from_utf8_lossy(&output.stdout)) {
+            if let Ok(mut latest) = self.remote_quota.lock() {
+                *latest = Some(quota);
+            }
+        }
+        if output.status.success() {
+            Ok(())
+        } else if output.status.code() == Some(i32::from(exit::AUTH)) {
+            Err(format!("{} is not logged in to MEGA: {}", self.engine_path, String::from_utf8_lossy(&output.stderr).trim()))
+        } else {
+            Err(format!("{} exited with {}: {}", self.engine_path, output.status,
+                String::from_utf8_lossy(&output.stderr).trim()))
+        }
+    }
+
+    // Pending markers, oldest first, then those of per-product files
+    fn pending(&self) -> io::Result<Vec<PathBuf>> {
+        let markers_in = |dir: &Path| -> io::Result<Vec<PathBuf>> {
+            let mut markers: Vec<PathBuf> = fs::read_dir(dir)?
+                .filter_map(|entry| entry.ok().map(|e| e.path()))
+                .filter(|path| path.extension().is_some_and(|ext| ext == "pending"))
+                .collect();
+            markers.sort();
+            Ok(markers)
+        };
+        let mut markers = markers_in(&self.local_dir)?;
+        match markers_in(&self.local_dir.join(BY_PRODUCT_DIR)) {
+            Ok(by_product) => markers.extend(by_product),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
+        Ok(markers)
+    }
+
+    // Deletes the per-product files of all but the newest `keep` cycles, except those still waiting
+    // for their push
+    fn prune_by_product(&self, keep: usize) -> io::Result<usize> {
+        let files: Vec<(String, PathBuf)> = fs::read_dir(self.local_dir.join(BY_PRODUCT_DIR))?
+            .filter_map(|entry| entry.ok().map(|e| e.path()))
+            .filter_map(|path| {
+                let stamp = path.file_name()?.to_str()?.strip_suffix(".json")?.rsplit_once('_')?.1.to_string();
+                Some((stamp, path))
+            })
+            .collect();
+        let stamps: BTreeSet<&str> = files.iter().map(|(stamp, _)| stamp.as_str()).collect();
+        let expired: BTreeSet<&str> = stamps.into_iter().rev().skip(keep).collect();
+        let mut pruned = 0;
+        for (_, path) in files.iter().filter(|(stamp, path)| expired.contains(stamp.as_str()) && !pending_marker(path).exists()) {
+            fs::remove_file(path)?;
+            let _ = fs::remove_file(checksum::sidecar_path(path));
+            pruned += 1;
+        }
+        Ok(pruned)
+    }
+
+    // Deletes all but the newest `keep` already-pushed cycles, whole: every file of the cycle
+    // (JSON, shards, manifest, sidecar, extra formats) with its checksum. A cycle with any file still
+    // waiting for its push is never touched, since the local files are its only copy. Stamps sort
+    // chronologically. Returns the number of files deleted.
+    pub fn prune(&self, keep: usize) -> io::Result<usize> {
+        let mut cycles: BTreeMap<String, Vec<PathBuf>> = BTreeMap::new();
+        for path in fs::read_dir(&self.local_dir)?.filter_map(|entry| entry.ok().map(|e| e.path())) {
+            let Some(name) = path.file_name().and_then(|n| n.to_str()) else { continue };
+            if name.ends_with(".sha256") || name.ends_with(".pending") {
+                continue;
+            }
+            if let Some(stamp) = cycle_stamp(name) {
+                cycles.entry(stamp.to_string()).or_default().push(path);
+            }
+        }
+        let pushed: Vec<Vec<PathBuf>> = cycles.into_values()
+            .filter(|files| files.iter().all(|path| !pending_marker(path).exists()))
+            .collect();
+        let excess = pushed.len().saturating_sub(keep);
+        let mut pruned = 0;
+        for path in pushed[..excess].iter().flatten() {
+            fs::remove_file(path)?;
+            let _ = fs::remove_file(checksum::sidecar_path(path));
+            pruned += 1;
+        }
+        Ok(pruned)
+    }
+
+    pub fn backlog(&self) -> io::Result<PendingBacklog> {
+        let markers = self.pending()?;
+        let oldest_age_secs = markers.iter()
+            .filter_map(|marker| fs::metadata(marker).and_then(|meta| meta.modified()).ok())
+            .filter_map(|modified| SystemTime::now().duration_since(modified).ok())
+            .map(|age| age.as_secs())
+            .max();
+        Ok(PendingBacklog { count: markers.len(), oldest_age_secs })
+    }
+
+    // Drains the pending pushes in batches of `retry_concurrency`. A failing file only fails itself,
+    // except a quota or auth error, which stops the drain since every later push would hit it too.
+    pub fn retry_pending(&self) -> RetryReport {
+        let mut report = RetryReport::default();
+        let markers = self.pending().unwrap_or_default();
+        for batch in markers.chunks(self.retry_concurrency.max(1)) {
+            let outcomes: Vec<Result<Option<u64>, String>> = std::thread::scope(|scope| {
+                let pushes: Vec<_> = batch.iter().map(|marker| scope.spawn(|| self.retry_one(marker))).collect();
+                pushes.into_iter()
+                    .map(|push| push.join().unwrap_or_else(|_| Err("retry thread panicked".to_string())))
+                    .collect()
+            });
+            for (marker, outcome) in batch.iter().zip(outcomes) {
+                match outcome {
+                    Ok(Some(bytes)) => {
+                        report.uploaded += 1;
+                        report.bytes += bytes;
+                    }
+                    Ok(None) => {}
+                    Err(e) => {
+                        eprintln!("[GiantWizard] Retry of {} still failing: {}", marker.with_extension("").display(), e);
+                        report.failed += 1;
+                        if is_quota_error(&e) || is_auth_error(&e) {
+                            report.stopped = Some(e);
+                        }
+                    }
+                }
+            }
+            if let Some(reason) = &report.stopped {
+                eprintln!("[GiantWizard] ⛔ Remote unusable, leaving the remaining pushes pending: {}", reason);
+                break;
+            }
+        }
+        report
+    }
+
+    // Bytes pushed, or None when the marker was stale and dropped
+    fn retry_one(&self, marker: &Path) -> Result<Option<u64>, String> {
+        let local_path = marker.with_extension("");
+        let Ok(meta) = fs::metadata(&local_path) else {
+            eprintln!("[GiantWizard] Dropping pending push for missing {}", local_path.display());
+            let _ = fs::remove_file(marker);
+            return Ok(None);
+        };
+        let remote_path = fs::read_to_string(marker).map_err(|e| format!("unreadable marker: {}", e))?;
+        self.push_remote(&local_path, remote_path.trim())?;
+        let _ = fs::remove_file(marker);
+        Ok(Some(meta.len()))
+    }
+}
+
+// Hash shards always number N (some may be empty) so consumers see a stable file set. Size shards
+// are filled in order up to the cap as measured per result, so the files run slightly over it by
+// the array and wrapper overhead; a single result larger than the cap gets a shard of its own.
+fn split_shards(results: &[AnalysisResult], sharding: ExportSharding, pretty: bool) -> Vec<Vec<&AnalysisResult>> {
+    match sharding {
+        ExportSharding::Single => vec![results.iter().collect()],
+        ExportSharding::Hash(count) => {
+            let mut shards = vec![Vec::new(); count];
+            for result in results {
+                shards[(fnv1a(result.product_id.as_bytes()) % count as u64) as usize].push(result);
+            }
+            shards
+        }
+        ExportSharding::MaxBytes(cap) => {
+            let mut shards: Vec<Vec<&AnalysisResult>> = vec![Vec::new()];
+            let mut bytes = 0;
+            for result in results {
+                let size = if pretty { serde_json::to_vec_pretty(result) } else { serde_json::to_vec(result) }
+                    .map_or(0, |encoded| encoded.len() as u64);
+                if bytes > 0 && bytes + size > cap {
+                    shards.push(Vec::new());
+                    bytes = 0;
+                }
+                bytes += size;
+                shards.last_mut().unwrap().push(result);
+            }
+            shards
+        }
+    }
+}
+
+fn write_json<W: io::Write, T: Serialize>(writer: W, value: &T, pretty: bool) -> serde_json::Result<()> {
+    if pretty {
+        serde_json::to_writer_pretty(writer, value)
+    } else {
+        serde_json::to_writer(writer, value)
+    }
+}
+
+// FNV-1a: stable across Rust releases, unlike `DefaultHasher`
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, &byte| (hash ^ byte as u64).wrapping_mul(0x0100_0000_01b3))
+}
+
+// Reads a child's pipe to the end on its own thread
+fn drain<R: Read + Send + 'static>(pipe: Option<R>) -> thread::JoinHandle<Vec<u8>> {
+    thread::spawn(move || {
+        let mut output = Vec::new();
+        if let Some(mut pipe) = pipe {
+            let _ = pipe.read_to_end(&mut output);
+        }
+        output
+    })
+}
+
+// Bare names are looked up on PATH the way `Command` would
+fn resolve_executable(program: &str) -> Option<PathBuf> {
+    let path = Path::new(program);
+    if path.components().count() > 1 {
+        return path.is_file().then(|| path.to_path_buf());
+    }
+    std::env::split_paths(&std::env::var_os("PATH")?)
+        .map(|dir| dir.join(program))
+        .find(|candidate| candidate.is_file())
+}
+
+#[cfg(unix)]
+fn is_executable(path: &Path) -> bool {
+    use std::os::unix::fs::PermissionsExt;
+    fs::metadata(path).is_ok_and(|meta| meta.permissions().mode() & 0o111 != 0)
+}
+
+#[cfg(not(unix))]
+fn is_executable(path: &Path) -> bool {
+    path.is_file()
+}
+
+// MEGA reports an exhausted transfer or storage quota as EOVERQUOTA / "over quota"; the engine's
+// own pre-upload check as "quota exhausted"
+fn is_quota_error(message: &str) -> bool {
+    let message = message.to_lowercase();
+    message.contains("eoverquota") || message.contains("over quota") || message.contains("quota exceeded")
+        || message.contains("quota exhausted")
+}
+
+// The engine exits with the auth code (see exit.rs) when MEGA has no logged-in session
+pub fn is_auth_error(message: &str) -> bool {
+    message.contains("not logged in to MEGA")
+}
+
+pub fn is_disk_full(error: &io::Error) -> bool {
+    matches!(error.kind(), io::ErrorKind::StorageFull | io::ErrorKind::QuotaExceeded)
+}
+
+// `<product_id>_<stamp>.json`, with every byte of the id outside [A-Za-z0-9_-] written as %XX
+fn product_file_name(product_id: &str, stamp: &str) -> String {
+    let mut name = String::new();
+    for byte in product_id.bytes() {
+        if byte.is_ascii_alphanumeric() || byte == b'_' || byte == b'-' {
+            name.push(char::from(byte));
+        } else {
+            name.push_str(&format!("%{:02X}", byte));
+        }
+    }
+    format!("{}_{}.json", name, stamp)
+}
+
+// `<stamp>` of any `metrics_<stamp>...` export file
+fn cycle_stamp(name: &str) -> Option<&str> {
+    name.strip_prefix("metrics_")?.get(..14).filter(|stamp| stamp.bytes().all(|b| b.is_ascii_digit()))
+}
+
+fn pending_marker(local_path: &Path) -> PathBuf {
+    let mut marker = local_path.as_os_str().to_owned();
+    marker.push(".pending");
+    PathBuf::from(marker)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn failed_remote_push_is_retried_by_the_next_export() {
+        let dir = tempfile::tempdir().unwrap();
+
+        let report = Exporter::for_tests(dir.path(), "false").export("20250101000000", &[], true, None, Vec::new());
+        assert_eq!(report.status, ExportStatus::LocalOnly);
+        assert_eq!(report.pending_remote, 1);
+        let marker = dir.path().join("metrics_20250101000000.json.pending");
+        assert_eq!(fs::read_to_string(&marker).unwrap(), "/remote_metrics/metrics_20250101000000.json");
+
+        let report = Exporter::for_tests(dir.path(), "true").export("20250101010000", &[], true, None, Vec::new());
+        assert_eq!(report.status, ExportStatus::Both);
+        assert_eq!(report.retry.uploaded, 1);
+        assert_eq!(report.pending_remote, 0);
+        assert!(!marker.exists());
+        assert!(dir.path().join("metrics_20250101000000.json").exists());
+    }
+
+    #[test]
+    fn an_engine_printing_more_than_a_pipe_holds_still_finishes() {
+        use std::os::unix::fs::PermissionsExt;
+        let dir = tempfile::tempdir().unwrap();
+        // About 1 MiB on each stream, far past the 64 KiB a pipe buffers
+        let engine = dir.path().join("engine.sh");
+        fs::write(&engine, "#!/bin/sh\nyes listing | head -c 1048576\nyes progress | head -c 1048576 >&2\n").unwrap();
+        fs::set_permissions(&engine, fs::Permissions::from_mode(0o755)).unwrap();
+        let exporter = Exporter::for_tests(dir.path(), engine.to_str().unwrap());
+        let local = dir.path().join("metrics_20250101000000.json");
+        fs::write(&local, "[]").unwrap();
+        assert_eq!(exporter.push_remote(&local, "/remote_metrics/metrics_20250101000000.json"), Ok(()));
+
+        exporter.cancelled.store(1, Ordering::Relaxed);
+        assert!(exporter.push_remote(&local, "/remote_metrics/metrics_20250101000000.json").unwrap_err().contains("cancelled"));
+    }
+
+    #[test]
+    fn an_engine_leaving_a_helper_on_its_pipes_does_not_hang_the_push() {
+        use std::os::unix::fs::PermissionsExt;
+        let dir = tempfile::tempdir().unwrap();
+        // The helper inherits stdout and stderr and outlives the engine, like a stuck mega-* command
+        let pid = dir.path().join("helper.pid");
+        let engine = dir.path().join("engine.sh");
+        fs::write(&engine, format!("#!/bin/sh
+sleep 600 &
+echo $! > {}
+exit 0
+", pid.display())).unwrap();
+        fs::set_permissions(&engine, fs::Permissions::from_mode(0o755)).unwrap();
+        let exporter = Exporter::for_tests(dir.path(), engine.to_str().unwrap());
+        let local = dir.path().join("metrics_20250101000000.json");
+        fs::write(&local, "[]").unwrap();
+
+        let pushed = exporter.push_remote(&local, "/remote_metrics/metrics_20250101000000.json");
+        let _ = Command::new("kill").arg(fs::read_to_string(&pid).unwrap().trim()).status();
+        assert_eq!(pushed, Ok(()));
+    }
+
+    #[test]
+    fn missing_or_non_executable_engine_is_reported_distinctly() {
+        let dir = tempfile::tempdir().unwrap();
+        assert!(Exporter::for_tests(dir.path(), "true").validate_engine().is_ok());
+
+        let missing = dir.path().join("no_such_engine");
+        let missing = Exporter::for_tests(dir.path(), missing.to_str().unwrap());
+        assert!(missing.validate_engine().unwrap_err().contains("not found"));
+        let report = missing.export("20250101000000", &[], true, None, Vec::new());
+        assert_eq!(report.status, ExportStatus::LocalOnly);
+        assert!(report.error.unwrap().contains("set EXPORT_ENGINE_PATH"));
+
+        let plain = dir.path().join("plain_file");
+        fs::write(&plain, "not a program").unwrap();
+        let plain = Exporter::for_tests(dir.path(), plain.to_str().unwrap());
+        assert!(plain.validate_engine().unwrap_err().contains("not executable"));
+    }
+
+    fn failed_exports(dir: &Path, count: usize) {
+        for i in 0..count {
+            Exporter::for_tests(dir, "false").export(&format!("2025010100{:02}00", i), &[], false, None, Vec::new());
+        }
+    }
+
+    #[test]
+    fn pending_backlog_drains_in_one_batch() {
+        let dir = tempfile::tempdir().unwrap();
+        failed_exports(dir.path(), 5);
+
+        let report = Exporter::for_tests(dir.path(), "true").retry_pending();
+        assert_eq!((report.uploaded, report.failed), (5, 0));
+        assert_eq!(report.bytes, 5 * 2);
+        assert!(Exporter::for_tests(dir.path(), "true").pending().unwrap().is_empty());
+    }
+
+    #[test]
+    fn backlog_reports_pending_count_and_oldest_age() {
+        let dir = tempfile::tempdir().unwrap();
+        assert_eq!(Exporter::for_tests(dir.path(), "true").backlog().unwrap(), PendingBacklog::default());
+
+        failed_exports(dir.path(), 3);
+        let oldest = fs::File::options().write(true).open(dir.path().join("metrics_20250101000000.json.pending")).unwrap();
+        oldest.set_modified(SystemTime::now() - std::time::Duration::from_secs(7_200)).unwrap();
+        let backlog = Exporter::for_tests(dir.path(), "true").backlog().unwrap();
+        assert_eq!(backlog.count, 3);
+        assert!((7_200..7_260).contains(&backlog.oldest_age_secs.unwrap()));
+    }
+
+    #[test]
+    fn quota_and_auth_errors_stop_the_batch_early() {
+        use std::os::unix::fs::PermissionsExt;
+        let dir = tempfile::tempdir().unwrap();
+        failed_exports(dir.path(), 5);
+        let engine = dir.path().join("over_quota.sh");
+        fs::write(&engine, "#!/bin/sh\necho 'API:EOVERQUOTA: Quota exceeded' >&2\nexit 1\n").unwrap();
+        fs::set_permissions(&engine, fs::Permissions::from_mode(0o755)).unwrap();
+
+        let report = Exporter::for_tests(dir.path(), engine.to_str().unwrap()).retry_pending();
+        assert_eq!((report.uploaded, report.failed), (0, 2));
+        assert!(report.stopped.unwrap().contains("EOVERQUOTA"));
+        assert_eq!(Exporter::for_tests(dir.path(), "true").pending().unwrap().len(), 5);
+
+        fs::write(&engine, "#!/bin/sh\necho 'Export Engine: not logged in to MEGA' >&2\nexit 3\n").unwrap();
+        let report = Exporter::for_tests(dir.path(), engine.to_str().unwrap()).retry_pending();
+        assert_eq!(report.failed, 2);
+        assert!(is_auth_error(&report.stopped.unwrap()));
+    }
+
+    #[test]
+    fn engine_quota_reports_are_kept_and_exhaustion_leaves_the_export_pending() {
+        use std::os::unix::fs::PermissionsExt;
+        let dir = tempfile::tempdir().unwrap();
+        let engine = dir.path().join("quota.sh");
+        fs::write(&engine, "#!/bin/sh\necho 'Export Engine: MEGA storage used=100 total=1000'\necho 'Export Engine: MEGA storage used=102 total=1000'\n").unwrap();
+        fs::set_permissions(&engine, fs::Permissions::from_mode(0o755)).unwrap();
+        let exporter = Exporter::for_tests(dir.path(), engine.to_str().unwrap());
+        assert_eq!(exporter.export("20250101000000", &[], false, None, Vec::new()).remote_quota, Some(RemoteQuota { used_bytes: 102, total_bytes: 1000 }));
+
+        fs::write(&engine, "#!/bin/sh\necho 'Export Engine: MEGA storage used=999 total=1000'\necho 'Export Engine: MEGA storage quota exhausted: 2 bytes needed, 1 of 1000 free' >&2\nexit 1\n").unwrap();
+        let report = exporter.export("20250101010000", &[], false, None, Vec::new());
+        assert_eq!(report.status, ExportStatus::LocalOnly);
+        assert_eq!(report.remote_quota.map(|q| q.used_bytes), Some(999));
+        assert_eq!(report.pending_remote, 1);
+        let retry = exporter.retry_pending();
+        assert!(retry.stopped.is_some_and(|reason| is_quota_error(&reason)));
+    }
+
+    #[test]
+    fn disk_full_write_prunes_pushed_exports_but_never_pending_ones() {
+        let dir = tempfile::tempdir().unwrap();
+        for i in 0..5 {
+            Exporter::for_tests(dir.path(), "true").export(&format!("2025010100{:02}00", i), &[], false, None, Vec::new());
+        }
+        fs::write(pending_marker(&dir.path().join("metrics_20250101000000.json")), "/remote_metrics").unwrap();
+
+        let full = |_: &mut io::BufWriter<fs::File>| Err(serde_json::Error::io(io::ErrorKind::StorageFull.into()));
+        let (status, error, sha256) = Exporter::for_tests(dir.path(), "true").write_and_push("metrics_20250101000500.json", full);
+        assert_eq!(status, ExportStatus::DiskFull);
+        assert!(error.unwrap().contains("disk full"));
+        assert_eq!(sha256, None);
+        assert!(!dir.path().join("metrics_20250101000500.json").exists());
+
+        // The pending export stays even though it is the oldest
+        assert_eq!(Exporter::for_tests(dir.path(), "true").prune(2).unwrap(), 2);
+        let mut left: Vec<String> = fs::read_dir(dir.path()).unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .filter(|name| name.ends_with(".json"))
+            .collect();
+        left.sort();
+        assert_eq!(left, ["metrics_20250101000000.json", "metrics_20250101000300.json", "metrics_20250101000400.json"]);
+    }
+
+    #[test]
+    fn pruning_removes_whole_cycles_across_formats_and_shards() {
+        let dir = tempfile::tempdir().unwrap();
+        let cycle_files = |stamp: &str| [
+            format!("metrics_{}_000.json", stamp), format!("metrics_{}_001.json", stamp), format!("metrics_{}_manifest.json", stamp),
+            format!("metrics_{}.csv", stamp), format!("metrics_{}.ndjson", stamp), format!("metrics_{}_cycle.json", stamp),
+        ];
+        for stamp in ["20250101000000", "20250101010000", "20250101020000", "20250101030000"] {
+            for file in cycle_files(stamp) {
+                fs::write(dir.path().join(&file), "[]").unwrap();
+                fs::write(checksum::sidecar_path(&dir.path().join(&file)), "").unwrap();
+            }
+        }
+        // One shard of the oldest cycle is still pending, so that whole cycle stays
+        fs::write(pending_marker(&dir.path().join("metrics_20250101000000_001.json")), "/remote_metrics").unwrap();
+        fs::write(dir.path().join("delta_base.json"), "{}").unwrap();
+
+        assert_eq!(Exporter::for_tests(dir.path(), "true").prune(1).unwrap(), 12);
+        let mut stamps: Vec<String> = fs::read_dir(dir.path()).unwrap()
+            .filter_map(|entry| cycle_stamp(&entry.unwrap().file_name().into_string().unwrap()).map(str::to_string))
+            .collect();
+        stamps.sort();
+        stamps.dedup();
+        assert_eq!(stamps, ["20250101000000", "20250101030000"]);
+        for file in cycle_files("20250101030000") {
+            assert!(dir.path().join(&file).exists());
+            assert!(checksum::sidecar_path(&dir.path().join(&file)).exists());
+        }
+        assert!(dir.path().join("delta_base.json").exists());
+    }
+
+    #[test]
+    fn meta_wraps_results_with_build_and_config_provenance() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = Config::default();
+        let meta = ExportMeta::for_config(&config);
+        let exporter = Exporter { meta: Some(meta.clone()), ..Exporter::for_tests(dir.path(), "true") };
+        exporter.export("20250101000000", &[], false, None, Vec::new());
+
+        let written: serde_json::Value = serde_json::from_slice(&fs::read(dir.path().join("metrics_20250101000000.json")).unwrap()).unwrap();
+        assert!(written.get("schema_version").is_none());
+        assert_eq!(written["meta"]["crate_version"], env!("CARGO_PKG_VERSION"));
+        assert_eq!(written["meta"]["config_hash"], meta.config_hash);
+        assert!(written["results"].as_array().unwrap().is_empty());
+
+        // Settings that don't shape the results leave the hash alone
+        let runtime = Config { export_engine_path: "/opt/export_engine".into(), ..config.clone() };
+        assert_eq!(ExportMeta::for_config(&runtime), meta);
+        let mut changed = config;
+        changed.analysis.spike_median_multiple += 1.0;
+        assert_ne!(ExportMeta::for_config(&changed).config_hash, meta.config_hash);
+    }
+
+    #[tokio::test]
+    async fn sharded_exports_push_each_shard_and_list_them_in_a_manifest() {
+        use std::os::unix::fs::PermissionsExt;
+        let results = crate::replay::replay_cycle().await;
+        let dir = tempfile::tempdir().unwrap();
+        let engine = dir.path().join("fails_shard_1.sh");
+        fs::write(&engine, "#!/bin/sh\ncase \"$1\" in *_001.json) exit 1;; esac\n").unwrap();
+        fs::set_permissions(&engine, fs::Permissions::from_mode(0o755)).unwrap();
+
+        let sharded = Exporter { sharding: ExportSharding::Hash(3), ..Exporter::for_tests(dir.path(), engine.to_str().unwrap()) };
+        let report = sharded.export("20250101000000", &results, false, None, Vec::new());
+        assert_eq!(report.status, ExportStatus::LocalOnly);
+        assert!(report.local_path.ends_with("metrics_20250101000000_manifest.json"));
+        assert_eq!(report.shards.iter().map(|s| s.status).collect::<Vec<_>>(), [ExportStatus::Both, ExportStatus::LocalOnly, ExportStatus::Both]);
+        assert_eq!(report.pending_remote, 1);
+
+        let manifest_bytes = fs::read(&report.local_path).unwrap();
+        assert_eq!(checksum::check(report.local_path.as_ref(), &manifest_bytes).unwrap(), report.sha256);
+        let manifest: serde_json::Value = serde_json::from_slice(&manifest_bytes).unwrap();
+        assert_eq!(manifest["json_style"], "compact");
+        let mut exported = Vec::new();
+        for entry in manifest["shards"].as_array().unwrap() {
+            let bytes = fs::read(dir.path().join(entry["file"].as_str().unwrap())).unwrap();
+            assert_eq!(entry["sha256"], checksum::sha256_hex(&bytes));
+            let shard: Vec<serde_json::Value> = serde_json::from_slice(&bytes).unwrap();
+            assert_eq!(entry["products"], shard.len());
+            exported.extend(shard.into_iter().map(|r| r["product_id"].as_str().unwrap().to_string()));
+        }
+        exported.sort();
+        let mut expected: Vec<String> = results.iter().map(|r| r.product_id.clone()).collect();
+        expected.sort();
+        assert_eq!(exported, expected);
+        let by_method: BTreeMap<String, usize> = serde_json::from_value(manifest["detection"]["by_method"].clone()).unwrap();
+        assert_eq!(by_method.values().sum::<usize>(), results.len());
+        assert_eq!(manifest["detection"]["products"], results.len());
+        assert_eq!(by_method["skipped_inactive"], manifest["detection"]["skipped_inactive"]);
+        assert_eq!(report.detection, DetectionSummary::of(&results));
+
+        let largest = results.iter().map(|r| serde_json::to_vec(r).unwrap().len()).max().unwrap() as u64;
+        let by_size = split_shards(&results, ExportSharding::MaxBytes(largest), false);
+        assert!(by_size.len() > 1);
+        assert_eq!(by_size.iter().map(Vec::len).sum::<usize>(), results.len());
+        for shard in by_size {
+            assert!(shard.iter().map(|r| serde_json::to_vec(r).unwrap().len() as u64).sum::<u64>() <= largest);
+        }
+    }
+
+    #[test]
+    fn single_and_delta_exports_keep_the_profile_in_the_cycle_sidecar() {
+        let dir = tempfile::tempdir().unwrap();
+        let exporter = Exporter::for_tests(dir.path(), "true");
+        let sidecar = |stamp: &str| dir.path().join(format!("metrics_{}_cycle.json", stamp));
+
+        exporter.export("20250101000000", &[], false, None, Vec::new());
+        assert!(!sidecar("20250101000000").exists());
+
+        let profile = FinalizeProfile { products: 0, total_ms: 1.5, slowest: Vec::new() };
+        let report = exporter.export("20250101010000", &[], false, Some(profile.clone()), Vec::new());
+        assert_eq!(report.status, ExportStatus::Both);
+        let written: serde_json::Value = serde_json::from_slice(&fs::read(sidecar("20250101010000")).unwrap()).unwrap();
+        assert_eq!(written["profile"]["total_ms"], 1.5);
+        assert!(written.get("dead_letters").is_none());
+
+        let delta = DeltaDocument { schema_version: SCHEMA_VERSION, generated_at: 0, kind: "delta".into(), cycle: 2, base_cycle: 1, threshold: 0.5,
+            changed: Vec::new(), unchanged: Vec::new(), removed: Vec::new() };
+        exporter.export_delta("20250101020000", &delta, DetectionSummary::default(), false, Some(profile), Vec::new());
+        let written: serde_json::Value = serde_json::from_slice(&fs::read(sidecar("20250101020000")).unwrap()).unwrap();
+        assert_eq!(written["profile"]["total_ms"], 1.5);
+    }
+
+    #[test]
+    fn each_extra_format_is_pushed_and_reported_on_its_own() {
+        use std::os::unix::fs::PermissionsExt;
+        let dir = tempfile::tempdir().unwrap();
+        let engine = dir.path().join("fails_csv.sh");
+        fs::write(&engine, "#!/bin/sh\ncase \"$1\" in *.csv) exit 1;; esac\n").unwrap();
+        fs::set_permissions(&engine, fs::Permissions::from_mode(0o755)).unwrap();
+        let formats = crate::formatter::build(&["ndjson".to_string(), "csv".to_string()]).unwrap();
+        let exporter = Exporter { formats, ..Exporter::for_tests(dir.path(), engine.to_str().unwrap()) };
+
+        let report = exporter.export("20250101000000", &[], false, None, Vec::new());
+        assert_eq!(report.status, ExportStatus::Both);
+        let statuses: Vec<(&str, ExportStatus)> = report.formats.iter().map(|f| (f.file.as_str(), f.status)).collect();
+        assert_eq!(statuses, [("metrics_20250101000000.ndjson", ExportStatus::Both), ("metrics_20250101000000.csv", ExportStatus::LocalOnly)]);
+        assert!(report.formats[1].error.as_ref().unwrap().starts_with("remote push failed"));
+        assert_eq!(report.pending_remote, 1);
+    }
+
+    #[tokio::test]
+    async fn selected_products_get_their_own_file_per_cycle() {
+        let mut results = crate::replay::replay_cycle().await;
+        results[0].product_id = "INK_SACK:3".to_string();
+        let dir = tempfile::tempdir().unwrap();
+        let per_product = PerProductExport { filter: ProductFilter::from_lists("ink_*,ENCHANTED_*", ""), push: true, keep_cycles: 1 };
+        let exporter = Exporter { per_product: Some(per_product), ..Exporter::for_tests(dir.path(), "true") };
+
+        let report = exporter.export("20250101000000", &results, false, None, Vec::new());
+        let expected = PerProductReport { written: 2, pushed: 2, ..Default::default() };
+        assert_eq!(report.per_product.as_ref(), Some(&expected));
+        let by_product = dir.path().join("by_product");
+        let mut files: Vec<String> = fs::read_dir(&by_product).unwrap().map(|e| e.unwrap().file_name().into_string().unwrap()).collect();
+        files.sort();
+        assert_eq!(files, [
+            "ENCHANTED_DIAMOND_20250101000000.json", "ENCHANTED_DIAMOND_20250101000000.json.sha256",
+            "INK_SACK%3A3_20250101000000.json", "INK_SACK%3A3_20250101000000.json.sha256",
+        ]);
+        let ink: serde_json::Value = serde_json::from_slice(&fs::read(by_product.join("INK_SACK%3A3_20250101000000.json")).unwrap()).unwrap();
+        assert_eq!(ink, serde_json::to_value(&results[0]).unwrap());
+
+        // Keeping one cycle, the next one replaces them; a file still waiting for its push stays
+        fs::write(pending_marker(&by_product.join("INK_SACK%3A3_20250101000000.json")), "/remote_metrics/by_product/x").unwrap();
+        let exporter = Exporter { engine_path: "false".into(), ..exporter };
+        let report = exporter.export("20250101010000", &results, false, None, Vec::new());
+        let per_product = report.per_product.unwrap();
+        assert_eq!((per_product.written, per_product.pending, per_product.pruned), (2, 2, 1));
+        assert!(!by_product.join("ENCHANTED_DIAMOND_20250101000000.json").exists());
+        assert!(by_product.join("INK_SACK%3A3_20250101000000.json").exists());
+        assert_eq!(report.pending_remote, 4, "the combined file, the old marker and both new files");
+    }
+
+    #[test]
+    fn unwritable_local_dir_fails_without_pushing() {
+        let dir = tempfile::tempdir().unwrap();
+        let missing = dir.path().join("missing");
+
+        let report = Exporter::for_tests(&missing, "true").export("20250101000000", &[], false, None, Vec::new());
+        assert_eq!(report.status, ExportStatus::Failed);
+        assert!(report.error.unwrap().starts_with("local write failed"));
+    }
+}
@@ -0,0 +1,153 @@
+use crate::BazaarInfo;
+use serde::Serialize;
+
+// Include/exclude product selection shared by the collection loop and the /export query.
+// Patterns support `*` as a wildcard; an empty include list matches every product.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ProductFilter {
+    pub include: Vec<String>,
+    pub exclude: Vec<String>,
+    pub min_volume: Option<i64>,
+}
+
+impl ProductFilter {
+    pub fn from_lists(include: &str, exclude: &str) -> Self {
+        Self {
+            include: split_patterns(include),
+            exclude: split_patterns(exclude),
+            min_volume: None,
+        }
+    }
+
+    pub fn matches_id(&self, product_id: &str) -> bool {
+        (self.include.is_empty() || self.include.iter().any(|p| glob_match(p, product_id)))
+            && !self.exclude.iter().any(|p| glob_match(p, product_id))
+    }
+
+    // Volume is the combined buy + sell moving-week counter of the snapshot
+    pub fn matches(&self, info: &BazaarInfo) -> bool {
+        self.matches_id(&info.product_id)
+            && self.min_volume.is_none_or(|min| info.buy_moving_week + info.sell_moving_week >= min)
+    }
+}
+
+// AMOUNT_STACK_SIZES: per-product (or, with `*`, per-category) stack sizes, e.g.
+// `ENCHANTED_*=160,WHEAT=1`, for products whose order-book `amount` counts stacks rather than units.
+// Every level's amount is multiplied by the first matching size when the snapshot is collected, so
+// inferred volume, new-offer amounts and book depth all come out in units. Unmatched products keep
+// a size of 1, a no-op. Moving-week counters are left alone; they are already in units.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct StackSizes {
+    pub rules: Vec<StackSize>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct StackSize {
+    pub pattern: String,
+    // 0 when the entry didn't parse, so `validate` reports it
+    pub size: i64,
+}
+
+impl StackSizes {
+    pub fn from_list(list: &str) -> Self {
+        let rules = list.split(',')
+            .map(str::trim)
+            .filter(|entry| !entry.is_empty())
+            .map(|entry| match entry.split_once('=') {
+                Some((pattern, size)) => StackSize { pattern: pattern.trim().to_uppercase(), size: size.trim().parse().unwrap_or(0) },
+                None => StackSize { pattern: entry.to_uppercase(), size: 0 },
+            })
+            .collect();
+        Self { rules }
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        match self.rules.iter().find(|rule| rule.size <= 0) {
+            Some(bad) => Err(format!("AMOUNT_STACK_SIZES entries must be PATTERN=<positive integer>, got {}", bad.pattern)),
+            None => Ok(()),
+        }
+    }
+
+    pub fn size_for(&self, product_id: &str) -> i64 {
+        self.rules.iter().find(|rule| glob_match(&rule.pattern, product_id)).map_or(1, |rule| rule.size)
+    }
+
+    pub fn normalize(&self, info: &mut BazaarInfo) {
+        let size = self.size_for(&info.product_id);
+        if size == 1 {
+            return;
+        }
+        for order in info.buy_orders.iter_mut().chain(info.sell_orders.iter_mut()) {
+            order.amount = order.amount.saturating_mul(size);
+        }
+    }
+}
+
+pub fn split_patterns(list: &str) -> Vec<String> {
+    list.split(',')
+        .map(|p| p.trim().to_uppercase())
+        .filter(|p| !p.is_empty())
+        .collect()
+}
+
+pub fn glob_match(pattern: &str, text: &str) -> bool {
+    let parts: Vec<&str> = pattern.split('*').collect();
+    if parts.len() == 1 {
+        return pattern == text;
+    }
+
+    let mut rest = text;
+    for (i, part) in parts.iter().enumerate() {
+        if i == 0 {
+            match rest.strip_prefix(part) {
+                Some(r) => rest = r,
+                None => return false,
+            }
+        } else if i == parts.len() - 1 {
+            return rest.ends_with(part);
+        } else if let Some(pos) = rest.find(part) {
+            rest = &rest[pos + part.len()..];
+        } else {
+            return false;
+        }
+    }
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn glob_patterns_match_prefix_suffix_and_infix() {
+        assert!(glob_match("ENCHANTED_*", "ENCHANTED_LAPIS_BLOCK"));
+        assert!(!glob_match("ENCHANTED_*", "LAPIS_BLOCK"));
+        assert!(glob_match("*_BLOCK", "ENCHANTED_LAPIS_BLOCK"));
+        assert!(glob_match("ENCHANTED_*_BLOCK", "ENCHANTED_LAPIS_BLOCK"));
+        assert!(!glob_match("ENCHANTED_*_BLOCK", "ENCHANTED_LAPIS"));
+        assert!(glob_match("DIAMOND", "DIAMOND"));
+        assert!(!glob_match("DIAMOND", "DIAMOND_BLOCK"));
+    }
+
+    #[test]
+    fn exclude_wins_over_include() {
+        let filter = ProductFilter::from_lists("ENCHANTED_*", "ENCHANTED_LAPIS_*");
+        assert!(filter.matches_id("ENCHANTED_DIAMOND"));
+        assert!(!filter.matches_id("ENCHANTED_LAPIS_BLOCK"));
+        assert!(!filter.matches_id("DIAMOND"));
+        assert!(ProductFilter::default().matches_id("DIAMOND"));
+    }
+
+    #[test]
+    fn stack_sizes_take_the_first_matching_pattern_and_must_be_positive() {
+        let sizes = StackSizes::from_list("enchanted_lapis_* = 64, ENCHANTED_*=160");
+        assert!(sizes.validate().is_ok());
+        assert_eq!(sizes.size_for("ENCHANTED_LAPIS_BLOCK"), 64);
+        assert_eq!(sizes.size_for("ENCHANTED_DIAMOND"), 160);
+        assert_eq!(sizes.size_for("WHEAT"), 1);
+
+        for bad in ["WHEAT=0", "WHEAT=-4", "WHEAT=many", "WHEAT"] {
+            assert!(StackSizes::from_list(bad).validate().is_err(), "{} accepted", bad);
+        }
+    }
+}
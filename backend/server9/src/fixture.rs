@@ -0,0 +1,151 @@
+// `--serve-fixture <path>`: serves a pre-generated export over the HTTP API (HTTP_ADDR) without
+// polling the API, so dashboard work has a stable offline data source. <path> is one export (a
+// bare array or the OUTPUT_META document) or a directory of them, played in name order as frames.
+//   --fixture-interval <secs>  move to the next frame every <secs>; without it the first frame is
+//                              served unchanged
+//   --fixture-loop             after the last frame start over from the first; otherwise the last
+//                              frame keeps being served
+//   --fixture-jitter <f>       on every tick, also scale each price and volume of the frame by a
+//                              random factor within 1 ± f, so even a single file looks live.
+//                              WIZ_SEED seeds it (see rng.rs).
+// GET /export serves the current frame, honoring `include`/`exclude` (`min_volume` needs a live
+// snapshot and is ignored), and GET /products/{id}/metrics one product of it.
+use crate::rng::{SplitMix64, DEFAULT_SEED};
+use serde_json::Value;
+use std::error::Error;
+use std::fs;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+pub type SharedFixture = Arc<Mutex<Fixture>>;
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct FixtureOptions {
+    pub looping: bool,
+    pub jitter: f64,
+    pub seed: Option<u64>,
+}
+
+pub struct Fixture {
+    frames: Vec<Vec<Value>>,
+    position: usize,
+    current: Vec<Value>,
+    options: FixtureOptions,
+    rng: SplitMix64,
+}
+
+impl Fixture {
+    pub fn load(path: &Path, options: FixtureOptions) -> Result<Self, Box<dyn Error>> {
+        let files = if path.is_dir() {
+            let mut files: Vec<_> = fs::read_dir(path)?
+                .filter_map(|entry| entry.ok().map(|e| e.path()))
+                .filter(|file| file.extension().is_some_and(|ext| ext == "json"))
+                .collect();
+            files.sort();
+            files
+        } else {
+            vec![path.to_path_buf()]
+        };
+        let frames = files.iter()
+            .map(|file| {
+                let export: Value = serde_json::from_slice(&fs::read(file)?)?;
+                // A bare array, or the OUTPUT_META wrapper with the array under `results`
+                let rows = match &export {
+                    Value::Object(document) => document.get("results").and_then(Value::as_array),
+                    other => other.as_array(),
+                };
+                rows.cloned().ok_or_else(|| format!("{}: not an export of products", file.display()).into())
+            })
+            .collect::<Result<Vec<_>, Box<dyn Error>>>()?;
+        if frames.is_empty() {
+            return Err(format!("{}: no exports to serve", path.display()).into());
+        }
+        Ok(Self {
+            current: frames[0].clone(),
+            frames,
+            position: 0,
+            options,
+            rng: SplitMix64(options.seed.unwrap_or(DEFAULT_SEED)),
+        })
+    }
+
+    pub fn current(&self) -> &[Value] {
+        &self.current
+    }
+
+    pub fn frames(&self) -> usize {
+        self.frames.len()
+    }
+
+    pub fn tick(&mut self) {
+        if self.position + 1 < self.frames.len() {
+            self.position += 1;
+        } else if self.options.looping {
+            self.position = 0;
+        }
+        self.current = self.frames[self.position].clone();
+        if self.options.jitter > 0.0 {
+            for row in &mut self.current {
+                jitter(row, self.options.jitter, &mut self.rng);
+            }
+        }
+    }
+}
+
+pub async fn animate(fixture: SharedFixture, interval: Duration) {
+    loop {
+        tokio::time::sleep(interval).await;
+        match fixture.lock() {
+            Ok(mut fixture) => fixture.tick(),
+            Err(_) => return,
+        }
+    }
+}
+
+fn jitter(row: &mut Value, fraction: f64, rng: &mut SplitMix64) {
+    let Value::Object(fields) = row else { return };
+    for (name, field) in fields.iter_mut() {
+        if !(name.contains("price") || name.contains("volume")) {
+            continue;
+        }
+        if field.is_f64() {
+            let factor = 1.0 + fraction * rng.range(-1_000, 1_000) as f64 / 1_000.0;
+            *field = Value::from(field.as_f64().unwrap_or_default() * factor);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn frames_advance_loop_and_jitter_within_bounds() {
+        let golden: Value = serde_json::from_slice(&fs::read(Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/replay_golden.json")).unwrap()).unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("metrics_1.json"), serde_json::to_vec(&golden).unwrap()).unwrap();
+        let second = serde_json::json!({ "schema_version": 7, "results": [golden[0].clone()] });
+        fs::write(dir.path().join("metrics_2.json"), serde_json::to_vec(&second).unwrap()).unwrap();
+
+        let mut fixture = Fixture::load(dir.path(), FixtureOptions::default()).unwrap();
+        assert_eq!((fixture.frames(), fixture.current().len()), (2, golden.as_array().unwrap().len()));
+        fixture.tick();
+        assert_eq!(fixture.current().len(), 1);
+        fixture.tick();
+        assert_eq!(fixture.current().len(), 1, "without --fixture-loop the last frame stays");
+
+        let options = FixtureOptions { looping: true, jitter: 0.05, seed: Some(7) };
+        let mut fixture = Fixture::load(dir.path(), options).unwrap();
+        fixture.tick();
+        fixture.tick();
+        assert_eq!(fixture.current().len(), golden.as_array().unwrap().len());
+        let (before, after) = (&golden[1], &fixture.current()[1]);
+        let ratio = after["instabuy_price_average"].as_f64().unwrap() / before["instabuy_price_average"].as_f64().unwrap();
+        assert!((0.95..=1.05).contains(&ratio) && ratio != 1.0, "{}", ratio);
+        assert_eq!(after["product_id"], before["product_id"]);
+        assert_eq!(after["flash_order_count"], before["flash_order_count"]);
+
+        assert!(Fixture::load(&dir.path().join("missing.json"), options).is_err());
+    }
+}
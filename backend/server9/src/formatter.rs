@@ -0,0 +1,156 @@
+// OUTPUT_FORMAT lists the formats written each cycle, comma-separated, e.g. `compact,csv`. `auto`,
+// `pretty` and `compact` (or just `json`) choose how the JSON export is printed. That export is
+// always written and is not an `OutputFormatter`: the Exporter writes it itself, since only it can
+// be sharded, written as a delta or wrapped with OUTPUT_META, and `--verify` and `--recompute` read
+// it. Every other name is an `OutputFormatter` from FORMATTERS (currently ndjson and csv), written
+// next to the JSON export as `metrics_<stamp>.<extension>` and pushed, retried and reported
+// (`ExportReport.formats`) on its own.
+//
+// SQLITE_PATH and NATS_URL stay separate sinks: they append to a store across cycles rather than
+// write one file per cycle.
+//
+// Another extra format is an `OutputFormatter` plus its entry in FORMATTERS.
+use crate::atomic::write_atomic_with;
+use crate::sqlite::METRIC_COLUMNS;
+use crate::AnalysisResult;
+use serde_json::Value;
+use std::error::Error;
+use std::io::Write;
+use std::path::Path;
+
+pub trait OutputFormatter: Send + Sync {
+    fn name(&self) -> &'static str;
+    fn extension(&self) -> &'static str;
+    // Writes the whole file at `dest`, atomically
+    fn write(&self, results: &[AnalysisResult], dest: &Path) -> Result<(), Box<dyn Error>>;
+}
+
+type MakeFormatter = fn() -> Box<dyn OutputFormatter>;
+
+const FORMATTERS: &[(&str, MakeFormatter)] = &[
+    ("ndjson", || Box::new(Ndjson)),
+    ("csv", || Box::new(Csv)),
+];
+
+// Names in OUTPUT_FORMAT that pick the JSON export's style rather than an extra format
+const JSON_STYLES: &[&str] = &["json", "auto", "pretty", "compact"];
+
+pub fn build(names: &[String]) -> Result<Vec<Box<dyn OutputFormatter>>, String> {
+    names.iter()
+        .filter(|name| !JSON_STYLES.contains(&name.as_str()))
+        .map(|name| {
+            FORMATTERS.iter().find(|(registered, _)| registered == name).map(|(_, make)| make()).ok_or_else(|| {
+                let known: Vec<&str> = JSON_STYLES.iter().chain(FORMATTERS.iter().map(|(registered, _)| registered)).copied().collect();
+                format!("Unknown OUTPUT_FORMAT {}; expected any of {}", name, known.join(", "))
+            })
+        })
+        .collect()
+}
+
+// One compact JSON object per line
+struct Ndjson;
+
+impl OutputFormatter for Ndjson {
+    fn name(&self) -> &'static str {
+        "ndjson"
+    }
+
+    fn extension(&self) -> &'static str {
+        "ndjson"
+    }
+
+    fn write(&self, results: &[AnalysisResult], dest: &Path) -> Result<(), Box<dyn Error>> {
+        write_atomic_with(dest, |writer| {
+            for result in results {
+                serde_json::to_writer(&mut *writer, result)?;
+                writer.write_all(b"\n")?;
+            }
+            Ok(())
+        })?;
+        Ok(())
+    }
+}
+
+// One row per product: product_id, the scalar columns of the SQLite sink and the detection method.
+// Booleans are written as true/false, and a null, NaN or infinity as an empty field.
+struct Csv;
+
+impl OutputFormatter for Csv {
+    fn name(&self) -> &'static str {
+        "csv"
+    }
+
+    fn extension(&self) -> &'static str {
+        "csv"
+    }
+
+    fn write(&self, results: &[AnalysisResult], dest: &Path) -> Result<(), Box<dyn Error>> {
+        let rows = results.iter().map(serde_json::to_value).collect::<Result<Vec<Value>, _>>()?;
+        write_atomic_with(dest, |writer| {
+            let header: Vec<&str> = ["product_id"].into_iter().chain(METRIC_COLUMNS.iter().map(|(name, _)| *name)).chain(["detection_method"]).collect();
+            writeln!(writer, "{}", header.join(","))?;
+            for row in &rows {
+                let fields: Vec<String> = [&row["product_id"]].into_iter()
+                    .chain(METRIC_COLUMNS.iter().map(|(name, _)| &row[*name]))
+                    .chain([&row["pattern_details"]["detection_method"]])
+                    .map(csv_field)
+                    .collect();
+                writeln!(writer, "{}", fields.join(","))?;
+            }
+            Ok(())
+        })?;
+        Ok(())
+    }
+}
+
+fn csv_field(value: &Value) -> String {
+    let text = match value {
+        Value::Null => return String::new(),
+        Value::String(s) => s.clone(),
+        other => other.to_string(),
+    };
+    if text.contains([',', '"', '\n']) {
+        format!("\"{}\"", text.replace('"', "\"\""))
+    } else {
+        text
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::replay::replay_cycle;
+    use std::fs;
+
+    #[tokio::test]
+    async fn every_registered_format_writes_one_record_per_product() {
+        let mut results = replay_cycle().await;
+        results[0].display_name = "Wheat, \"Enchanted\"".to_string();
+        results[1].instabuy_price_average = f64::NAN;
+        results[1].instasell_price_average = f64::INFINITY;
+        let dir = tempfile::tempdir().unwrap();
+
+        let names: Vec<String> = ["compact", "ndjson", "csv"].map(String::from).to_vec();
+        let formatters = build(&names).unwrap();
+        assert_eq!(formatters.iter().map(|f| f.name()).collect::<Vec<_>>(), ["ndjson", "csv"]);
+        for formatter in &formatters {
+            formatter.write(&results, &dir.path().join(format!("metrics.{}", formatter.extension()))).unwrap();
+        }
+
+        let ndjson = fs::read_to_string(dir.path().join("metrics.ndjson")).unwrap();
+        let ids: Vec<Value> = ndjson.lines().map(|line| serde_json::from_str::<Value>(line).unwrap()["product_id"].clone()).collect();
+        assert_eq!(ids, results.iter().map(|r| Value::from(r.product_id.as_str())).collect::<Vec<_>>());
+
+        let csv = fs::read_to_string(dir.path().join("metrics.csv")).unwrap();
+        let mut lines = csv.lines();
+        let header = lines.next().unwrap();
+        assert!(header.starts_with("product_id,schema_version,display_name,"));
+        assert_eq!(lines.count(), results.len());
+        assert!(csv.contains(&format!("{},{},\"Wheat, \"\"Enchanted\"\"\",", results[0].product_id, results[0].schema_version)));
+        let column = |name: &str| header.split(',').position(|column| column == name).unwrap();
+        let row: Vec<&str> = csv.lines().nth(2).unwrap().split(',').collect();
+        assert_eq!((row[column("instabuy_price_average")], row[column("instasell_price_average")]), ("", ""));
+
+        assert!(build(&["parquet".to_string()]).err().unwrap().contains("expected any of json, auto, pretty, compact, ndjson, csv"));
+    }
+}
@@ -0,0 +1,282 @@
+use crate::cadence::SharedCadence;
+use crate::config::AnalysisConfig;
+use crate::export::{ExportStatus, Exporter, SharedExportReport};
+use crate::filter::{split_patterns, ProductFilter};
+use crate::fixture::SharedFixture;
+use crate::query::{aggregate, AggregationSpec};
+use crate::{apply_reference_prices, AnalysisResult, ProductMetricsState};
+use axum::body::{Body, Bytes};
+use axum::extract::{Path, Query, State};
+use axum::http::{header, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::routing::{get, post};
+use axum::{Json, Router};
+use futures::stream;
+use serde::Deserialize;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+pub type SharedStates = Arc<Mutex<HashMap<String, ProductMetricsState>>>;
+
+#[derive(Clone)]
+pub struct AppState {
+    pub states: SharedStates,
+    pub max_products: usize,
+    pub analysis: AnalysisConfig,
+    pub last_export: SharedExportReport,
+    pub cadence: SharedCadence,
+    pub exporter: Arc<Exporter>,
+    // GET /ready answers 503 once more local exports than this are waiting for their remote push
+    pub ready_max_pending: usize,
+    // Redacted effective config for GET /config; None unless DEBUG_ENDPOINTS is set
+    pub effective_config: Option<Arc<Value>>,
+    // Serve GET /snapshots; set by DEBUG_ENDPOINTS
+    pub debug_endpoints: bool,
+    // With --serve-fixture, GET /export and /products/{id}/metrics serve this instead of the
+    // in-progress cycle (see fixture.rs)
+    pub fixture: Option<SharedFixture>,
+}
+
+#[derive(Debug, Deserialize)]
+struct AggregationQuery {
+    product_id: String,
+    spec: AggregationSpec,
+}
+
+#[derive(Debug, Deserialize)]
+struct SnapshotsQuery {
+    product_id: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct ExportQuery {
+    include: Option<String>,
+    exclude: Option<String>,
+    min_volume: Option<i64>,
+}
+
+pub fn router(app: AppState) -> Router {
+    Router::new()
+        .route("/export", get(export))
+        .route("/products/{product_id}/metrics", get(product_metrics))
+        .route("/health", get(health))
+        .route("/ready", get(ready))
+        .route("/query", post(query))
+        .route("/config", get(effective_config))
+        .route("/snapshots", get(recent_snapshots))
+        .with_state(app)
+}
+
+pub async fn serve(addr: String, app: AppState) -> std::io::Result<()> {
+    let listener = tokio::net::TcpListener::bind(&addr).await?;
+    println!("[GiantWizard] HTTP API listening on {}", addr);
+    axum::serve(listener, router(app)).await
+}
+
+// Finalizes the matching products from the in-progress cycle without touching the hourly export
+async fn export(State(app): State<AppState>, Query(query): Query<ExportQuery>) -> Response {
+    let filter = ProductFilter {
+        include: split_patterns(query.include.as_deref().unwrap_or_default()),
+        exclude: split_patterns(query.exclude.as_deref().unwrap_or_default()),
+        min_volume: query.min_volume,
+    };
+    if let Some(fixture) = &app.fixture {
+        return fixture_export(fixture, &filter, app.max_products);
+    }
+
+    // Finalizing the catalog takes a while, so it runs on clones, off the lock the processor needs
+    let (matching, reference, total_matches) = {
+        let states = match app.states.lock() {
+            Ok(states) => states,
+            Err(_) => return (StatusCode::INTERNAL_SERVER_ERROR, "state lock poisoned").into_response(),
+        };
+        let mut matching: Vec<&String> = states.iter()
+            .filter(|(_, state)| state.prev_snapshot.as_ref().is_some_and(|snap| filter.matches(snap)))
+            .map(|(pid, _)| pid)
+            .collect();
+        matching.sort();
+        let total = matching.len();
+        let matching: Vec<(String, ProductMetricsState)> = matching.into_iter()
+            .take(app.max_products)
+            .map(|pid| (pid.clone(), states[pid].clone()))
+            .collect();
+        // The reference product prices every row, whether or not it is one of them
+        let reference: HashMap<String, ProductMetricsState> = app.analysis.reference_product.as_ref()
+            .and_then(|id| states.get_key_value(id))
+            .map(|(id, state)| (id.clone(), state.clone()))
+            .into_iter()
+            .collect();
+        (matching, reference, total)
+    };
+    let analysis = app.analysis.clone();
+    let finalized = tokio::task::spawn_blocking(move || {
+        let mut results: Vec<AnalysisResult> = matching.into_iter()
+            .map(|(pid, state)| state.finalize_with_sequences(pid, &analysis))
+            .collect();
+        apply_reference_prices(&mut results, &reference, &analysis);
+        results
+    }).await;
+    let Ok(results) = finalized else {
+        return (StatusCode::INTERNAL_SERVER_ERROR, "finalize failed").into_response();
+    };
+
+    let truncated = total_matches > results.len();
+    let closing: &'static [u8] = if results.is_empty() { b"[]" } else { b"]" };
+    let chunks = results.into_iter().enumerate().map(|(i, result)| {
+        let mut chunk = if i == 0 { b"[".to_vec() } else { b",".to_vec() };
+        serde_json::to_writer(&mut chunk, &result).map(|_| Bytes::from(chunk))
+    });
+    let body_stream = stream::iter(chunks.chain(std::iter::once(Ok(Bytes::from_static(closing)))));
+
+    Response::builder()
+        .header(header::CONTENT_TYPE, "application/json")
+        .header("x-total-matches", total_matches.to_string())
+        .header("x-truncated", truncated.to_string())
+        .body(Body::from_stream(body_stream))
+        .unwrap_or_else(|_| StatusCode::INTERNAL_SERVER_ERROR.into_response())
+}
+
+fn fixture_export(fixture: &SharedFixture, filter: &ProductFilter, max_products: usize) -> Response {
+    let Ok(fixture) = fixture.lock() else {
+        return (StatusCode::INTERNAL_SERVER_ERROR, "fixture lock poisoned").into_response();
+    };
+    let matching: Vec<&Value> = fixture.current().iter()
+        .filter(|row| row["product_id"].as_str().is_some_and(|pid| filter.matches_id(pid)))
+        .collect();
+    let total_matches = matching.len();
+    let results: Vec<&Value> = matching.into_iter().take(max_products).collect();
+    (
+        [("x-total-matches", total_matches.to_string()), ("x-truncated", (total_matches > results.len()).to_string())],
+        Json(results),
+    ).into_response()
+}
+
+// One product's metrics: from the fixture, or finalized from the in-progress cycle
+async fn product_metrics(State(app): State<AppState>, Path(product_id): Path<String>) -> Response {
+    if let Some(fixture) = &app.fixture {
+        return match fixture.lock() {
+            Ok(fixture) => match fixture.current().iter().find(|row| row["product_id"] == product_id.as_str()) {
+                Some(row) => Json(row).into_response(),
+                None => (StatusCode::NOT_FOUND, format!("no data for {}", product_id)).into_response(),
+            },
+            Err(_) => (StatusCode::INTERNAL_SERVER_ERROR, "fixture lock poisoned").into_response(),
+        };
+    }
+    let states = match app.states.lock() {
+        Ok(states) => states,
+        Err(_) => return (StatusCode::INTERNAL_SERVER_ERROR, "state lock poisoned").into_response(),
+    };
+    match states.get(&product_id) {
+        Some(state) => {
+            let mut results = vec![state.finalize_with_sequences(product_id.clone(), &app.analysis)];
+            apply_reference_prices(&mut results, &states, &app.analysis);
+            Json(&results[0]).into_response()
+        }
+        None => (StatusCode::NOT_FOUND, format!("no data for {}", product_id)).into_response(),
+    }
+}
+
+// Evaluates one aggregation over a product's sequences from the in-progress cycle
+async fn query(State(app): State<AppState>, Json(request): Json<AggregationQuery>) -> Response {
+    let sequences = match app.states.lock() {
+        Ok(states) => match states.get(&request.product_id) {
+            Some(state) => state.delta_sequences(&app.analysis),
+            None => return (StatusCode::NOT_FOUND, format!("no data for {}", request.product_id)).into_response(),
+        },
+        Err(_) => return (StatusCode::INTERNAL_SERVER_ERROR, "state lock poisoned").into_response(),
+    };
+    match aggregate(&sequences, &request.spec) {
+        Ok(value) => Json(serde_json::json!({ "product_id": request.product_id, "spec": request.spec, "value": value })).into_response(),
+        Err(e) => (StatusCode::BAD_REQUEST, e).into_response(),
+    }
+}
+
+// Status of the most recent hourly export, the learned API update cadence and the pending-push
+// backlog; `last_export` is null until the first cycle completes, `cadence_secs` until a few
+// updates have been seen
+async fn health(State(app): State<AppState>) -> Response {
+    let pending = app.exporter.backlog().ok();
+    match (app.last_export.lock(), app.cadence.lock()) {
+        (Ok(last), Ok(cadence)) => Json(serde_json::json!({
+            "disk_full": last.as_ref().is_some_and(|report| report.status == ExportStatus::DiskFull),
+            "last_export": *last,
+            "update_cadence": cadence.report(),
+            "pending_exports": pending,
+        })).into_response(),
+        _ => (StatusCode::INTERNAL_SERVER_ERROR, "status lock poisoned").into_response(),
+    }
+}
+
+// For orchestration: fetching can be fine while every push fails, which only the backlog shows
+async fn ready(State(app): State<AppState>) -> Response {
+    match app.exporter.backlog() {
+        Ok(backlog) if backlog.count <= app.ready_max_pending => Json(backlog).into_response(),
+        Ok(backlog) => (StatusCode::SERVICE_UNAVAILABLE, Json(backlog)).into_response(),
+        Err(e) => (StatusCode::SERVICE_UNAVAILABLE, format!("export dir unreadable: {}", e)).into_response(),
+    }
+}
+
+// Debug-only: hidden (404) unless DEBUG_ENDPOINTS is set, since even redacted it maps the deployment
+async fn effective_config(State(app): State<AppState>) -> Response {
+    match app.effective_config {
+        Some(config) => Json(config.as_ref().clone()).into_response(),
+        None => StatusCode::NOT_FOUND.into_response(),
+    }
+}
+
+// Debug-only: the product's last RECENT_SNAPSHOTS raw snapshots, oldest first, to inspect the books
+// behind a suspicious delta. Empty while RECENT_SNAPSHOTS is 0.
+async fn recent_snapshots(State(app): State<AppState>, Query(query): Query<SnapshotsQuery>) -> Response {
+    if !app.debug_endpoints {
+        return StatusCode::NOT_FOUND.into_response();
+    }
+    match app.states.lock() {
+        Ok(states) => match states.get(&query.product_id) {
+            Some(state) => Json(&state.recent_snapshots).into_response(),
+            None => (StatusCode::NOT_FOUND, format!("no data for {}", query.product_id)).into_response(),
+        },
+        Err(_) => (StatusCode::INTERNAL_SERVER_ERROR, "state lock poisoned").into_response(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::replay::replay_states;
+
+    #[tokio::test]
+    async fn export_streams_the_matching_products_finalized_from_the_cycle() {
+        let analysis = AnalysisConfig { reference_product: Some("WHEAT".into()), ..Default::default() };
+        let states = replay_states(&analysis).await;
+        let mut expected = vec![states["ENCHANTED_DIAMOND"].finalize_with_sequences("ENCHANTED_DIAMOND".into(), &analysis)];
+        apply_reference_prices(&mut expected, &states, &analysis);
+
+        let dir = tempfile::tempdir().unwrap();
+        let app = AppState {
+            states: Arc::new(Mutex::new(states)),
+            max_products: 1,
+            analysis,
+            last_export: Arc::new(Mutex::new(None)),
+            cadence: SharedCadence::default(),
+            exporter: Arc::new(Exporter::for_tests(dir.path(), "true")),
+            ready_max_pending: 0,
+            effective_config: None,
+            debug_endpoints: false,
+            fixture: None,
+        };
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/export?include=ENCHANTED_*,WHEAT", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, router(app)).await });
+
+        let response = reqwest::get(&url).await.unwrap();
+        assert_eq!(response.status(), reqwest::StatusCode::OK);
+        let header = |name: &str| response.headers()[name].to_str().unwrap().to_string();
+        assert_eq!(header("content-type"), "application/json");
+        // Two products match; WHEAT is cut by max_products but still prices the other row
+        assert_eq!((header("x-total-matches"), header("x-truncated")), ("2".into(), "true".into()));
+        let body: Value = response.json().await.unwrap();
+        assert!(expected[0].instabuy_price_relative.is_some());
+        assert_eq!(body, serde_json::to_value(&expected).unwrap());
+    }
+}
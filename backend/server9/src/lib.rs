@@ -0,0 +1,4936 @@
+// The analysis engine: per-product state, pattern detection and the whole poll/finalize/export
+// loop. The `timestamp_generator` binary (main.rs) only calls `run_cli`.
+pub mod activity;
+mod alert;
+mod alias;
+mod atomic;
+mod baseline;
+mod cadence;
+mod checkpoint;
+mod checksum;
+mod calibrate;
+mod capture;
+pub mod config;
+mod correlate;
+mod delta;
+mod exit;
+mod explain;
+mod export;
+mod filter;
+mod formatter;
+mod fixture;
+mod http;
+mod list;
+mod recompute;
+mod query;
+mod replay;
+mod resources;
+mod rng;
+mod selftest;
+mod sqlite;
+mod stream;
+mod synthetic;
+mod units;
+mod verify;
+
+pub use replay::replay_dir;
+
+use activity::ActivityIndex;
+use chrono::{Utc, Local};
+use config::{AnalysisConfig, Config, DeltaSequenceOutput, DetectorConfig, EventInference, RuntimeConfig, RuntimeFlavor, TimestampFormat};
+use exit::ExitError;
+use export::{ExportStatus, Exporter};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::hash_map::Entry;
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::sync::mpsc;
+use tokio::time::sleep;
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Order {
+    pub amount: i64,
+    pub price_per_unit: f64,
+    pub orders: i64,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct BazaarInfo {
+    pub product_id: String,
+    pub buy_price: f64,
+    pub sell_price: f64,
+    pub buy_orders: Vec<Order>,
+    pub sell_orders: Vec<Order>,
+    pub buy_moving_week: i64,
+    pub sell_moving_week: i64,
+    // Standing order volume from quick_status; None when the API left the field out
+    pub buy_volume: Option<i64>,
+    pub sell_volume: Option<i64>,
+    // The API omitted the summary entirely (a partial response), as opposed to an empty book
+    #[serde(default)]
+    pub buy_summary_missing: bool,
+    #[serde(default)]
+    pub sell_summary_missing: bool,
+    // Levels the parser dropped for negative, non-finite or non-numeric fields, both sides together
+    #[serde(default)]
+    pub malformed_levels: usize,
+    // quick_status fields that weren't numbers and were read as their default instead
+    #[serde(default)]
+    pub invalid_fields: usize,
+}
+
+// Lowest and highest value seen in a price stream
+#[derive(Debug, Clone, Copy)]
+struct PriceRange {
+    min: f64,
+    max: f64,
+}
+
+impl PriceRange {
+    fn of(prices: &VecDeque<f64>) -> Self {
+        prices.iter().fold(Self { min: f64::INFINITY, max: f64::NEG_INFINITY }, |range, &price| {
+            Self { min: range.min.min(price), max: range.max.max(price) }
+        })
+    }
+
+    // A range within `epsilon` also bounds the variance by epsilon²/4, and comparing two prices
+    // loses no precision however large they are (see RunningMoments)
+    fn is_pinned(&self, epsilon: f64) -> bool {
+        self.max - self.min <= epsilon
+    }
+}
+
+// Realized volatility of a price stream: the square root of the summed squared log-returns between
+// consecutive snapshots, scaled to one hour of observed time. Each return is taken as
+// ln_1p(change / previous), which keeps its digits for the tiny moves between polls. A return
+// touching a non-positive or non-finite price is skipped; None without a single usable return.
+fn realized_volatility(prices: &VecDeque<f64>, span_secs: u64) -> Option<f64> {
+    let usable = |price: f64| price.is_finite() && price > 0.0;
+    let squared_returns: Vec<f64> = prices.iter().zip(prices.iter().skip(1))
+        .filter(|&(&prev, &next)| usable(prev) && usable(next))
+        .map(|(&prev, &next)| ((next - prev) / prev).ln_1p().powi(2))
+        .collect();
+    if squared_returns.is_empty() || span_secs == 0 {
+        return None;
+    }
+    Some(units::per_hour(squared_returns.iter().sum(), span_secs).sqrt())
+}
+
+// Welford's online mean and variance: O(1) per price and free of the cancellation a sum of squares
+// suffers at multi-million coin prices. `remove` reverses an `add`, for sliding-mode eviction.
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+struct RunningMoments {
+    count: usize,
+    mean: f64,
+    m2: f64,
+}
+
+impl RunningMoments {
+    fn add(&mut self, value: f64) {
+        self.count += 1;
+        let delta = value - self.mean;
+        self.mean += delta / self.count as f64;
+        self.m2 += delta * (value - self.mean);
+    }
+
+    fn remove(&mut self, value: f64) {
+        if self.count <= 1 {
+            *self = Self::default();
+            return;
+        }
+        let delta = value - self.mean;
+        self.count -= 1;
+        self.mean -= delta / self.count as f64;
+        self.m2 = (self.m2 - delta * (value - self.mean)).max(0.0);
+    }
+
+    fn sample_variance(&self) -> Option<f64> {
+        (self.count >= 2).then(|| self.m2 / (self.count - 1) as f64)
+    }
+}
+
+// Time-weighted mean price: each snapshot's price counts for the seconds until the next accepted
+// snapshot, so a price that held through a long polling gap isn't outweighed by a burst of quick
+// polls. The newest snapshot has no duration yet and waits for the next one. Non-finite prices
+// and zero-length gaps add nothing. `remove` reverses an `add`, for sliding-mode eviction.
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+struct TimeWeightedMean {
+    weighted_sum: f64,
+    total_secs: f64,
+}
+
+impl TimeWeightedMean {
+    fn add(&mut self, price: f64, secs: u64) {
+        if price.is_finite() && secs > 0 {
+            self.weighted_sum += price * secs as f64;
+            self.total_secs += secs as f64;
+        }
+    }
+
+    fn remove(&mut self, price: f64, secs: u64) {
+        if price.is_finite() && secs > 0 {
+            self.weighted_sum -= price * secs as f64;
+            self.total_secs = (self.total_secs - secs as f64).max(0.0);
+        }
+    }
+
+    fn mean(&self) -> Option<f64> {
+        (self.total_secs > 0.0).then(|| self.weighted_sum / self.total_secs)
+    }
+}
+
+// Running averages of per-snapshot order-book shape for one side of the book. Each snapshot's
+// sample is kept so the oldest can be taken back out in sliding mode.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+struct BookShapeAccumulator {
+    slope_sum: f64,
+    slope_samples: usize,
+    concentration_sum: f64,
+    concentration_samples: usize,
+    samples: VecDeque<(Option<f64>, Option<f64>)>,
+}
+
+impl BookShapeAccumulator {
+    // Slope: coins of price movement from the first to the last level per unit of total depth.
+    // Concentration: Herfindahl index of level amounts, 1/levels for an even book up to 1.0.
+    fn book_shape(orders: &[Order]) -> (Option<f64>, Option<f64>) {
+        let depth: i64 = orders.iter().map(|o| o.amount.max(0)).sum();
+        if depth <= 0 {
+            return (None, None);
+        }
+        let slope = match (orders.first(), orders.last()) {
+            (Some(first), Some(last)) if orders.len() >= 2 => {
+                Some((last.price_per_unit - first.price_per_unit).abs() / depth as f64).filter(|slope| slope.is_finite())
+            }
+            _ => None,
+        };
+        let concentration = orders.iter()
+            .map(|o| (o.amount.max(0) as f64 / depth as f64).powi(2))
+            .sum::<f64>();
+        (slope, Some(concentration))
+    }
+
+    // A missing summary still takes a (empty) sample slot, keeping one sample per snapshot
+    fn add(&mut self, orders: &[Order], known: bool) {
+        let (slope, concentration) = if known { Self::book_shape(orders) } else { (None, None) };
+        if let Some(slope) = slope {
+            self.slope_sum += slope;
+            self.slope_samples += 1;
+        }
+        if let Some(concentration) = concentration {
+            self.concentration_sum += concentration;
+            self.concentration_samples += 1;
+        }
+        self.samples.push_back((slope, concentration));
+    }
+
+    fn remove_oldest(&mut self) {
+        let Some((slope, concentration)) = self.samples.pop_front() else { return };
+        if let Some(slope) = slope {
+            self.slope_sum -= slope;
+            self.slope_samples -= 1;
+        }
+        if let Some(concentration) = concentration {
+            self.concentration_sum -= concentration;
+            self.concentration_samples -= 1;
+        }
+    }
+
+    fn slope_average(&self) -> Option<f64> {
+        (self.slope_samples > 0).then(|| self.slope_sum / self.slope_samples as f64)
+    }
+
+    fn concentration_average(&self) -> Option<f64> {
+        (self.concentration_samples > 0).then(|| self.concentration_sum / self.concentration_samples as f64)
+    }
+}
+
+// Player transactions inferred at book levels whose amount fell, counted three ways:
+//   amount:   one per level. One big order partly filled and several small orders consumed whole
+//             both count once.
+//   orders:   the drop in order count at the level. A partial fill leaves the count unchanged and
+//             counts nothing; a level that emptied counts every order it had.
+//   combined: the order-count drop, or one when the count didn't move (a partial fill).
+// A level whose count fell while its amount rose (a cancel plus a new listing) counts under none.
+// A fill that also cancels an order at the same level over-counts under `orders` and `combined`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+struct EventCounts {
+    amount: usize,
+    orders: usize,
+    combined: usize,
+}
+
+impl EventCounts {
+    fn get(&self, mode: EventInference) -> usize {
+        match mode {
+            EventInference::Amount => self.amount,
+            EventInference::Orders => self.orders,
+            EventInference::Combined => self.combined,
+        }
+    }
+
+    fn add(&mut self, other: EventCounts) {
+        self.amount += other.amount;
+        self.orders += other.orders;
+        self.combined += other.combined;
+    }
+
+    fn since(&self, before: &EventCounts) -> EventCounts {
+        EventCounts {
+            amount: self.amount - before.amount,
+            orders: self.orders - before.orders,
+            combined: self.combined - before.combined,
+        }
+    }
+}
+
+// What one window added to a product's running totals, so the window can be subtracted again
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+struct WindowTotals {
+    new_demand_offers: f64,
+    new_demand_offer_amount: f64,
+    new_supply_offers: f64,
+    new_supply_offer_amount: f64,
+    instabuy_events: EventCounts,
+    instabuy_volume: f64,
+    instasell_events: EventCounts,
+    instasell_volume: f64,
+    buy_moving_week_activity: i64,
+    sell_moving_week_activity: i64,
+    flash_orders: usize,
+    buy_counter_resets: usize,
+    sell_counter_resets: usize,
+}
+
+impl WindowTotals {
+    fn since(&self, before: &WindowTotals) -> WindowTotals {
+        WindowTotals {
+            new_demand_offers: self.new_demand_offers - before.new_demand_offers,
+            new_demand_offer_amount: self.new_demand_offer_amount - before.new_demand_offer_amount,
+            new_supply_offers: self.new_supply_offers - before.new_supply_offers,
+            new_supply_offer_amount: self.new_supply_offer_amount - before.new_supply_offer_amount,
+            instabuy_events: self.instabuy_events.since(&before.instabuy_events),
+            instabuy_volume: self.instabuy_volume - before.instabuy_volume,
+            instasell_events: self.instasell_events.since(&before.instasell_events),
+            instasell_volume: self.instasell_volume - before.instasell_volume,
+            buy_moving_week_activity: self.buy_moving_week_activity - before.buy_moving_week_activity,
+            sell_moving_week_activity: self.sell_moving_week_activity - before.sell_moving_week_activity,
+            flash_orders: self.flash_orders - before.flash_orders,
+            buy_counter_resets: self.buy_counter_resets - before.buy_counter_resets,
+            sell_counter_resets: self.sell_counter_resets - before.sell_counter_resets,
+        }
+    }
+}
+
+const MIN_RELATIVE_DIFF_BASE: f64 = 0.1;
+
+// Flash (likely spoofed) orders: a level of at least FLASH_MIN_AMOUNT units that vanishes from the
+// book entirely while that side's moving-week counter rose by less than FLASH_MAX_FILL_FRACTION of
+// it was pulled, not filled. Such levels are counted in `flash_order_count` and left out of the
+// inferred player volume and events. A real fill of the same level moves the counter by about
+// its size; a large level that only shrinks is always treated as fills.
+const FLASH_MIN_AMOUNT: i64 = 10_000;
+const FLASH_MAX_FILL_FRACTION: f64 = 0.1;
+
+#[derive(Debug, Clone)]
+pub struct PatternPeriod {
+    pub position: usize,
+    pub moving_week_delta: i64,
+    pub inferred_volume: i64,
+    pub timestamp: u64,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct FuzzyPattern {
+    pub pattern_type: String,
+    pub size: f64,
+    pub interval_minutes: f64,
+    pub confidence: f64,
+    pub occurrences: usize,
+    pub method_confidence: f64,
+    // Delta indexes (windows) the pattern was built from, for `--explain`
+    pub positions: Vec<usize>,
+}
+
+#[derive(Debug, Clone)]
+pub struct ModalPattern {
+    pub size: f64,
+    // Moving-week delta over inferred order-book volume, summed across the periods where both
+    // were positive. None when no such period exists, so it can't be mistaken for a real 1.0.
+    pub ratio: Option<f64>,
+    pub interval_minutes: f64,
+    pub occurrence_count: usize,
+    pub confidence: f64,
+    pub detection_method: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DeltaSequences {
+    pub buy_moving_week: Vec<i64>,
+    pub sell_moving_week: Vec<i64>,
+    pub buy_orders: Vec<i64>,
+    pub sell_orders: Vec<i64>,
+    pub buy_amount: Vec<i64>,
+    pub sell_amount: Vec<i64>,
+    // Per-window order-book inferred volume; absent from exports made before --recompute existed
+    #[serde(default)]
+    pub buy_inferred_volume: Vec<i64>,
+    #[serde(default)]
+    pub sell_inferred_volume: Vec<i64>,
+    pub timestamps: Timestamps,
+    // Per-product sequence number of each accepted snapshot, aligned with `timestamps`; a gap
+    // means snapshots in between were rejected as out of order
+    #[serde(default)]
+    pub sequence_numbers: Vec<u64>,
+    // Set when only every Kth element was kept (`DELTA_SEQUENCES=every:K`)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub downsampled_every: Option<usize>,
+}
+
+impl DeltaSequences {
+    // The detectors read `timestamps[i + 1]` as the end of delta `i`, so each side needs exactly
+    // one more timestamp than deltas
+    fn check_alignment(&self) -> Result<(), String> {
+        for (side, deltas) in [("buy", &self.buy_moving_week), ("sell", &self.sell_moving_week)] {
+            if self.timestamps.len() != deltas.len() + 1 {
+                return Err(format!("{} has {} deltas for {} timestamps", side, deltas.len(), self.timestamps.len()));
+            }
+        }
+        Ok(())
+    }
+
+    // Shapes the sequences for the export after detection has used them in full
+    fn for_output(self, output: DeltaSequenceOutput) -> Option<Self> {
+        fn every<T>(values: Vec<T>, k: usize) -> Vec<T> {
+            values.into_iter().step_by(k).collect()
+        }
+        match output {
+            DeltaSequenceOutput::Full => Some(self),
+            DeltaSequenceOutput::Omit => None,
+            DeltaSequenceOutput::Every(k) => Some(Self {
+                buy_moving_week: every(self.buy_moving_week, k),
+                sell_moving_week: every(self.sell_moving_week, k),
+                buy_orders: every(self.buy_orders, k),
+                sell_orders: every(self.sell_orders, k),
+                buy_amount: every(self.buy_amount, k),
+                sell_amount: every(self.sell_amount, k),
+                buy_inferred_volume: every(self.buy_inferred_volume, k),
+                sell_inferred_volume: every(self.sell_inferred_volume, k),
+                timestamps: Timestamps { values: every(self.timestamps.values, k), format: self.timestamps.format },
+                sequence_numbers: every(self.sequence_numbers, k),
+                downsampled_every: Some(k),
+            }),
+        }
+    }
+}
+
+// Unix-second timestamps that serialize as numbers or, with `TIMESTAMP_FORMAT=rfc3339`, as RFC3339
+// strings. Strings make `delta_sequences` noticeably larger (about 22 bytes per timestamp instead
+// of 10). Either form deserializes, and re-serializes the way it was read.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Timestamps {
+    values: Vec<u64>,
+    format: TimestampFormat,
+}
+
+impl std::ops::Deref for Timestamps {
+    type Target = [u64];
+
+    fn deref(&self) -> &[u64] {
+        &self.values
+    }
+}
+
+impl Serialize for Timestamps {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        use serde::ser::{Error as _, SerializeSeq};
+        let mut seq = serializer.serialize_seq(Some(self.values.len()))?;
+        for &value in &self.values {
+            match self.format {
+                TimestampFormat::Epoch => seq.serialize_element(&value)?,
+                TimestampFormat::Rfc3339 => {
+                    let time = chrono::DateTime::from_timestamp(value as i64, 0)
+                        .ok_or_else(|| S::Error::custom(format!("timestamp out of range: {}", value)))?;
+                    seq.serialize_element(&time.to_rfc3339_opts(chrono::SecondsFormat::Secs, true))?
+                }
+            }
+        }
+        seq.end()
+    }
+}
+
+impl<'de> Deserialize<'de> for Timestamps {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        use serde::de::Error as _;
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Raw {
+            Epoch(u64),
+            Text(String),
+        }
+
+        let raw = Vec::<Raw>::deserialize(deserializer)?;
+        let format = match raw.first() {
+            Some(Raw::Text(_)) => TimestampFormat::Rfc3339,
+            _ => TimestampFormat::Epoch,
+        };
+        let values = raw.into_iter()
+            .map(|value| match value {
+                Raw::Epoch(secs) => Ok(secs),
+                Raw::Text(text) => chrono::DateTime::parse_from_rfc3339(&text)
+                    .ok()
+                    .and_then(|time| u64::try_from(time.timestamp()).ok())
+                    .ok_or_else(|| D::Error::custom(format!("invalid RFC3339 timestamp: {}", text))),
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(Self { values, format })
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PatternDetails {
+    pub detection_method: String,
+    pub fuzzy_confidence: f64,
+    pub legacy_confidence: Option<f64>,
+    pub sequence_patterns_found: usize,
+    pub velocity_patterns_found: usize,
+    pub rhythm_patterns_found: usize,
+}
+
+// The `AnalysisResult` fields derived only from `DeltaSequences` and the inferred coverage, which
+// is exactly the set `--recompute` can rebuild from an existing export
+#[derive(Debug, Serialize)]
+struct PatternFields {
+    instabuy_modal_size: f64,
+    instabuy_modal_ratio: Option<f64>,
+    instabuy_pattern_per_hour: f64,
+    instabuy_scale_factor: f64,
+    instasell_modal_size: f64,
+    instasell_modal_ratio: Option<f64>,
+    instasell_pattern_per_hour: f64,
+    instasell_scale_factor: f64,
+    pattern_detection_confidence: f64,
+    pattern_details: PatternDetails,
+}
+
+impl PatternFields {
+    fn detect(sequences: &DeltaSequences, instabuy_coverage: Option<f64>, instasell_coverage: Option<f64>, detector: &DetectorConfig) -> Result<Self, String> {
+        let buy = ActivityIndex::from_deltas(&sequences.buy_moving_week, &sequences.timestamps);
+        let sell = ActivityIndex::from_deltas(&sequences.sell_moving_week, &sequences.timestamps);
+        Self::detect_indexed(sequences, [&buy, &sell], instabuy_coverage, instasell_coverage, detector)
+    }
+
+    // With the buy and sell `ActivityIndex` of `sequences` already at hand
+    fn detect_indexed(sequences: &DeltaSequences, [buy, sell]: [&ActivityIndex; 2], instabuy_coverage: Option<f64>, instasell_coverage: Option<f64>,
+        detector: &DetectorConfig) -> Result<Self, String> {
+        sequences.check_alignment()?;
+        let (instabuy_modal_pattern, instabuy_pattern_details) = ProductMetricsState::detect_fuzzy_modal_pattern(
+            buy,
+            &sequences.buy_moving_week, 
+            &sequences.buy_inferred_volume, 
+            &sequences.timestamps,
+            detector,
+        );
+        let (instasell_modal_pattern, instasell_pattern_details) = ProductMetricsState::detect_fuzzy_modal_pattern(
+            sell,
+            &sequences.sell_moving_week, 
+            &sequences.sell_inferred_volume, 
+            &sequences.timestamps,
+            detector,
+        );
+
+        let buy_confidence = instabuy_modal_pattern.as_ref().map(|p| p.confidence).unwrap_or(0.0);
+        let sell_confidence = instasell_modal_pattern.as_ref().map(|p| p.confidence).unwrap_or(0.0);
+        let pattern_detection_confidence = ((buy_confidence + sell_confidence) / 2.0) * 100.0;
+
+        let pattern_details = PatternDetails {
+            detection_method: format!("buy:{}, sell:{}", 
+                instabuy_pattern_details.detection_method,
+                instasell_pattern_details.detection_method
+            ),
+            fuzzy_confidence: (instabuy_pattern_details.fuzzy_confidence + instasell_pattern_details.fuzzy_confidence) / 2.0,
+            legacy_confidence: match (instabuy_pattern_details.legacy_confidence, instasell_pattern_details.legacy_confidence) {
+                (Some(a), Some(b)) => Some((a + b) / 2.0),
+                (Some(a), None) => Some(a),
+                (None, Some(b)) => Some(b),
+                (None, None) => None,
+            },
+            sequence_patterns_found: 0,
+            velocity_patterns_found: instabuy_pattern_details.velocity_patterns_found + instasell_pattern_details.velocity_patterns_found,
+            rhythm_patterns_found: instabuy_pattern_details.rhythm_patterns_found + instasell_pattern_details.rhythm_patterns_found,
+        };
+
+        Ok(Self {
+            instabuy_modal_size: instabuy_modal_pattern.as_ref().map(|p| p.size).unwrap_or(0.0),
+            instabuy_modal_ratio: instabuy_modal_pattern.as_ref().and_then(|p| p.ratio),
+            instabuy_pattern_per_hour: instabuy_modal_pattern.as_ref().map_or(0.0, |p| units::interval_minutes_to_per_hour(p.interval_minutes)),
+            instabuy_scale_factor: Self::scale_factor(instabuy_modal_pattern.as_ref(), instabuy_coverage),
+            instasell_modal_size: instasell_modal_pattern.as_ref().map(|p| p.size).unwrap_or(0.0),
+            instasell_modal_ratio: instasell_modal_pattern.as_ref().and_then(|p| p.ratio),
+            instasell_pattern_per_hour: instasell_modal_pattern.as_ref().map_or(0.0, |p| units::interval_minutes_to_per_hour(p.interval_minutes)),
+            instasell_scale_factor: Self::scale_factor(instasell_modal_pattern.as_ref(), instasell_coverage),
+            pattern_detection_confidence,
+            pattern_details,
+        })
+    }
+
+    // Zeroed pattern fields for a product whose detection did not run
+    fn undetected(detection_method: String) -> Self {
+        Self {
+            instabuy_modal_size: 0.0,
+            instabuy_modal_ratio: None,
+            instabuy_pattern_per_hour: 0.0,
+            instabuy_scale_factor: 1.0,
+            instasell_modal_size: 0.0,
+            instasell_modal_ratio: None,
+            instasell_pattern_per_hour: 0.0,
+            instasell_scale_factor: 1.0,
+            pattern_detection_confidence: 0.0,
+            pattern_details: PatternDetails {
+                detection_method,
+                fuzzy_confidence: 0.0,
+                legacy_confidence: None,
+                sequence_patterns_found: 0,
+                velocity_patterns_found: 0,
+                rhythm_patterns_found: 0,
+            },
+        }
+    }
+
+    // Scale factor calculated but NOT applied to final volume
+    fn scale_factor(pattern: Option<&ModalPattern>, volume_coverage: Option<f64>) -> f64 {
+        let volume_coverage = volume_coverage.unwrap_or(1.0);
+        if pattern.is_some() && volume_coverage < 0.7 {
+            (1.0 / volume_coverage).clamp(1.0, 2.0)
+        } else {
+            1.0
+        }
+    }
+}
+
+// Which sides of the market actually traded this cycle, so a dead side's zeros aren't mistaken for
+// a failed detection
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum MarketActivity {
+    TwoSided,
+    BuyOnly,
+    SellOnly,
+    Inactive,
+}
+
+impl MarketActivity {
+    fn from_sides(buy_active: bool, sell_active: bool) -> Self {
+        match (buy_active, sell_active) {
+            (true, true) => Self::TwoSided,
+            (true, false) => Self::BuyOnly,
+            (false, true) => Self::SellOnly,
+            (false, false) => Self::Inactive,
+        }
+    }
+}
+
+// Bumped whenever a field of `AnalysisResult` is added, removed, renamed or changes meaning; the
+// serialized field names are pinned in tests/fixtures/output_fields.json. 2: rates are per hour.
+// 3: adds buy_/sell_mw_hourly_rate. 4: adds instabuy_/instasell_realized_vol. 5: adds data_quality.
+// 6: adds display_name. 7: adds instabuy_/instasell_price_time_weighted. 8: adds
+// buy_/sell_counter_resets; a reset window's moving-week delta is 0 rather than negative.
+const SCHEMA_VERSION: u32 = 8;
+
+// Rates (`*_per_hour`) follow the convention in `units`: events per hour of observed time
+#[derive(Debug, Serialize, Deserialize)]
+pub struct AnalysisResult {
+    pub product_id: String,
+    // For presentation only; from PRODUCT_ALIASES_PATH at export time, else the id in title case
+    pub display_name: String,
+    pub schema_version: u32,
+    pub instabuy_price_average: f64,
+    pub instasell_price_average: f64,
+    // Averages weighted by how long each price held until the next snapshot; None before a
+    // second snapshot with a later timestamp
+    pub instabuy_price_time_weighted: Option<f64>,
+    pub instasell_price_time_weighted: Option<f64>,
+    // Prices in the cycle's final snapshot, for decisions that need the current quote
+    pub instabuy_price_last: f64,
+    pub instasell_price_last: f64,
+    // Sample variance of each price over the cycle's snapshots; None with fewer than two
+    pub instabuy_price_variance: Option<f64>,
+    pub instasell_price_variance: Option<f64>,
+    // Realized volatility per hour: sqrt of the summed squared log-returns between snapshots,
+    // scaled to an hour of observed time. None without two consecutive positive prices.
+    pub instabuy_realized_vol: Option<f64>,
+    pub instasell_realized_vol: Option<f64>,
+    // Prices as a multiple of the reference product's average prices over the same cycle
+    pub instabuy_price_relative: Option<f64>,
+    pub instasell_price_relative: Option<f64>,
+    // The price never moved (beyond `price_pin_epsilon`) across the cycle's snapshots
+    pub buy_price_pinned: bool,
+    pub sell_price_pinned: bool,
+    // Average standing order volume over the snapshots that reported it: liquidity on the book,
+    // as opposed to the moving-week throughput. None when never reported or STANDING_VOLUME=0.
+    pub buy_standing_volume_average: Option<f64>,
+    pub sell_standing_volume_average: Option<f64>,
+    // Average |quick_status price - top-of-book price| over the snapshots with a book on that
+    // side. quick_status lags the book, so a large, persistent gap points at a stale or unusual
+    // market. None when the side never had a book.
+    pub buy_quickstatus_topofbook_divergence: Option<f64>,
+    pub sell_quickstatus_topofbook_divergence: Option<f64>,
+    pub new_demand_offers_per_hour: f64,
+    pub new_demand_offer_size_average: f64,
+    pub player_instabuy_transactions_per_hour: f64,
+    pub player_instabuy_transaction_size_average: f64,
+    pub new_supply_offers_per_hour: f64,
+    pub new_supply_offer_size_average: f64,
+    pub player_instasell_transactions_per_hour: f64,
+    pub player_instasell_transaction_size_average: f64,
+    pub instabuy_modal_size: f64,
+    pub instabuy_modal_ratio: Option<f64>,
+    pub instabuy_pattern_per_hour: f64,
+    pub instabuy_scale_factor: f64,
+    pub instabuy_estimated_true_volume: f64,
+    pub instasell_modal_size: f64,
+    pub instasell_modal_ratio: Option<f64>,
+    pub instasell_pattern_per_hour: f64,
+    pub instasell_scale_factor: f64,
+    pub instasell_estimated_true_volume: f64,
+    // Moving-week throughput vs. order-book inferred volume; coverage is inferred / moving-week
+    pub instabuy_moving_week_volume: f64,
+    pub instabuy_inferred_volume: f64,
+    pub instabuy_inferred_coverage: Option<f64>,
+    pub instasell_moving_week_volume: f64,
+    pub instasell_inferred_volume: f64,
+    pub instasell_inferred_coverage: Option<f64>,
+    // The moving-week counters alone as an hourly rate (their summed rises over the observed span),
+    // a baseline that needs neither the order book nor a detected pattern. A window where a counter
+    // fell, as the rolling week ages out, counts as no volume rather than a negative one.
+    pub buy_mw_hourly_rate: f64,
+    pub sell_mw_hourly_rate: f64,
+    // Per-window cross-check of the two series: Pearson correlation of inferred volume against the
+    // moving-week delta, and the mean inferred / moving-week ratio over windows where the counter
+    // rose. A ratio well under 1 means fills are being missed, e.g. orders placed and filled
+    // between two polls. None with too few windows (or no variation) to tell.
+    pub instabuy_inference_correlation: Option<f64>,
+    pub instabuy_inference_ratio: Option<f64>,
+    pub instasell_inference_correlation: Option<f64>,
+    pub instasell_inference_ratio: Option<f64>,
+    // Large levels that vanished without matching moving-week volume, on either side; see
+    // FLASH_MIN_AMOUNT. They are excluded from the inferred volumes above.
+    pub flash_order_count: usize,
+    // Windows where that side's moving-week counter reset (fell by more than
+    // `counter_reset_fraction`); their delta is recorded as 0
+    pub buy_counter_resets: usize,
+    pub sell_counter_resets: usize,
+    pub market_activity: MarketActivity,
+    // windows_processed / target_windows; the *_extrapolated volumes are only scaled when below 1.0
+    pub window_coverage: f64,
+    pub extrapolated: bool,
+    // 0-1 summary of coverage, missing or malformed book data, out-of-order snapshots, counter
+    // resets and book-vs-counter agreement; see `ProductMetricsState::data_quality`. Rows are
+    // never altered for a low score; filter on it downstream.
+    pub data_quality: f64,
+    pub instabuy_estimated_true_volume_extrapolated: Option<f64>,
+    pub instabuy_inferred_volume_extrapolated: Option<f64>,
+    pub instasell_estimated_true_volume_extrapolated: Option<f64>,
+    pub instasell_inferred_volume_extrapolated: Option<f64>,
+    pub buy_book_slope: Option<f64>,
+    pub buy_book_concentration: Option<f64>,
+    pub sell_book_slope: Option<f64>,
+    pub sell_book_concentration: Option<f64>,
+    // Largest single-window inferred volume as a multiple of the median window, and its window
+    // index, when it exceeds `spike_median_multiple`
+    pub buy_spike_detected: bool,
+    pub buy_spike_magnitude: Option<f64>,
+    pub buy_spike_window: Option<usize>,
+    pub sell_spike_detected: bool,
+    pub sell_spike_magnitude: Option<f64>,
+    pub sell_spike_window: Option<usize>,
+    // This cycle's traded volume and spread in stddevs from the product's trailing baseline (see
+    // baseline.rs); None without BASELINE_PATH or before the product has a baseline
+    pub volume_vs_baseline: Option<f64>,
+    pub spread_vs_baseline: Option<f64>,
+    // How closely this cycle's pattern repeats the product's previous one, in [0, 1] (see
+    // baseline.rs); None without BASELINE_PATH, before a previous pattern exists, or when
+    // detection didn't run
+    pub detection_stability: Option<f64>,
+    pub pattern_detection_confidence: f64,
+    // Null under `DELTA_SEQUENCES=omit`
+    pub delta_sequences: Option<DeltaSequences>,
+    pub pattern_details: PatternDetails,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ProductMetricsState {
+    buy_price_moments: RunningMoments,
+    sell_price_moments: RunningMoments,
+    buy_price_time_weighted: TimeWeightedMean,
+    sell_price_time_weighted: TimeWeightedMean,
+    snapshot_count: usize,
+    windows_processed: usize,
+    prev_snapshot: Option<BazaarInfo>,
+    total_new_demand_offers: f64,
+    total_new_demand_offer_amount: f64,
+    total_new_supply_offers: f64,
+    total_new_supply_offer_amount: f64,
+    player_instabuy_events: EventCounts,
+    player_instabuy_volume_total: f64,
+    player_instasell_events: EventCounts,
+    player_instasell_volume_total: f64,
+    flash_orders: usize,
+    // Windows whose moving-week counter reset (see `AnalysisConfig::counter_reset_fraction`)
+    buy_counter_resets: usize,
+    sell_counter_resets: usize,
+    prev_buy_moving_week: i64,
+    prev_sell_moving_week: i64,
+    buy_moving_week_history: VecDeque<i64>,
+    sell_moving_week_history: VecDeque<i64>,
+    inferred_buy_volume_history: VecDeque<i64>,
+    inferred_sell_volume_history: VecDeque<i64>,
+    timestamps: VecDeque<u64>,
+    sequence_numbers: VecDeque<u64>,
+    next_sequence: u64,
+    // Newest source `Last-Modified` (unix seconds) accepted so far
+    last_source_time: Option<u64>,
+    total_buy_moving_week_activity: i64,
+    total_sell_moving_week_activity: i64,
+    buy_moving_week_deltas: VecDeque<i64>,
+    sell_moving_week_deltas: VecDeque<i64>,
+    buy_orders_deltas: VecDeque<i64>,
+    sell_orders_deltas: VecDeque<i64>,
+    buy_amount_deltas: VecDeque<i64>,
+    sell_amount_deltas: VecDeque<i64>,
+    // The moving-week deltas' active windows, kept for the velocity and rhythm detectors
+    buy_activity: ActivityIndex,
+    sell_activity: ActivityIndex,
+    buy_book_shape: BookShapeAccumulator,
+    sell_book_shape: BookShapeAccumulator,
+    // Per-snapshot prices and per-window totals, so sliding mode can evict the oldest window
+    buy_prices: VecDeque<f64>,
+    sell_prices: VecDeque<f64>,
+    buy_volumes: VecDeque<Option<i64>>,
+    sell_volumes: VecDeque<Option<i64>>,
+    buy_divergences: VecDeque<Option<f64>>,
+    sell_divergences: VecDeque<Option<f64>>,
+    // Per snapshot: missing order-book sides plus malformed levels dropped, for `data_quality`
+    snapshot_defects: VecDeque<usize>,
+    window_totals: VecDeque<WindowTotals>,
+    // The last RECENT_SNAPSHOTS accepted snapshots as received, for GET /snapshots; empty when off
+    recent_snapshots: VecDeque<RecentSnapshot>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct RecentSnapshot {
+    captured_at: u64,
+    snapshot: BazaarInfo,
+}
+
+impl ProductMetricsState {
+    pub fn new_at(first: &BazaarInfo, current_timestamp: u64) -> Self {
+        let mut buy_book_shape = BookShapeAccumulator::default();
+        let mut sell_book_shape = BookShapeAccumulator::default();
+        buy_book_shape.add(&first.buy_orders, !first.buy_summary_missing);
+        sell_book_shape.add(&first.sell_orders, !first.sell_summary_missing);
+        Self {
+            buy_price_moments: RunningMoments { count: 1, mean: first.buy_price, m2: 0.0 },
+            sell_price_moments: RunningMoments { count: 1, mean: first.sell_price, m2: 0.0 },
+            buy_price_time_weighted: TimeWeightedMean::default(),
+            sell_price_time_weighted: TimeWeightedMean::default(),
+            snapshot_count: 1,
+            windows_processed: 0,
+            prev_snapshot: Some(first.clone()),
+            total_new_demand_offers: 0.0,
+            total_new_demand_offer_amount: 0.0,
+            total_new_supply_offers: 0.0,
+            total_new_supply_offer_amount: 0.0,
+            player_instabuy_events: EventCounts::default(),
+            player_instabuy_volume_total: 0.0,
+            player_instasell_events: EventCounts::default(),
+            player_instasell_volume_total: 0.0,
+            flash_orders: 0,
+            buy_counter_resets: 0,
+            sell_counter_resets: 0,
+            prev_buy_moving_week: first.buy_moving_week,
+            prev_sell_moving_week: first.sell_moving_week,
+            buy_moving_week_history: VecDeque::from([first.buy_moving_week]),
+            sell_moving_week_history: VecDeque::from([first.sell_moving_week]),
+            inferred_buy_volume_history: VecDeque::new(),
+            inferred_sell_volume_history: VecDeque::new(),
+            timestamps: VecDeque::from([current_timestamp]),
+            sequence_numbers: VecDeque::from([0]),
+            next_sequence: 1,
+            last_source_time: None,
+            total_buy_moving_week_activity: 0,
+            total_sell_moving_week_activity: 0,
+            buy_moving_week_deltas: VecDeque::new(),
+            sell_moving_week_deltas: VecDeque::new(),
+            buy_orders_deltas: VecDeque::new(),
+            sell_orders_deltas: VecDeque::new(),
+            buy_amount_deltas: VecDeque::new(),
+            sell_amount_deltas: VecDeque::new(),
+            buy_activity: ActivityIndex::default(),
+            sell_activity: ActivityIndex::default(),
+            buy_book_shape,
+            sell_book_shape,
+            buy_prices: VecDeque::from([first.buy_price]),
+            sell_prices: VecDeque::from([first.sell_price]),
+            buy_volumes: VecDeque::from([first.buy_volume]),
+            sell_volumes: VecDeque::from([first.sell_volume]),
+            buy_divergences: VecDeque::from([Self::top_of_book_divergence(first.buy_price, &first.buy_orders)]),
+            sell_divergences: VecDeque::from([Self::top_of_book_divergence(first.sell_price, &first.sell_orders)]),
+            snapshot_defects: VecDeque::from([Self::defects(first)]),
+            window_totals: VecDeque::new(),
+            recent_snapshots: VecDeque::new(),
+        }
+    }
+
+    // None for a negative or non-finite price: that level is skipped instead of saturating onto key 0
+    fn price_to_key(price: f64) -> Option<u64> {
+        (price.is_finite() && price >= 0.0).then(|| (price * 1000.0).round() as u64)
+    }
+
+    fn levels_by_price(orders: &[Order], field: fn(&Order) -> i64) -> HashMap<u64, i64> {
+        orders.iter().filter_map(|o| Some((Self::price_to_key(o.price_per_unit)?, field(o)))).collect()
+    }
+
+    // (volume, events, flash orders) consumed from one side's book between two snapshots, given
+    // the side's moving-week delta over the same window
+    fn consumed(prev: &[Order], current: &[Order], moving_week_delta: i64) -> (i64, EventCounts, usize) {
+        let current_amount = Self::levels_by_price(current, |o| o.amount);
+        let current_orders = Self::levels_by_price(current, |o| o.orders);
+        let mut volume = 0;
+        let mut events = EventCounts::default();
+        let mut flash_orders = 0;
+        for level in prev {
+            let Some(key) = Self::price_to_key(level.price_per_unit) else { continue };
+            let amount_now = current_amount.get(&key).copied();
+            if amount_now.is_none() && level.amount >= FLASH_MIN_AMOUNT
+                && (moving_week_delta as f64) < FLASH_MAX_FILL_FRACTION * level.amount as f64 {
+                flash_orders += 1;
+                continue;
+            }
+            let amount_now = amount_now.unwrap_or(0);
+            if level.amount > amount_now {
+                let orders_filled = (level.orders - current_orders.get(&key).copied().unwrap_or(0)).max(0) as usize;
+                volume += level.amount - amount_now;
+                events.add(EventCounts { amount: 1, orders: orders_filled, combined: orders_filled.max(1) });
+            }
+        }
+        (volume, events, flash_orders)
+    }
+
+    // Accepts a snapshot only if it moves forward in time: by the source `Last-Modified` when
+    // known, and by observation time. Rejected snapshots still consume a sequence number.
+    pub fn offer_at(&mut self, current: &BazaarInfo, current_timestamp: u64, source_time: Option<u64>, counter_reset_fraction: f64) -> bool {
+        let stale_source = matches!((source_time, self.last_source_time), (Some(t), Some(last)) if t < last);
+        let stale_observation = self.timestamps.back().is_some_and(|&last| current_timestamp < last);
+        if stale_source || stale_observation {
+            self.next_sequence += 1;
+            return false;
+        }
+        self.update_with(current, current_timestamp, counter_reset_fraction);
+        if source_time.is_some() {
+            self.last_source_time = source_time;
+        }
+        true
+    }
+
+    fn remember(&mut self, snapshot: BazaarInfo, captured_at: u64, capacity: usize) {
+        if capacity == 0 {
+            return;
+        }
+        if self.recent_snapshots.len() >= capacity {
+            self.recent_snapshots.drain(..=self.recent_snapshots.len() - capacity);
+        }
+        self.recent_snapshots.push_back(RecentSnapshot { captured_at, snapshot });
+    }
+
+    #[cfg(test)]
+    fn update_at(&mut self, current: &BazaarInfo, current_timestamp: u64) {
+        self.update_with(current, current_timestamp, AnalysisConfig::default().counter_reset_fraction);
+    }
+
+    // A drop of more than `fraction` of the previous counter; the week ageing out moves it by a few
+    // percent at most
+    fn is_counter_reset(prev: i64, current: i64, fraction: f64) -> bool {
+        prev > 0 && (prev - current) as f64 > fraction * prev as f64
+    }
+
+    // `current_timestamp` is the unix time the snapshot was taken; replay passes the recorded one
+    pub fn update_with(&mut self, current: &BazaarInfo, current_timestamp: u64, counter_reset_fraction: f64) {
+        self.snapshot_count += 1;
+        self.sequence_numbers.push_back(self.next_sequence);
+        self.next_sequence += 1;
+        self.buy_price_moments.add(current.buy_price);
+        self.sell_price_moments.add(current.sell_price);
+        // The previous snapshot's price held until this one
+        let gap = self.timestamps.back().map_or(0, |&last| current_timestamp.saturating_sub(last));
+        if let (Some(&buy), Some(&sell)) = (self.buy_prices.back(), self.sell_prices.back()) {
+            self.buy_price_time_weighted.add(buy, gap);
+            self.sell_price_time_weighted.add(sell, gap);
+        }
+        self.buy_prices.push_back(current.buy_price);
+        self.sell_prices.push_back(current.sell_price);
+        self.buy_volumes.push_back(current.buy_volume);
+        self.sell_volumes.push_back(current.sell_volume);
+        self.buy_divergences.push_back(Self::top_of_book_divergence(current.buy_price, &current.buy_orders));
+        self.snapshot_defects.push_back(Self::defects(current));
+        self.sell_divergences.push_back(Self::top_of_book_divergence(current.sell_price, &current.sell_orders));
+
+        self.buy_moving_week_history.push_back(current.buy_moving_week);
+        self.sell_moving_week_history.push_back(current.sell_moving_week);
+        self.timestamps.push_back(current_timestamp);
+        self.buy_book_shape.add(&current.buy_orders, !current.buy_summary_missing);
+        self.sell_book_shape.add(&current.sell_orders, !current.sell_summary_missing);
+
+        let totals_before = self.totals();
+        if let Some(prev) = &self.prev_snapshot {
+            // Book diffs need both books; a missing summary contributes nothing this window
+            // instead of looking like the whole book was consumed or newly listed
+            let buy_book_known = !prev.buy_summary_missing && !current.buy_summary_missing;
+            let sell_book_known = !prev.sell_summary_missing && !current.sell_summary_missing;
+
+            self.windows_processed += 1;
+
+            // A reset window counts as no movement; the next delta starts from the restarted counter
+            let buy_reset = Self::is_counter_reset(self.prev_buy_moving_week, current.buy_moving_week, counter_reset_fraction);
+            let sell_reset = Self::is_counter_reset(self.prev_sell_moving_week, current.sell_moving_week, counter_reset_fraction);
+            self.buy_counter_resets += usize::from(buy_reset);
+            self.sell_counter_resets += usize::from(sell_reset);
+            let buy_mw_delta = if buy_reset { 0 } else { current.buy_moving_week - self.prev_buy_moving_week };
+            let sell_mw_delta = if sell_reset { 0 } else { current.sell_moving_week - self.prev_sell_moving_week };
+
+            self.buy_moving_week_deltas.push_back(buy_mw_delta);
+            self.sell_moving_week_deltas.push_back(sell_mw_delta);
+            let window_start = self.timestamps[self.timestamps.len() - 2];
+            self.buy_activity.push(buy_mw_delta, window_start, current_timestamp);
+            self.sell_activity.push(sell_mw_delta, window_start, current_timestamp);
+
+            let prev_buy_orders_total: i64 = prev.buy_orders.iter().map(|o| o.orders).sum();
+            let current_buy_orders_total: i64 = current.buy_orders.iter().map(|o| o.orders).sum();
+            let prev_sell_orders_total: i64 = prev.sell_orders.iter().map(|o| o.orders).sum();
+            let current_sell_orders_total: i64 = current.sell_orders.iter().map(|o| o.orders).sum();
+            
+            let prev_buy_amount_total: i64 = prev.buy_orders.iter().map(|o| o.amount).sum();
+            let current_buy_amount_total: i64 = current.buy_orders.iter().map(|o| o.amount).sum();
+            let prev_sell_amount_total: i64 = prev.sell_orders.iter().map(|o| o.amount).sum();
+            let current_sell_amount_total: i64 = current.sell_orders.iter().map(|o| o.amount).sum();
+
+            let known_delta = |known: bool, current: i64, prev: i64| if known { current - prev } else { 0 };
+            self.buy_orders_deltas.push_back(known_delta(buy_book_known, current_buy_orders_total, prev_buy_orders_total));
+            self.sell_orders_deltas.push_back(known_delta(sell_book_known, current_sell_orders_total, prev_sell_orders_total));
+            self.buy_amount_deltas.push_back(known_delta(buy_book_known, current_buy_amount_total, prev_buy_amount_total));
+            self.sell_amount_deltas.push_back(known_delta(sell_book_known, current_sell_amount_total, prev_sell_amount_total));
+
+            // INSTABUY analysis
+            let (inferred_instabuy_volume, inferred_instabuy_events, buy_flash_orders) = if buy_book_known {
+                Self::consumed(&prev.buy_orders, &current.buy_orders, buy_mw_delta)
+            } else {
+                (0, EventCounts::default(), 0)
+            };
+            self.inferred_buy_volume_history.push_back(inferred_instabuy_volume);
+            let actual_instabuy_volume = buy_mw_delta.max(0);
+            self.total_buy_moving_week_activity += actual_instabuy_volume;
+            
+            if inferred_instabuy_events.amount > 0 {
+                self.player_instabuy_events.add(inferred_instabuy_events);
+                self.player_instabuy_volume_total += inferred_instabuy_volume as f64;
+            }
+
+            // INSTASELL analysis
+            let (inferred_instasell_volume, inferred_instasell_events, sell_flash_orders) = if sell_book_known {
+                Self::consumed(&prev.sell_orders, &current.sell_orders, sell_mw_delta)
+            } else {
+                (0, EventCounts::default(), 0)
+            };
+            self.flash_orders += buy_flash_orders + sell_flash_orders;
+            self.inferred_sell_volume_history.push_back(inferred_instasell_volume);
+            let actual_instasell_volume = sell_mw_delta.max(0);
+            self.total_sell_moving_week_activity += actual_instasell_volume;
+            
+            if inferred_instasell_events.amount > 0 {
+                self.player_instasell_events.add(inferred_instasell_events);
+                self.player_instasell_volume_total += inferred_instasell_volume as f64;
+            }
+
+            // New offer tracking
+            let prev_demand_orders = Self::levels_by_price(&prev.buy_orders, |o| o.orders);
+            let prev_demand_amount = Self::levels_by_price(&prev.buy_orders, |o| o.amount);
+            for offer in current.buy_orders.iter().filter(|_| buy_book_known) {
+                let Some(key) = Self::price_to_key(offer.price_per_unit) else { continue };
+                if let Some(prev_orders) = prev_demand_orders.get(&key) {
+                    if offer.orders > *prev_orders {
+                        self.total_new_demand_offers += (offer.orders - prev_orders) as f64;
+                        let prev_amount = prev_demand_amount.get(&key).unwrap_or(&0);
+                        if offer.amount > *prev_amount {
+                            self.total_new_demand_offer_amount += (offer.amount - prev_amount) as f64;
+                        }
+                    }
+                } else {
+                    self.total_new_demand_offers += offer.orders as f64;
+                    self.total_new_demand_offer_amount += offer.amount as f64;
+                }
+            }
+
+            let prev_supply_orders = Self::levels_by_price(&prev.sell_orders, |o| o.orders);
+            let prev_supply_amount = Self::levels_by_price(&prev.sell_orders, |o| o.amount);
+            for offer in current.sell_orders.iter().filter(|_| sell_book_known) {
+                let Some(key) = Self::price_to_key(offer.price_per_unit) else { continue };
+                if let Some(prev_orders) = prev_supply_orders.get(&key) {
+                    if offer.orders > *prev_orders {
+                        self.total_new_supply_offers += (offer.orders - prev_orders) as f64;
+                        let prev_amount = prev_supply_amount.get(&key).unwrap_or(&0);
+                        if offer.amount > *prev_amount {
+                            self.total_new_supply_offer_amount += (offer.amount - prev_amount) as f64;
+                        }
+                    }
+                } else {
+                    self.total_new_supply_offers += offer.orders as f64;
+                    self.total_new_supply_offer_amount += offer.amount as f64;
+                }
+            }
+            self.window_totals.push_back(self.totals().since(&totals_before));
+        } else {
+            self.inferred_buy_volume_history.push_back(0);
+            self.inferred_sell_volume_history.push_back(0);
+        }
+        // Keep the last populated book as the baseline for the next diff
+        let mut baseline = current.clone();
+        if let Some(prev) = &self.prev_snapshot {
+            if current.buy_summary_missing {
+                baseline.buy_orders = prev.buy_orders.clone();
+                baseline.buy_summary_missing = prev.buy_summary_missing;
+            }
+            if current.sell_summary_missing {
+                baseline.sell_orders = prev.sell_orders.clone();
+                baseline.sell_summary_missing = prev.sell_summary_missing;
+            }
+        }
+        self.prev_snapshot = Some(baseline);
+        self.prev_buy_moving_week = current.buy_moving_week;
+        self.prev_sell_moving_week = current.sell_moving_week;
+        debug_assert_eq!(self.timestamps.len(), self.buy_moving_week_deltas.len() + 1);
+        debug_assert_eq!(self.timestamps.len(), self.sell_moving_week_deltas.len() + 1);
+    }
+
+    fn totals(&self) -> WindowTotals {
+        WindowTotals {
+            new_demand_offers: self.total_new_demand_offers,
+            new_demand_offer_amount: self.total_new_demand_offer_amount,
+            new_supply_offers: self.total_new_supply_offers,
+            new_supply_offer_amount: self.total_new_supply_offer_amount,
+            instabuy_events: self.player_instabuy_events,
+            instabuy_volume: self.player_instabuy_volume_total,
+            instasell_events: self.player_instasell_events,
+            instasell_volume: self.player_instasell_volume_total,
+            buy_moving_week_activity: self.total_buy_moving_week_activity,
+            sell_moving_week_activity: self.total_sell_moving_week_activity,
+            flash_orders: self.flash_orders,
+            buy_counter_resets: self.buy_counter_resets,
+            sell_counter_resets: self.sell_counter_resets,
+        }
+    }
+
+    // Sliding mode: keeps only the newest `max_windows` windows (and the snapshots bounding them)
+    fn trim_to(&mut self, max_windows: usize) {
+        while self.windows_processed > max_windows {
+            self.evict_oldest_window();
+        }
+    }
+
+    // Drops the oldest window and the snapshot that opened it, taking both back out of every sum
+    fn evict_oldest_window(&mut self) {
+        let Some(window) = self.window_totals.pop_front() else { return };
+        self.windows_processed -= 1;
+        self.total_new_demand_offers -= window.new_demand_offers;
+        self.total_new_demand_offer_amount -= window.new_demand_offer_amount;
+        self.total_new_supply_offers -= window.new_supply_offers;
+        self.total_new_supply_offer_amount -= window.new_supply_offer_amount;
+        self.player_instabuy_events = self.player_instabuy_events.since(&window.instabuy_events);
+        self.player_instabuy_volume_total -= window.instabuy_volume;
+        self.player_instasell_events = self.player_instasell_events.since(&window.instasell_events);
+        self.player_instasell_volume_total -= window.instasell_volume;
+        self.total_buy_moving_week_activity -= window.buy_moving_week_activity;
+        self.total_sell_moving_week_activity -= window.sell_moving_week_activity;
+        self.flash_orders -= window.flash_orders;
+        self.buy_counter_resets -= window.buy_counter_resets;
+        self.sell_counter_resets -= window.sell_counter_resets;
+        for history in [
+            &mut self.buy_moving_week_deltas, &mut self.sell_moving_week_deltas,
+            &mut self.buy_orders_deltas, &mut self.sell_orders_deltas,
+            &mut self.buy_amount_deltas, &mut self.sell_amount_deltas,
+            &mut self.inferred_buy_volume_history, &mut self.inferred_sell_volume_history,
+            &mut self.buy_moving_week_history, &mut self.sell_moving_week_history,
+        ] {
+            history.pop_front();
+        }
+        self.buy_activity.evict_oldest();
+        self.sell_activity.evict_oldest();
+
+        self.snapshot_count -= 1;
+        let held = match (self.timestamps.front(), self.timestamps.get(1)) {
+            (Some(&first), Some(&next)) => next.saturating_sub(first),
+            _ => 0,
+        };
+        if let Some(price) = self.buy_prices.pop_front() {
+            self.buy_price_moments.remove(price);
+            self.buy_price_time_weighted.remove(price, held);
+        }
+        if let Some(price) = self.sell_prices.pop_front() {
+            self.sell_price_moments.remove(price);
+            self.sell_price_time_weighted.remove(price, held);
+        }
+        self.buy_volumes.pop_front();
+        self.sell_volumes.pop_front();
+        self.buy_divergences.pop_front();
+        self.sell_divergences.pop_front();
+        self.snapshot_defects.pop_front();
+        self.timestamps.pop_front();
+        self.sequence_numbers.pop_front();
+        self.buy_book_shape.remove_oldest();
+        self.sell_book_shape.remove_oldest();
+    }
+
+    // Uses the start timestamp of each delta period, not its end
+    pub fn detect_velocity_patterns(activity: &ActivityIndex, detector: &DetectorConfig) -> Vec<FuzzyPattern> {
+        let mut patterns = Vec::new();
+        // (delta_index, velocity, delta_value, start_timestamp), already in velocity order
+        let activity_periods: Vec<(usize, f64, i64, u64)> = activity.by_velocity().collect();
+
+        if activity_periods.len() < detector.min_cluster_size {
+            return patterns;
+        }
+
+        // Cluster by velocity
+        let mut clusters = Vec::new();
+        let mut current_cluster = vec![activity_periods[0]];
+        
+        for &period in &activity_periods[1..] {
+            let prev_velocity = current_cluster.last().unwrap().1;
+            let curr_velocity = period.1;
+            
+            if Self::relative_diff(prev_velocity, curr_velocity) <= 0.4 {
+                current_cluster.push(period);
+            } else {
+                if current_cluster.len() >= detector.min_cluster_size {
+                    clusters.push(current_cluster);
+                }
+                current_cluster = vec![period];
+            }
+        }
+        if current_cluster.len() >= detector.min_cluster_size {
+            clusters.push(current_cluster);
+        }
+
+        // Calculate intervals using start timestamps
+        for cluster in clusters {
+            if cluster.len() >= 2 {
+                let mut intervals = Vec::new();
+                
+                // Sort cluster by timestamp to ensure chronological order
+                let mut sorted_cluster = cluster.clone();
+                sorted_cluster.sort_by_key(|item| item.3); // Sort by timestamp
+                
+                for window in sorted_cluster.windows(2) {
+                    let time1 = window[0].3; // Start time of first delta
+                    let time2 = window[1].3; // Start time of second delta
+                    if time2 > time1 {
+                        let interval_minutes = (time2 - time1) as f64 / 60.0;
+                        if interval_minutes > 0.0 && interval_minutes <= 120.0 {
+                            intervals.push(interval_minutes);
+                        }
+                    }
+                }
+                
+                if !intervals.is_empty() {
+                    let avg_interval = intervals.iter().sum::<f64>() / intervals.len() as f64;
+                    let variance = intervals.iter()
+                        .map(|&x| (x - avg_interval).powi(2))
+                        .sum::<f64>() / intervals.len() as f64;
+                    let cv = (variance.sqrt() / avg_interval.max(1.0)).min(1.0);
+
+                    if cv < 0.6 {
+                        let avg_size = sorted_cluster.iter().map(|&(_, _, delta, _)| delta as f64).sum::<f64>() / sorted_cluster.len() as f64;
+                        let confidence = sorted_cluster.len() as f64 / activity_periods.len() as f64;
+
+                        patterns.push(FuzzyPattern {
+                            pattern_type: "velocity_pattern".to_string(),
+                            size: avg_size,
+                            interval_minutes: avg_interval,
+                            confidence: confidence.min(1.0),
+                            occurrences: sorted_cluster.len(),
+                            method_confidence: confidence * (1.0 - cv),
+                            positions: sorted_cluster.iter().map(|&(i, _, _, _)| i).collect(),
+                        });
+                    }
+                }
+            }
+        }
+
+        patterns.sort_by(|a, b| b.method_confidence.partial_cmp(&a.method_confidence).unwrap_or(std::cmp::Ordering::Equal));
+        patterns.into_iter().take(2).collect()
+    }
+
+    // Uses the start timestamp of each delta period, not its end
+    pub fn detect_rhythm_patterns(activity: &ActivityIndex, detector: &DetectorConfig) -> Vec<FuzzyPattern> {
+        let mut patterns = Vec::new();
+
+        let activity_data: Vec<(usize, u64, i64)> = activity.active().collect();
+
+        if activity_data.len() < detector.min_cluster_size {
+            return patterns;
+        }
+
+        // Calculate intervals between activity start times
+        let intervals: Vec<f64> = activity_data.windows(2)
+            .map(|w| {
+                let interval_seconds = w[1].1.saturating_sub(w[0].1);
+                interval_seconds as f64 / 60.0
+            })
+            .filter(|&interval| interval > 0.0 && interval <= 120.0)
+            .collect();
+
+        if intervals.is_empty() {
+            return patterns;
+        }
+
+        // Find modal intervals with tolerance
+        let Some(avg_size) = activity.mean_active_delta() else { return patterns };
+        for &tolerance in &detector.rhythm_tolerances {
+            for cluster in Self::find_approximate_modes(&intervals, tolerance, detector.min_cluster_size) {
+                let avg_interval = cluster.iter().sum::<f64>() / cluster.len() as f64;
+                let confidence = cluster.len() as f64 / intervals.len() as f64;
+
+                patterns.push(FuzzyPattern {
+                    pattern_type: format!("rhythm_{}pct", (tolerance * 100.0) as u32),
+                    size: avg_size,
+                    interval_minutes: avg_interval,
+                    confidence: confidence.min(1.0),
+                    occurrences: cluster.len(),
+                    method_confidence: confidence * (1.0 - tolerance * 0.5),
+                    // The size averages every active window, so all of them count
+                    positions: activity_data.iter().map(|&(i, _, _)| i).collect(),
+                });
+            }
+        }
+
+        patterns.sort_by(|a, b| b.method_confidence.partial_cmp(&a.method_confidence).unwrap_or(std::cmp::Ordering::Equal));
+        patterns.into_iter().take(1).collect()
+    }
+
+    // Difference relative to `base`, with the base floored so near-zero values don't blow it up
+    pub fn relative_diff(base: f64, other: f64) -> f64 {
+        (base - other).abs() / base.abs().max(MIN_RELATIVE_DIFF_BASE)
+    }
+
+    // Greedy clustering: each unused value seeds a cluster and absorbs every unused value within
+    // `tolerance` of it. Only clusters of at least `min_cluster_size` are returned; a tolerance
+    // outside (0, 1) or non-finite values never form clusters.
+    pub fn find_approximate_modes(values: &[f64], tolerance: f64, min_cluster_size: usize) -> Vec<Vec<f64>> {
+        let mut modes = Vec::new();
+        if !(tolerance > 0.0 && tolerance < 1.0) {
+            return modes;
+        }
+
+        let mut used: Vec<bool> = values.iter().map(|v| !v.is_finite()).collect();
+        for (i, &seed) in values.iter().enumerate() {
+            if used[i] {
+                continue;
+            }
+            let mut cluster = vec![seed];
+            used[i] = true;
+
+            for (j, &other) in values.iter().enumerate() {
+                if !used[j] && Self::relative_diff(seed, other) <= tolerance {
+                    cluster.push(other);
+                    used[j] = true;
+                }
+            }
+
+            if cluster.len() >= min_cluster_size.max(1) {
+                modes.push(cluster);
+            }
+        }
+        modes
+    }
+
+    // `activity` indexes `moving_week_deltas`; legacy clustering reads the sequences themselves
+    pub fn detect_fuzzy_modal_pattern(
+        activity: &ActivityIndex,
+        moving_week_deltas: &[i64],
+        inferred_volume_history: &[i64],
+        timestamps: &[u64],
+        detector: &DetectorConfig,
+    ) -> (Option<ModalPattern>, PatternDetails) {
+        
+        let vel_patterns = Self::detect_velocity_patterns(activity, detector);
+        let rhythm_patterns = Self::detect_rhythm_patterns(activity, detector);
+
+        let pattern_details = PatternDetails {
+            detection_method: "fuzzy_combined".to_string(),
+            fuzzy_confidence: 0.0,
+            legacy_confidence: None,
+            sequence_patterns_found: 0,
+            velocity_patterns_found: vel_patterns.len(),
+            rhythm_patterns_found: rhythm_patterns.len(),
+        };
+
+        let mut all_patterns = vel_patterns;
+        all_patterns.extend(rhythm_patterns);
+
+        if let Some(best_pattern) = all_patterns.first() {
+            let pattern_periods = Self::find_patterns_from_deltas(moving_week_deltas, inferred_volume_history, timestamps);
+            let ratio = Self::pattern_ratio(&pattern_periods);
+
+            let fuzzy_pattern = ModalPattern {
+                size: best_pattern.size,
+                ratio,
+                interval_minutes: best_pattern.interval_minutes,
+                occurrence_count: best_pattern.occurrences,
+                confidence: best_pattern.confidence,
+                detection_method: best_pattern.pattern_type.clone(),
+            };
+
+            let mut updated_details = pattern_details;
+            updated_details.fuzzy_confidence = best_pattern.confidence;
+            return (Some(fuzzy_pattern), updated_details);
+        }
+
+        let pattern_periods = Self::find_patterns_from_deltas(moving_week_deltas, inferred_volume_history, timestamps);
+        if let Some(legacy_pattern) = Self::detect_modal_pattern_legacy(&pattern_periods, detector) {
+            let mut legacy_details = pattern_details;
+            legacy_details.detection_method = "legacy_clustering".to_string();
+            legacy_details.legacy_confidence = Some(legacy_pattern.confidence);
+            return (Some(legacy_pattern), legacy_details);
+        }
+
+        (None, pattern_details)
+    }
+
+    fn pattern_ratio(pattern_periods: &[PatternPeriod]) -> Option<f64> {
+        let total_mw: i64 = pattern_periods.iter().map(|p| p.moving_week_delta).sum();
+        let total_inf: i64 = pattern_periods.iter().map(|p| p.inferred_volume).sum();
+        if total_inf > 0 {
+            Some(total_mw as f64 / total_inf as f64)
+        } else {
+            None
+        }
+    }
+
+    // Uses the start timestamp of each pattern period
+    pub fn find_patterns_from_deltas(
+        moving_week_deltas: &[i64],
+        inferred_volume_history: &[i64],
+        timestamps: &[u64],
+    ) -> Vec<PatternPeriod> {
+        let mut patterns = Vec::new();
+        let max_len = moving_week_deltas.len().min(inferred_volume_history.len()).min(timestamps.len().saturating_sub(1));
+        
+        for i in 0..max_len {
+            let delta = moving_week_deltas[i];
+            let inferred = inferred_volume_history[i];
+            if delta > 0 && inferred > 0 {
+                patterns.push(PatternPeriod {
+                    position: i,
+                    moving_week_delta: delta,
+                    inferred_volume: inferred,
+                    timestamp: timestamps[i],
+                });
+            }
+        }
+        patterns
+    }
+
+    pub fn detect_modal_pattern_legacy(pattern_periods: &[PatternPeriod], detector: &DetectorConfig) -> Option<ModalPattern> {
+        if pattern_periods.len() < detector.min_cluster_size {
+            return None;
+        }
+        
+        // BTreeMap so equally sized clusters resolve the same way on every run
+        let mut cluster_map: BTreeMap<(i64, i64), Vec<PatternPeriod>> = BTreeMap::new();
+        for p in pattern_periods {
+            let ratio = if p.inferred_volume > 0 {
+                (p.moving_week_delta as f64 / p.inferred_volume as f64 * 10000.0).round() as i64
+            } else {
+                0
+            };
+            cluster_map
+                .entry((p.moving_week_delta, ratio))
+                .or_default()
+                .push(p.clone());
+        }
+        
+        let mut modal: Option<(Vec<PatternPeriod>, i64, i64)> = None;
+        for ((delta, ratio), cluster) in &cluster_map {
+            if cluster.len() >= detector.min_cluster_size
+                && (modal.is_none() || cluster.len() > modal.as_ref().unwrap().0.len())
+            {
+                modal = Some((cluster.clone(), *delta, *ratio));
+            }
+        }
+        
+        if modal.is_none() {
+            let mut ratio_map: BTreeMap<i64, Vec<PatternPeriod>> = BTreeMap::new();
+            for p in pattern_periods {
+                let ratio = if p.inferred_volume > 0 {
+                    (p.moving_week_delta as f64 / p.inferred_volume as f64 * 10000.0).round() as i64
+                } else {
+                    0
+                };
+                ratio_map.entry(ratio).or_default().push(p.clone());
+            }
+            for (_ratio, cluster) in &ratio_map {
+                if cluster.len() < detector.min_cluster_size {
+                    continue;
+                }
+                let avg_delta = cluster.iter().map(|p| p.moving_week_delta).sum::<i64>() / cluster.len() as i64;
+                if cluster.iter().all(|p| (p.moving_week_delta - avg_delta).abs() <= (avg_delta as f64 * 0.1).max(1.0) as i64)
+                    && (modal.is_none() || cluster.len() > modal.as_ref().unwrap().0.len())
+                {
+                    modal = Some((cluster.clone(), avg_delta, *_ratio));
+                }
+            }
+        }
+        
+        let (pattern_set, modal_size, modal_ratio) = modal?;
+        
+        let timestamps: Vec<u64> = pattern_set.iter().map(|p| p.timestamp).collect();
+        if timestamps.len() < 2 {
+            return None;
+        }
+        
+        let intervals: Vec<f64> = timestamps.windows(2)
+            .map(|w| w[1].saturating_sub(w[0]) as f64 / 60.0)
+            .collect();
+        
+        let interval_minutes = if !intervals.is_empty() {
+            intervals.iter().sum::<f64>() / intervals.len() as f64
+        } else {
+            60.0
+        };
+        
+        let confidence = pattern_set.len() as f64 / pattern_periods.len() as f64;
+        
+        Some(ModalPattern {
+            size: modal_size as f64,
+            ratio: Some(modal_ratio as f64 / 10000.0),
+            interval_minutes,
+            occurrence_count: pattern_set.len(),
+            confidence,
+            detection_method: "legacy_exact_clustering".to_string(),
+        })
+    }
+
+    // Share of moving-week throughput the order-book inference accounted for; None without activity
+    fn volume_coverage(inferred_volume: f64, moving_week_activity: i64) -> Option<f64> {
+        if moving_week_activity > 0 {
+            Some(inferred_volume / moving_week_activity as f64)
+        } else {
+            None
+        }
+    }
+
+    // (correlation, mean ratio) of inferred volume against moving-week deltas, window by window
+    fn inference_agreement(inferred: &VecDeque<i64>, moving_week: &VecDeque<i64>) -> (Option<f64>, Option<f64>) {
+        let pairs: Vec<(f64, f64)> = inferred.iter().zip(moving_week).map(|(&i, &m)| (i as f64, m as f64)).collect();
+        let correlation = pearson(&pairs);
+
+        let ratios: Vec<f64> = pairs.iter().filter(|(_, m)| *m > 0.0).map(|(i, m)| i / m).collect();
+        let ratio = (!ratios.is_empty()).then(|| ratios.iter().sum::<f64>() / ratios.len() as f64);
+        (correlation, ratio)
+    }
+
+    // "Buy" is the instabuy side: the buy moving-week counter and inferred instabuy events
+    fn market_activity(&self, analysis: &AnalysisConfig) -> MarketActivity {
+        let active = |moving_week: i64, events: usize| {
+            moving_week >= analysis.activity_min_moving_week_volume && events >= analysis.activity_min_inferred_events
+        };
+        MarketActivity::from_sides(
+            active(self.total_buy_moving_week_activity, self.player_instabuy_events.get(analysis.event_inference)),
+            active(self.total_sell_moving_week_activity, self.player_instasell_events.get(analysis.event_inference)),
+        )
+    }
+
+    // (window index, max / median) of the largest window when it is over `multiple` times the
+    // median. Cycles where most windows saw nothing have a zero median and never flag.
+    fn detect_spike(volume_history: &[i64], multiple: f64) -> Option<(usize, f64)> {
+        let mut sorted = volume_history.to_vec();
+        sorted.sort_unstable();
+        let median = match sorted.len() {
+            0 => return None,
+            n if n % 2 == 0 => (sorted[n / 2 - 1] + sorted[n / 2]) as f64 / 2.0,
+            n => sorted[n / 2] as f64,
+        };
+        if median <= 0.0 {
+            return None;
+        }
+        let (window, &max) = volume_history.iter().enumerate().max_by_key(|(_, &volume)| volume)?;
+        let magnitude = max as f64 / median;
+        (magnitude > multiple).then_some((window, magnitude))
+    }
+
+    // Factor that scales a cycle's totals up to a full cycle, assuming activity is uniform across
+    // it. Only totals are scaled: the *_per_hour frequencies are already rates over the span the
+    // cycle observed (`units::per_hour`), and prices and sizes are averages.
+    fn extrapolation_factor(&self, analysis: &AnalysisConfig) -> Option<f64> {
+        if !analysis.extrapolate_partial_windows || self.windows_processed == 0 {
+            return None;
+        }
+        Some((analysis.target_windows as f64 / self.windows_processed as f64).max(1.0))
+    }
+
+    // Mean of the values the API reported this cycle; None when it reported none
+    fn reported_average(values: &VecDeque<Option<i64>>) -> Option<f64> {
+        let reported: Vec<i64> = values.iter().flatten().copied().collect();
+        (!reported.is_empty()).then(|| reported.iter().sum::<i64>() as f64 / reported.len() as f64)
+    }
+
+    // The summaries list the best level first; an empty or missing book has no top to compare with
+    fn top_of_book_divergence(quick_status_price: f64, orders: &[Order]) -> Option<f64> {
+        orders.first().map(|top| (quick_status_price - top.price_per_unit).abs()).filter(|d| d.is_finite())
+    }
+
+    fn defects(snapshot: &BazaarInfo) -> usize {
+        usize::from(snapshot.buy_summary_missing) + usize::from(snapshot.sell_summary_missing) + snapshot.malformed_levels + snapshot.invalid_fields
+    }
+
+    // One 0-1 score over the signals of how trustworthy this product's row is, weighted:
+    //   0.30  window coverage: windows_processed / target_windows, capped at 1
+    //   0.25  completeness: share of snapshots with both order-book sides and no malformed levels or fields
+    //   0.15  ordering: share of the product's snapshots accepted rather than rejected as out of order
+    //   0.15  counter stability: 1 / (1 + moving-week counter resets on either side)
+    //   0.15  book agreement: mean over sides with moving-week volume of min(c, 1/c) for the
+    //         inferred coverage c; 1 when neither side traded
+    // It only annotates the row; no metric is changed or withheld because of it. Rejected price
+    // outliers are not a signal: nothing upstream rejects a price as an outlier, so there is no count.
+    fn data_quality(&self, analysis: &AnalysisConfig, inferred_coverages: [Option<f64>; 2]) -> f64 {
+        let coverage = if analysis.target_windows > 0 {
+            (self.windows_processed as f64 / analysis.target_windows as f64).min(1.0)
+        } else {
+            1.0
+        };
+        let snapshots = self.snapshot_defects.len().max(1) as f64;
+        let completeness = self.snapshot_defects.iter().filter(|&&defects| defects == 0).count() as f64 / snapshots;
+        let ordering = match (self.sequence_numbers.front(), self.sequence_numbers.back()) {
+            (Some(first), Some(last)) => self.sequence_numbers.len() as f64 / (last - first + 1) as f64,
+            _ => 1.0,
+        };
+        let resets = self.buy_counter_resets + self.sell_counter_resets;
+        let counter_stability = 1.0 / (1 + resets) as f64;
+        let agreements: Vec<f64> = inferred_coverages.iter().flatten()
+            .map(|&c| if c > 0.0 { c.min(1.0 / c) } else { 0.0 })
+            .collect();
+        let book_agreement = if agreements.is_empty() { 1.0 } else { agreements.iter().sum::<f64>() / agreements.len() as f64 };
+        0.30 * coverage + 0.25 * completeness + 0.15 * ordering + 0.15 * counter_stability + 0.15 * book_agreement
+    }
+
+    fn divergence_average(values: &VecDeque<Option<f64>>) -> Option<f64> {
+        let reported: Vec<f64> = values.iter().flatten().copied().collect();
+        (!reported.is_empty()).then(|| reported.iter().sum::<f64>() / reported.len() as f64)
+    }
+
+    // (instabuy, instasell) averages over the snapshots seen this cycle
+    fn price_averages(&self) -> (f64, f64) {
+        if self.snapshot_count == 0 {
+            return (0.0, 0.0);
+        }
+        (self.buy_price_moments.mean, self.sell_price_moments.mean)
+    }
+
+    // Cheap prefilter ahead of the detectors: a product is only worth detecting when some of its
+    // windows saw activity (moving-week or inferred, either side) or it traded enough moving-week
+    // volume. The defaults skip only products that were completely dead.
+    fn worth_detecting(&self, analysis: &AnalysisConfig) -> bool {
+        if analysis.force_full_detection {
+            return true;
+        }
+        let active_windows = (0..self.windows_processed)
+            .filter(|&i| {
+                let positive = |series: &VecDeque<i64>| series.get(i).is_some_and(|&v| v > 0);
+                positive(&self.buy_moving_week_deltas) || positive(&self.sell_moving_week_deltas)
+                    || positive(&self.inferred_buy_volume_history) || positive(&self.inferred_sell_volume_history)
+            })
+            .count();
+        let active_fraction = if self.windows_processed > 0 { active_windows as f64 / self.windows_processed as f64 } else { 0.0 };
+        let activity = self.total_buy_moving_week_activity + self.total_sell_moving_week_activity;
+        active_fraction > analysis.prefilter_min_active_fraction || activity > analysis.prefilter_min_moving_week_activity
+    }
+
+    // Economic relevance rather than activity: a product nobody can flip at a profit, or whose
+    // busier side barely trades, gets no pattern worth reporting
+    fn above_pattern_threshold(&self, analysis: &AnalysisConfig) -> bool {
+        if analysis.force_full_detection {
+            return true;
+        }
+        let (instabuy_price_average, instasell_price_average) = self.price_averages();
+        let activity = self.total_buy_moving_week_activity.max(self.total_sell_moving_week_activity);
+        instabuy_price_average - instasell_price_average > analysis.pattern_min_spread
+            && activity > analysis.pattern_min_moving_week_activity
+    }
+
+    // Time from the first to the last accepted snapshot of the cycle
+    fn observed_span_secs(&self) -> u64 {
+        match (self.timestamps.front(), self.timestamps.back()) {
+            (Some(first), Some(last)) => last.saturating_sub(*first),
+            _ => 0,
+        }
+    }
+
+    // (instabuy, instasell) prices of the newest accepted snapshot
+    fn last_prices(&self) -> (f64, f64) {
+        self.prev_snapshot.as_ref().map_or((0.0, 0.0), |last| (last.buy_price, last.sell_price))
+    }
+
+    // Full-resolution sequences of the current cycle, before any DELTA_SEQUENCES downsampling
+    fn delta_sequences(&self, analysis: &AnalysisConfig) -> DeltaSequences {
+        DeltaSequences {
+            buy_moving_week: self.buy_moving_week_deltas.clone().into(),
+            sell_moving_week: self.sell_moving_week_deltas.clone().into(),
+            buy_orders: self.buy_orders_deltas.clone().into(),
+            sell_orders: self.sell_orders_deltas.clone().into(),
+            buy_amount: self.buy_amount_deltas.clone().into(),
+            sell_amount: self.sell_amount_deltas.clone().into(),
+            buy_inferred_volume: self.inferred_buy_volume_history.clone().into(),
+            sell_inferred_volume: self.inferred_sell_volume_history.clone().into(),
+            timestamps: Timestamps { values: self.timestamps.clone().into(), format: analysis.timestamp_format },
+            sequence_numbers: self.sequence_numbers.clone().into(),
+            downsampled_every: None,
+        }
+    }
+
+    pub fn finalize_with_sequences(&self, product_id: String, analysis: &AnalysisConfig) -> AnalysisResult {
+        let windows = self.windows_processed as f64;
+        let (instabuy_price_average, instasell_price_average) = self.price_averages();
+        let (instabuy_price_last, instasell_price_last) = self.last_prices();
+        let span_secs = self.observed_span_secs();
+        let new_demand_offers_per_hour = units::per_hour(self.total_new_demand_offers, span_secs);
+        let new_demand_offer_size_average = if self.total_new_demand_offers > 0.0 { self.total_new_demand_offer_amount / self.total_new_demand_offers } else { 0.0 };
+        let new_supply_offers_per_hour = units::per_hour(self.total_new_supply_offers, span_secs);
+        let new_supply_offer_size_average = if self.total_new_supply_offers > 0.0 { self.total_new_supply_offer_amount / self.total_new_supply_offers } else { 0.0 };
+        let instabuy_events = self.player_instabuy_events.get(analysis.event_inference);
+        let instasell_events = self.player_instasell_events.get(analysis.event_inference);
+        let player_instabuy_transactions_per_hour = units::per_hour(instabuy_events as f64, span_secs);
+        let player_instabuy_transaction_size_average = if instabuy_events > 0 { self.player_instabuy_volume_total / instabuy_events as f64 } else { 0.0 };
+        let player_instasell_transactions_per_hour = units::per_hour(instasell_events as f64, span_secs);
+        let player_instasell_transaction_size_average = if instasell_events > 0 { self.player_instasell_volume_total / instasell_events as f64 } else { 0.0 };
+
+        let delta_sequences = self.delta_sequences(analysis);
+        let instabuy_inferred_coverage = Self::volume_coverage(self.player_instabuy_volume_total, self.total_buy_moving_week_activity);
+        let instasell_inferred_coverage = Self::volume_coverage(self.player_instasell_volume_total, self.total_sell_moving_week_activity);
+        let (instabuy_inference_correlation, instabuy_inference_ratio) =
+            Self::inference_agreement(&self.inferred_buy_volume_history, &self.buy_moving_week_deltas);
+        let (instasell_inference_correlation, instasell_inference_ratio) =
+            Self::inference_agreement(&self.inferred_sell_volume_history, &self.sell_moving_week_deltas);
+        let patterns = if !self.worth_detecting(analysis) {
+            PatternFields::undetected("skipped_inactive".to_string())
+        } else if !self.above_pattern_threshold(analysis) {
+            PatternFields::undetected("below_pattern_threshold".to_string())
+        } else {
+            let activity = [&self.buy_activity, &self.sell_activity];
+            PatternFields::detect_indexed(&delta_sequences, activity, instabuy_inferred_coverage, instasell_inferred_coverage, &analysis.detector.for_product(&product_id))
+                .unwrap_or_else(|e| {
+                    eprintln!("[GiantWizard] ❌ Skipping pattern detection for {}: misaligned sequences ({})", product_id, e);
+                    PatternFields::undetected("skipped_misaligned".to_string())
+                })
+        };
+
+        // Always use moving week total as ground truth
+        let instabuy_estimated_true_volume = self.total_buy_moving_week_activity as f64;
+        let instasell_estimated_true_volume = self.total_sell_moving_week_activity as f64;
+
+        let window_coverage = if analysis.target_windows > 0 { windows / analysis.target_windows as f64 } else { 1.0 };
+        let extrapolation_factor = self.extrapolation_factor(analysis);
+        let extrapolate = |total: f64| extrapolation_factor.map(|factor| total * factor);
+        let buy_spike = Self::detect_spike(&delta_sequences.buy_inferred_volume, analysis.spike_median_multiple);
+        let sell_spike = Self::detect_spike(&delta_sequences.sell_inferred_volume, analysis.spike_median_multiple);
+
+        AnalysisResult { 
+            display_name: alias::default_display_name(&product_id),
+            product_id, 
+            schema_version: SCHEMA_VERSION,
+            instabuy_price_average, 
+            instasell_price_average, 
+            instabuy_price_time_weighted: self.buy_price_time_weighted.mean(),
+            instasell_price_time_weighted: self.sell_price_time_weighted.mean(),
+            instabuy_price_last,
+            instasell_price_last,
+            instabuy_price_variance: self.buy_price_moments.sample_variance(),
+            instasell_price_variance: self.sell_price_moments.sample_variance(),
+            instabuy_realized_vol: realized_volatility(&self.buy_prices, span_secs),
+            instasell_realized_vol: realized_volatility(&self.sell_prices, span_secs),
+            instabuy_price_relative: None,
+            instasell_price_relative: None,
+            buy_price_pinned: PriceRange::of(&self.buy_prices).is_pinned(analysis.price_pin_epsilon),
+            sell_price_pinned: PriceRange::of(&self.sell_prices).is_pinned(analysis.price_pin_epsilon),
+            buy_standing_volume_average: analysis.standing_volume.then(|| Self::reported_average(&self.buy_volumes)).flatten(),
+            sell_standing_volume_average: analysis.standing_volume.then(|| Self::reported_average(&self.sell_volumes)).flatten(),
+            buy_quickstatus_topofbook_divergence: Self::divergence_average(&self.buy_divergences),
+            sell_quickstatus_topofbook_divergence: Self::divergence_average(&self.sell_divergences),
+            new_demand_offers_per_hour, 
+            new_demand_offer_size_average, 
+            player_instabuy_transactions_per_hour, 
+            player_instabuy_transaction_size_average, 
+            new_supply_offers_per_hour, 
+            new_supply_offer_size_average, 
+            player_instasell_transactions_per_hour, 
+            player_instasell_transaction_size_average,
+            instabuy_modal_size: patterns.instabuy_modal_size,
+            instabuy_modal_ratio: patterns.instabuy_modal_ratio,
+            instabuy_pattern_per_hour: patterns.instabuy_pattern_per_hour,
+            instabuy_scale_factor: patterns.instabuy_scale_factor,
+            instabuy_estimated_true_volume,
+            instasell_modal_size: patterns.instasell_modal_size,
+            instasell_modal_ratio: patterns.instasell_modal_ratio,
+            instasell_pattern_per_hour: patterns.instasell_pattern_per_hour,
+            instasell_scale_factor: patterns.instasell_scale_factor,
+            instasell_estimated_true_volume,
+            instabuy_moving_week_volume: self.total_buy_moving_week_activity as f64,
+            instabuy_inferred_volume: self.player_instabuy_volume_total,
+            instabuy_inferred_coverage,
+            instasell_moving_week_volume: self.total_sell_moving_week_activity as f64,
+            instasell_inferred_volume: self.player_instasell_volume_total,
+            instasell_inferred_coverage,
+            buy_mw_hourly_rate: units::per_hour(self.total_buy_moving_week_activity as f64, span_secs),
+            sell_mw_hourly_rate: units::per_hour(self.total_sell_moving_week_activity as f64, span_secs),
+            instabuy_inference_correlation,
+            instabuy_inference_ratio,
+            instasell_inference_correlation,
+            instasell_inference_ratio,
+            flash_order_count: self.flash_orders,
+            buy_counter_resets: self.buy_counter_resets,
+            sell_counter_resets: self.sell_counter_resets,
+            market_activity: self.market_activity(analysis),
+            window_coverage,
+            extrapolated: extrapolation_factor.is_some_and(|factor| factor > 1.0),
+            data_quality: self.data_quality(analysis, [instabuy_inferred_coverage, instasell_inferred_coverage]),
+            instabuy_estimated_true_volume_extrapolated: extrapolate(instabuy_estimated_true_volume),
+            instabuy_inferred_volume_extrapolated: extrapolate(self.player_instabuy_volume_total),
+            instasell_estimated_true_volume_extrapolated: extrapolate(instasell_estimated_true_volume),
+            instasell_inferred_volume_extrapolated: extrapolate(self.player_instasell_volume_total),
+            buy_book_slope: self.buy_book_shape.slope_average(),
+            buy_book_concentration: self.buy_book_shape.concentration_average(),
+            sell_book_slope: self.sell_book_shape.slope_average(),
+            sell_book_concentration: self.sell_book_shape.concentration_average(),
+            buy_spike_detected: buy_spike.is_some(),
+            buy_spike_magnitude: buy_spike.map(|(_, magnitude)| magnitude),
+            buy_spike_window: buy_spike.map(|(window, _)| window),
+            sell_spike_detected: sell_spike.is_some(),
+            sell_spike_magnitude: sell_spike.map(|(_, magnitude)| magnitude),
+            sell_spike_window: sell_spike.map(|(window, _)| window),
+            volume_vs_baseline: None,
+            spread_vs_baseline: None,
+            detection_stability: None,
+            pattern_detection_confidence: patterns.pattern_detection_confidence,
+            delta_sequences: delta_sequences.for_output(analysis.delta_sequence_output),
+            pattern_details: patterns.pattern_details,
+        }
+    }
+}
+
+// Typed mirror of the bazaar response, deserialized from the body in one pass: every product is
+// read straight into these types, without buffering its JSON or building a Value tree. Fields we
+// don't use are skipped, not allocated; what is allocated is the product ids and the order books.
+// Missing fields fall back to their defaults; a missing summary stays None so a partial response
+// can be told apart from an empty book. Numeric fields also accept the other number type or a
+// numeric string (see `ApiNumber`); any other value falls back to the default and is flagged (see
+// `Lenient`). Only a product whose structure doesn't fit fails its parse (see `Shaped`).
+#[derive(Debug, Deserialize)]
+struct ApiResponse {
+    products: BTreeMap<String, Shaped<ShapedProduct>>,
+}
+
+// `ApiProduct` as it arrives, with every part that may have the wrong JSON type `Shaped`
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct ShapedProduct {
+    buy_summary: Option<Shaped<Vec<Shaped<ApiLevel>>>>,
+    sell_summary: Option<Shaped<Vec<Shaped<ApiLevel>>>>,
+    quick_status: Shaped<ApiQuickStatus>,
+}
+
+impl ShapedProduct {
+    // The product, or the first part that didn't fit
+    fn fit(self) -> Result<ApiProduct, String> {
+        let levels = |summary: Option<Shaped<Vec<Shaped<ApiLevel>>>>, name: &str| {
+            summary.map(|levels| levels.fit()?.into_iter().map(Shaped::fit).collect::<Result<Vec<_>, _>>())
+                .transpose()
+                .map_err(|e| format!("{}: {}", name, e))
+        };
+        Ok(ApiProduct {
+            buy_summary: levels(self.buy_summary, "buy_summary")?,
+            sell_summary: levels(self.sell_summary, "sell_summary")?,
+            quick_status: self.quick_status.fit().map_err(|e| format!("quick_status: {}", e))?,
+        })
+    }
+}
+
+// A part of a product as parsed: the value, or why its JSON didn't fit the type. A value of the
+// wrong type is consumed either way, so one bad product doesn't fail the rest of the body, which
+// keeps parsing from the same stream. Broken JSON still fails the body, as does an object that
+// repeats a field: both are only found part-way through a value, where the stream can't resume.
+#[derive(Debug)]
+enum Shaped<T> {
+    Fits(T),
+    Mismatch(String),
+}
+
+impl<T> Shaped<T> {
+    fn fit(self) -> Result<T, String> {
+        match self {
+            Shaped::Fits(value) => Ok(value),
+            Shaped::Mismatch(e) => Err(e),
+        }
+    }
+}
+
+impl<T: Default> Default for Shaped<T> {
+    fn default() -> Self {
+        Shaped::Fits(T::default())
+    }
+}
+
+impl<'de, T: Deserialize<'de>> Deserialize<'de> for Shaped<T> {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        use serde::de::value::{MapAccessDeserializer, SeqAccessDeserializer};
+        use serde::de::{IgnoredAny, IntoDeserializer};
+
+        struct ShapedVisitor<T>(std::marker::PhantomData<T>);
+
+        // A scalar is already consumed, so `T` failing on it only makes the part a mismatch
+        fn scalar<'de, T: Deserialize<'de>, E: serde::de::Error>(fitted: Result<T, E>) -> Result<Shaped<T>, E> {
+            Ok(fitted.map_or_else(|e| Shaped::Mismatch(e.to_string()), Shaped::Fits))
+        }
+
+        impl<'de, T: Deserialize<'de>> serde::de::Visitor<'de> for ShapedVisitor<T> {
+            type Value = Shaped<T>;
+
+            fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+                f.write_str("any JSON value")
+            }
+
+            fn visit_bool<E: serde::de::Error>(self, v: bool) -> Result<Shaped<T>, E> {
+                scalar(T::deserialize(v.into_deserializer()))
+            }
+
+            fn visit_i64<E: serde::de::Error>(self, v: i64) -> Result<Shaped<T>, E> {
+                scalar(T::deserialize(v.into_deserializer()))
+            }
+
+            fn visit_u64<E: serde::de::Error>(self, v: u64) -> Result<Shaped<T>, E> {
+                scalar(T::deserialize(v.into_deserializer()))
+            }
+
+            fn visit_f64<E: serde::de::Error>(self, v: f64) -> Result<Shaped<T>, E> {
+                scalar(T::deserialize(v.into_deserializer()))
+            }
+
+            fn visit_str<E: serde::de::Error>(self, v: &str) -> Result<Shaped<T>, E> {
+                scalar(T::deserialize(v.into_deserializer()))
+            }
+
+            fn visit_unit<E: serde::de::Error>(self) -> Result<Shaped<T>, E> {
+                scalar(T::deserialize(().into_deserializer()))
+            }
+
+            // A type that doesn't take a map or array refuses it before reading any of it, and the
+            // rest of it is skipped here; so is the rest of an array a struct stopped reading at its
+            // last field, which makes it a mismatch. An error part-way through is broken JSON: the
+            // parts inside are all `Shaped` or `Lenient`, which take any value.
+            fn visit_seq<A: serde::de::SeqAccess<'de>>(self, seq: A) -> Result<Shaped<T>, A::Error> {
+                let mut seq = Tracked::new(seq);
+                let fitted = match T::deserialize(SeqAccessDeserializer::new(&mut seq)) {
+                    Err(e) if seq.read && !seq.finished => return Err(e),
+                    fitted => fitted,
+                };
+                let mut extra = 0;
+                while !seq.finished && seq.access.next_element::<IgnoredAny>()?.is_some() {
+                    extra += 1;
+                }
+                Ok(match fitted {
+                    Ok(value) if extra == 0 => Shaped::Fits(value),
+                    Ok(_) => Shaped::Mismatch(format!("{} more array elements than expected", extra)),
+                    Err(e) => Shaped::Mismatch(e.to_string()),
+                })
+            }
+
+            fn visit_map<A: serde::de::MapAccess<'de>>(self, map: A) -> Result<Shaped<T>, A::Error> {
+                let mut map = Tracked::new(map);
+                let fitted = match T::deserialize(MapAccessDeserializer::new(&mut map)) {
+                    Err(e) if map.read && !map.finished => return Err(e),
+                    fitted => fitted,
+                };
+                while !map.finished && map.access.next_entry::<IgnoredAny, IgnoredAny>()?.is_some() {}
+                Ok(fitted.map_or_else(|e| Shaped::Mismatch(e.to_string()), Shaped::Fits))
+            }
+        }
+
+        deserializer.deserialize_any(ShapedVisitor(std::marker::PhantomData))
+    }
+}
+
+// A map or array being read by a `Shaped` part, noting whether any of it was read, and all of it
+struct Tracked<A> {
+    access: A,
+    read: bool,
+    finished: bool,
+}
+
+impl<A> Tracked<A> {
+    fn new(access: A) -> Self {
+        Self { access, read: false, finished: false }
+    }
+}
+
+impl<'de, A: serde::de::SeqAccess<'de>> serde::de::SeqAccess<'de> for Tracked<A> {
+    type Error = A::Error;
+
+    fn next_element_seed<S: serde::de::DeserializeSeed<'de>>(&mut self, seed: S) -> Result<Option<S::Value>, A::Error> {
+        self.read = true;
+        let element = self.access.next_element_seed(seed)?;
+        self.finished = element.is_none();
+        Ok(element)
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        self.access.size_hint()
+    }
+}
+
+impl<'de, A: serde::de::MapAccess<'de>> serde::de::MapAccess<'de> for Tracked<A> {
+    type Error = A::Error;
+
+    fn next_key_seed<K: serde::de::DeserializeSeed<'de>>(&mut self, seed: K) -> Result<Option<K::Value>, A::Error> {
+        self.read = true;
+        let key = self.access.next_key_seed(seed)?;
+        self.finished = key.is_none();
+        Ok(key)
+    }
+
+    fn next_value_seed<V: serde::de::DeserializeSeed<'de>>(&mut self, seed: V) -> Result<V::Value, A::Error> {
+        self.access.next_value_seed(seed)
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        self.access.size_hint()
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct ApiProduct {
+    buy_summary: Option<Vec<ApiLevel>>,
+    sell_summary: Option<Vec<ApiLevel>>,
+    quick_status: ApiQuickStatus,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+struct ApiQuickStatus {
+    buy_price: Lenient<f64>,
+    sell_price: Lenient<f64>,
+    buy_moving_week: Lenient<i64>,
+    sell_moving_week: Lenient<i64>,
+    buy_volume: Option<Lenient<i64>>,
+    sell_volume: Option<Lenient<i64>>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+struct ApiLevel {
+    amount: Lenient<i64>,
+    price_per_unit: Lenient<f64>,
+    orders: Lenient<i64>,
+}
+
+// A number as upstream may send it: usually the JSON type we expect, but mirrors and API quirks
+// have produced integers for prices, floats like `3.0` for counts, and numbers inside strings.
+// Integers are kept exact rather than routed through f64. Anything else (null, booleans, other
+// strings, arrays, objects) is consumed as `Invalid`, so it never fails the parse around it.
+enum ApiNumber {
+    Int(i64),
+    Float(f64),
+    Invalid,
+}
+
+impl<'de> Deserialize<'de> for ApiNumber {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct NumberVisitor;
+
+        impl<'de> serde::de::Visitor<'de> for NumberVisitor {
+            type Value = ApiNumber;
+
+            fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+                f.write_str("a number or a numeric string")
+            }
+
+            fn visit_i64<E>(self, v: i64) -> Result<ApiNumber, E> {
+                Ok(ApiNumber::Int(v))
+            }
+
+            fn visit_u64<E>(self, v: u64) -> Result<ApiNumber, E> {
+                Ok(i64::try_from(v).map_or(ApiNumber::Float(v as f64), ApiNumber::Int))
+            }
+
+            fn visit_f64<E>(self, v: f64) -> Result<ApiNumber, E> {
+                Ok(ApiNumber::Float(v))
+            }
+
+            fn visit_str<E>(self, v: &str) -> Result<ApiNumber, E> {
+                let trimmed = v.trim();
+                if let Ok(int) = trimmed.parse() {
+                    return Ok(ApiNumber::Int(int));
+                }
+                match trimmed.parse::<f64>() {
+                    Ok(float) if float.is_finite() => Ok(ApiNumber::Float(float)),
+                    _ => Ok(ApiNumber::Invalid),
+                }
+            }
+
+            fn visit_bool<E>(self, _: bool) -> Result<ApiNumber, E> {
+                Ok(ApiNumber::Invalid)
+            }
+
+            fn visit_unit<E>(self) -> Result<ApiNumber, E> {
+                Ok(ApiNumber::Invalid)
+            }
+
+            fn visit_seq<A: serde::de::SeqAccess<'de>>(self, mut seq: A) -> Result<ApiNumber, A::Error> {
+                while seq.next_element::<serde::de::IgnoredAny>()?.is_some() {}
+                Ok(ApiNumber::Invalid)
+            }
+
+            fn visit_map<A: serde::de::MapAccess<'de>>(self, mut map: A) -> Result<ApiNumber, A::Error> {
+                while map.next_entry::<serde::de::IgnoredAny, serde::de::IgnoredAny>()?.is_some() {}
+                Ok(ApiNumber::Invalid)
+            }
+        }
+
+        deserializer.deserialize_any(NumberVisitor)
+    }
+}
+
+trait FromApiNumber: Default {
+    fn from_api_number(number: ApiNumber) -> Option<Self>;
+}
+
+impl FromApiNumber for f64 {
+    fn from_api_number(number: ApiNumber) -> Option<Self> {
+        match number {
+            ApiNumber::Int(v) => Some(v as f64),
+            ApiNumber::Float(v) => Some(v),
+            ApiNumber::Invalid => None,
+        }
+    }
+}
+
+impl FromApiNumber for i64 {
+    // Floats only when they hold a whole number in range; 2.5 orders is invalid, not 2
+    fn from_api_number(number: ApiNumber) -> Option<Self> {
+        match number {
+            ApiNumber::Int(v) => Some(v),
+            ApiNumber::Float(v) if v.fract() == 0.0 && v >= i64::MIN as f64 && v < i64::MAX as f64 => Some(v as i64),
+            ApiNumber::Float(_) | ApiNumber::Invalid => None,
+        }
+    }
+}
+
+// A numeric field as parsed: the value, or the type's default flagged `invalid` when upstream sent
+// something that can't be coerced. The flag lets `parse_product` count it against the product
+// instead of the whole snapshot failing.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+struct Lenient<T> {
+    value: T,
+    invalid: bool,
+}
+
+impl<'de, T: FromApiNumber> Deserialize<'de> for Lenient<T> {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        Ok(match T::from_api_number(ApiNumber::deserialize(deserializer)?) {
+            Some(value) => Lenient { value, invalid: false },
+            None => Lenient { value: T::default(), invalid: true },
+        })
+    }
+}
+
+// Levels with a negative amount, order count or price, a non-finite price, or a field that isn't a
+// number are dropped with a warning; they would otherwise turn into nonsense book deltas
+fn parse_levels(pid: &str, summary: &str, levels: Option<Vec<ApiLevel>>) -> (Vec<Order>, usize) {
+    let levels = levels.unwrap_or_default();
+    let total = levels.len();
+    let valid: Vec<Order> = levels.into_iter()
+        .filter(|o| !(o.amount.invalid || o.price_per_unit.invalid || o.orders.invalid))
+        .map(|o| Order { amount: o.amount.value, price_per_unit: o.price_per_unit.value, orders: o.orders.value })
+        .filter(|o| o.amount >= 0 && o.orders >= 0 && o.price_per_unit.is_finite() && o.price_per_unit >= 0.0)
+        .collect();
+    let dropped = total - valid.len();
+    if dropped > 0 {
+        eprintln!("[GiantWizard] ⚠️ {}: dropped {} {} levels with negative, non-finite or non-numeric fields", pid, dropped, summary);
+    }
+    (valid, dropped)
+}
+
+fn parse_product(pid: String, prod: ApiProduct) -> BazaarInfo {
+    let quick = prod.quick_status;
+    let buy_summary_missing = prod.buy_summary.is_none();
+    let sell_summary_missing = prod.sell_summary.is_none();
+    let (sell_orders_vec, sell_malformed) = parse_levels(&pid, "sell_summary", prod.sell_summary);
+    let (buy_orders_vec, buy_malformed) = parse_levels(&pid, "buy_summary", prod.buy_summary);
+    // An uncoercible standing volume is unknown, like a missing one, rather than 0
+    let volume = |field: Option<Lenient<i64>>| field.filter(|v| !v.invalid).map(|v| v.value);
+    let invalid_fields = [quick.buy_price.invalid, quick.sell_price.invalid, quick.buy_moving_week.invalid, quick.sell_moving_week.invalid]
+        .into_iter()
+        .chain([quick.buy_volume, quick.sell_volume].into_iter().flatten().map(|v| v.invalid))
+        .filter(|&invalid| invalid)
+        .count();
+    if invalid_fields > 0 {
+        eprintln!("[GiantWizard] ⚠️ {}: {} quick_status fields weren't numbers and were read as defaults", pid, invalid_fields);
+    }
+    BazaarInfo {
+        product_id: pid,
+        buy_price: quick.buy_price.value,
+        sell_price: quick.sell_price.value,
+        sell_orders: sell_orders_vec,
+        buy_orders: buy_orders_vec,
+        buy_moving_week: quick.buy_moving_week.value,
+        sell_moving_week: quick.sell_moving_week.value,
+        buy_volume: volume(quick.buy_volume),
+        sell_volume: volume(quick.sell_volume),
+        buy_summary_missing,
+        sell_summary_missing,
+        malformed_levels: buy_malformed + sell_malformed,
+        invalid_fields,
+    }
+}
+
+// A snapshot's products in id order. A product that doesn't parse is logged and left out of this
+// snapshot only; the others keep their window.
+fn parse_bazaar_products(products: BTreeMap<String, Shaped<ShapedProduct>>) -> Vec<BazaarInfo> {
+    products.into_iter()
+        .filter_map(|(pid, prod)| match prod.fit().and_then(ShapedProduct::fit) {
+            Ok(prod) => Some(parse_product(pid, prod)),
+            Err(e) => {
+                eprintln!("[GiantWizard] ⚠️ {}: skipping malformed product this snapshot: {}", pid, e);
+                None
+            }
+        })
+        .collect()
+}
+
+struct RawSnapshot {
+    body: bytes::Bytes,
+    last_modified: Option<String>,
+}
+
+// One client for the process so connections are reused and every request identifies us
+fn build_http_client(config: &Config) -> Result<reqwest::Client, Box<dyn Error>> {
+    let mut headers = reqwest::header::HeaderMap::new();
+    if let Some(from) = &config.http_from {
+        headers.insert(reqwest::header::FROM, from.parse()?);
+    }
+    Ok(reqwest::Client::builder()
+        .user_agent(config.user_agent.as_str())
+        .default_headers(headers)
+        .build()?)
+}
+
+// Returns None when the endpoint's Last-Modified hasn't moved since the previous call, or, for a
+// response without the header, when the body is the same as last time
+async fn fetch_raw_snapshot(client: &reqwest::Client, endpoint: &mut Endpoint) -> Result<Option<RawSnapshot>, Box<dyn Error>> {
+    let resp = client.get(&endpoint.url).send().await?.error_for_status()?;
+    let new_mod = resp.headers().get("last-modified").and_then(|h| h.to_str().ok()).map(String::from);
+    if !last_modified_changed(&mut endpoint.last_modified, new_mod.as_deref()) {
+        return Ok(None);
+    }
+    let body = resp.bytes().await?;
+    if new_mod.is_none() && !endpoint.body_changed(&body) {
+        return Ok(None);
+    }
+    Ok(Some(RawSnapshot { body, last_modified: new_mod }))
+}
+
+// Records `current` and says whether it is a new update; a response without the header always is
+fn last_modified_changed(last_modified: &mut Option<String>, current: Option<&str>) -> bool {
+    if current.is_some() && last_modified.as_deref() == current {
+        return false;
+    }
+    *last_modified = current.map(String::from);
+    true
+}
+
+// Parses an HTTP date such as `Wed, 01 Jan 2025 00:00:00 GMT` into unix seconds
+fn parse_http_date(value: &str) -> Option<u64> {
+    chrono::DateTime::parse_from_rfc2822(value).ok()
+        .and_then(|date| u64::try_from(date.timestamp()).ok())
+}
+
+// One parsed bazaar response, with when it was observed and the API's `Last-Modified` (both unix
+// seconds). Live fetches and replayed captures both arrive as this.
+struct Snapshot {
+    captured_at: u64,
+    last_modified: Option<u64>,
+    // Polls that returned no new data since the previous snapshot
+    polls_skipped: u32,
+    products: Vec<BazaarInfo>,
+}
+
+// A polled bazaar-format endpoint and its own `Last-Modified` for dedup. Proxies and mirrors may
+// strip the header; its responses are then deduplicated by the sha256 of the body instead, which
+// costs hashing every poll's body.
+struct Endpoint {
+    url: String,
+    last_modified: Option<String>,
+    body_hash: Option<String>,
+    // Set once the header has been found missing, so that is only logged once
+    header_missing: bool,
+}
+
+impl Endpoint {
+    fn new(url: String) -> Self {
+        Self { url, last_modified: None, body_hash: None, header_missing: false }
+    }
+
+    // Records the body of a response without Last-Modified and says whether it differs from the
+    // previous one
+    fn body_changed(&mut self, body: &[u8]) -> bool {
+        if !self.header_missing {
+            self.header_missing = true;
+            eprintln!("[GiantWizard] ⚠️ {} sends no Last-Modified header; deduplicating its polls by content hash", self.url);
+        }
+        let hash = checksum::sha256_hex(body);
+        if self.body_hash.as_deref() == Some(hash.as_str()) {
+            return false;
+        }
+        self.body_hash = Some(hash);
+        true
+    }
+}
+
+// Merges the `products` of several bazaar-format bodies, in endpoint order; a product id already
+// seen in an earlier body is skipped, matching how `apply_snapshot` treats duplicates
+fn merge_products(bodies: Vec<ApiResponse>) -> BTreeMap<String, Shaped<ShapedProduct>> {
+    let mut merged = BTreeMap::new();
+    for body in bodies {
+        for (pid, prod) in body.products {
+            merged.entry(pid).or_insert(prod);
+        }
+    }
+    merged
+}
+
+// Fetches every endpoint concurrently and merges the ones that changed. Unchanged endpoints add
+// nothing, and a failing one is logged and skipped unless all of them fail. `last_modified` is
+// the newest among the merged responses.
+async fn fetch_snapshot(client: &reqwest::Client, clock: &MonotonicClock, endpoints: &mut [Endpoint]) -> Result<Option<Snapshot>, Box<dyn Error>> {
+    let captured_at = clock.now();
+    let fetches = endpoints.iter_mut().map(|endpoint| async move {
+        let fetched = fetch_raw_snapshot(client, endpoint).await;
+        (endpoint.url.as_str(), fetched)
+    });
+    let mut raws = Vec::new();
+    let mut errors = Vec::new();
+    let mut unrecoverable = 0;
+    for (url, fetched) in futures::future::join_all(fetches).await {
+        match fetched {
+            Ok(Some(raw)) => raws.push(raw),
+            Ok(None) => {}
+            Err(e) => {
+                unrecoverable += usize::from(is_unrecoverable_fetch(e.as_ref()));
+                errors.push(format!("{}: {}", url, e));
+            }
+        }
+    }
+    if unrecoverable == endpoints.len() {
+        return Err(ExitError::fetch(format!("every endpoint rejected the request: {}", errors.join("; "))).into());
+    }
+    if errors.len() == endpoints.len() {
+        return Err(errors.join("; ").into());
+    }
+    for error in &errors {
+        eprintln!("[GiantWizard] Fetch error, skipping endpoint this tick: {}", error);
+    }
+    if raws.is_empty() {
+        return Ok(None);
+    }
+
+    let bodies = raws.iter().map(|raw| serde_json::from_slice(&raw.body)).collect::<Result<Vec<ApiResponse>, _>>()?;
+    Ok(Some(Snapshot {
+        captured_at,
+        last_modified: raws.iter().filter_map(|raw| raw.last_modified.as_deref().and_then(parse_http_date)).max(),
+        polls_skipped: 0,
+        products: parse_bazaar_products(merge_products(bodies)),
+    }))
+}
+
+// A client error that retrying won't fix; 408 and 429 are the server asking us to come back later
+fn is_unrecoverable_fetch(error: &(dyn Error + 'static)) -> bool {
+    error.downcast_ref::<reqwest::Error>().and_then(reqwest::Error::status)
+        .is_some_and(|status| status.is_client_error() && status != reqwest::StatusCode::REQUEST_TIMEOUT && status != reqwest::StatusCode::TOO_MANY_REQUESTS)
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+// Pearson correlation of paired samples; None with fewer than two, or when either side is constant
+fn pearson(pairs: &[(f64, f64)]) -> Option<f64> {
+    if pairs.len() < 2 {
+        return None;
+    }
+    let n = pairs.len() as f64;
+    let (mean_x, mean_y) = (pairs.iter().map(|p| p.0).sum::<f64>() / n, pairs.iter().map(|p| p.1).sum::<f64>() / n);
+    let covariance: f64 = pairs.iter().map(|(x, y)| (x - mean_x) * (y - mean_y)).sum();
+    let spread_x: f64 = pairs.iter().map(|(x, _)| (x - mean_x).powi(2)).sum();
+    let spread_y: f64 = pairs.iter().map(|(_, y)| (y - mean_y).powi(2)).sum();
+    (spread_x > 0.0 && spread_y > 0.0).then(|| covariance / (spread_x * spread_y).sqrt())
+}
+
+// Unix seconds for the sequences fed to the detectors: the wall clock read once at start plus
+// monotonic time since, so an NTP step backward can't make a later snapshot look older. File
+// names and logs keep using the wall clock.
+#[derive(Debug, Clone, Copy)]
+struct MonotonicClock {
+    origin_unix: u64,
+    origin: Instant,
+}
+
+impl MonotonicClock {
+    fn start() -> Self {
+        Self { origin_unix: unix_now(), origin: Instant::now() }
+    }
+
+    fn now(&self) -> u64 {
+        self.at(Instant::now())
+    }
+
+    fn at(&self, instant: Instant) -> u64 {
+        self.origin_unix + instant.saturating_duration_since(self.origin).as_secs()
+    }
+}
+
+#[derive(Debug, Default, PartialEq)]
+pub struct ApplyOutcome {
+    // Products that already hold newer data than this snapshot
+    pub out_of_order: usize,
+    // Repeated product ids within the batch, dropped in favour of the first occurrence
+    pub duplicates: usize,
+    // Products whose quick_status lacked buyVolume or sellVolume; still applied
+    pub missing_volume: usize,
+}
+
+// Folds one snapshot into the per-product states, starting state for newly seen products
+pub fn apply_snapshot(states: &mut HashMap<String, ProductMetricsState>, snapshot: Vec<BazaarInfo>, timestamp: u64, source_time: Option<u64>) -> ApplyOutcome {
+    apply_snapshot_with(states, snapshot, timestamp, source_time, 0, AnalysisConfig::default().counter_reset_fraction)
+}
+
+// Also keeps each accepted snapshot in its product's ring of the last `recent_snapshots`, and
+// applies the configured `counter_reset_fraction` rather than the default
+pub fn apply_snapshot_with(
+    states: &mut HashMap<String, ProductMetricsState>,
+    snapshot: Vec<BazaarInfo>,
+    timestamp: u64,
+    source_time: Option<u64>,
+    recent_snapshots: usize,
+    counter_reset_fraction: f64,
+) -> ApplyOutcome {
+    let mut outcome = ApplyOutcome::default();
+    let mut seen = HashSet::new();
+    for info in snapshot {
+        if !seen.insert(info.product_id.clone()) {
+            outcome.duplicates += 1;
+            continue;
+        }
+        if info.buy_volume.is_none() || info.sell_volume.is_none() {
+            outcome.missing_volume += 1;
+        }
+        match states.entry(info.product_id.clone()) {
+            Entry::Occupied(mut entry) => {
+                if entry.get_mut().offer_at(&info, timestamp, source_time, counter_reset_fraction) {
+                    entry.get_mut().remember(info, timestamp, recent_snapshots);
+                } else {
+                    outcome.out_of_order += 1;
+                }
+            }
+            Entry::Vacant(entry) => {
+                let mut state = ProductMetricsState::new_at(&info, timestamp);
+                state.last_source_time = source_time;
+                state.remember(info, timestamp, recent_snapshots);
+                entry.insert(state);
+            }
+        }
+    }
+    outcome
+}
+
+// Products still warming up (fewer than `min_observations_before_export` windows) are left out
+pub fn finalize_all(states: &HashMap<String, ProductMetricsState>, analysis: &AnalysisConfig) -> Vec<AnalysisResult> {
+    finalize_cycle(states, analysis, false).0
+}
+
+// A product whose finalize panicked. It is left out of the cycle's results and listed with the
+// export (see export.rs); `state` is its state at the time, written next to the exports by the
+// processor (`dump_path`). Finalize is deterministic, so it isn't retried within the cycle; the
+// product keeps collecting and is tried again at the next emit.
+#[derive(Debug, Clone, Serialize)]
+pub struct DeadLetter {
+    pub product_id: String,
+    pub error: String,
+    #[serde(skip)]
+    state: String,
+    pub dump_path: Option<String>,
+}
+
+// How one product is finalized: `ProductMetricsState::finalize_with_sequences`, except in tests
+type FinalizeFn = fn(&ProductMetricsState, String, &AnalysisConfig) -> AnalysisResult;
+
+fn finalize_guarded(product_id: &str, state: &ProductMetricsState, analysis: &AnalysisConfig, finalize: FinalizeFn) -> Result<AnalysisResult, DeadLetter> {
+    std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| finalize(state, product_id.to_string(), analysis)))
+        .map_err(|payload| {
+            let error = payload.downcast_ref::<&str>().map(|s| s.to_string())
+                .or_else(|| payload.downcast_ref::<String>().cloned())
+                .unwrap_or_else(|| "finalize panicked".to_string());
+            eprintln!("[GiantWizard] ☠️ Finalize failed for {}, leaving it out of the cycle: {}", product_id, error);
+            DeadLetter { product_id: product_id.to_string(), error, state: format!("{:#?}", state), dump_path: None }
+        })
+}
+
+// Writes each dead letter's state to `<dir>/dead_letters/<product_id>_<stamp>.txt`. `dumped` holds
+// the dumps already written for the cycle being exported (product id to path), so retrying the
+// cycle after a DiskFull export points at the first dump instead of writing another one.
+fn dump_dead_letters(dead_letters: &mut [DeadLetter], dir: &std::path::Path, stamp: &str, dumped: &mut HashMap<String, String>) {
+    for letter in dead_letters {
+        if let Some(path) = dumped.get(&letter.product_id) {
+            letter.dump_path = Some(path.clone());
+            continue;
+        }
+        let path = dir.join("dead_letters").join(format!("{}_{}.txt", letter.product_id.replace(['/', ':'], "_"), stamp));
+        let written = fs::create_dir_all(dir.join("dead_letters"))
+            .and_then(|()| atomic::write_atomic(&path, format!("{}\n\n{}\n", letter.error, letter.state).as_bytes()));
+        match written {
+            Ok(()) => {
+                letter.dump_path = Some(path.display().to_string());
+                dumped.insert(letter.product_id.clone(), path.display().to_string());
+            }
+            Err(e) => eprintln!("[GiantWizard] ❌ Could not dump the state of {}: {}", letter.product_id, e),
+        }
+    }
+}
+
+// Products listed in a `FinalizeProfile`, slowest first
+const PROFILE_SLOWEST: usize = 10;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ProductTiming {
+    pub product_id: String,
+    pub ms: f64,
+}
+
+// PROFILE: wall time of one cycle's finalize step. `total_ms` also covers sorting and the
+// reference-price pass, so it is a little more than the sum of the per-product timings.
+#[derive(Debug, Clone, Serialize)]
+pub struct FinalizeProfile {
+    pub products: usize,
+    pub total_ms: f64,
+    pub slowest: Vec<ProductTiming>,
+}
+
+impl FinalizeProfile {
+    fn of(mut timings: Vec<(String, Duration)>, total: Duration) -> Self {
+        timings.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        let ms = |d: Duration| d.as_secs_f64() * 1000.0;
+        Self {
+            products: timings.len(),
+            total_ms: ms(total),
+            slowest: timings.into_iter().take(PROFILE_SLOWEST)
+                .map(|(product_id, elapsed)| ProductTiming { product_id, ms: ms(elapsed) })
+                .collect(),
+        }
+    }
+}
+
+// `finalize_all`, additionally timing every product when `profile` is set and returning the
+// products whose finalize failed
+fn finalize_cycle(states: &HashMap<String, ProductMetricsState>, analysis: &AnalysisConfig, profile: bool)
+    -> (Vec<AnalysisResult>, Option<FinalizeProfile>, Vec<DeadLetter>) {
+    finalize_cycle_with(states, analysis, profile, ProductMetricsState::finalize_with_sequences)
+}
+
+fn finalize_cycle_with(states: &HashMap<String, ProductMetricsState>, analysis: &AnalysisConfig, profile: bool, finalize: FinalizeFn)
+    -> (Vec<AnalysisResult>, Option<FinalizeProfile>, Vec<DeadLetter>) {
+    let started = Instant::now();
+    let mut timings = Vec::new();
+    let mut dead_letters = Vec::new();
+    let mut results: Vec<AnalysisResult> = states.iter()
+        .filter(|(_, state)| state.windows_processed >= analysis.min_observations_before_export)
+        .filter_map(|(pid, state)| {
+            let start = Instant::now();
+            let result = finalize_guarded(pid, state, analysis, finalize).map_err(|letter| dead_letters.push(letter)).ok();
+            if profile {
+                timings.push((pid.clone(), start.elapsed()));
+            }
+            result
+        })
+        .collect();
+    results.sort_by(|a, b| a.product_id.cmp(&b.product_id));
+    dead_letters.sort_by(|a, b| a.product_id.cmp(&b.product_id));
+    apply_reference_prices(&mut results, states, analysis);
+    (results, profile.then(|| FinalizeProfile::of(timings, started.elapsed())), dead_letters)
+}
+
+// Products handed to one blocking task by `finalize_pooled`
+const FINALIZE_CHUNK: usize = 64;
+const FINALIZE_PROGRESS_INTERVAL: Duration = Duration::from_secs(2);
+
+// `finalize_cycle` on the blocking pool, at most `concurrency` chunks at a time, so the runtime's
+// workers stay free for the HTTP API and fetching. Each chunk's states are copied out under the
+// lock and finalized without it; the processor is the only writer and waits for this, so the
+// copies can't go stale. Results match `finalize_cycle` exactly.
+async fn finalize_pooled(shared_states: &http::SharedStates, analysis: &AnalysisConfig, profile: bool, concurrency: usize, finalize: FinalizeFn)
+    -> Result<(Vec<AnalysisResult>, Option<FinalizeProfile>, Vec<DeadLetter>), String> {
+    let started = Instant::now();
+    let lock = || shared_states.lock().map_err(|_| "state lock poisoned".to_string());
+    let product_ids: Vec<String> = lock()?.iter()
+        .filter(|(_, state)| state.windows_processed >= analysis.min_observations_before_export)
+        .map(|(pid, _)| pid.clone())
+        .collect();
+    let total = product_ids.len();
+    let semaphore = Arc::new(tokio::sync::Semaphore::new(concurrency.max(1)));
+    let finalized = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+    let shared_analysis = Arc::new(analysis.clone());
+    let mut last_log = Instant::now();
+    let mut log_progress = |force: bool| {
+        if force || last_log.elapsed() >= FINALIZE_PROGRESS_INTERVAL {
+            println!("[GiantWizard] Finalized {}/{} products", finalized.load(std::sync::atomic::Ordering::Relaxed), total);
+            last_log = Instant::now();
+        }
+    };
+
+    let mut tasks = Vec::new();
+    for chunk in product_ids.chunks(FINALIZE_CHUNK) {
+        let permit = semaphore.clone().acquire_owned().await.map_err(|e| e.to_string())?;
+        log_progress(false);
+        let states: Vec<(String, ProductMetricsState)> = {
+            let guard = lock()?;
+            chunk.iter().filter_map(|pid| guard.get(pid).map(|state| (pid.clone(), state.clone()))).collect()
+        };
+        let (analysis, finalized) = (shared_analysis.clone(), finalized.clone());
+        tasks.push(tokio::task::spawn_blocking(move || {
+            let _permit = permit;
+            states.into_iter()
+                .map(|(pid, state)| {
+                    let start = Instant::now();
+                    let result = finalize_guarded(&pid, &state, &analysis, finalize);
+                    finalized.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+                    (result, (pid, start.elapsed()))
+                })
+                .collect::<Vec<_>>()
+        }));
+    }
+    let mut results = Vec::with_capacity(total);
+    let mut timings = Vec::new();
+    let mut dead_letters = Vec::new();
+    for task in tasks {
+        let chunk = task.await.map_err(|e| format!("finalize task failed: {}", e))?;
+        log_progress(false);
+        for (result, timing) in chunk {
+            match result {
+                Ok(result) => results.push(result),
+                Err(letter) => dead_letters.push(letter),
+            }
+            timings.push(timing);
+        }
+    }
+    if total > FINALIZE_CHUNK {
+        log_progress(true);
+    }
+
+    results.sort_by(|a, b| a.product_id.cmp(&b.product_id));
+    dead_letters.sort_by(|a, b| a.product_id.cmp(&b.product_id));
+    apply_reference_prices(&mut results, &*lock()?, analysis);
+    Ok((results, profile.then(|| FinalizeProfile::of(timings, started.elapsed())), dead_letters))
+}
+
+// Fixed mode starts over after each export, unless the disk was full: the state then keeps
+// accumulating windows and the next snapshot retries the export over all of them
+fn end_cycle(states: &mut HashMap<String, ProductMetricsState>, status: ExportStatus) -> bool {
+    let reset = !matches!(status, ExportStatus::DiskFull | ExportStatus::Abandoned);
+    if reset {
+        states.clear();
+    }
+    reset
+}
+
+fn warming_up_count(states: &HashMap<String, ProductMetricsState>, analysis: &AnalysisConfig) -> usize {
+    states.values().filter(|state| state.windows_processed < analysis.min_observations_before_export).count()
+}
+
+// Fills the *_price_relative fields against `analysis.reference_product`'s cycle averages. They
+// stay None without a reference, when it isn't tracked this cycle, or when its price is zero.
+fn apply_reference_prices(results: &mut [AnalysisResult], states: &HashMap<String, ProductMetricsState>, analysis: &AnalysisConfig) {
+    let Some(reference) = analysis.reference_product.as_ref().and_then(|id| states.get(id)) else {
+        return;
+    };
+    let (reference_instabuy, reference_instasell) = reference.price_averages();
+    let relative = |price: f64, reference: f64| (reference > 0.0).then(|| price / reference);
+    for result in results {
+        result.instabuy_price_relative = relative(result.instabuy_price_average, reference_instabuy);
+        result.instasell_price_relative = relative(result.instasell_price_average, reference_instasell);
+    }
+}
+
+fn to_json<T: Serialize>(value: &T, pretty: bool) -> serde_json::Result<String> {
+    if pretty { serde_json::to_string_pretty(value) } else { serde_json::to_string(value) }
+}
+
+fn arg_value(args: &[String], flag: &str) -> Option<String> {
+    args.iter().position(|a| a == flag).and_then(|i| args.get(i + 1)).cloned()
+}
+
+fn build_runtime(runtime: &RuntimeConfig) -> std::io::Result<tokio::runtime::Runtime> {
+    let mut builder = match runtime.flavor {
+        RuntimeFlavor::CurrentThread => tokio::runtime::Builder::new_current_thread(),
+        RuntimeFlavor::MultiThread => {
+            let mut builder = tokio::runtime::Builder::new_multi_thread();
+            if let Some(workers) = runtime.worker_threads {
+                builder.worker_threads(workers);
+            }
+            builder
+        }
+    };
+    builder.enable_all().build()
+}
+
+// Exits with the code `exit::exit_code` assigns to whatever error ends the run
+pub fn run_cli() -> ExitCode {
+    let outcome = load_config()
+        .and_then(|config| Ok((build_runtime(&config.runtime)?, config)))
+        .and_then(|(runtime, config)| runtime.block_on(run(config)));
+    match outcome {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("[GiantWizard] ❌ {}", e);
+            ExitCode::from(exit::exit_code(e.as_ref()))
+        }
+    }
+}
+
+fn load_config() -> Result<Config, Box<dyn Error>> {
+    let mut config = Config::from_env();
+    if let Some(path) = &config.detector_overrides_path {
+        config.analysis.detector.overrides = DetectorConfig::load_overrides(path).map_err(ExitError::config)?;
+    }
+    if let Some(path) = &config.product_aliases_path {
+        config.aliases = alias::ProductAliases::load(path).map_err(ExitError::config)?;
+    }
+    check_config(&config)?;
+    Ok(config)
+}
+
+fn check_config(config: &Config) -> Result<(), Box<dyn Error>> {
+    if !config.invalid.is_empty() {
+        let settings = if config.invalid.len() == 1 { "setting" } else { "settings" };
+        return Err(ExitError::config(format!("Invalid {} {}", settings, config.invalid.join("; "))).into());
+    }
+    config.analysis.detector.validate().map_err(ExitError::config)?;
+    config.stack_sizes.validate().map_err(ExitError::config)?;
+    formatter::build(&config.output_formats).map_err(ExitError::config)?;
+    Ok(())
+}
+
+async fn run(config: Config) -> Result<(), Box<dyn Error>> {
+    let args: Vec<String> = std::env::args().collect();
+    if let Some(dir) = arg_value(&args, "--capture") {
+        return capture::run(dir.into(), &config, &build_http_client(&config)?).await;
+    }
+    if args.iter().any(|arg| arg == "--list-products") {
+        let sort_by = arg_value(&args, "--sort-by").map(|s| s.parse::<list::SortBy>().map_err(ExitError::config))
+            .transpose()?.unwrap_or(list::SortBy::Name);
+        let limit = arg_value(&args, "--limit").map(|n| n.parse::<usize>().map_err(|_| ExitError::config(format!("Invalid --limit: {}", n))))
+            .transpose()?;
+        let format = arg_value(&args, "--format").map(|f| f.parse::<list::ListFormat>().map_err(ExitError::config))
+            .transpose()?.unwrap_or(list::ListFormat::Ids);
+
+        let mut endpoints: Vec<Endpoint> = config.fetch_endpoints.iter().cloned().map(Endpoint::new).collect();
+        let snapshot = fetch_snapshot(&build_http_client(&config)?, &MonotonicClock::start(), &mut endpoints).await?
+            .ok_or("the API returned no snapshot")?;
+        let rows = list::rows(&snapshot.products, sort_by, limit);
+        print!("{}", list::render(&rows, format)?);
+        eprintln!("[GiantWizard] Listed {} of {} products", rows.len(), snapshot.products.len());
+        return Ok(());
+    }
+    if let Some(dir) = arg_value(&args, "--dump-fixtures") {
+        let mut synthetic_config: synthetic::SyntheticConfig = match arg_value(&args, "--config") {
+            Some(path) => serde_json::from_slice(&fs::read(path)?)?,
+            None => synthetic::SyntheticConfig::default(),
+        };
+        if let Some(seed) = config.seed {
+            synthetic_config.seed = seed;
+        }
+        let written = synthetic::dump_fixtures(dir.as_ref(), &synthetic_config)?;
+        eprintln!("[GiantWizard] Wrote {} synthetic snapshots for {} products to {} (seed {})",
+            written, synthetic_config.products.len(), dir, synthetic_config.seed);
+        return Ok(());
+    }
+    if let Some(input) = arg_value(&args, "--recompute") {
+        let mut export: Value = serde_json::from_slice(&fs::read(&input)?)?;
+        let summary = recompute::recompute_export(&mut export, &config.analysis.detector)?;
+        let count = export.as_array().map_or(0, Vec::len);
+        let json = to_json(&export, config.output_format.is_pretty(count, config.pretty_max_results))?;
+        match arg_value(&args, "--out") {
+            Some(out) => atomic::write_atomic(out.as_ref(), json.as_bytes())?,
+            None => println!("{}", json),
+        }
+        eprintln!("[GiantWizard] Recomputed patterns for {} products ({} without delta sequences left as-is)",
+            summary.recomputed, summary.skipped);
+        return Ok(());
+    }
+    if let Some(product_id) = arg_value(&args, "--explain") {
+        let path = arg_value(&args, "--export").ok_or_else(|| ExitError::config("--explain needs --export <metrics.json>"))?;
+        let export: Value = serde_json::from_slice(&fs::read(&path)?)?;
+        let explanation = explain::explain(&export, &product_id, &config.analysis.detector)?;
+        match arg_value(&args, "--format").as_deref() {
+            None | Some("text") => print!("{}", explanation.render()),
+            Some("json") => println!("{}", serde_json::to_string_pretty(&explanation)?),
+            Some(other) => return Err(ExitError::config(format!("Unknown --format: {}", other)).into()),
+        }
+        return Ok(());
+    }
+    if let Some(path) = arg_value(&args, "--verify") {
+        let bytes = fs::read(&path)?;
+        let mut problems: Vec<String> = checksum::check(path.as_ref(), &bytes).err().into_iter().collect();
+        problems.extend(verify::verify(&bytes));
+        if !problems.is_empty() {
+            for problem in &problems {
+                eprintln!("[GiantWizard] {}", problem);
+            }
+            return Err(format!("{} failed verification with {} problem(s)", path, problems.len()).into());
+        }
+        println!("[GiantWizard] {} verified", path);
+        return Ok(());
+    }
+    if args.iter().any(|arg| arg == "--self-test") {
+        let report = selftest::self_test()?;
+        if !report.problems.is_empty() {
+            for problem in &report.problems {
+                eprintln!("[GiantWizard] {}", problem);
+            }
+            return Err(format!("Self-test failed with {} problem(s)", report.problems.len()).into());
+        }
+        println!("[GiantWizard] Self-test passed: {} synthetic snapshots, {} products in {} ms",
+            report.snapshots, report.products, report.elapsed.as_millis());
+        return Ok(());
+    }
+    if let Some(dir) = arg_value(&args, "--replay") {
+        let speed = match arg_value(&args, "--speed") {
+            Some(speed) => speed.parse::<f64>().map_err(|_| ExitError::config(format!("Invalid --speed: {}", speed)))?,
+            None => 0.0,
+        };
+        let states = replay::replay_dir(dir.as_ref(), speed, &config.analysis).await?;
+        let results = finalize_all(&states, &config.analysis);
+        let json = to_json(&results, config.output_format.is_pretty(results.len(), config.pretty_max_results))?;
+        match arg_value(&args, "--out") {
+            Some(out) => atomic::write_atomic(out.as_ref(), json.as_bytes())?,
+            None => println!("{}", json),
+        }
+        return Ok(());
+    }
+    if let Some(path) = arg_value(&args, "--correlate") {
+        let defaults = correlate::CorrelateOptions::default();
+        let options = correlate::CorrelateOptions {
+            threshold: arg_value(&args, "--threshold").map(|t| t.parse::<f64>().map_err(|_| ExitError::config(format!("Invalid --threshold: {}", t))))
+                .transpose()?.unwrap_or(defaults.threshold),
+            max_products: arg_value(&args, "--max-products").map(|n| n.parse::<usize>().map_err(|_| ExitError::config(format!("Invalid --max-products: {}", n))))
+                .transpose()?.unwrap_or(defaults.max_products),
+            top: arg_value(&args, "--top").map(|n| n.parse::<usize>().map_err(|_| ExitError::config(format!("Invalid --top: {}", n))))
+                .transpose()?.unwrap_or(defaults.top),
+        };
+        let export: Value = serde_json::from_slice(&fs::read(&path)?)?;
+        let report = correlate::correlate(&export, options)?;
+        let json = serde_json::to_string_pretty(&report)?;
+        match arg_value(&args, "--out") {
+            Some(out) => atomic::write_atomic(out.as_ref(), json.as_bytes())?,
+            None => println!("{}", json),
+        }
+        eprintln!("[GiantWizard] {} correlated pairs among {} products ({} skipped)",
+            report.pairs.len(), report.products_compared, report.products_skipped);
+        return Ok(());
+    }
+    if let Some(dir) = arg_value(&args, "--calibrate") {
+        let truth_path = arg_value(&args, "--truth").ok_or_else(|| ExitError::config("--calibrate needs --truth <labels.json>"))?;
+        let truth = calibrate::load_truth(&fs::read(&truth_path)?)?;
+        let bins = arg_value(&args, "--bins").map(|b| b.parse::<usize>().map_err(|_| ExitError::config(format!("Invalid --bins: {}", b))))
+            .transpose()?.unwrap_or(10);
+        let tolerance = arg_value(&args, "--tolerance").map(|t| t.parse::<f64>().map_err(|_| ExitError::config(format!("Invalid --tolerance: {}", t))))
+            .transpose()?.unwrap_or(0.1);
+
+        let states = replay::replay_dir(dir.as_ref(), 0.0, &config.analysis).await?;
+        let results = finalize_all(&states, &config.analysis);
+        let report = calibrate::calibration_curve(&calibrate::score_results(&results, &truth, tolerance), bins);
+        let output = match arg_value(&args, "--format").as_deref() {
+            Some("csv") => calibrate::to_csv(&report),
+            None | Some("json") => serde_json::to_string_pretty(&report)?,
+            Some(other) => return Err(ExitError::config(format!("Unknown --format: {}", other)).into()),
+        };
+        match arg_value(&args, "--out") {
+            Some(out) => atomic::write_atomic(out.as_ref(), output.as_bytes())?,
+            None => println!("{}", output),
+        }
+        eprintln!("[GiantWizard] Calibrated {} labelled products of {}", report.products_scored, results.len());
+        return Ok(());
+    }
+
+    if let Some(path) = arg_value(&args, "--serve-fixture") {
+        let addr = config.http_addr.clone().ok_or_else(|| ExitError::config("--serve-fixture needs HTTP_ADDR"))?;
+        let parse = |flag: &str| arg_value(&args, flag)
+            .map(|v| v.parse::<f64>().ok().filter(|v| *v >= 0.0).ok_or_else(|| ExitError::config(format!("Invalid {}: {}", flag, v))))
+            .transpose();
+        let interval = parse("--fixture-interval")?;
+        let options = fixture::FixtureOptions {
+            looping: args.iter().any(|arg| arg == "--fixture-loop"),
+            jitter: parse("--fixture-jitter")?.unwrap_or(0.0),
+            seed: config.seed,
+        };
+        let fixture = Arc::new(Mutex::new(fixture::Fixture::load(path.as_ref(), options)?));
+        let frames = fixture.lock().map_or(0, |fixture| fixture.frames());
+        println!("[GiantWizard] Serving {} fixture frame(s) from {}", frames, path);
+        if let Some(secs) = interval.filter(|secs| *secs > 0.0) {
+            tokio::spawn(fixture::animate(fixture.clone(), Duration::from_secs_f64(secs)));
+        }
+        let app = http::AppState {
+            states: Arc::new(Mutex::new(HashMap::new())),
+            max_products: config.export_query_max_products,
+            analysis: config.analysis.clone(),
+            last_export: Arc::new(Mutex::new(None)),
+            cadence: cadence::SharedCadence::default(),
+            exporter: Arc::new(Exporter::from_config(&config, Path::new("metrics")).map_err(ExitError::config)?),
+            ready_max_pending: config.ready_max_pending_exports,
+            effective_config: config.debug_endpoints.then(|| Arc::new(config.redacted_json())),
+            debug_endpoints: config.debug_endpoints,
+            fixture: Some(fixture),
+        };
+        tokio::select! {
+            served = http::serve(addr, app) => served?,
+            _ = tokio::signal::ctrl_c() => {}
+        }
+        return Ok(());
+    }
+
+    fs::create_dir_all("metrics").map_err(|e| ExitError::disk(format!("could not create the metrics dir: {}", e)))?;
+    let client = build_http_client(&config)?;
+    let shared_states: http::SharedStates = Arc::new(Mutex::new(HashMap::new()));
+    let last_export: export::SharedExportReport = Arc::new(Mutex::new(None));
+    let exporter = Arc::new(Exporter::from_config(&config, Path::new("metrics")).map_err(ExitError::config)?);
+    exporter.validate_engine().map_err(ExitError::config)?;
+    // Catch up on pushes left pending by an outage before the first cycle completes
+    let drain = exporter.clone();
+    let drained = tokio::task::spawn_blocking(move || drain.retry_pending()).await?;
+    if drained.uploaded > 0 || drained.failed > 0 {
+        println!("[GiantWizard] Pending remote pushes: {} uploaded ({} bytes), {} failed",
+            drained.uploaded, drained.bytes, drained.failed);
+    }
+    if let Some(reason) = drained.stopped.filter(|reason| export::is_auth_error(reason)) {
+        return Err(ExitError::auth(reason).into());
+    }
+    let api_poll_interval_secs = config.api_poll_interval_secs;
+
+    let target_windows = config.analysis.target_windows;
+
+    println!("[GiantWizard] Configuration: Target windows = {} ({} minutes), polling every {} seconds.",
+        target_windows, target_windows as u64 * api_poll_interval_secs / 60, api_poll_interval_secs);
+    let (poll_interval, auto_poll_interval) = match config.poll_fast_interval_secs {
+        Some(secs) => {
+            println!("[GiantWizard] Fast polling: every {:.1}s, committing a window only when the API has updated.", secs);
+            (Duration::from_secs_f64(secs), false)
+        }
+        None => (Duration::from_secs(api_poll_interval_secs), config.auto_poll_interval),
+    };
+    if let Some(emit_every) = config.sliding_emit_every {
+        println!("[GiantWizard] Sliding mode: emitting every {} windows over the trailing {}.", emit_every, target_windows);
+    }
+    if config.warmup_windows > 0 {
+        println!("[GiantWizard] Warmup: no export before {} windows have been observed.", config.warmup_windows);
+    }
+    if let Some(threshold) = config.delta_threshold {
+        println!("[GiantWizard] Delta exports: products changed by more than {:.1}%, full export every {} cycles.",
+            threshold * 100.0, config.delta_full_every);
+    }
+    println!("[GiantWizard] Fuzzy pattern detection: using start times for delta periods.");
+    println!("[GiantWizard] API requests identify as \"{}\"", config.user_agent);
+    println!("[GiantWizard] Scale analysis: Diagnostic only - volume estimates always use moving week totals as ground truth.");
+    if !config.product_filter.include.is_empty() || !config.product_filter.exclude.is_empty() {
+        println!("[GiantWizard] Product filter: include {:?}, exclude {:?}",
+            config.product_filter.include, config.product_filter.exclude);
+    }
+
+    let cadence = cadence::SharedCadence::default();
+    if let Some(addr) = config.http_addr.clone() {
+        let app = http::AppState {
+            states: shared_states.clone(),
+            max_products: config.export_query_max_products,
+            analysis: config.analysis.clone(),
+            last_export: last_export.clone(),
+            cadence: cadence.clone(),
+            exporter: exporter.clone(),
+            ready_max_pending: config.ready_max_pending_exports,
+            effective_config: config.debug_endpoints.then(|| Arc::new(config.redacted_json())),
+            debug_endpoints: config.debug_endpoints,
+            fixture: None,
+        };
+        tokio::spawn(async move {
+            if let Err(e) = http::serve(addr, app).await {
+                eprintln!("[GiantWizard] HTTP API error: {}", e);
+            }
+        });
+    }
+
+    // Fetching and processing are decoupled by a bounded queue: a slow export delays processing
+    // but not polling, and fetching waits once the queue is full
+    let (tx, rx) = mpsc::channel(config.snapshot_queue_capacity);
+    let processor = tokio::spawn(process_snapshots(rx, config.clone(), shared_states, exporter, last_export));
+    let fetched = tokio::select! {
+        fetched = fetch_snapshots(client, config.fetch_endpoints.clone(), poll_interval, auto_poll_interval, cadence, tx) => fetched,
+        _ = tokio::signal::ctrl_c() => {
+            println!("[GiantWizard] Shutdown requested; draining queued snapshots");
+            Ok(())
+        }
+    };
+    // The sender is gone once fetching stops, so the processor finishes what's queued and exits
+    processor.await??;
+    fetched
+}
+
+// Consecutive ticks on which every endpoint must reject the request before the daemon gives up, so
+// a brief 4xx from a CDN or a deploy doesn't end the run
+const MAX_REJECTED_TICKS: u32 = 5;
+
+// Returns when the processor has gone away, or with an error once fetching can't recover
+async fn fetch_snapshots(
+    client: reqwest::Client,
+    urls: Vec<String>,
+    poll_interval: Duration,
+    auto_poll_interval: bool,
+    cadence: cadence::SharedCadence,
+    tx: mpsc::Sender<Snapshot>,
+) -> Result<(), Box<dyn Error>> {
+    let mut endpoints: Vec<Endpoint> = urls.into_iter().map(Endpoint::new).collect();
+    let clock = MonotonicClock::start();
+    let mut interval = poll_interval;
+    let mut skips = cadence::PollSkips::default();
+    let mut rejected_ticks = 0;
+    loop {
+        println!("💓 heartbeat at Local: {}  UTC: {}", 
+            Local::now().format("%H:%M:%S"), 
+            Utc::now().format("%Y-%m-%d %H:%M:%S")
+        );
+
+        let fetched = fetch_snapshot(&client, &clock, &mut endpoints).await;
+        rejected_ticks = match &fetched {
+            Err(e) if e.is::<ExitError>() => rejected_ticks + 1,
+            _ => 0,
+        };
+        match fetched {
+            Ok(Some(mut snapshot)) => {
+                snapshot.polls_skipped = skips.commit();
+                if let (Some(updated), Ok(mut tracker)) = (snapshot.last_modified, cadence.lock()) {
+                    let before = tracker.cadence_secs();
+                    tracker.observe(updated);
+                    if let Some(learned) = tracker.cadence_secs().filter(|&c| Some(c) != before) {
+                        println!("[GiantWizard] API update cadence: {:.1}s over the last {} updates", learned, tracker.report().samples);
+                    }
+                    if auto_poll_interval {
+                        let tuned = tracker.poll_interval(poll_interval);
+                        if tuned != interval {
+                            println!("[GiantWizard] Poll interval tuned to {:.1}s", tuned.as_secs_f64());
+                            interval = tuned;
+                        }
+                    }
+                }
+                if tx.send(snapshot).await.is_err() {
+                    return Ok(());
+                }
+            }
+            Ok(None) => skips.skip(), // No new data
+            Err(e) if rejected_ticks >= MAX_REJECTED_TICKS => return Err(e),
+            Err(e) if rejected_ticks > 0 => eprintln!("[GiantWizard] ⚠️ Fetch rejected on {} of {} ticks before giving up: {}",
+                rejected_ticks, MAX_REJECTED_TICKS, e),
+            Err(e) => eprintln!("[GiantWizard] Fetch error: {}", e),
+        }
+
+        sleep(interval).await;
+    }
+}
+
+// Last-resort save of the in-progress cycle when the processor panics, written while unwinding to
+// `emergency_<ts>.json` next to the exports. Orderly shutdown drains the queue instead and never
+// triggers this. A panic mid-update may leave one product's state half-applied; it is kept anyway.
+// Nothing may panic out of `drop`, since a second panic while unwinding aborts the process: each
+// product is finalized behind `finalize_guarded`, and the flush as a whole behind catch_unwind.
+struct EmergencyFlush {
+    states: http::SharedStates,
+    analysis: AnalysisConfig,
+    dir: PathBuf,
+}
+
+impl Drop for EmergencyFlush {
+    fn drop(&mut self) {
+        if !std::thread::panicking() {
+            return;
+        }
+        if std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| self.flush())).is_err() {
+            eprintln!("[GiantWizard] ❌ Processor panicked and the emergency flush panicked too; nothing was saved");
+        }
+    }
+}
+
+impl EmergencyFlush {
+    fn flush(&self) {
+        let states = self.states.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        if states.is_empty() {
+            return;
+        }
+        let results = finalize_all(&states, &self.analysis);
+        let path = self.dir.join(format!("emergency_{}.json", Utc::now().format("%Y%m%d%H%M%S")));
+        let written = serde_json::to_vec(&results).map_err(|e| e.to_string())
+            .and_then(|json| atomic::write_atomic(&path, &json).map_err(|e| e.to_string()));
+        match written {
+            Ok(()) => eprintln!("[GiantWizard] 🚑 Processor panicked; saved {} products of the cycle to {}", results.len(), path.display()),
+            Err(e) => eprintln!("[GiantWizard] ❌ Processor panicked and the emergency flush failed: {}", e),
+        }
+    }
+}
+
+// Exports are named after the cycle's last snapshot rather than the moment they are written, so a
+// given input always produces the same names
+fn export_stamp(captured_at: u64) -> String {
+    chrono::DateTime::<Utc>::from_timestamp(captured_at as i64, 0).unwrap_or_default().format("%Y%m%d%H%M%S").to_string()
+}
+
+// How long a timed-out export gets to unwind after it is cancelled before it is left to finish in
+// the background
+const EXPORT_CANCEL_GRACE: Duration = Duration::from_secs(10);
+
+// Awaits a cycle's export task. Past `deadline` (EXPORT_TIMEOUT_SECONDS) the export is cancelled,
+// which fails its pushes and leaves its files pending; None if it still hasn't unwound within
+// EXPORT_CANCEL_GRACE, in which case it withdraws its cancellation itself once it does. Each
+// cancellation is counted, so one finishing late never lifts a newer one.
+async fn await_export<T: Send + 'static>(
+    mut task: tokio::task::JoinHandle<T>,
+    deadline: Option<tokio::time::Instant>,
+    exporter: Arc<Exporter>,
+) -> Result<Option<T>, String> {
+    let failed = |e: tokio::task::JoinError| format!("export task failed: {}", e);
+    let Some(deadline) = deadline else {
+        return task.await.map(Some).map_err(failed);
+    };
+    if let Ok(joined) = tokio::time::timeout_at(deadline, &mut task).await {
+        return joined.map(Some).map_err(failed);
+    }
+    eprintln!("[GiantWizard] ❌ Export exceeded EXPORT_TIMEOUT_SECONDS; cancelling it, its files stay pending for the next export");
+    exporter.cancelled.fetch_add(1, Ordering::Relaxed);
+    match tokio::time::timeout(EXPORT_CANCEL_GRACE, &mut task).await {
+        Ok(joined) => {
+            exporter.cancelled.fetch_sub(1, Ordering::Relaxed);
+            joined.map(Some).map_err(failed)
+        }
+        Err(_) => {
+            eprintln!("[GiantWizard] ❌ Cancelled export still running after {}s; resuming without it",
+                EXPORT_CANCEL_GRACE.as_secs());
+            tokio::spawn(async move {
+                let _ = task.await;
+                exporter.cancelled.fetch_sub(1, Ordering::Relaxed);
+                eprintln!("[GiantWizard] Cancelled export finished unwinding");
+            });
+            Ok(None)
+        }
+    }
+}
+
+async fn process_snapshots(
+    mut rx: mpsc::Receiver<Snapshot>,
+    config: Config,
+    shared_states: http::SharedStates,
+    exporter: Arc<Exporter>,
+    last_export: export::SharedExportReport,
+) -> Result<(), String> {
+    let target_windows = config.analysis.target_windows;
+    let mut windows_since_emit = 0;
+    let mut snapshots_since_start: usize = 0;
+    let _flush = EmergencyFlush { states: shared_states.clone(), analysis: config.analysis.clone(), dir: exporter.local_dir.clone() };
+    let mut baseline = config.baseline_path.as_ref().and_then(|path| {
+        baseline::BaselineStore::load(path.as_ref(), config.baseline_alpha)
+            .map_err(|e| eprintln!("[GiantWizard] ❌ Baseline store {} unusable, running without: {}", path, e))
+            .ok()
+    });
+    let mut delta = config.delta_threshold.and_then(|threshold| {
+        let path = exporter.local_dir.join("delta_base.json");
+        delta::DeltaTracker::load(&path, threshold, config.delta_full_every)
+            .map_err(|e| eprintln!("[GiantWizard] ❌ Delta base {} unusable, exporting every cycle in full: {}", path.display(), e))
+            .ok()
+    });
+    let mut resources = resources::ResourceTracker::default();
+    let mut poll_skips = cadence::SkipReport::default();
+    let nats = config.nats_url.as_ref().map(|url| stream::NatsSink {
+        url: url.clone(),
+        subject: config.nats_subject.clone(),
+        token: config.nats_token.clone(),
+        pending_dir: exporter.local_dir.join("stream_pending"),
+        timeout: Duration::from_secs(10),
+    });
+    let mut alerts = config.alert_webhook_url.as_ref().and_then(|url| {
+        build_http_client(&config)
+            .map(|client| alert::Notifier::new(url.clone(), config.alert_rules.cooldown_secs, client))
+            .map_err(|e| eprintln!("[GiantWizard] ❌ Alert webhook unusable, running without: {}", e))
+            .ok()
+    });
+    let mut consecutive_export_failures = 0;
+    let mut dead_letter_dumps = HashMap::new();
+    let layout = checkpoint::WindowLayout { target_windows, sliding: config.sliding_emit_every.is_some() };
+    if let Some(dir) = &config.checkpoint_dir {
+        match checkpoint::load(dir.as_ref(), layout, unix_now(), config.checkpoint_max_age_secs) {
+            Ok(Some(restored)) => {
+                let max_windows = restored.products.values().map(|s| s.windows_processed).max().unwrap_or(0);
+                println!("[GiantWizard] Resumed {} products at {}/{} windows from the checkpoint of {}",
+                    restored.products.len(), max_windows, target_windows, export_stamp(restored.saved_at));
+                // The restored windows count towards the warm-up
+                snapshots_since_start = max_windows + usize::from(!restored.products.is_empty());
+                windows_since_emit = restored.windows_since_emit;
+                *shared_states.lock().map_err(|_| "state lock poisoned")? = restored.products;
+            }
+            Ok(None) => {}
+            Err(e) => eprintln!("[GiantWizard] ❌ Checkpoint in {} unusable, starting a fresh cycle: {}", dir, e),
+        }
+    }
+
+    while let Some(snapshot) = rx.recv().await {
+        // The processor's only clock: live snapshots carry the real one, replays and tests their own
+        let captured_at = snapshot.captured_at;
+        poll_skips.add(snapshot.polls_skipped);
+        let mut cycle_reset = false;
+        let completed_cycle = {
+            let mut states = shared_states.lock().map_err(|_| "state lock poisoned")?;
+            let products = snapshot.products.into_iter()
+                .map(|mut info| {
+                    config.aliases.canonicalize(&mut info);
+                    info
+                })
+                .filter(|info| config.product_filter.matches(info))
+                .map(|mut info| {
+                    config.stack_sizes.normalize(&mut info);
+                    info
+                })
+                .collect();
+            let outcome = apply_snapshot_with(&mut states, products, snapshot.captured_at, snapshot.last_modified,
+                config.recent_snapshots, config.analysis.counter_reset_fraction);
+            if outcome.out_of_order > 0 {
+                eprintln!("[GiantWizard] Rejected out-of-order snapshot for {} products", outcome.out_of_order);
+            }
+            if outcome.duplicates > 0 {
+                eprintln!("[GiantWizard] Dropped {} duplicate product entries from the snapshot", outcome.duplicates);
+            }
+            if outcome.missing_volume > 0 {
+                eprintln!("[GiantWizard] {} products lacked quick_status buyVolume/sellVolume", outcome.missing_volume);
+            }
+            if config.sliding_emit_every.is_some() {
+                for state in states.values_mut() {
+                    state.trim_to(target_windows);
+                }
+                windows_since_emit += 1;
+            }
+            let max_windows = states.values().map(|s| s.windows_processed).max().unwrap_or(0);
+            println!("Updated {} products. Progress: {}/{} windows ({} snapshots queued)",
+                states.len(), max_windows, target_windows, rx.len());
+            snapshots_since_start += 1;
+            let windows_since_start = snapshots_since_start - 1;
+            let warm = windows_since_start >= config.warmup_windows;
+
+            match config.sliding_emit_every {
+                _ if !warm && max_windows >= target_windows => {
+                    println!("[GiantWizard] Warming up: {}/{} windows before the first export", windows_since_start, config.warmup_windows);
+                    None
+                }
+                Some(emit_every) if max_windows >= target_windows && windows_since_emit >= emit_every => {
+                    println!(">>> [GiantWizard] Sliding emit over the trailing {} windows", max_windows);
+                    windows_since_emit = 0;
+                    Some((warming_up_count(&states, &config.analysis), max_windows, false))
+                }
+                None if max_windows >= target_windows => {
+                    println!(">>> [GiantWizard] Hourly cycle complete: {} windows", max_windows);
+                    Some((warming_up_count(&states, &config.analysis), max_windows, true))
+                }
+                _ => None,
+            }
+        };
+        
+        if let Some((warming_up, windows, reset)) = completed_cycle {
+            // The timeout covers finalize too, though only the export can be cancelled
+            let deadline = config.export_timeout_secs.map(|secs| tokio::time::Instant::now() + Duration::from_secs(secs));
+            let (mut results, profile, mut dead_letters) = match config.finalize_concurrency {
+                Some(concurrency) => finalize_pooled(&shared_states, &config.analysis, config.profile, concurrency, ProductMetricsState::finalize_with_sequences).await?,
+                None => finalize_cycle(&*shared_states.lock().map_err(|_| "state lock poisoned")?, &config.analysis, config.profile),
+            };
+            let ts = export_stamp(captured_at);
+            dump_dead_letters(&mut dead_letters, &exporter.local_dir, &ts, &mut dead_letter_dumps);
+            config.aliases.apply(&mut results);
+            if let Some(store) = &baseline {
+                store.score(&mut results);
+            }
+            
+            let detection = export::DetectionSummary::of(&results);
+            let pretty = config.output_format.is_pretty(results.len(), config.pretty_max_results);
+            println!("[GiantWizard] Exporting {} products ({} JSON): {} fuzzy patterns, {} legacy patterns, {} skipped as inactive, {} warming up",
+                results.len(), if pretty { "pretty" } else { "compact" }, detection.fuzzy_detected, detection.legacy_detected,
+                detection.skipped_inactive, warming_up);
+            if let Some(profile) = &profile {
+                let slowest: Vec<String> = profile.slowest.iter().map(|t| format!("{} ({:.2} ms)", t.product_id, t.ms)).collect();
+                println!("[GiantWizard] Finalize took {:.1} ms for {} products; slowest: {}",
+                    profile.total_ms, profile.products, slowest.join(", "));
+            }
+            
+            let plan = delta.as_ref().and_then(|tracker| {
+                tracker.plan(&results, captured_at).map_err(|e| eprintln!("[GiantWizard] ❌ Delta export error, exporting in full: {}", e)).ok()
+            });
+            let task_exporter = exporter.clone();
+            let sqlite_path = config.sqlite_path.clone();
+            let sqlite_sequences = config.sqlite_sequences;
+            let stamp = ts.clone();
+            let results = Arc::new(results);
+            let task_results = results.clone();
+            let task = tokio::task::spawn_blocking(move || {
+                let (exporter, results) = (task_exporter, task_results);
+                let report = match plan.as_ref().and_then(|plan| plan.document.as_ref()) {
+                    Some(document) => {
+                        println!("[GiantWizard] Delta export: {} changed, {} unchanged, {} removed",
+                            document.changed.len(), document.unchanged.len(), document.removed.len());
+                        let formats = exporter.export_formats(&ts, &results);
+                        let per_product = exporter.export_per_product(&ts, &results);
+                        export::ExportReport { formats, per_product, ..exporter.export_delta(&ts, document, detection, pretty, profile, dead_letters) }
+                    }
+                    None => exporter.export(&ts, &results, pretty, profile, dead_letters),
+                };
+                let stored = sqlite_path.map(|path| {
+                    sqlite::SqliteExporter::open(path.as_ref(), sqlite_sequences)
+                        .and_then(|mut sink| sink.insert_cycle(captured_at, windows, &results))
+                        .map_err(|e| format!("{}: {}", path, e))
+                });
+                (report, stored, plan)
+            });
+            let (mut report, stored, plan) = match await_export(task, deadline, exporter.clone()).await? {
+                Some(finished) => finished,
+                None => (exporter.abandoned(&stamp, export::DetectionSummary::of(&results)), None, None),
+            };
+            match stored {
+                Some(Ok(rows)) => println!("[GiantWizard] Stored {} products in SQLite", rows),
+                Some(Err(e)) => eprintln!("[GiantWizard] ❌ SQLite export error: {}", e),
+                None => {}
+            }
+            if let Some(sink) = &nats {
+                let streamed = sink.publish_cycle(&stamp, &results).await;
+                match &streamed.error {
+                    None => println!("[GiantWizard] Published {} products to NATS ({} queued cycles replayed)",
+                        streamed.published, streamed.replayed),
+                    Some(e) => eprintln!("[GiantWizard] ⚠️ NATS publish failed, {} cycles queued locally: {}", streamed.pending, e),
+                }
+            }
+            match report.status {
+                ExportStatus::Both => println!("[GiantWizard] ✅ Exported to {} and {}", report.local_path, report.remote_path),
+                ExportStatus::LocalOnly => eprintln!("[GiantWizard] ⚠️ Exported to {} only ({}); remote push will be retried",
+                    report.local_path, report.error.as_deref().unwrap_or_default()),
+                ExportStatus::Failed => eprintln!("[GiantWizard] ❌ Export error: {}", report.error.as_deref().unwrap_or_default()),
+                ExportStatus::Abandoned => eprintln!("[GiantWizard] ❌ Export abandoned, keeping the cycle's state to retry: {}",
+                    report.error.as_deref().unwrap_or_default()),
+                ExportStatus::DiskFull => eprintln!("[GiantWizard] ❌ Disk full, pruned {} old exports; keeping the cycle's state to retry: {}",
+                    report.pruned, report.error.as_deref().unwrap_or_default()),
+            }
+            for format in report.formats.iter().filter(|format| format.error.is_some()) {
+                eprintln!("[GiantWizard] ⚠️ {} output {}: {}", format.format, format.file, format.error.as_deref().unwrap_or_default());
+            }
+            if let Some(per_product) = &report.per_product {
+                println!("[GiantWizard] Per-product files: {} written, {} pushed, {} pending, {} failed, {} pruned",
+                    per_product.written, per_product.pushed, per_product.pending, per_product.failed, per_product.pruned);
+                if let Some(e) = &per_product.error {
+                    eprintln!("[GiantWizard] ⚠️ Per-product export: {}", e);
+                }
+            }
+            if let (Some(tracker), Some(plan)) = (delta.as_mut(), plan) {
+                if matches!(report.status, ExportStatus::Both | ExportStatus::LocalOnly) {
+                    if let Err(e) = tracker.commit(plan) {
+                        eprintln!("[GiantWizard] ❌ Delta base save error: {}", e);
+                    }
+                }
+            }
+            if reset {
+                let mut states = shared_states.lock().map_err(|_| "state lock poisoned")?;
+                cycle_reset = end_cycle(&mut states, report.status);
+            }
+            // Unless the same cycle is about to be retried, in which case it is folded in then
+            if !reset || cycle_reset {
+                dead_letter_dumps.clear();
+                if let Some(store) = baseline.as_mut() {
+                    store.fold(&results);
+                    if let Err(e) = store.save() {
+                        eprintln!("[GiantWizard] ❌ Baseline save error: {}", e);
+                    }
+                }
+            }
+            consecutive_export_failures = match report.status {
+                ExportStatus::Failed | ExportStatus::DiskFull | ExportStatus::Abandoned => consecutive_export_failures + 1,
+                ExportStatus::Both | ExportStatus::LocalOnly => 0,
+            };
+            if let Some(notifier) = alerts.as_mut() {
+                let events = alert::evaluate(&config.alert_rules, &results, consecutive_export_failures);
+                match notifier.notify(events, captured_at).await {
+                    Ok(0) => {}
+                    Ok(sent) => println!("[GiantWizard] 🔔 Sent {} alerts to the webhook", sent),
+                    Err(e) => eprintln!("[GiantWizard] ⚠️ Alert webhook failed, will retry next cycle: {}", e),
+                }
+            }
+            if let Some(quota) = report.remote_quota {
+                let gb = |bytes: u64| bytes as f64 / 1e9;
+                let usage = format!("{:.2} of {:.2} GB ({:.0}%)", gb(quota.used_bytes), gb(quota.total_bytes), quota.used_fraction() * 100.0);
+                if quota.used_fraction() >= 0.9 {
+                    eprintln!("[GiantWizard] ⚠️ MEGA storage nearly full: {} used", usage);
+                } else {
+                    println!("[GiantWizard] MEGA storage: {} used", usage);
+                }
+            }
+            report.resources = resources.sample();
+            report.poll_skips = Some(std::mem::take(&mut poll_skips));
+            if let Some(skips) = report.poll_skips.as_ref().filter(|skips| skips.polls_skipped > 0) {
+                println!("[GiantWizard] Polling: {} unchanged polls skipped over {} windows ({:.1} per window, at most {})",
+                    skips.polls_skipped, skips.windows, skips.mean_per_window(), skips.max_per_window);
+            }
+            if let Some(usage) = &report.resources {
+                let mb = |bytes: u64| bytes as f64 / 1_048_576.0;
+                println!("[GiantWizard] Resources: {:.1} MB resident (peak {:.1} MB), {:.2}s CPU this cycle",
+                    mb(usage.rss_bytes), mb(usage.peak_rss_bytes), usage.cpu_secs);
+            }
+            if report.retry.uploaded > 0 || report.pending_remote > 0 {
+                println!("[GiantWizard] Remote retries: {} pushed ({} bytes), {} failed, {} still pending",
+                    report.retry.uploaded, report.retry.bytes, report.retry.failed, report.pending_remote);
+            }
+            if let Ok(mut last) = last_export.lock() {
+                *last = Some(report);
+            }
+        }
+
+        // Also right after a reset, so a restart doesn't resume, and export again, the cycle just exported
+        if let Some(dir) = config.checkpoint_dir.as_ref().filter(|_| cycle_reset || snapshots_since_start.is_multiple_of(config.checkpoint_every)) {
+            // Only the copy is taken under the lock; serializing and writing it happen off it
+            let products = shared_states.lock().map_err(|_| "state lock poisoned")?.clone();
+            let dir = dir.clone();
+            let saved = tokio::task::spawn_blocking(move || {
+                checkpoint::save(dir.as_ref(), layout, captured_at, windows_since_emit, &products).map_err(|e| e.to_string())
+            }).await.unwrap_or_else(|e| Err(e.to_string()));
+            if let Err(e) = saved {
+                eprintln!("[GiantWizard] ❌ Checkpoint save error: {}", e);
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Finalizes like production, except that this product panics, standing in for a detector bug
+    const PANICKING_PRODUCT: &str = "PANICKING_ITEM";
+
+    fn panicking_finalize(state: &ProductMetricsState, product_id: String, analysis: &AnalysisConfig) -> AnalysisResult {
+        if product_id == PANICKING_PRODUCT {
+            panic!("injected finalize failure");
+        }
+        state.finalize_with_sequences(product_id, analysis)
+    }
+
+    fn order(price_per_unit: f64, amount: i64, orders: i64) -> Order {
+        Order { amount, price_per_unit, orders }
+    }
+
+    fn snapshot(buy_orders: Vec<Order>, sell_orders: Vec<Order>, buy_moving_week: i64, sell_moving_week: i64) -> BazaarInfo {
+        BazaarInfo {
+            product_id: "TEST_ITEM".to_string(),
+            buy_price: 10.0,
+            sell_price: 9.0,
+            buy_orders,
+            sell_orders,
+            buy_moving_week,
+            sell_moving_week,
+            buy_volume: Some(5_000),
+            sell_volume: Some(4_000),
+            buy_summary_missing: false,
+            sell_summary_missing: false,
+            malformed_levels: 0,
+            invalid_fields: 0,
+        }
+    }
+
+    #[test]
+    fn volume_reconciliation_reports_inferred_share_of_moving_week() {
+        let first = snapshot(vec![order(10.0, 100, 2)], vec![order(9.0, 80, 1)], 1_000, 2_000);
+        let mut state = ProductMetricsState::new_at(&first, 1_700_000_000);
+        // 40 units consumed from the buy book while the counter moved 160; 20 sold vs 40
+        state.update_at(&snapshot(vec![order(10.0, 60, 2)], vec![order(9.0, 60, 1)], 1_160, 2_040), 1_700_000_020);
+
+        let result = state.finalize_with_sequences("TEST_ITEM".to_string(), &AnalysisConfig::default());
+        assert_eq!(result.instabuy_moving_week_volume, 160.0);
+        assert_eq!(result.instabuy_inferred_volume, 40.0);
+        assert_eq!(result.instabuy_inferred_coverage, Some(0.25));
+        assert_eq!(result.instasell_moving_week_volume, 40.0);
+        assert_eq!(result.instasell_inferred_volume, 20.0);
+        assert_eq!(result.instasell_inferred_coverage, Some(0.5));
+    }
+
+    #[test]
+    fn cycles_of_any_length_finalize_with_aligned_sequences() {
+        let info = |i: i64| snapshot(vec![order(10.0, 100_000 - 10 * i, 2)], vec![order(9.0, 100_000 - 10 * i, 2)], 1_000 + 10 * i, 2_000 + 10 * i);
+        for target_windows in [1, 90, 1_080] {
+            let analysis = AnalysisConfig { target_windows, ..Default::default() };
+            let mut state = ProductMetricsState::new_at(&info(0), 1_700_000_000);
+            for i in 1..=target_windows as i64 {
+                state.update_at(&info(i), 1_700_000_000 + 20 * i as u64);
+            }
+            let result = state.finalize_with_sequences("TEST_ITEM".to_string(), &analysis);
+            let sequences = result.delta_sequences.as_ref().unwrap();
+            assert_eq!(sequences.buy_moving_week.len(), target_windows);
+            sequences.check_alignment().unwrap();
+            assert_eq!(verify::verify(&serde_json::to_vec(&[&result]).unwrap()), Vec::<String>::new());
+        }
+    }
+
+    #[test]
+    fn moving_week_resets_are_recorded_as_no_movement() {
+        // The buy counter ages out by 1% a window, then restarts at window 6; the sell counter halves
+        // by 48% once, which only counts as a reset at a lower fraction
+        let buy = [100_000, 99_000, 98_010, 97_030, 96_060, 95_100, 2_000, 1_980, 1_960];
+        let sell = [50_000, 50_000, 50_000, 26_000, 26_000, 26_000, 26_000, 26_000, 26_000];
+        let feed = |fraction: f64| {
+            let mut state = ProductMetricsState::new_at(&snapshot(vec![], vec![], buy[0], sell[0]), 1_700_000_000);
+            for i in 1..buy.len() {
+                state.update_with(&snapshot(vec![], vec![], buy[i], sell[i]), 1_700_000_000 + 20 * i as u64, fraction);
+            }
+            state
+        };
+
+        let state = feed(0.5);
+        assert_eq!(Vec::from(state.buy_moving_week_deltas.clone()), [-1_000, -990, -980, -970, -960, 0, -20, -20]);
+        assert!(state.buy_moving_week_deltas.iter().all(|&d| d >= -1_000));
+        assert_eq!(state.sell_moving_week_deltas[2], -24_000);
+        let result = state.finalize_with_sequences("TEST_ITEM".to_string(), &AnalysisConfig::default());
+        assert_eq!((result.buy_counter_resets, result.sell_counter_resets), (1, 0));
+
+        let strict = feed(0.2);
+        assert_eq!((strict.sell_moving_week_deltas[2], strict.buy_counter_resets, strict.sell_counter_resets), (0, 1, 1));
+        // Sliding the reset window out takes it out of the count
+        let mut trimmed = strict.clone();
+        trimmed.trim_to(2);
+        assert_eq!((trimmed.buy_counter_resets, trimmed.sell_counter_resets), (0, 0));
+    }
+
+    #[test]
+    fn data_quality_scores_clean_data_high_and_injected_defects_low() {
+        // Every window the books lose 10 and both counters rise by 10
+        let clean = |i: i64| snapshot(vec![order(10.0, 1_000 - 10 * i, 2)], vec![order(9.0, 1_000 - 10 * i, 2)], 1_000 + 10 * i, 2_000 + 10 * i);
+        let analysis = AnalysisConfig { target_windows: 20, ..Default::default() };
+        let mut state = ProductMetricsState::new_at(&clean(0), 1_700_000_000);
+        for i in 1..=20 {
+            state.update_at(&clean(i), 1_700_000_000 + 20 * i as u64);
+        }
+        let result = state.finalize_with_sequences("TEST_ITEM".to_string(), &analysis);
+        assert!((result.data_quality - 1.0).abs() < 1e-12, "{}", result.data_quality);
+
+        // A quarter of the target, a book side missing from every other snapshot, malformed levels
+        // and a counter reset
+        let mut state = ProductMetricsState::new_at(&clean(0), 1_700_000_000);
+        for i in 1..=5 {
+            let mut info = clean(i);
+            info.buy_summary_missing = i % 2 == 1;
+            info.malformed_levels = usize::from(i == 2) * 3;
+            if i == 4 {
+                info.buy_moving_week = 100;
+            }
+            state.update_at(&info, 1_700_000_000 + 20 * i as u64);
+        }
+        let result = state.finalize_with_sequences("TEST_ITEM".to_string(), &analysis);
+        assert!(result.data_quality < 0.6, "{}", result.data_quality);
+        // The metrics themselves are reported as usual
+        assert_eq!(result.instasell_moving_week_volume, 50.0);
+    }
+
+    #[test]
+    fn moving_week_rate_skips_windows_where_the_counter_fell() {
+        let mut state = ProductMetricsState::new_at(&snapshot(vec![], vec![], 1_000, 2_000), 1_700_000_000);
+        // +300 buy over three minutes, with the counter aging out 500 in between; sell only falls
+        for (i, (buy_mw, sell_mw)) in [(1_200, 1_900), (700, 1_800), (800, 1_700)].into_iter().enumerate() {
+            state.update_at(&snapshot(vec![], vec![], buy_mw, sell_mw), 1_700_000_000 + 60 * (i as u64 + 1));
+        }
+
+        let result = state.finalize_with_sequences("TEST_ITEM".to_string(), &AnalysisConfig::default());
+        assert_eq!(result.buy_mw_hourly_rate, 6_000.0);
+        assert_eq!(result.sell_mw_hourly_rate, 0.0);
+    }
+
+    #[test]
+    fn inference_cross_check_separates_matching_from_undershooting_series() {
+        let moving_week = VecDeque::from([40, 0, 120, 80, 0, 160]);
+        let matching = moving_week.clone();
+        let (correlation, ratio) = ProductMetricsState::inference_agreement(&matching, &moving_week);
+        assert!((correlation.unwrap() - 1.0).abs() < 1e-12);
+        assert_eq!(ratio, Some(1.0));
+
+        // Catches a quarter of every fill, and nothing of the 160 burst
+        let undershooting = VecDeque::from([10, 0, 30, 20, 0, 0]);
+        let (correlation, ratio) = ProductMetricsState::inference_agreement(&undershooting, &moving_week);
+        assert!(correlation.unwrap() < 0.9);
+        assert_eq!(ratio, Some(0.75 * 0.25));
+
+        assert_eq!(ProductMetricsState::inference_agreement(&VecDeque::from([5, 5]), &moving_week), (None, Some(0.125)));
+        assert_eq!(ProductMetricsState::inference_agreement(&VecDeque::new(), &VecDeque::new()), (None, None));
+    }
+
+    #[test]
+    fn event_inference_modes_differ_where_orders_and_amount_disagree() {
+        // 10.0: one order partly filled; 10.5: five orders consumed whole; 11.0: orders cancelled
+        // while the amount grew
+        let before = vec![order(10.0, 100, 2), order(10.5, 50, 5), order(11.0, 30, 3)];
+        let after = vec![order(10.0, 60, 2), order(11.0, 40, 1)];
+        let (volume, events, _) = ProductMetricsState::consumed(&before, &after, 90);
+        assert_eq!(volume, 90);
+        assert_eq!(events, EventCounts { amount: 2, orders: 5, combined: 6 });
+
+        let mut state = ProductMetricsState::new_at(&snapshot(before, vec![], 1_000, 2_000), 1_700_000_000);
+        state.update_at(&snapshot(after, vec![], 1_090, 2_000), 1_700_000_020);
+        let sizes: Vec<f64> = [EventInference::Amount, EventInference::Orders, EventInference::Combined].into_iter()
+            .map(|event_inference| {
+                let analysis = AnalysisConfig { event_inference, ..Default::default() };
+                state.finalize_with_sequences("TEST_ITEM".into(), &analysis).player_instabuy_transaction_size_average
+            })
+            .collect();
+        assert_eq!(sizes, [45.0, 18.0, 15.0]);
+    }
+
+    #[test]
+    fn vanished_large_level_without_moving_week_change_is_a_flash_order() {
+        let before = vec![order(10.0, 100, 2), order(10.5, 50_000, 1)];
+        let after = vec![order(10.0, 80, 2)];
+        let run = |bought: i64| {
+            let mut state = ProductMetricsState::new_at(&snapshot(before.clone(), vec![], 1_000, 2_000), 1_700_000_000);
+            state.update_at(&snapshot(after.clone(), vec![], 1_000 + bought, 2_000), 1_700_000_020);
+            state.finalize_with_sequences("TEST_ITEM".into(), &AnalysisConfig::default())
+        };
+
+        // Bought out: the counter moved by the whole level
+        let fill = run(50_020);
+        assert_eq!((fill.flash_order_count, fill.instabuy_inferred_volume), (0, 50_020.0));
+        // Pulled: only the small partial fill at 10.0 shows in the counter
+        let spoof = run(20);
+        assert_eq!((spoof.flash_order_count, spoof.instabuy_inferred_volume), (1, 20.0));
+        assert_eq!(spoof.player_instabuy_transactions_per_hour, 180.0);
+
+        // Below FLASH_MIN_AMOUNT a vanished level is always a fill
+        let (volume, _, flash) = ProductMetricsState::consumed(&[order(10.5, 9_999, 1)], &[], 0);
+        assert_eq!((volume, flash), (9_999, 0));
+    }
+
+    #[test]
+    fn volume_coverage_is_none_without_moving_week_activity() {
+        let first = snapshot(vec![order(10.0, 100, 2)], vec![], 1_000, 2_000);
+        let mut state = ProductMetricsState::new_at(&first, 1_700_000_000);
+        state.update_at(&snapshot(vec![order(10.0, 50, 1)], vec![], 1_000, 2_000), 1_700_000_020);
+
+        let result = state.finalize_with_sequences("TEST_ITEM".to_string(), &AnalysisConfig::default());
+        assert_eq!(result.instabuy_inferred_volume, 50.0);
+        assert_eq!(result.instabuy_inferred_coverage, None);
+        assert_eq!(result.instasell_inferred_coverage, None);
+    }
+
+    #[test]
+    fn partial_cycle_volumes_are_extrapolated_to_target_windows() {
+        let first = snapshot(vec![order(10.0, 100, 2)], vec![], 1_000, 2_000);
+        let mut state = ProductMetricsState::new_at(&first, 1_700_000_000);
+        state.update_at(&snapshot(vec![order(10.0, 80, 2)], vec![], 1_100, 2_000), 1_700_000_020);
+        state.update_at(&snapshot(vec![order(10.0, 60, 2)], vec![], 1_160, 2_000), 1_700_000_040);
+
+        let analysis = AnalysisConfig { target_windows: 8, extrapolate_partial_windows: true, ..Default::default() };
+        let result = state.finalize_with_sequences("TEST_ITEM".to_string(), &analysis);
+        assert!(result.extrapolated);
+        assert_eq!(result.window_coverage, 0.25);
+        assert_eq!(result.instabuy_estimated_true_volume, 160.0);
+        assert_eq!(result.instabuy_estimated_true_volume_extrapolated, Some(640.0));
+        assert_eq!(result.instabuy_inferred_volume_extrapolated, Some(160.0));
+        // Averages and per-hour rates are left alone
+        assert_eq!(result.instabuy_price_average, 10.0);
+        let frequency = result.player_instabuy_transactions_per_hour;
+
+        let disabled = AnalysisConfig { target_windows: 8, extrapolate_partial_windows: false, ..Default::default() };
+        let result = state.finalize_with_sequences("TEST_ITEM".to_string(), &disabled);
+        assert!(!result.extrapolated);
+        assert_eq!(result.instabuy_estimated_true_volume_extrapolated, None);
+        assert_eq!(result.player_instabuy_transactions_per_hour, frequency);
+    }
+
+    // One window in which the buy book loses `bought` units and the sell book `sold`, while the
+    // moving-week counters advance by `buy_mw`/`sell_mw`
+    fn one_window_activity(bought: i64, sold: i64, buy_mw: i64, sell_mw: i64, analysis: &AnalysisConfig) -> MarketActivity {
+        let first = snapshot(vec![order(10.0, 100, 2)], vec![order(9.0, 100, 2)], 1_000, 2_000);
+        let mut state = ProductMetricsState::new_at(&first, 1_700_000_000);
+        let next = snapshot(vec![order(10.0, 100 - bought, 2)], vec![order(9.0, 100 - sold, 2)], 1_000 + buy_mw, 2_000 + sell_mw);
+        state.update_at(&next, 1_700_000_020);
+        state.finalize_with_sequences("TEST_ITEM".to_string(), analysis).market_activity
+    }
+
+    #[test]
+    fn market_activity_classifies_each_side() {
+        let analysis = AnalysisConfig::default();
+        assert_eq!(one_window_activity(40, 20, 160, 40, &analysis), MarketActivity::TwoSided);
+        assert_eq!(one_window_activity(40, 0, 160, 0, &analysis), MarketActivity::BuyOnly);
+        assert_eq!(one_window_activity(0, 20, 0, 40, &analysis), MarketActivity::SellOnly);
+        assert_eq!(one_window_activity(0, 0, 0, 0, &analysis), MarketActivity::Inactive);
+        // Inferred flow the moving-week counter never confirmed doesn't make a side active
+        assert_eq!(one_window_activity(40, 20, 160, 0, &analysis), MarketActivity::BuyOnly);
+    }
+
+    #[test]
+    fn market_activity_thresholds_come_from_config() {
+        let strict = AnalysisConfig { activity_min_moving_week_volume: 100, ..Default::default() };
+        assert_eq!(one_window_activity(40, 20, 160, 40, &strict), MarketActivity::BuyOnly);
+
+        let strict = AnalysisConfig { activity_min_inferred_events: 2, ..Default::default() };
+        assert_eq!(one_window_activity(40, 20, 160, 40, &strict), MarketActivity::Inactive);
+    }
+
+    #[test]
+    fn runtime_honours_flavor_and_worker_count() {
+        let multi = RuntimeConfig { flavor: RuntimeFlavor::MultiThread, worker_threads: Some(2) };
+        assert_eq!(build_runtime(&multi).unwrap().metrics().num_workers(), 2);
+
+        let current = RuntimeConfig { flavor: RuntimeFlavor::CurrentThread, worker_threads: Some(8) };
+        assert_eq!(build_runtime(&current).unwrap().metrics().num_workers(), 1);
+        assert_eq!("current-thread".parse(), Ok(RuntimeFlavor::CurrentThread));
+    }
+
+    #[test]
+    fn negative_and_nan_order_fields_are_dropped_without_corrupting_aggregates() {
+        let product = serde_json::json!({
+            "quick_status": { "buyPrice": 10.0, "sellPrice": 9.0, "buyMovingWeek": 1_000, "sellMovingWeek": 2_000 },
+            "buy_summary": [
+                { "amount": 100, "pricePerUnit": 10.0, "orders": 2 },
+                { "amount": -50, "pricePerUnit": 10.1, "orders": 1 },
+                { "amount": 40, "pricePerUnit": -10.2, "orders": 1 },
+                { "amount": 40, "pricePerUnit": 10.3, "orders": -1 },
+            ],
+            "sell_summary": [{ "amount": 80, "pricePerUnit": 9.0, "orders": 1 }],
+        });
+        let parsed = parse_product("TEST_ITEM".into(), serde_json::from_value(product).unwrap());
+        assert_eq!(parsed.buy_orders.iter().map(|o| (o.amount, o.orders)).collect::<Vec<_>>(), [(100, 2)]);
+        assert_eq!(parsed.sell_orders.len(), 1);
+
+        // Levels built in-process bypass the parser; an unusable price is skipped, not keyed as 0
+        assert_eq!(ProductMetricsState::price_to_key(f64::NAN), None);
+        assert_eq!(ProductMetricsState::price_to_key(-0.5), None);
+        assert_eq!(ProductMetricsState::price_to_key(0.0), Some(0));
+        let first = snapshot(vec![order(10.0, 100, 2), order(f64::NAN, 500, 1), order(-1.0, 500, 1)], vec![], 1_000, 2_000);
+        let mut state = ProductMetricsState::new_at(&first, 1_700_000_000);
+        state.update_at(&snapshot(vec![order(10.0, 60, 2), order(0.0, 30, 1)], vec![], 1_040, 2_000), 1_700_000_020);
+
+        let result = state.finalize_with_sequences("TEST_ITEM".into(), &AnalysisConfig::default());
+        assert_eq!(result.instabuy_inferred_volume, 40.0);
+        assert_eq!(result.new_demand_offers_per_hour, units::per_hour(1.0, 20));
+        assert!(result.buy_book_slope.is_none_or(f64::is_finite));
+    }
+
+    #[test]
+    fn quick_status_divergence_from_the_top_of_book_is_averaged_per_side() {
+        // quick_status says 10 / 9 throughout; the buy book's top sits at 10.5, then 10, then 11
+        let first = snapshot(vec![order(10.5, 100, 1), order(10.6, 50, 1)], vec![], 1_000, 2_000);
+        let mut state = ProductMetricsState::new_at(&first, 1_700_000_000);
+        state.update_at(&snapshot(vec![order(10.0, 100, 1)], vec![], 1_000, 2_000), 1_700_000_020);
+        state.update_at(&snapshot(vec![order(11.0, 100, 1)], vec![order(8.0, 10, 1)], 1_000, 2_000), 1_700_000_040);
+
+        let result = state.finalize_with_sequences("TEST_ITEM".into(), &AnalysisConfig::default());
+        assert_eq!(result.buy_quickstatus_topofbook_divergence, Some(0.5));
+        // Only the last snapshot had a sell book to compare against
+        assert_eq!(result.sell_quickstatus_topofbook_divergence, Some(1.0));
+
+        let empty = ProductMetricsState::new_at(&snapshot(vec![], vec![], 1_000, 2_000), 1_700_000_000);
+        assert_eq!(empty.finalize_with_sequences("TEST_ITEM".into(), &AnalysisConfig::default()).buy_quickstatus_topofbook_divergence, None);
+    }
+
+    #[test]
+    fn stack_sizes_scale_every_amount_derived_metric_to_units() {
+        let first = snapshot(vec![order(10.0, 100, 2)], vec![order(9.0, 50, 1)], 1_000, 2_000);
+        let second = snapshot(vec![order(10.0, 60, 2), order(10.5, 5, 1)], vec![order(9.0, 50, 1)], 1_040, 2_000);
+        let finalize = |sizes: &filter::StackSizes| {
+            let [mut first, mut second] = [first.clone(), second.clone()];
+            sizes.normalize(&mut first);
+            sizes.normalize(&mut second);
+            let mut state = ProductMetricsState::new_at(&first, 1_700_000_000);
+            state.update_at(&second, 1_700_000_020);
+            state.finalize_with_sequences("TEST_ITEM".into(), &AnalysisConfig::default())
+        };
+
+        let units = finalize(&filter::StackSizes::default());
+        let stacks = finalize(&filter::StackSizes::from_list("TEST_*=64"));
+        assert!(units.instabuy_inferred_volume > 0.0 && units.new_demand_offer_size_average > 0.0);
+        assert_eq!(stacks.instabuy_inferred_volume, 64.0 * units.instabuy_inferred_volume);
+        assert_eq!(stacks.new_demand_offer_size_average, 64.0 * units.new_demand_offer_size_average);
+        assert_eq!(stacks.instabuy_moving_week_volume, units.instabuy_moving_week_volume);
+    }
+
+    #[test]
+    fn recent_snapshot_ring_never_exceeds_its_capacity() {
+        let mut states = HashMap::new();
+        for i in 0..10 {
+            apply_snapshot_with(&mut states, vec![varied_snapshot(i)], 1_700_000_000 + 20 * i as u64, None, 3, 0.5);
+            assert!(states["TEST_ITEM"].recent_snapshots.len() <= 3);
+        }
+        // Out-of-order snapshots are rejected and never reach the ring
+        apply_snapshot_with(&mut states, vec![varied_snapshot(10)], 1_700_000_000, None, 3, 0.5);
+        let kept: Vec<u64> = states["TEST_ITEM"].recent_snapshots.iter().map(|r| r.captured_at).collect();
+        assert_eq!(kept, [1_700_000_140, 1_700_000_160, 1_700_000_180]);
+        assert_eq!(states["TEST_ITEM"].recent_snapshots[2].snapshot.buy_moving_week, varied_snapshot(9).buy_moving_week);
+
+        let mut off = HashMap::new();
+        apply_snapshot(&mut off, vec![varied_snapshot(0)], 1_700_000_000, None);
+        assert!(off["TEST_ITEM"].recent_snapshots.is_empty());
+    }
+
+    #[test]
+    fn standing_volume_is_averaged_over_the_snapshots_that_report_it() {
+        let mut product = serde_json::json!({
+            "quick_status": { "buyPrice": 10.0, "sellPrice": 9.0, "buyMovingWeek": 1_000, "sellMovingWeek": 2_000 },
+            "buy_summary": [], "sell_summary": [],
+        });
+        let without = parse_product("TEST_ITEM".into(), serde_json::from_value(product.clone()).unwrap());
+        product["quick_status"]["buyVolume"] = 3_000.into();
+        product["quick_status"]["sellVolume"] = 1_200.into();
+        let with = parse_product("TEST_ITEM".into(), serde_json::from_value(product).unwrap());
+        assert_eq!((with.buy_volume, with.sell_volume), (Some(3_000), Some(1_200)));
+        assert_eq!((without.buy_volume, without.sell_volume), (None, None));
+
+        let mut states = HashMap::new();
+        let mut outcomes = Vec::new();
+        for (i, info) in [with.clone(), without.clone(), BazaarInfo { buy_volume: Some(5_000), ..with }].into_iter().enumerate() {
+            outcomes.push(apply_snapshot(&mut states, vec![info], 1_700_000_000 + 20 * i as u64, None).missing_volume);
+        }
+        assert_eq!(outcomes, [0, 1, 0]);
+        let result = states["TEST_ITEM"].finalize_with_sequences("TEST_ITEM".into(), &AnalysisConfig::default());
+        assert_eq!((result.buy_standing_volume_average, result.sell_standing_volume_average), (Some(4_000.0), Some(1_200.0)));
+
+        let mut states = HashMap::new();
+        apply_snapshot(&mut states, vec![without], 1_700_000_000, None);
+        let result = states["TEST_ITEM"].finalize_with_sequences("TEST_ITEM".into(), &AnalysisConfig::default());
+        assert_eq!(result.buy_standing_volume_average, None);
+        let disabled = AnalysisConfig { standing_volume: false, ..Default::default() };
+        assert_eq!(states["TEST_ITEM"].finalize_with_sequences("TEST_ITEM".into(), &disabled).sell_standing_volume_average, None);
+    }
+
+    #[test]
+    fn missing_summary_is_not_inferred_as_consumed_book() {
+        let populated = snapshot(vec![order(10.0, 5_000, 40)], vec![order(9.0, 3_000, 30)], 1_000, 2_000);
+        let mut state = ProductMetricsState::new_at(&populated, 1_700_000_000);
+
+        let mut partial = snapshot(vec![], vec![order(9.0, 2_990, 30)], 1_010, 2_010);
+        partial.buy_summary_missing = true;
+        state.update_at(&partial, 1_700_000_020);
+        assert_eq!(state.inferred_buy_volume_history, vec![0]);
+        assert_eq!(state.buy_amount_deltas, vec![0]);
+        assert_eq!(state.inferred_sell_volume_history, vec![10]);
+
+        // The next full snapshot diffs against the last populated book, not the missing one
+        state.update_at(&snapshot(vec![order(10.0, 4_980, 40)], vec![order(9.0, 2_990, 30)], 1_030, 2_010), 1_700_000_040);
+        assert_eq!(state.inferred_buy_volume_history, vec![0, 20]);
+        assert_eq!(state.buy_amount_deltas, vec![0, -20]);
+        assert_eq!(state.total_new_demand_offers, 0.0);
+
+        let result = state.finalize_with_sequences("TEST_ITEM".to_string(), &AnalysisConfig::default());
+        assert_eq!(result.instabuy_inferred_volume, 20.0);
+        assert_eq!(result.buy_book_concentration, Some(1.0));
+    }
+
+    #[test]
+    fn older_last_modified_snapshot_is_rejected() {
+        let mut states = HashMap::new();
+        let book = |amount| snapshot(vec![order(10.0, amount, 2)], vec![], 1_000, 2_000);
+        assert_eq!(apply_snapshot(&mut states, vec![book(100)], 1_700_000_000, Some(1_000)).out_of_order, 0);
+        assert_eq!(apply_snapshot(&mut states, vec![book(90)], 1_700_000_020, Some(1_020)).out_of_order, 0);
+        // A retried request served from an older cache: newer observation time, older source time
+        assert_eq!(apply_snapshot(&mut states, vec![book(100)], 1_700_000_040, Some(1_000)).out_of_order, 1);
+        // Observed before the last accepted snapshot
+        assert_eq!(apply_snapshot(&mut states, vec![book(100)], 1_700_000_010, None).out_of_order, 1);
+        assert_eq!(apply_snapshot(&mut states, vec![book(80)], 1_700_000_060, Some(1_060)).out_of_order, 0);
+
+        let state = &states["TEST_ITEM"];
+        assert_eq!(state.timestamps, vec![1_700_000_000, 1_700_000_020, 1_700_000_060]);
+        assert_eq!(state.sequence_numbers, vec![0, 1, 4]);
+        // The rejected snapshot didn't re-list (or later re-consume) the 10 units
+        assert_eq!(state.inferred_buy_volume_history, vec![10, 10]);
+        assert_eq!(parse_http_date("Wed, 01 Jan 2025 00:00:00 GMT"), Some(1_735_689_600));
+    }
+
+    #[test]
+    fn spike_is_flagged_against_a_flat_history() {
+        let mut history = vec![20, 22, 18, 20, 21, 19, 20, 20];
+        assert_eq!(ProductMetricsState::detect_spike(&history, 5.0), None);
+
+        history[5] = 300;
+        assert_eq!(ProductMetricsState::detect_spike(&history, 5.0), Some((5, 15.0)));
+        assert_eq!(ProductMetricsState::detect_spike(&history, 20.0), None);
+
+        // Mostly idle windows have no meaningful median
+        assert_eq!(ProductMetricsState::detect_spike(&[0, 0, 0, 500], 5.0), None);
+        assert_eq!(ProductMetricsState::detect_spike(&[], 5.0), None);
+    }
+
+    #[test]
+    fn finalize_reports_spike_per_side() {
+        let mut amount = 10_000;
+        let first = snapshot(vec![order(10.0, amount, 2)], vec![], 0, 0);
+        let mut state = ProductMetricsState::new_at(&first, 1_700_000_000);
+        for (i, consumed) in [10, 12, 9, 10, 200, 11, 10].into_iter().enumerate() {
+            amount -= consumed;
+            state.update_at(&snapshot(vec![order(10.0, amount, 2)], vec![], 0, 0), 1_700_000_020 + 20 * i as u64);
+        }
+
+        let result = state.finalize_with_sequences("TEST_ITEM".to_string(), &AnalysisConfig::default());
+        assert!(result.buy_spike_detected);
+        assert_eq!(result.buy_spike_window, Some(4));
+        assert_eq!(result.buy_spike_magnitude, Some(20.0));
+        assert!(!result.sell_spike_detected);
+        assert_eq!(result.sell_spike_magnitude, None);
+    }
+
+    #[test]
+    fn prices_are_expressed_relative_to_the_reference_product() {
+        let priced = |id: &str, buy_price: f64, sell_price: f64| BazaarInfo {
+            product_id: id.to_string(),
+            buy_price,
+            sell_price,
+            ..snapshot(vec![], vec![], 0, 0)
+        };
+        let mut states = HashMap::new();
+        apply_snapshot(&mut states, vec![priced("REF", 100.0, 80.0), priced("ITEM", 250.0, 100.0), priced("FREE", 1.0, 0.0)], 1_700_000_000, None);
+        apply_snapshot(&mut states, vec![priced("REF", 300.0, 0.0), priced("ITEM", 350.0, 140.0), priced("FREE", 1.0, 0.0)], 1_700_000_020, None);
+
+        let analysis = AnalysisConfig { reference_product: Some("REF".into()), ..Default::default() };
+        let results = finalize_all(&states, &analysis);
+        let item = results.iter().find(|r| r.product_id == "ITEM").unwrap();
+        assert_eq!(item.instabuy_price_relative, Some(1.5));
+        assert_eq!(item.instasell_price_relative, Some(3.0));
+        let reference = results.iter().find(|r| r.product_id == "REF").unwrap();
+        assert_eq!(reference.instabuy_price_relative, Some(1.0));
+
+        // A reference with no sell price, or none at all, leaves the fields empty
+        let zero = AnalysisConfig { reference_product: Some("FREE".into()), ..Default::default() };
+        let item = finalize_all(&states, &zero).into_iter().find(|r| r.product_id == "ITEM").unwrap();
+        assert_eq!(item.instabuy_price_relative, Some(300.0));
+        assert_eq!(item.instasell_price_relative, None);
+        let missing = AnalysisConfig { reference_product: Some("GONE".into()), ..Default::default() };
+        assert!(finalize_all(&states, &missing).iter().all(|r| r.instabuy_price_relative.is_none()));
+    }
+
+    #[test]
+    fn profiling_reports_the_slowest_products_without_changing_results() {
+        let mut states = HashMap::new();
+        for i in 0..5 {
+            let products = (0..12).map(|p| BazaarInfo { product_id: format!("ITEM_{:02}", p), ..varied_snapshot(i) }).collect();
+            apply_snapshot(&mut states, products, 1_700_000_000 + 20 * i as u64, None);
+        }
+        let analysis = AnalysisConfig::default();
+
+        let (plain, none, _) = finalize_cycle(&states, &analysis, false);
+        assert!(none.is_none());
+        let (profiled, profile, _) = finalize_cycle(&states, &analysis, true);
+        let profile = profile.unwrap();
+        assert_eq!(serde_json::to_value(&plain).unwrap(), serde_json::to_value(&profiled).unwrap());
+        assert_eq!(profile.products, 12);
+        assert_eq!(profile.slowest.len(), PROFILE_SLOWEST);
+        assert!(profile.slowest.windows(2).all(|pair| pair[0].ms >= pair[1].ms));
+        assert!(profile.total_ms >= profile.slowest.iter().map(|t| t.ms).sum::<f64>());
+    }
+
+    #[tokio::test]
+    async fn processor_drains_queued_snapshots_after_fetching_stops() {
+        let (tx, rx) = mpsc::channel(4);
+        let states: http::SharedStates = Arc::new(Mutex::new(HashMap::new()));
+        let exporter = Arc::new(Exporter::for_tests(std::path::Path::new("metrics"), "true"));
+        let mut config = Config::default();
+        config.analysis.target_windows = 100;
+
+        for (i, amount) in [100, 90, 75].into_iter().enumerate() {
+            let products = vec![snapshot(vec![order(10.0, amount, 2)], vec![], 1_000, 2_000)];
+            tx.send(Snapshot { captured_at: 1_700_000_000 + 20 * i as u64, last_modified: None, polls_skipped: 0, products }).await.unwrap();
+        }
+        drop(tx);
+
+        process_snapshots(rx, config, states.clone(), exporter, Arc::new(Mutex::new(None))).await.unwrap();
+        let states = states.lock().unwrap();
+        assert_eq!(states["TEST_ITEM"].windows_processed, 2);
+        assert_eq!(states["TEST_ITEM"].inferred_buy_volume_history, vec![10, 15]);
+    }
+
+    #[tokio::test]
+    async fn panic_mid_cycle_flushes_the_states_to_an_emergency_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let states: http::SharedStates = Arc::new(Mutex::new(HashMap::new()));
+        let flush = EmergencyFlush { states: states.clone(), analysis: AnalysisConfig::default(), dir: dir.path().into() };
+
+        let cycle = tokio::spawn(async move {
+            let _flush = flush;
+            for i in 0..4 {
+                let mut states = states.lock().unwrap();
+                apply_snapshot(&mut states, vec![varied_snapshot(i)], 1_700_000_000 + 20 * i as u64, None);
+                assert!(i < 3, "injected panic with the state lock held");
+            }
+        });
+        assert!(cycle.await.unwrap_err().is_panic());
+
+        let flushed: Vec<PathBuf> = fs::read_dir(dir.path()).unwrap().map(|e| e.unwrap().path()).collect();
+        assert_eq!(flushed.len(), 1);
+        assert!(flushed[0].file_name().unwrap().to_string_lossy().starts_with("emergency_"));
+        let results: Vec<Value> = serde_json::from_slice(&fs::read(&flushed[0]).unwrap()).unwrap();
+        assert_eq!(results[0]["product_id"], "TEST_ITEM");
+        assert_eq!(results[0]["delta_sequences"]["buy_moving_week"].as_array().unwrap().len(), 3);
+    }
+
+    fn varied_snapshot(i: i64) -> BazaarInfo {
+        let mut info = snapshot(
+            vec![order(10.0, 1_000 - 7 * i - (i % 3) * 5, 4 + i % 2), order(10.5, 300 + (i % 4) * 10, 2)],
+            vec![order(9.0, 800 - 3 * i, 3), order(8.5, 500 + (i % 5), 1 + i % 3)],
+            5_000 + 11 * i + (i % 4) * 2,
+            4_000 + 6 * i,
+        );
+        info.buy_price = 10.0 + (i % 3) as f64;
+        info.sell_summary_missing = i == 7;
+        info
+    }
+
+    #[test]
+    fn evicted_state_matches_a_fresh_state_over_the_trailing_windows() {
+        let analysis = AnalysisConfig::default();
+        let mut sliding = ProductMetricsState::new_at(&varied_snapshot(0), 1_700_000_000);
+        for i in 1..20 {
+            sliding.update_at(&varied_snapshot(i), 1_700_000_000 + 20 * i as u64);
+            sliding.trim_to(6);
+        }
+        let mut fresh = ProductMetricsState::new_at(&varied_snapshot(13), 1_700_000_260);
+        for i in 14..20 {
+            fresh.update_at(&varied_snapshot(i), 1_700_000_000 + 20 * i as u64);
+        }
+
+        assert_eq!(sliding.windows_processed, 6);
+        assert_eq!(sliding.snapshot_count, 7);
+        assert_eq!(sliding.sequence_numbers, vec![13, 14, 15, 16, 17, 18, 19]);
+        let finalized = |state: &ProductMetricsState| {
+            let mut result = serde_json::to_value(state.finalize_with_sequences("TEST_ITEM".into(), &analysis)).unwrap();
+            result["delta_sequences"]["sequence_numbers"].take();
+            result
+        };
+        let (mut sliding, mut fresh) = (finalized(&sliding), finalized(&fresh));
+        // Running sums that had samples subtracted back out only agree to rounding
+        for field in ["buy_book_slope", "buy_book_concentration", "sell_book_slope", "sell_book_concentration"] {
+            let (a, b) = (sliding[field].take().as_f64().unwrap(), fresh[field].take().as_f64().unwrap());
+            assert!((a - b).abs() <= 1e-12 * b.abs(), "{}: {} vs {}", field, a, b);
+        }
+        assert_eq!(sliding, fresh);
+    }
+
+    #[tokio::test]
+    async fn sliding_mode_emits_every_e_windows_without_resetting() {
+        use std::os::unix::fs::PermissionsExt;
+        let dir = tempfile::tempdir().unwrap();
+        let engine = dir.path().join("engine.sh");
+        fs::write(&engine, format!("#!/bin/sh\necho \"$2\" >> {}/pushes\n", dir.path().display())).unwrap();
+        fs::set_permissions(&engine, fs::Permissions::from_mode(0o755)).unwrap();
+        let (tx, rx) = mpsc::channel(8);
+        let states: http::SharedStates = Arc::new(Mutex::new(HashMap::new()));
+        let exporter = Arc::new(Exporter::for_tests(dir.path(), engine.to_str().unwrap()));
+        let mut config = Config { sliding_emit_every: Some(2), ..Config::default() };
+        config.analysis.target_windows = 3;
+
+        for i in 0..6 {
+            let products = vec![varied_snapshot(i)];
+            tx.send(Snapshot { captured_at: 1_700_000_000 + 20 * i as u64, last_modified: None, polls_skipped: 0, products }).await.unwrap();
+        }
+        drop(tx);
+
+        let last_export = Arc::new(Mutex::new(None));
+        process_snapshots(rx, config, states.clone(), exporter, last_export.clone()).await.unwrap();
+        // Warm after the fourth snapshot, then again two windows later; each export is named after
+        // the snapshot that completed it
+        assert_eq!(fs::read_to_string(dir.path().join("pushes")).unwrap().lines().count(), 2);
+        assert!(dir.path().join("metrics_20231114221420.json").exists());
+        let report = last_export.lock().unwrap().clone().unwrap();
+        assert_eq!(report.status, ExportStatus::Both);
+        assert!(report.local_path.ends_with("metrics_20231114221500.json"));
+        let states = states.lock().unwrap();
+        assert_eq!(states["TEST_ITEM"].windows_processed, 3);
+        assert_eq!(states["TEST_ITEM"].timestamps, vec![1_700_000_040, 1_700_000_060, 1_700_000_080, 1_700_000_100]);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn pooled_finalize_matches_inline_finalize() {
+        // Enough products for several chunks, each with its own slightly different history
+        let mut states = HashMap::new();
+        for i in 0..12 {
+            let products = (0..150).map(|p| BazaarInfo { product_id: format!("ITEM_{:03}", p), ..varied_snapshot(i + p % 5) }).collect();
+            apply_snapshot(&mut states, products, 1_700_000_000 + 20 * i as u64, None);
+        }
+        let analysis = AnalysisConfig { reference_product: Some("ITEM_000".into()), ..Default::default() };
+        let (inline, _, _) = finalize_cycle(&states, &analysis, false);
+        let shared: http::SharedStates = Arc::new(Mutex::new(states));
+        let (pooled, profile, _) = finalize_pooled(&shared, &analysis, true, 2, ProductMetricsState::finalize_with_sequences).await.unwrap();
+
+        assert_eq!(serde_json::to_value(&pooled).unwrap(), serde_json::to_value(&inline).unwrap());
+        assert_eq!(profile.unwrap().products, 150);
+        assert_eq!(shared.lock().unwrap().len(), 150);
+    }
+
+    #[tokio::test]
+    async fn cold_start_holds_the_first_export_until_warmed_up() {
+        use std::os::unix::fs::PermissionsExt;
+        // Feeds `snapshots` snapshots to a fresh processor; returns how many exports were pushed
+        async fn pushes_after(snapshots: i64) -> usize {
+            let dir = tempfile::tempdir().unwrap();
+            let engine = dir.path().join("engine.sh");
+            fs::write(&engine, format!("#!/bin/sh\necho \"$2\" >> {}/pushes\n", dir.path().display())).unwrap();
+            fs::set_permissions(&engine, fs::Permissions::from_mode(0o755)).unwrap();
+            let exporter = Arc::new(Exporter::for_tests(dir.path(), engine.to_str().unwrap()));
+            let mut config = Config { warmup_windows: 5, ..Config::default() };
+            config.analysis.target_windows = 3;
+
+            let (tx, rx) = mpsc::channel(8);
+            for i in 0..snapshots {
+                let products = vec![varied_snapshot(i)];
+                tx.send(Snapshot { captured_at: 1_700_000_000 + 20 * i as u64, last_modified: None, polls_skipped: 0, products }).await.unwrap();
+            }
+            drop(tx);
+            let states: http::SharedStates = Arc::new(Mutex::new(HashMap::new()));
+            process_snapshots(rx, config, states, exporter, Arc::new(Mutex::new(None))).await.unwrap();
+            fs::read_to_string(dir.path().join("pushes")).map_or(0, |pushes| pushes.lines().count())
+        }
+
+        // The cycle is due after 3 windows, but nothing goes out before the fifth
+        assert_eq!(pushes_after(5).await, 0);
+        assert_eq!(pushes_after(6).await, 1);
+    }
+
+    #[tokio::test]
+    async fn a_restarted_processor_resumes_the_cycle_from_its_checkpoint() {
+        // Feeds snapshots `from..to` to a fresh processor checkpointing to `checkpoints`
+        async fn run(from: i64, to: i64, exports: &std::path::Path, checkpoints: &std::path::Path) {
+            let exporter = Arc::new(Exporter::for_tests(exports, "true"));
+            // Saved after every snapshot, so the restart resumes exactly where the first run stopped
+            let mut config = Config {
+                checkpoint_dir: Some(checkpoints.display().to_string()),
+                checkpoint_every: 1,
+                checkpoint_max_age_secs: None,
+                ..Config::default()
+            };
+            config.analysis.target_windows = 6;
+
+            let (tx, rx) = mpsc::channel(16);
+            for i in from..to {
+                let products = vec![varied_snapshot(i), BazaarInfo { product_id: "OTHER_ITEM".into(), ..varied_snapshot(i + 3) }];
+                tx.send(Snapshot { captured_at: 1_700_000_000 + 20 * i as u64, last_modified: None, polls_skipped: 0, products }).await.unwrap();
+            }
+            drop(tx);
+            let states: http::SharedStates = Arc::new(Mutex::new(HashMap::new()));
+            process_snapshots(rx, config, states, exporter, Arc::new(Mutex::new(None))).await.unwrap();
+        }
+        let export = |dir: &std::path::Path| fs::read(dir.join(format!("metrics_{}.json", export_stamp(1_700_000_120)))).unwrap();
+
+        let uninterrupted = (tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap());
+        run(0, 7, uninterrupted.0.path(), uninterrupted.1.path()).await;
+        // Stopped four windows in, then restarted for the rest of the cycle
+        let restarted = (tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap());
+        run(0, 5, restarted.0.path(), restarted.1.path()).await;
+        run(5, 7, restarted.0.path(), restarted.1.path()).await;
+        assert_eq!(export(restarted.0.path()), export(uninterrupted.0.path()));
+
+        // The checkpoint written after the reset holds an empty cycle, so a further restart
+        // doesn't export the same cycle twice
+        run(7, 8, restarted.0.path(), restarted.1.path()).await;
+        let exports = fs::read_dir(restarted.0.path()).unwrap().filter(|e| e.as_ref().unwrap().file_name().to_string_lossy().ends_with(".json")).count();
+        assert_eq!(exports, 1);
+    }
+
+    #[tokio::test]
+    async fn a_stuck_export_is_cancelled_at_the_timeout_and_pushed_by_the_next_one() {
+        use std::os::unix::fs::PermissionsExt;
+        let dir = tempfile::tempdir().unwrap();
+        // The first push hangs, as on a stalled MEGA upload; every later one goes through
+        let engine = dir.path().join("engine.sh");
+        fs::write(&engine, format!("#!/bin/sh
+if [ ! -e {0}/stalled ]; then touch {0}/stalled; exec sleep 60; fi
+echo \"$2\" >> {0}/pushes\n",
+            dir.path().display())).unwrap();
+        fs::set_permissions(&engine, fs::Permissions::from_mode(0o755)).unwrap();
+        let exporter = Arc::new(Exporter::for_tests(dir.path(), engine.to_str().unwrap()));
+        let mut config = Config::default();
+        config.analysis.target_windows = 3;
+        config.export_timeout_secs = Some(1);
+
+        let (tx, rx) = mpsc::channel(8);
+        let states: http::SharedStates = Arc::new(Mutex::new(HashMap::new()));
+        let last_export = Arc::new(Mutex::new(None));
+        let processor = tokio::spawn(process_snapshots(rx, config, states.clone(), exporter.clone(), last_export.clone()));
+        let started = Instant::now();
+        for i in 0..4 {
+            tx.send(Snapshot { captured_at: 1_700_000_000 + 20 * i as u64, last_modified: None, polls_skipped: 0, products: vec![varied_snapshot(i)] }).await.unwrap();
+        }
+        let report = loop {
+            if let Some(report) = last_export.lock().unwrap().clone() {
+                break report;
+            }
+            assert!(started.elapsed() < Duration::from_secs(20), "the stuck export was never cancelled");
+            sleep(Duration::from_millis(20)).await;
+        };
+        assert_eq!(report.status, ExportStatus::LocalOnly);
+        assert!(dir.path().join("metrics_20231114221420.json.pending").exists());
+        assert!(report.error.unwrap().contains("timed out"));
+        assert_eq!(exporter.cancelled.load(Ordering::Relaxed), 0);
+        assert!(states.lock().unwrap().is_empty(), "the cycle still resets");
+
+        // Processing carried on, and the next cycle's export retries the cancelled push first
+        for i in 4..8 {
+            tx.send(Snapshot { captured_at: 1_700_000_000 + 20 * i as u64, last_modified: None, polls_skipped: 0, products: vec![varied_snapshot(i)] }).await.unwrap();
+        }
+        drop(tx);
+        processor.await.unwrap().unwrap();
+        let report = last_export.lock().unwrap().clone().unwrap();
+        assert_eq!((report.status, report.retry.uploaded, report.pending_remote), (ExportStatus::Both, 1, 0));
+        assert_eq!(fs::read_to_string(dir.path().join("pushes")).unwrap().lines().count(), 2);
+    }
+
+    #[test]
+    fn delta_sequences_can_be_omitted_or_downsampled_for_output() {
+        let mut state = ProductMetricsState::new_at(&varied_snapshot(0), 1_700_000_000);
+        for i in 1..10 {
+            state.update_at(&varied_snapshot(i), 1_700_000_000 + 20 * i as u64);
+        }
+        let exported = |output: &str| {
+            let analysis = AnalysisConfig { delta_sequence_output: output.parse().unwrap(), ..Default::default() };
+            serde_json::to_value(state.finalize_with_sequences("TEST_ITEM".into(), &analysis)).unwrap()
+        };
+
+        let full = exported("full");
+        let omitted = exported("omit");
+        assert!(omitted["delta_sequences"].is_null());
+        // Detection still ran over the full sequences
+        assert_eq!(omitted["pattern_details"], full["pattern_details"]);
+
+        let sampled = &exported("every:4")["delta_sequences"];
+        assert_eq!(sampled["downsampled_every"], 4);
+        assert_eq!(sampled["timestamps"], serde_json::json!([1_700_000_000, 1_700_000_080, 1_700_000_160]));
+        assert_eq!(sampled["buy_moving_week"][1], full["delta_sequences"]["buy_moving_week"][4]);
+        assert!(full["delta_sequences"].get("downsampled_every").is_none());
+        assert!("every:0".parse::<DeltaSequenceOutput>().is_err());
+    }
+
+    #[test]
+    fn drifted_timestamps_skip_detection_instead_of_misreading() {
+        let mut state = ProductMetricsState::new_at(&varied_snapshot(0), 1_700_000_000);
+        for i in 1..12 {
+            state.update_at(&varied_snapshot(i), 1_700_000_000 + 20 * i as u64);
+        }
+        let analysis = AnalysisConfig::default();
+        assert_ne!(state.finalize_with_sequences("TEST_ITEM".into(), &analysis).pattern_details.detection_method, "skipped_misaligned");
+
+        state.timestamps.push_back(1_700_000_240);
+        let result = state.finalize_with_sequences("TEST_ITEM".into(), &analysis);
+        assert_eq!(result.pattern_details.detection_method, "skipped_misaligned");
+        assert_eq!((result.instabuy_modal_size, result.pattern_detection_confidence), (0.0, 0.0));
+        // Scalar metrics are unaffected
+        assert!(result.player_instabuy_transactions_per_hour > 0.0);
+
+        let sequences = result.delta_sequences.unwrap();
+        assert_eq!(sequences.check_alignment(), Err("buy has 11 deltas for 13 timestamps".to_string()));
+    }
+
+    #[test]
+    fn products_below_min_observations_are_held_back() {
+        let mut states = HashMap::new();
+        let mut veteran = ProductMetricsState::new_at(&varied_snapshot(0), 1_700_000_000);
+        let mut newcomer = ProductMetricsState::new_at(&varied_snapshot(0), 1_700_000_100);
+        for i in 1..=5 {
+            veteran.update_at(&varied_snapshot(i), 1_700_000_000 + 20 * i as u64);
+        }
+        for i in 1..=4 {
+            newcomer.update_at(&varied_snapshot(i), 1_700_000_100 + 20 * i as u64);
+        }
+        states.insert("VETERAN".to_string(), veteran);
+        states.insert("NEWCOMER".to_string(), newcomer);
+
+        let ids = |min| {
+            let analysis = AnalysisConfig { min_observations_before_export: min, ..Default::default() };
+            let ids: Vec<String> = finalize_all(&states, &analysis).into_iter().map(|r| r.product_id).collect();
+            (ids, warming_up_count(&states, &analysis))
+        };
+        assert_eq!(ids(4), (vec!["NEWCOMER".to_string(), "VETERAN".to_string()], 0));
+        assert_eq!(ids(5), (vec!["VETERAN".to_string()], 1));
+        assert_eq!(ids(6), (vec![], 2));
+    }
+
+    #[test]
+    fn dead_products_skip_detection_but_keep_scalar_metrics() {
+        let flat = snapshot(vec![order(10.0, 100, 2)], vec![order(9.0, 50, 1)], 1_000, 2_000);
+        let mut dead = ProductMetricsState::new_at(&flat, 1_700_000_000);
+        let mut quiet = ProductMetricsState::new_at(&flat, 1_700_000_000);
+        for i in 1..=10u64 {
+            dead.update_at(&flat, 1_700_000_000 + 20 * i);
+            let bought = if i == 5 { 1_003 } else { 1_000 };
+            quiet.update_at(&snapshot(vec![order(10.0, 100, 2)], vec![order(9.0, 50, 1)], bought, 2_000), 1_700_000_000 + 20 * i);
+        }
+
+        let analysis = AnalysisConfig::default();
+        let result = dead.finalize_with_sequences("DEAD".into(), &analysis);
+        assert_eq!(result.pattern_details.detection_method, "skipped_inactive");
+        assert_eq!(result.instabuy_price_average, 10.0);
+        assert_ne!(quiet.finalize_with_sequences("QUIET".into(), &analysis).pattern_details.detection_method, "skipped_inactive");
+
+        // One active window in ten sits exactly at a 0.1 fraction, which is not above it
+        let stricter = AnalysisConfig { prefilter_min_active_fraction: 0.1, prefilter_min_moving_week_activity: 3, ..Default::default() };
+        assert!(!quiet.worth_detecting(&stricter));
+        let forced = AnalysisConfig { force_full_detection: true, ..stricter };
+        assert_ne!(dead.finalize_with_sequences("DEAD".into(), &forced).pattern_details.detection_method, "skipped_inactive");
+    }
+
+    #[test]
+    fn negative_spread_skips_detection_despite_high_volume() {
+        let priced = |buy_price: f64, sell_price: f64, i: i64| BazaarInfo {
+            buy_price,
+            sell_price,
+            ..snapshot(vec![order(10.0, 100_000 - 160 * i, 20)], vec![order(9.0, 100_000 - 160 * i, 20)], 160 * i, 160 * i)
+        };
+        let mut inverted = ProductMetricsState::new_at(&priced(8.0, 9.0, 0), 1_700_000_000);
+        let mut normal = ProductMetricsState::new_at(&priced(10.0, 9.0, 0), 1_700_000_000);
+        for i in 1..=30 {
+            inverted.update_at(&priced(8.0, 9.0, i), 1_700_000_000 + 20 * i as u64);
+            normal.update_at(&priced(10.0, 9.0, i), 1_700_000_000 + 20 * i as u64);
+        }
+
+        let analysis = AnalysisConfig::default();
+        let result = inverted.finalize_with_sequences("INVERTED".into(), &analysis);
+        assert_eq!(result.pattern_details.detection_method, "below_pattern_threshold");
+        assert_eq!(result.instabuy_modal_size, 0.0);
+        assert_eq!(result.instabuy_estimated_true_volume, 4_800.0);
+        assert_ne!(normal.finalize_with_sequences("NORMAL".into(), &analysis).pattern_details.detection_method, "below_pattern_threshold");
+
+        // A volume floor above the busier side's 4,800 gates the normal product too
+        let floored = AnalysisConfig { pattern_min_moving_week_activity: 4_800, ..Default::default() };
+        assert_eq!(normal.finalize_with_sequences("NORMAL".into(), &floored).pattern_details.detection_method, "below_pattern_threshold");
+    }
+
+    #[test]
+    fn backward_wall_clock_jump_does_not_reorder_snapshots() {
+        let clock = MonotonicClock { origin_unix: 1_700_000_000, origin: Instant::now() };
+        let polls = [0u64, 20, 40, 60].map(|secs| clock.origin + Duration::from_secs(secs));
+        // The wall clock is stepped back 45s by NTP between the second and third poll
+        let wall = [1_700_000_000u64, 1_700_000_020, 1_699_999_995, 1_700_000_015];
+        let monotonic = polls.map(|instant| clock.at(instant));
+        assert_eq!(monotonic, [1_700_000_000, 1_700_000_020, 1_700_000_040, 1_700_000_060]);
+
+        let accepted = |stamps: [u64; 4]| {
+            let mut state = ProductMetricsState::new_at(&varied_snapshot(0), stamps[0]);
+            (1..4).filter(|&i| state.offer_at(&varied_snapshot(i as i64), stamps[i], None, 0.5)).count()
+        };
+        assert_eq!(accepted(wall), 1);
+        assert_eq!(accepted(monotonic), 3);
+    }
+
+    #[test]
+    fn disk_full_or_abandoned_export_keeps_the_cycle_for_a_retry() {
+        let mut states = HashMap::new();
+        for i in 0..4 {
+            apply_snapshot(&mut states, vec![varied_snapshot(i)], 1_700_000_000 + 20 * i as u64, None);
+        }
+        assert!(!end_cycle(&mut states, ExportStatus::DiskFull));
+        assert!(!end_cycle(&mut states, ExportStatus::Abandoned));
+        assert_eq!(states["TEST_ITEM"].windows_processed, 3);
+        apply_snapshot(&mut states, vec![varied_snapshot(4)], 1_700_000_080, None);
+        assert_eq!(states["TEST_ITEM"].windows_processed, 4);
+
+        // Any other failure (here a missing export dir) still starts over
+        let dir = tempfile::tempdir().unwrap();
+        let exporter = Exporter::for_tests(&dir.path().join("gone"), "true");
+        let report = exporter.export("20250101000000", &finalize_all(&states, &AnalysisConfig::default()), false, None, Vec::new());
+        assert_eq!(report.status, ExportStatus::Failed);
+        assert!(end_cycle(&mut states, report.status));
+        assert!(states.is_empty());
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn a_panicking_product_is_dead_lettered_and_the_cycle_proceeds() {
+        let mut states = HashMap::new();
+        for i in 0..4 {
+            let products = ["TEST_ITEM", PANICKING_PRODUCT, "OTHER_ITEM"].map(|id| BazaarInfo { product_id: id.into(), ..varied_snapshot(i) });
+            apply_snapshot(&mut states, products.into(), 1_700_000_000 + 20 * i as u64, None);
+        }
+        let analysis = AnalysisConfig::default();
+        let (results, _, mut dead_letters) = finalize_cycle_with(&states, &analysis, false, panicking_finalize);
+        assert_eq!(results.iter().map(|r| r.product_id.as_str()).collect::<Vec<_>>(), ["OTHER_ITEM", "TEST_ITEM"]);
+        assert_eq!(dead_letters.len(), 1);
+        assert_eq!((dead_letters[0].product_id.as_str(), dead_letters[0].error.as_str()), (PANICKING_PRODUCT, "injected finalize failure"));
+
+        let shared: http::SharedStates = Arc::new(Mutex::new(states));
+        let (pooled, _, pooled_letters) = finalize_pooled(&shared, &analysis, false, 2, panicking_finalize).await.unwrap();
+        assert_eq!(pooled.len(), 2);
+        assert_eq!(pooled_letters[0].product_id, PANICKING_PRODUCT);
+
+        // The state is dumped next to the exports, once per cycle however often its export is retried
+        let dir = tempfile::tempdir().unwrap();
+        let mut dumped = HashMap::new();
+        dump_dead_letters(&mut dead_letters, dir.path(), "20250101000000", &mut dumped);
+        let dump = fs::read_to_string(dead_letters[0].dump_path.as_ref().unwrap()).unwrap();
+        assert!(dump.starts_with("injected finalize failure") && dump.contains("windows_processed"));
+        let mut retried = pooled_letters;
+        dump_dead_letters(&mut retried, dir.path(), "20250101000020", &mut dumped);
+        assert_eq!(retried[0].dump_path, dead_letters[0].dump_path);
+        assert_eq!(fs::read_dir(dir.path().join("dead_letters")).unwrap().count(), 1);
+
+        // Listed in a single export's cycle sidecar, and in a sharded export's manifest
+        let single = Exporter::for_tests(dir.path(), "true");
+        assert_eq!(single.export("20241231230000", &results, false, None, dead_letters.clone()).status, ExportStatus::Both);
+        let sidecar: Value = serde_json::from_slice(&fs::read(dir.path().join("metrics_20241231230000_cycle.json")).unwrap()).unwrap();
+        assert_eq!(sidecar["dead_letters"][0]["product_id"], PANICKING_PRODUCT);
+        single.export("20241231220000", &results, false, None, Vec::new());
+        assert!(!dir.path().join("metrics_20241231220000_cycle.json").exists());
+        let exporter = Exporter { sharding: config::ExportSharding::Hash(2), ..Exporter::for_tests(dir.path(), "true") };
+        let report = exporter.export("20250101000000", &results, false, None, dead_letters);
+        assert_eq!(report.status, ExportStatus::Both);
+        let manifest: Value = serde_json::from_slice(&fs::read(&report.local_path).unwrap()).unwrap();
+        assert_eq!(manifest["dead_letters"][0]["product_id"], PANICKING_PRODUCT);
+        assert!(manifest["dead_letters"][0].get("state").is_none());
+    }
+
+    #[test]
+    fn invalid_config_exits_with_the_config_code() {
+        let mut config = Config::default();
+        config.analysis.detector.min_cluster_size = 1;
+        let error = check_config(&config).unwrap_err();
+        assert_eq!(exit::exit_code(error.as_ref()), exit::CONFIG);
+        assert!(error.to_string().contains("DETECTOR_MIN_CLUSTER_SIZE"));
+
+        config.analysis.detector.min_cluster_size = 3;
+        assert!(check_config(&config).is_ok());
+
+        config.invalid.push("EXPORT_SHARDING=\"category\": Unknown export sharding: category".into());
+        config.invalid.push("BASELINE_ALPHA=\"0\": expected a weight in (0, 1]".into());
+        let error = check_config(&config).unwrap_err();
+        assert_eq!(exit::exit_code(error.as_ref()), exit::CONFIG);
+        assert!(error.to_string().contains("EXPORT_SHARDING") && error.to_string().contains("BASELINE_ALPHA"), "{}", error);
+    }
+
+    #[test]
+    fn endpoint_products_merge_in_order_with_the_first_entry_winning() {
+        // The source is told apart by buyPrice: 1 for the bazaar, 2 for the mirror
+        let bazaar = r#"{ "products": { "WHEAT": { "quick_status": { "buyPrice": 1 } }, "CARROT": { "quick_status": { "buyPrice": 1 } } } }"#;
+        let mirror = r#"{ "products": { "WHEAT": { "quick_status": { "buyPrice": 2 } }, "POTATO": { "quick_status": { "buyPrice": 2 } } } }"#;
+        let parse = |body: &str| serde_json::from_str::<ApiResponse>(body).unwrap();
+        let merged = parse_bazaar_products(merge_products(vec![parse(bazaar), parse(mirror)]));
+        let sources: Vec<(&str, f64)> = merged.iter().map(|info| (info.product_id.as_str(), info.buy_price)).collect();
+        assert_eq!(sources, [("CARROT", 1.0), ("POTATO", 2.0), ("WHEAT", 1.0)]);
+
+        // A body without products is rejected outright; a product missing everything parses as an
+        // empty, partial entry
+        assert!(serde_json::from_str::<ApiResponse>(r#"{ "success": false }"#).is_err());
+        let bare = parse_product("WHEAT".into(), serde_json::from_str("{}").unwrap());
+        assert!(bare.buy_summary_missing && bare.sell_summary_missing && bare.buy_volume.is_none());
+    }
+
+    #[test]
+    fn bazaar_products_parse_in_id_order_with_missing_parts_defaulted() {
+        let body = r#"{ "success": true, "products": {
+            "WHEAT": {
+                "quick_status": { "buyPrice": 6.5, "sellPrice": 5.9, "buyMovingWeek": 1000, "sellMovingWeek": "900", "buyVolume": 40, "sellVolume": 30 },
+                "buy_summary": [{ "amount": 10, "pricePerUnit": 6.5, "orders": 1 }],
+                "sell_summary": []
+            },
+            "CARROT": { "buy_summary": [{ "amount": -1, "pricePerUnit": 2.0, "orders": 1 }] }
+        } }"#;
+        let products = parse_bazaar_products(serde_json::from_str::<ApiResponse>(body).unwrap().products);
+        assert_eq!(products.iter().map(|p| p.product_id.as_str()).collect::<Vec<_>>(), ["CARROT", "WHEAT"]);
+
+        let wheat = &products[1];
+        assert_eq!((wheat.buy_price, wheat.sell_price, wheat.buy_moving_week, wheat.sell_moving_week), (6.5, 5.9, 1_000, 900));
+        assert_eq!((wheat.buy_volume, wheat.sell_volume), (Some(40), Some(30)));
+        assert_eq!((wheat.buy_orders.len(), wheat.sell_orders.len(), wheat.sell_summary_missing), (1, 0, false));
+
+        // No quick_status: zero prices and counters, no volumes; the malformed level is dropped
+        let carrot = &products[0];
+        assert_eq!((carrot.buy_price, carrot.buy_moving_week, carrot.buy_volume), (0.0, 0, None));
+        assert_eq!((carrot.buy_orders.len(), carrot.malformed_levels, carrot.sell_summary_missing), (0, 1, true));
+
+        assert!(parse_bazaar_products(serde_json::from_str::<ApiResponse>(r#"{ "products": {} }"#).unwrap().products).is_empty());
+    }
+
+    #[test]
+    fn a_malformed_product_is_skipped_without_losing_the_others() {
+        // Parsed as one stream, so every malformed product is followed by one that must survive it
+        let body = r#"{ "products": {
+            "APPLE": "delisted",
+            "WHEAT": { "quick_status": { "buyPrice": 6.5, "buyMovingWeek": 1000 } },
+            "CARROT": { "quick_status": "unavailable" },
+            "PUMPKIN": { "buy_summary": null, "quick_status": { "buyPrice": 2 } },
+            "POTATO": { "buy_summary": { "amount": 1 } },
+            "MELON": { "sell_summary": ["level", { "amount": 1, "pricePerUnit": 2, "orders": 1 }] },
+            "BEET": { "quick_status": [1, 2, 3, 4, 5, 6, 7] },
+            "CACTUS": { "sell_summary": [{ "amount": 1, "pricePerUnit": 2, "orders": 1 }] }
+        } }"#;
+        let products = parse_bazaar_products(serde_json::from_str::<ApiResponse>(body).unwrap().products);
+        assert_eq!(products.iter().map(|p| p.product_id.as_str()).collect::<Vec<_>>(), ["CACTUS", "PUMPKIN", "WHEAT"]);
+        assert_eq!((products[2].buy_price, products[2].buy_moving_week), (6.5, 1_000));
+        assert_eq!((products[0].sell_orders.len(), products[1].buy_price, products[1].buy_summary_missing), (1, 2.0, true));
+
+        // Broken JSON still fails the whole body
+        assert!(serde_json::from_str::<ApiResponse>(r#"{ "products": { "WHEAT": { "quick_status": [1, } } }"#).is_err());
+    }
+
+    // Counts what each thread has allocated and its peak, for `typed_parse_cost`. Per thread, so
+    // the other tests running alongside don't show up in a measurement.
+    struct PeakAlloc;
+
+    thread_local! {
+        static ALLOCATED: std::cell::Cell<isize> = const { std::cell::Cell::new(0) };
+        static PEAK: std::cell::Cell<isize> = const { std::cell::Cell::new(0) };
+    }
+
+    fn track(bytes: isize) {
+        let _ = ALLOCATED.try_with(|allocated| {
+            allocated.set(allocated.get() + bytes);
+            let _ = PEAK.try_with(|peak| peak.set(peak.get().max(allocated.get())));
+        });
+    }
+
+    unsafe impl std::alloc::GlobalAlloc for PeakAlloc {
+        unsafe fn alloc(&self, layout: std::alloc::Layout) -> *mut u8 {
+            let ptr = std::alloc::System.alloc(layout);
+            if !ptr.is_null() {
+                track(layout.size() as isize);
+            }
+            ptr
+        }
+
+        unsafe fn dealloc(&self, ptr: *mut u8, layout: std::alloc::Layout) {
+            std::alloc::System.dealloc(ptr, layout);
+            track(-(layout.size() as isize));
+        }
+
+        unsafe fn realloc(&self, ptr: *mut u8, layout: std::alloc::Layout, new_size: usize) -> *mut u8 {
+            let new_ptr = std::alloc::System.realloc(ptr, layout, new_size);
+            if !new_ptr.is_null() {
+                track(new_size as isize - layout.size() as isize);
+            }
+            new_ptr
+        }
+    }
+
+    #[global_allocator]
+    static PEAK_ALLOC: PeakAlloc = PeakAlloc;
+
+    // What `run` returns, and the most it had allocated on this thread at any one time
+    fn peak_allocation<R>(run: impl FnOnce() -> R) -> (R, usize) {
+        let start = ALLOCATED.with(|allocated| allocated.get());
+        PEAK.with(|peak| peak.set(start));
+        let result = run();
+        (result, (PEAK.with(|peak| peak.get()) - start) as usize)
+    }
+
+    // Parse time and peak allocation of a full-size body as a Value tree and through the typed
+    // streaming path:
+    // cargo test --release -- --ignored --nocapture typed_parse_cost
+    #[test]
+    #[ignore]
+    fn typed_parse_cost() {
+        const RUNS: u32 = 10;
+        let level = |i: usize| serde_json::json!({ "amount": 640 + i, "pricePerUnit": 12.5 + i as f64 * 0.1, "orders": 1 + i % 4 });
+        let product = serde_json::json!({
+            "quick_status": { "buyPrice": 12.5, "sellPrice": 11.9, "buyMovingWeek": 4_000_000, "sellMovingWeek": 3_900_000, "buyVolume": 120_000, "sellVolume": 98_000 },
+            "buy_summary": (0..30).map(level).collect::<Vec<_>>(),
+            "sell_summary": (0..30).map(level).collect::<Vec<_>>(),
+        });
+        let products: serde_json::Map<String, Value> = (0..1_400).map(|i| (format!("PRODUCT_{}", i), product.clone())).collect();
+        let body = serde_json::to_vec(&serde_json::json!({ "success": true, "products": products })).unwrap();
+
+        let started = Instant::now();
+        for _ in 0..RUNS {
+            std::hint::black_box(serde_json::from_slice::<Value>(&body).unwrap());
+        }
+        let value_tree = started.elapsed() / RUNS;
+        let started = Instant::now();
+        for _ in 0..RUNS {
+            let response = serde_json::from_slice::<ApiResponse>(&body).unwrap();
+            std::hint::black_box(parse_bazaar_products(merge_products(vec![response])));
+        }
+        let typed = started.elapsed() / RUNS;
+
+        let (_, value_tree_peak) = peak_allocation(|| serde_json::from_slice::<Value>(&body).unwrap());
+        let (products, typed_peak) = peak_allocation(|| {
+            parse_bazaar_products(merge_products(vec![serde_json::from_slice::<ApiResponse>(&body).unwrap()]))
+        });
+        assert_eq!(products.len(), 1_400);
+        println!("{} bytes: Value tree {:?} per parse, peak {} KiB; typed {:?} per parse, peak {} KiB",
+            body.len(), value_tree, value_tree_peak / 1024, typed, typed_peak / 1024);
+    }
+
+    #[test]
+    fn fast_polling_commits_once_per_update_and_counts_the_skipped_polls() {
+        // The API publishes every 7 s and is polled every 2 s for 140 s
+        let http_date = |t: u64| chrono::DateTime::<Utc>::from_timestamp(t as i64, 0).unwrap().to_rfc2822();
+        let mut last_modified = None;
+        let mut skips = cadence::PollSkips::default();
+        let mut report = cadence::SkipReport::default();
+        let mut committed = Vec::new();
+        for poll in (0..140).step_by(2) {
+            let updated = poll / 7 * 7;
+            if last_modified_changed(&mut last_modified, Some(&http_date(1_700_000_000 + updated))) {
+                report.add(skips.commit());
+                committed.push((updated, poll));
+            } else {
+                skips.skip();
+            }
+        }
+        assert_eq!(committed.len(), 20);
+        assert!(committed.iter().all(|(updated, polled)| polled - updated < 2), "{:?}", committed);
+        // Every poll either committed or was skipped; the two after the last update wait for the next window
+        assert_eq!(report.windows as u64 + report.polls_skipped + u64::from(skips.commit()), 70);
+        assert!((2..=3).contains(&report.max_per_window));
+        assert!((report.mean_per_window() - 2.4).abs() < 1e-9, "{}", report.mean_per_window());
+
+        // Without the header nothing can be told apart here; the body hash decides instead
+        assert!(last_modified_changed(&mut last_modified, None));
+        assert!(last_modified_changed(&mut last_modified, None));
+    }
+
+    #[tokio::test]
+    async fn fetching_gives_up_only_after_consecutive_rejected_ticks() {
+        // Rejects the first `rejected` requests with a 403, then serves an empty bazaar
+        async fn serve(rejected: usize) -> (String, Arc<Mutex<usize>>) {
+            let requests = Arc::new(Mutex::new(0));
+            let counted = requests.clone();
+            let app = axum::Router::new().route("/", axum::routing::get(move || async move {
+                let mut requests = counted.lock().unwrap();
+                *requests += 1;
+                if *requests <= rejected { Err(axum::http::StatusCode::FORBIDDEN) } else { Ok(r#"{"success":true,"products":{}}"#) }
+            }));
+            let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let url = format!("http://{}/", listener.local_addr().unwrap());
+            tokio::spawn(async move { axum::serve(listener, app).await });
+            (url, requests)
+        }
+        let fetch = |url: String, tx| fetch_snapshots(reqwest::Client::new(), vec![url], Duration::ZERO, false, Default::default(), tx);
+
+        // A short run of rejections is waited out
+        let (url, _) = serve(MAX_REJECTED_TICKS as usize - 1).await;
+        let (tx, mut rx) = mpsc::channel(1);
+        tokio::select! {
+            fetched = fetch(url, tx) => panic!("fetching stopped: {:?}", fetched.err()),
+            received = rx.recv() => assert!(received.is_some()),
+        }
+
+        // A persistent one ends the run with the fetch exit code
+        let (url, requests) = serve(usize::MAX).await;
+        let (tx, _rx) = mpsc::channel(1);
+        let error = fetch(url, tx).await.unwrap_err();
+        assert_eq!(exit::exit_code(error.as_ref()), exit::FETCH);
+        assert_eq!(*requests.lock().unwrap(), MAX_REJECTED_TICKS as usize);
+    }
+
+    #[tokio::test]
+    async fn responses_without_last_modified_are_deduplicated_by_content() {
+        // A mirror that strips Last-Modified and serves whatever body is current
+        let body = Arc::new(Mutex::new(r#"{"success":true,"products":{}}"#.to_string()));
+        let served = body.clone();
+        let app = axum::Router::new().route("/", axum::routing::get(move || async move { served.lock().unwrap().clone() }));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        let client = reqwest::Client::new();
+        let clock = MonotonicClock::start();
+        let mut endpoints = vec![Endpoint::new(url)];
+        assert!(fetch_snapshot(&client, &clock, &mut endpoints).await.unwrap().is_some());
+        assert!(endpoints[0].header_missing);
+        for _ in 0..3 {
+            assert!(fetch_snapshot(&client, &clock, &mut endpoints).await.unwrap().is_none(), "an identical body is a duplicate");
+        }
+
+        let product = r#"{"product_id":"WHEAT","quick_status":{"buyPrice":10.0,"sellPrice":9.0,"buyMovingWeek":5,"sellMovingWeek":4}}"#;
+        *body.lock().unwrap() = format!(r#"{{"success":true,"products":{{"WHEAT":{}}}}}"#, product);
+        let snapshot = fetch_snapshot(&client, &clock, &mut endpoints).await.unwrap().expect("a changed body is new data");
+        assert_eq!((snapshot.products.len(), snapshot.last_modified), (1, None));
+        assert!(fetch_snapshot(&client, &clock, &mut endpoints).await.unwrap().is_none());
+    }
+
+    #[test]
+    fn numeric_fields_accept_integers_floats_and_numeric_strings() {
+        let level = |json: serde_json::Value| {
+            let level = serde_json::from_value::<ApiLevel>(json).unwrap();
+            (level.amount.value, level.price_per_unit.value, level.orders.value)
+        };
+        let expected = (64, 10.0, 2);
+        assert_eq!(level(serde_json::json!({ "amount": 64, "pricePerUnit": 10.0, "orders": 2 })), expected);
+        assert_eq!(level(serde_json::json!({ "amount": 64.0, "pricePerUnit": 10, "orders": 2.0 })), expected);
+        assert_eq!(level(serde_json::json!({ "amount": "64", "pricePerUnit": " 10.0 ", "orders": "2" })), expected);
+        assert_eq!(level(serde_json::json!({ "amount": "6.4e1", "pricePerUnit": "1e1", "orders": "2.0" })), expected);
+
+        let quick: ApiQuickStatus = serde_json::from_value(serde_json::json!({
+            "buyPrice": "12", "sellPrice": 11, "buyMovingWeek": 1.5e6, "sellMovingWeek": "2000000",
+            "buyVolume": "5000", "sellVolume": null,
+        })).unwrap();
+        assert_eq!((quick.buy_price.value, quick.sell_price.value), (12.0, 11.0));
+        assert_eq!((quick.buy_moving_week.value, quick.sell_moving_week.value), (1_500_000, 2_000_000));
+        assert_eq!((quick.buy_volume.map(|v| v.value), quick.sell_volume), (Some(5_000), None));
+        // Integers beyond 2^53 stay exact
+        let big: ApiQuickStatus = serde_json::from_str(r#"{ "buyMovingWeek": "9007199254740993" }"#).unwrap();
+        assert_eq!(big.buy_moving_week.value, 9_007_199_254_740_993);
+    }
+
+    #[test]
+    fn uncoercible_numbers_degrade_per_field_instead_of_failing_the_product() {
+        let prod: ApiProduct = serde_json::from_value(serde_json::json!({
+            "quick_status": { "buyPrice": null, "sellPrice": "ten", "buyMovingWeek": 1.5, "sellMovingWeek": 900,
+                              "buyVolume": { "n": 1 }, "sellVolume": [40] },
+            "buy_summary": [
+                { "amount": 64, "pricePerUnit": 10.0, "orders": 2 },
+                { "amount": 64.5, "pricePerUnit": 10.0, "orders": 2 },
+                { "amount": 64, "pricePerUnit": "NaN", "orders": 2 },
+                { "amount": 64, "pricePerUnit": 10.0, "orders": true }
+            ],
+            "sell_summary": [{ "amount": 10, "pricePerUnit": [1], "orders": 1 }]
+        })).unwrap();
+        let info = parse_product("WHEAT".into(), prod);
+        // Prices, counters and volumes that aren't numbers read as 0 (volumes as unknown) and are counted
+        assert_eq!((info.buy_price, info.sell_price, info.buy_moving_week, info.sell_moving_week), (0.0, 0.0, 0, 900));
+        assert_eq!((info.buy_volume, info.sell_volume, info.invalid_fields), (None, None, 5));
+        // A level with any such field is dropped as malformed, the rest of the book stays
+        assert_eq!((info.buy_orders.len(), info.sell_orders.len(), info.malformed_levels), (1, 0, 4));
+        assert_eq!(ProductMetricsState::defects(&info), 9);
+    }
+
+    #[test]
+    fn duplicate_product_ids_keep_the_first_entry() {
+        let mut states = HashMap::new();
+        let book = |amount| snapshot(vec![order(10.0, amount, 2)], vec![], 1_000, 2_000);
+        let outcome = apply_snapshot(&mut states, vec![book(100), book(40), book(70)], 1_700_000_000, None);
+        assert_eq!(outcome, ApplyOutcome { out_of_order: 0, duplicates: 2, missing_volume: 0 });
+        assert_eq!(states["TEST_ITEM"].snapshot_count, 1);
+
+        apply_snapshot(&mut states, vec![book(90), book(10)], 1_700_000_020, None);
+        // Diffed 100 -> 90; neither duplicate leaked into the state
+        assert_eq!(states["TEST_ITEM"].inferred_buy_volume_history, vec![10]);
+        assert_eq!(states["TEST_ITEM"].windows_processed, 1);
+    }
+
+    #[test]
+    fn timestamps_round_trip_in_both_formats() {
+        let epoch = Timestamps { values: vec![1_735_689_600, 1_735_689_620], format: TimestampFormat::Epoch };
+        let json = serde_json::to_string(&epoch).unwrap();
+        assert_eq!(json, "[1735689600,1735689620]");
+        assert_eq!(serde_json::from_str::<Timestamps>(&json).unwrap(), epoch);
+
+        let rfc3339 = Timestamps { format: TimestampFormat::Rfc3339, ..epoch.clone() };
+        let json = serde_json::to_string(&rfc3339).unwrap();
+        assert_eq!(json, r#"["2025-01-01T00:00:00Z","2025-01-01T00:00:20Z"]"#);
+        assert_eq!(serde_json::from_str::<Timestamps>(&json).unwrap(), rfc3339);
+        // Offsets are accepted and normalised to unix seconds
+        let offset: Timestamps = serde_json::from_str(r#"["2025-01-01T01:00:00+01:00"]"#).unwrap();
+        assert_eq!(offset.values, vec![1_735_689_600]);
+        assert!(serde_json::from_str::<Timestamps>(r#"["yesterday"]"#).is_err());
+    }
+
+    #[test]
+    fn last_prices_come_from_the_final_accepted_snapshot() {
+        let priced = |buy_price, sell_price| BazaarInfo { buy_price, sell_price, ..snapshot(vec![], vec![], 0, 0) };
+        let mut state = ProductMetricsState::new_at(&priced(10.0, 9.0), 1_700_000_000);
+        assert!(state.offer_at(&priced(12.0, 11.0), 1_700_000_020, None, 0.5));
+        assert!(state.offer_at(&BazaarInfo { buy_summary_missing: true, ..priced(14.0, 12.5) }, 1_700_000_040, None, 0.5));
+        assert!(!state.offer_at(&priced(99.0, 98.0), 1_700_000_030, None, 0.5));
+
+        let result = state.finalize_with_sequences("TEST_ITEM".to_string(), &AnalysisConfig::default());
+        assert_eq!((result.instabuy_price_last, result.instasell_price_last), (14.0, 12.5));
+        assert_eq!(result.instabuy_price_average, 12.0);
+    }
+
+    #[test]
+    fn welford_variance_matches_exact_batch_computation() {
+        // Multi-million coin prices in tenths, so the batch result can be computed exactly in integers
+        let tenths: Vec<i64> = (0..5_000i64).map(|i| 25_000_000 + (i * 7_919) % 1_013 - 500).collect();
+        let mut moments = RunningMoments::default();
+        for &price in &tenths {
+            moments.add(price as f64 / 10.0);
+        }
+
+        let (n, sum, sum_squares) = tenths.iter().fold((0i128, 0i128, 0i128), |(n, sum, squares), &x| {
+            (n + 1, sum + x as i128, squares + x as i128 * x as i128)
+        });
+        let exact_variance = (n * sum_squares - sum * sum) as f64 / (n * (n - 1) * 100) as f64;
+        let exact_mean = sum as f64 / n as f64 / 10.0;
+        assert!((moments.mean - exact_mean).abs() <= 1e-9 * exact_mean);
+        let variance = moments.sample_variance().unwrap();
+        assert!((variance - exact_variance).abs() <= 1e-9 * exact_variance, "{} vs {}", variance, exact_variance);
+
+        for &price in &tenths[..4_000] {
+            moments.remove(price as f64 / 10.0);
+        }
+        let mut trailing = RunningMoments::default();
+        tenths[4_000..].iter().for_each(|&price| trailing.add(price as f64 / 10.0));
+        assert!((moments.sample_variance().unwrap() - trailing.sample_variance().unwrap()).abs() <= 1e-6 * exact_variance);
+        assert_eq!(RunningMoments { count: 1, mean: 3.0, m2: 0.0 }.sample_variance(), None);
+    }
+
+    #[test]
+    fn time_weighted_price_weights_each_price_by_how_long_it_held() {
+        // 10 holds for 100s, 20 for 20s, 30 is the newest and has no duration yet
+        let priced = |buy_price| BazaarInfo { buy_price, ..snapshot(vec![], vec![], 0, 0) };
+        let mut state = ProductMetricsState::new_at(&priced(10.0), 1_700_000_000);
+        assert_eq!(state.finalize_with_sequences("TEST_ITEM".into(), &AnalysisConfig::default()).instabuy_price_time_weighted, None);
+        state.update_at(&priced(20.0), 1_700_000_100);
+        state.update_at(&priced(30.0), 1_700_000_120);
+        let result = state.finalize_with_sequences("TEST_ITEM".into(), &AnalysisConfig::default());
+        assert_eq!(result.instabuy_price_average, 20.0);
+        assert!((result.instabuy_price_time_weighted.unwrap() - 1_400.0 / 120.0).abs() < 1e-12);
+        assert_eq!(result.instasell_price_time_weighted, Some(9.0));
+
+        // Evicting the first window takes its 100s back out
+        state.trim_to(1);
+        assert_eq!(state.buy_price_time_weighted.mean(), Some(20.0));
+    }
+
+    #[test]
+    fn realized_volatility_matches_a_known_return_series() {
+        // +10%, -10%, flat over three minutes: (ln 1.1² + ln 0.9²) per 3 minutes, scaled by 20 to an hour
+        let prices = VecDeque::from([100.0, 110.0, 99.0, 99.0]);
+        let expected = ((1.1f64.ln().powi(2) + 0.9f64.ln().powi(2)) * 20.0).sqrt();
+        assert!((realized_volatility(&prices, 180).unwrap() - expected).abs() < 1e-12);
+
+        // Returns touching a zero or negative price are skipped, not clamped
+        let gappy = VecDeque::from([100.0, 0.0, 100.0, 110.0, -5.0]);
+        let expected = (1.1f64.ln().powi(2) * 20.0).sqrt();
+        assert!((realized_volatility(&gappy, 180).unwrap() - expected).abs() < 1e-12);
+        assert_eq!(realized_volatility(&VecDeque::from([0.0, 5.0]), 180), None);
+        assert_eq!(realized_volatility(&VecDeque::from([5.0]), 180), None);
+
+        // A one-coin tick on a multi-million price keeps its precision
+        let tick = realized_volatility(&VecDeque::from([2_500_000.0, 2_500_001.0]), 3_600).unwrap();
+        assert!((tick - 1.0 / 2_500_000.5).abs() < 1e-18);
+    }
+
+    fn pinned_flags(buy_prices: &[f64], epsilon: f64) -> (bool, bool) {
+        let priced = |buy_price| BazaarInfo { buy_price, ..snapshot(vec![], vec![], 0, 0) };
+        let mut state = ProductMetricsState::new_at(&priced(buy_prices[0]), 1_700_000_000);
+        for (i, &price) in buy_prices[1..].iter().enumerate() {
+            state.update_at(&priced(price), 1_700_000_020 + 20 * i as u64);
+        }
+        let analysis = AnalysisConfig { price_pin_epsilon: epsilon, ..Default::default() };
+        let result = state.finalize_with_sequences("TEST_ITEM".to_string(), &analysis);
+        (result.buy_price_pinned, result.sell_price_pinned)
+    }
+
+    #[test]
+    fn constant_price_is_pinned() {
+        assert_eq!(pinned_flags(&[2_500_000.3; 180], 1e-6), (true, true));
+        assert_eq!(pinned_flags(&[7.0], 1e-6), (true, true));
+    }
+
+    #[test]
+    fn perturbation_at_the_epsilon_boundary() {
+        let mut prices = vec![1_000.0; 30];
+        prices[17] = 1_000.5;
+        assert_eq!(pinned_flags(&prices, 0.5), (true, true));
+        assert_eq!(pinned_flags(&prices, 0.49), (false, true));
+        assert_eq!(pinned_flags(&prices, 1e-6), (false, true));
+    }
+
+    #[test]
+    fn book_shape_separates_flat_and_steep_books() {
+        let flat = vec![order(100.0, 250, 5), order(100.1, 250, 5), order(100.2, 250, 5), order(100.3, 250, 5)];
+        let steep = vec![order(100.0, 10, 1), order(110.0, 10, 1), order(130.0, 980, 1)];
+
+        let (flat_slope, flat_concentration) = BookShapeAccumulator::book_shape(&flat);
+        let (steep_slope, steep_concentration) = BookShapeAccumulator::book_shape(&steep);
+        assert!((flat_slope.unwrap() - 0.3 / 1000.0).abs() < 1e-12);
+        assert_eq!(flat_concentration, Some(0.25));
+        assert!((steep_slope.unwrap() - 30.0 / 1000.0).abs() < 1e-12);
+        assert!(steep_concentration.unwrap() > 0.96);
+
+        // A single level has no slope and an empty book has no shape at all
+        assert_eq!(BookShapeAccumulator::book_shape(&[order(5.0, 10, 1)]), (None, Some(1.0)));
+        assert_eq!(BookShapeAccumulator::book_shape(&[]), (None, None));
+    }
+
+    #[test]
+    fn book_shape_is_averaged_over_snapshots() {
+        let first = snapshot(vec![order(10.0, 50, 1), order(11.0, 50, 1)], vec![], 0, 0);
+        let mut state = ProductMetricsState::new_at(&first, 1_700_000_000);
+        state.update_at(&snapshot(vec![order(10.0, 100, 1)], vec![], 0, 0), 1_700_000_020);
+
+        let result = state.finalize_with_sequences("TEST_ITEM".to_string(), &AnalysisConfig::default());
+        assert_eq!(result.buy_book_slope, Some(0.01));
+        assert_eq!(result.buy_book_concentration, Some(0.75));
+        assert_eq!(result.sell_book_concentration, None);
+    }
+
+    #[test]
+    fn approximate_modes_keep_clusters_of_exactly_min_size() {
+        let intervals = [1.0, 1.1, 0.95, 5.0, 5.2];
+
+        let modes = ProductMetricsState::find_approximate_modes(&intervals, 0.25, 3);
+        assert_eq!(modes, vec![vec![1.0, 1.1, 0.95]]);
+
+        let modes = ProductMetricsState::find_approximate_modes(&intervals, 0.25, 2);
+        assert_eq!(modes, vec![vec![1.0, 1.1, 0.95], vec![5.0, 5.2]]);
+
+        assert!(ProductMetricsState::find_approximate_modes(&intervals, 0.25, 4).is_empty());
+    }
+
+    #[test]
+    fn approximate_modes_reject_invalid_tolerance_and_non_finite_values() {
+        let intervals = [1.0, 1.0, 1.0];
+        assert!(ProductMetricsState::find_approximate_modes(&intervals, 0.0, 3).is_empty());
+        assert!(ProductMetricsState::find_approximate_modes(&intervals, 1.0, 3).is_empty());
+        assert!(ProductMetricsState::find_approximate_modes(&intervals, f64::NAN, 3).is_empty());
+
+        let with_nan = [1.0, f64::NAN, 1.0, 1.0];
+        assert_eq!(ProductMetricsState::find_approximate_modes(&with_nan, 0.25, 3), vec![vec![1.0, 1.0, 1.0]]);
+
+        // Tiny intervals are compared against the floored base rather than dividing by ~0
+        let tiny = [0.001, 0.02, 0.01];
+        assert_eq!(ProductMetricsState::find_approximate_modes(&tiny, 0.25, 3).len(), 1);
+    }
+
+    // Regular 10-unit fills every other 20s window, enough for the velocity detector to fire
+    fn periodic_deltas(windows: usize) -> (Vec<i64>, Vec<u64>) {
+        let deltas = (0..windows).map(|i| if i % 2 == 0 { 10 } else { 0 }).collect();
+        let timestamps = (0..=windows as u64).map(|i| 1_700_000_000 + i * 20).collect();
+        (deltas, timestamps)
+    }
+
+    #[test]
+    fn modal_ratio_is_none_without_pattern_periods() {
+        let (deltas, timestamps) = periodic_deltas(20);
+        let inferred = vec![0; deltas.len()];
+
+        let activity = ActivityIndex::from_deltas(&deltas, &timestamps);
+        let (pattern, _) = ProductMetricsState::detect_fuzzy_modal_pattern(&activity, &deltas, &inferred, &timestamps, &DetectorConfig::default());
+        let pattern = pattern.expect("periodic deltas should produce a pattern");
+        assert_eq!(pattern.ratio, None);
+    }
+
+    #[test]
+    fn modal_ratio_is_moving_week_over_inferred_volume() {
+        let (deltas, timestamps) = periodic_deltas(20);
+        let inferred: Vec<i64> = deltas.iter().map(|&d| d / 2).collect();
+
+        let activity = ActivityIndex::from_deltas(&deltas, &timestamps);
+        let (pattern, _) = ProductMetricsState::detect_fuzzy_modal_pattern(&activity, &deltas, &inferred, &timestamps, &DetectorConfig::default());
+        assert_eq!(pattern.and_then(|p| p.ratio), Some(2.0));
+    }
+}
@@ -0,0 +1,165 @@
+// `--list-products`: one fetch of the live API, printed as product ids (or a table of prices and
+// moving-week volumes) for choosing PRODUCT_INCLUDE/PRODUCT_EXCLUDE patterns. No state is kept
+// and nothing is exported.
+use crate::BazaarInfo;
+use serde::Serialize;
+use std::fmt::Write;
+use std::str::FromStr;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SortBy {
+    // Busiest first, by buy + sell moving-week volume
+    Volume,
+    // Widest instabuy - instasell spread first
+    Spread,
+    Name,
+}
+
+impl FromStr for SortBy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "volume" => Ok(Self::Volume),
+            "spread" => Ok(Self::Spread),
+            "name" => Ok(Self::Name),
+            _ => Err(format!("Unknown --sort-by: {} (expected volume, spread or name)", s)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ListFormat {
+    Ids,
+    Table,
+    Json,
+}
+
+impl FromStr for ListFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "ids" => Ok(Self::Ids),
+            "table" => Ok(Self::Table),
+            "json" => Ok(Self::Json),
+            _ => Err(format!("Unknown --format: {} (expected ids, table or json)", s)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ProductRow {
+    pub product_id: String,
+    pub instabuy_price: f64,
+    pub instasell_price: f64,
+    pub spread: f64,
+    pub buy_moving_week: i64,
+    pub sell_moving_week: i64,
+}
+
+impl ProductRow {
+    fn of(info: &BazaarInfo) -> Self {
+        Self {
+            product_id: info.product_id.clone(),
+            instabuy_price: info.buy_price,
+            instasell_price: info.sell_price,
+            spread: info.buy_price - info.sell_price,
+            buy_moving_week: info.buy_moving_week,
+            sell_moving_week: info.sell_moving_week,
+        }
+    }
+
+    fn volume(&self) -> i64 {
+        self.buy_moving_week.saturating_add(self.sell_moving_week)
+    }
+}
+
+// Rows in `sort_by` order, ties broken by product id, cut to `limit` when given
+pub fn rows(products: &[BazaarInfo], sort_by: SortBy, limit: Option<usize>) -> Vec<ProductRow> {
+    let mut rows: Vec<ProductRow> = products.iter().map(ProductRow::of).collect();
+    rows.sort_by(|a, b| {
+        let order = match sort_by {
+            SortBy::Volume => b.volume().cmp(&a.volume()),
+            SortBy::Spread => b.spread.total_cmp(&a.spread),
+            SortBy::Name => std::cmp::Ordering::Equal,
+        };
+        order.then_with(|| a.product_id.cmp(&b.product_id))
+    });
+    rows.truncate(limit.unwrap_or(usize::MAX));
+    rows
+}
+
+pub fn render(rows: &[ProductRow], format: ListFormat) -> serde_json::Result<String> {
+    let mut out = String::new();
+    match format {
+        ListFormat::Ids => rows.iter().for_each(|row| {
+            let _ = writeln!(out, "{}", row.product_id);
+        }),
+        ListFormat::Json => out = serde_json::to_string_pretty(rows)? + "\n",
+        ListFormat::Table => {
+            let width = rows.iter().map(|row| row.product_id.len()).max().unwrap_or(0).max("PRODUCT_ID".len());
+            let _ = writeln!(out, "{:<width$}  {:>14}  {:>14}  {:>14}  {:>14}  {:>14}", "PRODUCT_ID",
+                "INSTABUY", "INSTASELL", "SPREAD", "BUY_MW", "SELL_MW");
+            for row in rows {
+                let _ = writeln!(out, "{:<width$}  {:>14.1}  {:>14.1}  {:>14.1}  {:>14}  {:>14}", row.product_id,
+                    row.instabuy_price, row.instasell_price, row.spread, row.buy_moving_week, row.sell_moving_week);
+            }
+        }
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn product(product_id: &str, buy_price: f64, sell_price: f64, buy_moving_week: i64, sell_moving_week: i64) -> BazaarInfo {
+        BazaarInfo {
+            product_id: product_id.to_string(),
+            buy_price,
+            sell_price,
+            buy_orders: vec![],
+            sell_orders: vec![],
+            buy_moving_week,
+            sell_moving_week,
+            buy_volume: None,
+            sell_volume: None,
+            buy_summary_missing: false,
+            sell_summary_missing: false,
+            malformed_levels: 0,
+            invalid_fields: 0,
+        }
+    }
+
+    #[test]
+    fn sorts_by_volume_spread_or_name_and_limits() {
+        let products = [
+            product("WHEAT", 3.0, 2.5, 900_000, 800_000),
+            product("ENCHANTED_DIAMOND", 1_600.0, 1_400.0, 20_000, 30_000),
+            product("DEAD_ITEM", 3.0, 1.0, 0, 0),
+            product("BOOSTER_COOKIE", 3_000_000.0, 2_900_000.0, 50_000, 50_000),
+        ];
+        let ids = |sort_by, limit| rows(&products, sort_by, limit).into_iter().map(|row| row.product_id).collect::<Vec<_>>();
+
+        assert_eq!(ids(SortBy::Volume, None), ["WHEAT", "BOOSTER_COOKIE", "ENCHANTED_DIAMOND", "DEAD_ITEM"]);
+        assert_eq!(ids(SortBy::Spread, Some(2)), ["BOOSTER_COOKIE", "ENCHANTED_DIAMOND"]);
+        assert_eq!(ids(SortBy::Name, Some(10)), ["BOOSTER_COOKIE", "DEAD_ITEM", "ENCHANTED_DIAMOND", "WHEAT"]);
+        assert_eq!("SPREAD".parse(), Ok(SortBy::Spread));
+        assert!("price".parse::<SortBy>().is_err());
+    }
+
+    #[test]
+    fn renders_ids_table_and_json() {
+        let rows = rows(&[product("WHEAT", 3.0, 2.5, 900, 800)], SortBy::Name, None);
+        assert_eq!(render(&rows, ListFormat::Ids).unwrap(), "WHEAT\n");
+
+        let table = render(&rows, ListFormat::Table).unwrap();
+        let lines: Vec<&str> = table.lines().collect();
+        assert!(lines[0].starts_with("PRODUCT_ID  "));
+        assert_eq!(lines[1].split_whitespace().collect::<Vec<_>>(), ["WHEAT", "3.0", "2.5", "0.5", "900", "800"]);
+
+        let json: serde_json::Value = serde_json::from_str(&render(&rows, ListFormat::Json).unwrap()).unwrap();
+        assert_eq!(json[0]["spread"], 0.5);
+    }
+}
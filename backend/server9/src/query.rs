@@ -0,0 +1,120 @@
+// Ad-hoc aggregations over a product's delta sequences, for metrics the crate doesn't export as a
+// field. Works on live state (POST /query) and on `delta_sequences` read back from an export.
+//
+// A spec names one sequence, one aggregation and an optional half-open window slice, e.g.
+//   { "sequence": "buy_moving_week", "aggregation": "count_nonzero", "start": 0, "end": 90 }
+// `end` past the last window is clamped. Mean, min and max of an empty slice are null.
+use crate::DeltaSequences;
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SequenceName {
+    BuyMovingWeek,
+    SellMovingWeek,
+    BuyOrders,
+    SellOrders,
+    BuyAmount,
+    SellAmount,
+    BuyInferredVolume,
+    SellInferredVolume,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Aggregation {
+    Sum,
+    Mean,
+    Min,
+    Max,
+    CountNonzero,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AggregationSpec {
+    pub sequence: SequenceName,
+    pub aggregation: Aggregation,
+    #[serde(default)]
+    pub start: Option<usize>,
+    #[serde(default)]
+    pub end: Option<usize>,
+}
+
+fn sequence(sequences: &DeltaSequences, name: SequenceName) -> &[i64] {
+    match name {
+        SequenceName::BuyMovingWeek => &sequences.buy_moving_week,
+        SequenceName::SellMovingWeek => &sequences.sell_moving_week,
+        SequenceName::BuyOrders => &sequences.buy_orders,
+        SequenceName::SellOrders => &sequences.sell_orders,
+        SequenceName::BuyAmount => &sequences.buy_amount,
+        SequenceName::SellAmount => &sequences.sell_amount,
+        SequenceName::BuyInferredVolume => &sequences.buy_inferred_volume,
+        SequenceName::SellInferredVolume => &sequences.sell_inferred_volume,
+    }
+}
+
+pub fn aggregate(sequences: &DeltaSequences, spec: &AggregationSpec) -> Result<Option<f64>, String> {
+    // Downsampled windows no longer add up to the cycle, so any answer would be silently wrong
+    if let Some(k) = sequences.downsampled_every {
+        return Err(format!("sequences were downsampled (every:{}) and can't be aggregated", k));
+    }
+    let values = sequence(sequences, spec.sequence);
+    let end = spec.end.unwrap_or(values.len()).min(values.len());
+    let start = spec.start.unwrap_or(0);
+    if start > end {
+        return Err(format!("window slice {}..{} is empty or reversed ({} windows)", start, end, values.len()));
+    }
+    let slice = &values[start..end];
+
+    Ok(match spec.aggregation {
+        Aggregation::Sum => Some(slice.iter().sum::<i64>() as f64),
+        Aggregation::Mean if slice.is_empty() => None,
+        Aggregation::Mean => Some(slice.iter().sum::<i64>() as f64 / slice.len() as f64),
+        Aggregation::Min => slice.iter().min().map(|&v| v as f64),
+        Aggregation::Max => slice.iter().max().map(|&v| v as f64),
+        Aggregation::CountNonzero => Some(slice.iter().filter(|&&v| v != 0).count() as f64),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn sequences(buy_moving_week: &[i64]) -> DeltaSequences {
+        let timestamps: Vec<u64> = (0..=buy_moving_week.len() as u64).map(|i| i * 20).collect();
+        serde_json::from_value(json!({
+            "buy_moving_week": buy_moving_week, "sell_moving_week": [],
+            "buy_orders": [], "sell_orders": [], "buy_amount": [], "sell_amount": [],
+            "timestamps": timestamps,
+        }))
+        .unwrap()
+    }
+
+    fn spec(aggregation: Aggregation, start: Option<usize>, end: Option<usize>) -> AggregationSpec {
+        AggregationSpec { sequence: SequenceName::BuyMovingWeek, aggregation, start, end }
+    }
+
+    #[test]
+    fn aggregations_over_whole_and_sliced_sequences() {
+        let seq = sequences(&[0, 64, 0, -3, 160, 64]);
+        assert_eq!(aggregate(&seq, &spec(Aggregation::Sum, None, None)), Ok(Some(285.0)));
+        assert_eq!(aggregate(&seq, &spec(Aggregation::Mean, Some(1), Some(3))), Ok(Some(32.0)));
+        assert_eq!(aggregate(&seq, &spec(Aggregation::Min, None, None)), Ok(Some(-3.0)));
+        assert_eq!(aggregate(&seq, &spec(Aggregation::Max, None, Some(4))), Ok(Some(64.0)));
+        assert_eq!(aggregate(&seq, &spec(Aggregation::CountNonzero, Some(2), Some(100))), Ok(Some(3.0)));
+        assert_eq!(aggregate(&seq, &spec(Aggregation::Mean, Some(6), None)), Ok(None));
+        assert_eq!(aggregate(&seq, &spec(Aggregation::Sum, Some(6), None)), Ok(Some(0.0)));
+        assert!(aggregate(&seq, &spec(Aggregation::Sum, Some(7), None)).is_err());
+    }
+
+    #[test]
+    fn specs_parse_from_json_and_reject_downsampled_sequences() {
+        let parsed: AggregationSpec = serde_json::from_value(json!({ "sequence": "sell_inferred_volume", "aggregation": "count_nonzero" })).unwrap();
+        assert_eq!((parsed.sequence, parsed.aggregation, parsed.start), (SequenceName::SellInferredVolume, Aggregation::CountNonzero, None));
+
+        let mut seq = sequences(&[1, 2, 3]);
+        seq.downsampled_every = Some(2);
+        assert!(aggregate(&seq, &spec(Aggregation::Sum, None, None)).is_err());
+    }
+}
@@ -0,0 +1,113 @@
+// Re-runs pattern detection over an existing export without re-collecting data.
+//
+// Recomputable: the `PatternFields` set (modal size/ratio/rate, scale factors,
+// pattern_detection_confidence, pattern_details), rebuilt from each product's `delta_sequences`
+// and its stored inferred coverage. Everything else (prices, offer and transaction averages,
+// volume totals, book shape) depends on raw snapshots that aren't in the file and is copied
+// through untouched. Exports that predate `buy_inferred_volume`/`sell_inferred_volume` can only
+// produce velocity/rhythm patterns: the legacy clustering and the modal ratio need those series.
+// Products exported with omitted or downsampled sequences (`DELTA_SEQUENCES`), or whose sequences
+// are misaligned, are skipped, and so are products the prefilter marked "skipped_inactive".
+use crate::config::DetectorConfig;
+use crate::{DeltaSequences, PatternFields};
+use serde_json::Value;
+use std::error::Error;
+
+pub struct RecomputeSummary {
+    pub recomputed: usize,
+    pub skipped: usize,
+}
+
+pub fn recompute_export(export: &mut Value, detector: &DetectorConfig) -> Result<RecomputeSummary, Box<dyn Error>> {
+    // A bare array, or the OUTPUT_META wrapper with the array under `results`
+    let products = match export {
+        Value::Object(document) => document.get_mut("results").and_then(Value::as_array_mut),
+        other => other.as_array_mut(),
+    }
+    .ok_or("Export is not a JSON array of products")?;
+    let mut summary = RecomputeSummary { recomputed: 0, skipped: 0 };
+
+    for product in products.iter_mut() {
+        let Some(fields) = recompute_product(product, detector) else {
+            summary.skipped += 1;
+            continue;
+        };
+        if let (Some(product), Value::Object(fields)) = (product.as_object_mut(), serde_json::to_value(fields)?) {
+            product.extend(fields);
+            summary.recomputed += 1;
+        }
+    }
+    Ok(summary)
+}
+
+fn recompute_product(product: &Value, detector: &DetectorConfig) -> Option<PatternFields> {
+    if product["pattern_details"]["detection_method"] == "skipped_inactive" {
+        return None;
+    }
+    let sequences: DeltaSequences = serde_json::from_value(product.get("delta_sequences")?.clone()).ok()?;
+    if sequences.downsampled_every.is_some() {
+        return None;
+    }
+    PatternFields::detect(
+        &sequences,
+        product.get("instabuy_inferred_coverage").and_then(Value::as_f64),
+        product.get("instasell_inferred_coverage").and_then(Value::as_f64),
+        &detector.for_product(product["product_id"].as_str().unwrap_or_default()),
+    ).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+
+    fn golden() -> Value {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/replay_golden.json");
+        serde_json::from_slice(&std::fs::read(path).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn recompute_with_unchanged_detectors_reproduces_export() {
+        let original = golden();
+        let mut recomputed = original.clone();
+
+        let summary = recompute_export(&mut recomputed, &DetectorConfig::default()).unwrap();
+        // DEAD_ITEM was skipped by the prefilter and is left as exported
+        assert_eq!(summary.recomputed, original.as_array().unwrap().len() - 1);
+        assert_eq!(summary.skipped, 1);
+        assert_eq!(recomputed, original);
+    }
+
+    #[test]
+    fn wrapped_exports_are_recomputed_in_place() {
+        let mut wrapped = serde_json::json!({ "schema_version": 2, "meta": {}, "results": golden() });
+        let summary = recompute_export(&mut wrapped, &DetectorConfig::default()).unwrap();
+        assert_eq!(summary.recomputed, golden().as_array().unwrap().len() - 1);
+        assert_eq!(wrapped["results"], golden());
+    }
+
+    #[test]
+    fn older_or_reduced_exports_recompute_what_they_can() {
+        let mut export = golden();
+        for product in export.as_array_mut().unwrap() {
+            let sequences = product["delta_sequences"].as_object_mut().unwrap();
+            sequences.remove("buy_inferred_volume");
+            sequences.remove("sell_inferred_volume");
+        }
+        export.as_array_mut().unwrap().push(serde_json::json!({ "product_id": "NO_SEQUENCES" }));
+        export.as_array_mut().unwrap().push(serde_json::json!({ "product_id": "OMITTED", "delta_sequences": null }));
+        let mut downsampled = export[0].clone();
+        downsampled["delta_sequences"]["downsampled_every"] = 4.into();
+        export.as_array_mut().unwrap().push(downsampled);
+
+        let summary = recompute_export(&mut export, &DetectorConfig::default()).unwrap();
+        assert_eq!(summary.skipped, 4);
+        let diamond = export.as_array().unwrap().iter()
+            .find(|p| p["product_id"] == "ENCHANTED_DIAMOND")
+            .unwrap();
+        assert_eq!(diamond["instabuy_modal_size"], 160.0);
+        assert!(diamond["instabuy_modal_ratio"].is_null());
+        // Fields outside PatternFields are preserved verbatim
+        assert_eq!(diamond["instabuy_price_average"], 1620.5);
+    }
+}
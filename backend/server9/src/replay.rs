@@ -0,0 +1,200 @@
+use crate::capture::{list_captures, meta_path, CaptureMeta};
+use crate::config::AnalysisConfig;
+use crate::{apply_snapshot_with, parse_bazaar_products, parse_http_date, ApiResponse, ProductMetricsState, Snapshot};
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs;
+use std::path::Path;
+use std::time::{Duration, Instant};
+use tokio::time::sleep;
+
+const PROGRESS_LOG_INTERVAL: Duration = Duration::from_secs(2);
+
+fn load_meta(path: &Path) -> Result<CaptureMeta, Box<dyn Error>> {
+    Ok(serde_json::from_slice(&fs::read(meta_path(path))?)?)
+}
+
+pub fn load_snapshot(path: &Path) -> Result<Snapshot, Box<dyn Error>> {
+    let meta = load_meta(path)?;
+    let response: ApiResponse = serde_json::from_slice(&fs::read(path)?)?;
+    // BTreeMap order, so products come out sorted by id
+    let products = parse_bazaar_products(response.products);
+    let last_modified = meta.last_modified.as_deref().and_then(parse_http_date);
+    Ok(Snapshot { captured_at: meta.captured_at, last_modified, polls_skipped: 0, products })
+}
+
+// How long to wait before feeding a snapshot: the recorded gap divided by `speed`, or not at all
+// when `speed` is 0 (as fast as possible)
+fn pacing_delay(prev_captured_at: u64, captured_at: u64, speed: f64) -> Duration {
+    if speed <= 0.0 {
+        return Duration::ZERO;
+    }
+    Duration::from_secs_f64(captured_at.saturating_sub(prev_captured_at) as f64 / speed)
+}
+
+// Paced runs know their remaining recorded time; unpaced runs extrapolate from throughput so far
+fn replay_eta(elapsed: Duration, processed: usize, total: usize, recorded_remaining_secs: u64, speed: f64) -> Duration {
+    if speed > 0.0 {
+        return Duration::from_secs_f64(recorded_remaining_secs as f64 / speed);
+    }
+    if processed == 0 {
+        return Duration::ZERO;
+    }
+    elapsed.mul_f64((total - processed) as f64 / processed as f64)
+}
+
+// Replays a `--capture` directory in recording order through the same update path as the live
+// loop, optionally in (scaled) real time. The recorded timestamps are what reach the detectors
+// regardless of playback speed. Progress goes to stderr so stdout stays valid JSON.
+pub async fn replay_dir(dir: &Path, speed: f64, analysis: &AnalysisConfig) -> Result<HashMap<String, ProductMetricsState>, Box<dyn Error>> {
+    if !speed.is_finite() || speed < 0.0 {
+        return Err(format!("--speed must be a non-negative number, got {}", speed).into());
+    }
+    let paths = list_captures(dir)?;
+    let captured: Vec<u64> = paths.iter().map(|path| load_meta(path).map(|m| m.captured_at)).collect::<Result<_, _>>()?;
+    let total = paths.len();
+    let last_captured_at = captured.last().copied().unwrap_or(0);
+
+    eprintln!("[GiantWizard] Replaying {} snapshots from {} at speed {}", total, dir.display(), speed);
+    let started = Instant::now();
+    let mut last_log = started;
+    let mut states = HashMap::new();
+
+    for (i, path) in paths.iter().enumerate() {
+        if i > 0 {
+            let delay = pacing_delay(captured[i - 1], captured[i], speed);
+            if !delay.is_zero() {
+                sleep(delay).await;
+            }
+        }
+        let snapshot = load_snapshot(path)?;
+        let outcome = apply_snapshot_with(&mut states, snapshot.products, snapshot.captured_at, snapshot.last_modified, 0, analysis.counter_reset_fraction);
+        if outcome.out_of_order > 0 {
+            eprintln!("[GiantWizard] {} is out of order for {} products; skipped", path.display(), outcome.out_of_order);
+        }
+        if outcome.duplicates > 0 {
+            eprintln!("[GiantWizard] {} has {} duplicate product entries; kept the first of each", path.display(), outcome.duplicates);
+        }
+        if outcome.missing_volume > 0 {
+            eprintln!("[GiantWizard] {} has {} products without quick_status buyVolume/sellVolume", path.display(), outcome.missing_volume);
+        }
+
+        let processed = i + 1;
+        if processed == total || last_log.elapsed() >= PROGRESS_LOG_INTERVAL {
+            let max_windows = states.values().map(|s: &ProductMetricsState| s.windows_processed).max().unwrap_or(0);
+            let eta = replay_eta(started.elapsed(), processed, total, last_captured_at.saturating_sub(captured[i]), speed);
+            eprintln!("[GiantWizard] Replay progress: {}/{} snapshots, {}/{} windows, ETA {}s",
+                processed, total, max_windows, analysis.target_windows, eta.as_secs());
+            last_log = Instant::now();
+        }
+    }
+    Ok(states)
+}
+
+// The recorded corpus the tests replay (tests/fixtures/replay): its states under `analysis`, or
+// the cycle they finalize to with the default analysis
+#[cfg(test)]
+pub(crate) async fn replay_states(analysis: &AnalysisConfig) -> HashMap<String, ProductMetricsState> {
+    replay_dir(&Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/replay"), 0.0, analysis).await.unwrap()
+}
+
+#[cfg(test)]
+pub(crate) async fn replay_cycle() -> Vec<crate::AnalysisResult> {
+    let analysis = AnalysisConfig::default();
+    crate::finalize_all(&replay_states(&analysis).await, &analysis)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{AnalysisConfig, DetectorOverride};
+    use crate::{finalize_all, SCHEMA_VERSION};
+    use serde_json::Value;
+    use std::collections::BTreeSet;
+    use std::path::PathBuf;
+
+    fn fixtures_dir() -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures")
+    }
+
+    // Every key of a serialized value, nested ones as `parent.child`; array elements share their
+    // array's path
+    fn serialized_fields(value: &Value, prefix: &str, fields: &mut BTreeSet<String>) {
+        match value {
+            Value::Object(object) => {
+                for (key, child) in object {
+                    let path = format!("{}{}", prefix, key);
+                    serialized_fields(child, &format!("{}.", path), fields);
+                    fields.insert(path);
+                }
+            }
+            Value::Array(items) => items.iter().for_each(|item| serialized_fields(item, prefix, fields)),
+            _ => {}
+        }
+    }
+
+    #[test]
+    fn pacing_scales_recorded_gaps_by_speed() {
+        assert_eq!(pacing_delay(100, 120, 0.0), Duration::ZERO);
+        assert_eq!(pacing_delay(100, 120, 1.0), Duration::from_secs(20));
+        assert_eq!(pacing_delay(100, 120, 10.0), Duration::from_secs(2));
+        // Out-of-order recordings never produce a negative wait
+        assert_eq!(pacing_delay(120, 100, 1.0), Duration::ZERO);
+    }
+
+    #[test]
+    fn eta_uses_recorded_time_when_paced_and_throughput_otherwise() {
+        assert_eq!(replay_eta(Duration::from_secs(5), 10, 30, 400, 2.0), Duration::from_secs(200));
+        assert_eq!(replay_eta(Duration::from_secs(5), 10, 30, 400, 0.0), Duration::from_secs(10));
+    }
+
+    #[tokio::test]
+    async fn rejects_invalid_speed() {
+        let dir = fixtures_dir().join("replay");
+        assert!(replay_dir(&dir, -1.0, &AnalysisConfig::default()).await.is_err());
+        assert!(replay_dir(&dir, f64::NAN, &AnalysisConfig::default()).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn detector_overrides_only_touch_matching_products() {
+        let states = replay_states(&AnalysisConfig::default()).await;
+        let mut analysis = AnalysisConfig::default();
+        let defaults = serde_json::to_value(finalize_all(&states, &analysis)).unwrap();
+        analysis.detector.overrides.insert("WHEAT".into(), DetectorOverride { min_cluster_size: Some(50), rhythm_tolerances: None });
+        let tuned = serde_json::to_value(finalize_all(&states, &analysis)).unwrap();
+
+        for (before, after) in defaults.as_array().unwrap().iter().zip(tuned.as_array().unwrap()) {
+            if before["product_id"] == "WHEAT" {
+                assert_ne!(before["pattern_details"], after["pattern_details"]);
+            } else {
+                assert_eq!(before, after);
+            }
+        }
+    }
+
+    // External tools key on these names, so adding, removing or renaming a field must be deliberate:
+    // bump SCHEMA_VERSION, then regenerate the list with
+    //   UPDATE_OUTPUT_FIELDS=1 cargo test output_fields_match_committed_list
+    #[tokio::test]
+    async fn output_fields_match_committed_list() {
+        let mut fields = BTreeSet::new();
+        serialized_fields(&serde_json::to_value(replay_cycle().await).unwrap(), "", &mut fields);
+        let list_path = fixtures_dir().join("output_fields.json");
+        let committed: Value = serde_json::from_slice(&fs::read(&list_path).unwrap()).unwrap();
+        let committed_fields: BTreeSet<String> = serde_json::from_value(committed["fields"].clone()).unwrap();
+
+        if std::env::var("UPDATE_OUTPUT_FIELDS").is_ok() {
+            assert!(committed_fields == fields || committed["schema_version"] != SCHEMA_VERSION,
+                "output fields changed; bump SCHEMA_VERSION before updating the list");
+            let list = serde_json::json!({ "schema_version": SCHEMA_VERSION, "fields": fields });
+            fs::write(&list_path, serde_json::to_string_pretty(&list).unwrap() + "\n").unwrap();
+            return;
+        }
+
+        let added: Vec<_> = fields.difference(&committed_fields).collect();
+        let removed: Vec<_> = committed_fields.difference(&fields).collect();
+        assert!(added.is_empty() && removed.is_empty(),
+            "output fields changed (added {:?}, removed {:?}); bump SCHEMA_VERSION and update {}", added, removed, list_path.display());
+        assert_eq!(committed["schema_version"], SCHEMA_VERSION, "{} was recorded for another schema version", list_path.display());
+    }
+}
@@ -0,0 +1,80 @@
+// Per-cycle resource self-report, for sizing containers as the catalog grows: resident memory now
+// and at its peak over the process lifetime (VmRSS / VmHWM in /proc/self/status), and the CPU time,
+// user + system across all threads, spent since the previous cycle (/proc/self/stat). Reading two
+// small procfs files once a cycle costs nothing measurable, so it is always on. Off Linux there
+// is no procfs and no report.
+use serde::Serialize;
+use std::fs;
+
+// /proc/self/stat counts CPU time in clock ticks of USER_HZ, which Linux fixes at 100 for userspace
+const CLOCK_TICKS_PER_SEC: f64 = 100.0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct ResourceUsage {
+    pub rss_bytes: u64,
+    pub peak_rss_bytes: u64,
+    // Since the previous sample; the whole process lifetime on the first
+    pub cpu_secs: f64,
+    pub cpu_total_secs: f64,
+}
+
+#[derive(Debug, Default)]
+pub struct ResourceTracker {
+    last_cpu_secs: f64,
+}
+
+impl ResourceTracker {
+    pub fn sample(&mut self) -> Option<ResourceUsage> {
+        let (rss_bytes, peak_rss_bytes) = parse_status(&fs::read_to_string("/proc/self/status").ok()?)?;
+        let cpu_total_secs = parse_stat_cpu_secs(&fs::read_to_string("/proc/self/stat").ok()?)?;
+        let cpu_secs = cpu_total_secs - self.last_cpu_secs;
+        self.last_cpu_secs = cpu_total_secs;
+        Some(ResourceUsage { rss_bytes, peak_rss_bytes, cpu_secs, cpu_total_secs })
+    }
+}
+
+// "VmRSS:    12345 kB" and "VmHWM:    23456 kB"
+fn parse_status(status: &str) -> Option<(u64, u64)> {
+    let kilobytes = |label: &str| {
+        status.lines()
+            .find_map(|line| line.strip_prefix(label))
+            .and_then(|rest| rest.split_whitespace().next()?.parse::<u64>().ok())
+            .map(|kb| kb * 1024)
+    };
+    Some((kilobytes("VmRSS:")?, kilobytes("VmHWM:")?))
+}
+
+// utime and stime are fields 14 and 15; the command name (field 2) can contain spaces and
+// parentheses, so fields are counted from the last ')'
+fn parse_stat_cpu_secs(stat: &str) -> Option<f64> {
+    let fields: Vec<&str> = stat.get(stat.rfind(')')? + 1..)?.split_whitespace().collect();
+    let ticks = |field: usize| fields.get(field - 3)?.parse::<u64>().ok();
+    Some((ticks(14)? + ticks(15)?) as f64 / CLOCK_TICKS_PER_SEC)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn status_and_stat_lines_parse_into_bytes_and_seconds() {
+        let status = "Name:\ttimestamp_gen\nVmHWM:\t  204800 kB\nVmRSS:\t  102400 kB\nThreads:\t9\n";
+        assert_eq!(parse_status(status), Some((100 * 1024 * 1024, 200 * 1024 * 1024)));
+        assert_eq!(parse_status("Name:\tx\n"), None);
+
+        let stat = "4242 (a (weird) name) S 1 4242 4242 0 -1 4194560 900 0 0 0 250 75 0 0 20 0 9 0 100 0";
+        assert_eq!(parse_stat_cpu_secs(stat), Some(3.25));
+        assert_eq!(parse_stat_cpu_secs("4242 (truncated"), None);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn samples_this_process_on_linux() {
+        let mut tracker = ResourceTracker::default();
+        let first = tracker.sample().unwrap();
+        assert!(first.rss_bytes > 0 && first.peak_rss_bytes >= first.rss_bytes);
+        let second = tracker.sample().unwrap();
+        assert!(second.cpu_total_secs >= first.cpu_total_secs);
+        assert_eq!(second.cpu_secs, second.cpu_total_secs - first.cpu_total_secs);
+    }
+}
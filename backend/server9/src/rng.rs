@@ -0,0 +1,42 @@
+// The one seedable RNG used wherever this crate needs randomness, so a run can be reproduced by
+// pinning WIZ_SEED. Current consumers:
+//   --dump-fixtures  noise and fill sizes of the synthetic corpus (WIZ_SEED overrides the
+//                    config file's `seed`; both default to DEFAULT_SEED)
+//   --serve-fixture  the `--fixture-jitter` factors (WIZ_SEED, else DEFAULT_SEED)
+// Hash sharding uses FNV-1a of the product id and needs no seed. Anything random added later
+// (backoff jitter, sampling) should take its generator from here, seeded from WIZ_SEED.
+pub const DEFAULT_SEED: u64 = 42;
+
+// SplitMix64: tiny, seedable and stable across platforms and releases
+pub struct SplitMix64(pub u64);
+
+impl SplitMix64 {
+    pub fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+
+    // Uniform in low..=high
+    pub fn range(&mut self, low: i64, high: i64) -> i64 {
+        if high <= low {
+            return low;
+        }
+        low + (self.next_u64() % (high - low + 1) as u64) as i64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sequence_matches_the_reference_splitmix64() {
+        let mut rng = SplitMix64(0);
+        assert_eq!(rng.next_u64(), 0xE220_A839_7B1D_CDAF);
+        assert_eq!(rng.next_u64(), 0x6E78_9E6A_A1B9_65F4);
+        assert!((0..1_000).all(|_| (-3..=3).contains(&rng.range(-3, 3))));
+    }
+}
@@ -0,0 +1,78 @@
+// `--self-test`: the full pipeline on a synthetic corpus, in memory and offline, as a quick check
+// that this binary works where it is deployed (CI, or a container readiness gate). The default
+// `--dump-fixtures` corpus goes through the live update path (`apply_snapshot`), `finalize_all`
+// and the JSON export, and passes when:
+//   - the export passes `--verify` (finite fields, sequence lengths, confidences, schema)
+//   - every synthetic product is exported with a full window buffer
+//   - detection finds the modal size of each behavior that has one (its `truth.json` label) within
+//     SIZE_TOLERANCE
+// The detector runs with its defaults, not DETECTOR_OVERRIDES, so the expectations hold anywhere.
+// No network, MEGA or disk is touched.
+use crate::calibrate::load_truth;
+use crate::config::AnalysisConfig;
+use crate::synthetic::{snapshots, truth, SyntheticConfig};
+use crate::{apply_snapshot, finalize_all, parse_bazaar_products, verify, ApiResponse, ProductMetricsState};
+use std::collections::HashMap;
+use std::error::Error;
+use std::time::{Duration, Instant};
+
+const SIZE_TOLERANCE: f64 = 0.1;
+
+#[derive(Debug)]
+pub struct SelfTestReport {
+    pub snapshots: usize,
+    pub products: usize,
+    pub elapsed: Duration,
+    pub problems: Vec<String>,
+}
+
+pub fn self_test() -> Result<SelfTestReport, Box<dyn Error>> {
+    let started = Instant::now();
+    let config = SyntheticConfig::default();
+    let analysis = AnalysisConfig::default();
+
+    let mut states = HashMap::new();
+    for snapshot in snapshots(&config) {
+        let response: ApiResponse = serde_json::from_str(&snapshot.body.to_string())?;
+        apply_snapshot(&mut states, parse_bazaar_products(response.products), snapshot.captured_at, None);
+    }
+    let results = finalize_all(&states, &analysis);
+    let mut problems = verify::verify(&serde_json::to_vec(&results)?);
+
+    for product in &config.products {
+        match results.iter().find(|result| result.product_id == product.id) {
+            None => problems.push(format!("{}: not exported", product.id)),
+            Some(result) => {
+                let windows = result.delta_sequences.as_ref().map_or(0, |sequences| sequences.buy_moving_week.len());
+                if windows != analysis.target_windows {
+                    problems.push(format!("{}: {} windows exported, expected {}", product.id, windows, analysis.target_windows));
+                }
+            }
+        }
+    }
+
+    let labels = load_truth(&serde_json::to_vec(&truth(&config))?)?;
+    for result in &results {
+        let Some(label) = labels.get(&result.product_id) else { continue };
+        let sides = [("instabuy", label.instabuy_modal_size, result.instabuy_modal_size), ("instasell", label.instasell_modal_size, result.instasell_modal_size)];
+        for (side, expected, detected) in sides {
+            if let Some(expected) = expected.filter(|&size| ProductMetricsState::relative_diff(size, detected) > SIZE_TOLERANCE) {
+                problems.push(format!("{}: {} modal size {}, expected {}", result.product_id, side, detected, expected));
+            }
+        }
+    }
+
+    Ok(SelfTestReport { snapshots: config.snapshots, products: results.len(), elapsed: started.elapsed(), problems })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn self_test_passes_on_this_build() {
+        let report = self_test().unwrap();
+        assert_eq!(report.problems, Vec::<String>::new());
+        assert_eq!((report.snapshots, report.products), (181, 4));
+    }
+}
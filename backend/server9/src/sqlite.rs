@@ -0,0 +1,214 @@
+// Optional SQLite sink (SQLITE_PATH): every exported cycle is also inserted into a local database,
+// so history can be queried with plain SQL instead of by walking metrics_*.json files.
+//
+// Schema, created on first use:
+//   metrics(id INTEGER PRIMARY KEY, product_id TEXT, captured_at INTEGER, windows INTEGER,
+//           one column per scalar `AnalysisResult` field (see METRIC_COLUMNS), detection_method TEXT)
+//     index metrics_product_time ON (product_id, captured_at)
+//   delta_sequences(metric_id INTEGER PRIMARY KEY REFERENCES metrics(id), sequences TEXT)
+//     the product's `delta_sequences` as JSON, only with SQLITE_SEQUENCES=1
+//
+// `captured_at` is the unix time of the export and `windows` the cycle's window count. Booleans
+// are stored as 0/1 and `market_activity` as its name. Columns added to METRIC_COLUMNS later are
+// added to an existing database on open; older rows read NULL for them.
+use crate::AnalysisResult;
+use rusqlite::types::Value as SqlValue;
+use rusqlite::{params_from_iter, Connection};
+use serde_json::Value;
+use std::error::Error;
+use std::path::Path;
+
+pub const METRIC_COLUMNS: &[(&str, &str)] = &[
+    ("schema_version", "INTEGER"),
+    ("display_name", "TEXT"),
+    ("instabuy_price_average", "REAL"),
+    ("instasell_price_average", "REAL"),
+    ("instabuy_price_time_weighted", "REAL"),
+    ("instasell_price_time_weighted", "REAL"),
+    ("instabuy_price_last", "REAL"),
+    ("instasell_price_last", "REAL"),
+    ("instabuy_price_variance", "REAL"),
+    ("instasell_price_variance", "REAL"),
+    ("instabuy_realized_vol", "REAL"),
+    ("instasell_realized_vol", "REAL"),
+    ("instabuy_price_relative", "REAL"),
+    ("instasell_price_relative", "REAL"),
+    ("buy_price_pinned", "INTEGER"),
+    ("sell_price_pinned", "INTEGER"),
+    ("buy_standing_volume_average", "REAL"),
+    ("sell_standing_volume_average", "REAL"),
+    ("buy_quickstatus_topofbook_divergence", "REAL"),
+    ("sell_quickstatus_topofbook_divergence", "REAL"),
+    ("new_demand_offers_per_hour", "REAL"),
+    ("new_demand_offer_size_average", "REAL"),
+    ("player_instabuy_transactions_per_hour", "REAL"),
+    ("player_instabuy_transaction_size_average", "REAL"),
+    ("new_supply_offers_per_hour", "REAL"),
+    ("new_supply_offer_size_average", "REAL"),
+    ("player_instasell_transactions_per_hour", "REAL"),
+    ("player_instasell_transaction_size_average", "REAL"),
+    ("instabuy_modal_size", "REAL"),
+    ("instabuy_modal_ratio", "REAL"),
+    ("instabuy_pattern_per_hour", "REAL"),
+    ("instabuy_scale_factor", "REAL"),
+    ("instabuy_estimated_true_volume", "REAL"),
+    ("instasell_modal_size", "REAL"),
+    ("instasell_modal_ratio", "REAL"),
+    ("instasell_pattern_per_hour", "REAL"),
+    ("instasell_scale_factor", "REAL"),
+    ("instasell_estimated_true_volume", "REAL"),
+    ("instabuy_moving_week_volume", "REAL"),
+    ("instabuy_inferred_volume", "REAL"),
+    ("instabuy_inferred_coverage", "REAL"),
+    ("instasell_moving_week_volume", "REAL"),
+    ("instasell_inferred_volume", "REAL"),
+    ("instasell_inferred_coverage", "REAL"),
+    ("buy_mw_hourly_rate", "REAL"),
+    ("sell_mw_hourly_rate", "REAL"),
+    ("instabuy_inference_correlation", "REAL"),
+    ("instabuy_inference_ratio", "REAL"),
+    ("instasell_inference_correlation", "REAL"),
+    ("instasell_inference_ratio", "REAL"),
+    ("flash_order_count", "INTEGER"),
+    ("buy_counter_resets", "INTEGER"),
+    ("sell_counter_resets", "INTEGER"),
+    ("market_activity", "TEXT"),
+    ("window_coverage", "REAL"),
+    ("extrapolated", "INTEGER"),
+    ("data_quality", "REAL"),
+    ("instabuy_estimated_true_volume_extrapolated", "REAL"),
+    ("instabuy_inferred_volume_extrapolated", "REAL"),
+    ("instasell_estimated_true_volume_extrapolated", "REAL"),
+    ("instasell_inferred_volume_extrapolated", "REAL"),
+    ("buy_book_slope", "REAL"),
+    ("buy_book_concentration", "REAL"),
+    ("sell_book_slope", "REAL"),
+    ("sell_book_concentration", "REAL"),
+    ("buy_spike_detected", "INTEGER"),
+    ("buy_spike_magnitude", "REAL"),
+    ("buy_spike_window", "INTEGER"),
+    ("sell_spike_detected", "INTEGER"),
+    ("sell_spike_magnitude", "REAL"),
+    ("sell_spike_window", "INTEGER"),
+    ("volume_vs_baseline", "REAL"),
+    ("spread_vs_baseline", "REAL"),
+    ("detection_stability", "REAL"),
+    ("pattern_detection_confidence", "REAL"),
+];
+
+pub struct SqliteExporter {
+    conn: Connection,
+    include_sequences: bool,
+}
+
+impl SqliteExporter {
+    pub fn open(path: &Path, include_sequences: bool) -> Result<Self, Box<dyn Error>> {
+        let conn = Connection::open(path)?;
+        let columns: Vec<String> = METRIC_COLUMNS.iter().map(|(name, kind)| format!("{} {}", name, kind)).collect();
+        conn.execute_batch(&format!(
+            "CREATE TABLE IF NOT EXISTS metrics (
+                id INTEGER PRIMARY KEY,
+                product_id TEXT NOT NULL,
+                captured_at INTEGER NOT NULL,
+                windows INTEGER NOT NULL,
+                {},
+                detection_method TEXT
+            );
+            CREATE INDEX IF NOT EXISTS metrics_product_time ON metrics (product_id, captured_at);
+            CREATE TABLE IF NOT EXISTS delta_sequences (
+                metric_id INTEGER PRIMARY KEY REFERENCES metrics (id),
+                sequences TEXT NOT NULL
+            );",
+            columns.join(",\n                "),
+        ))?;
+
+        let existing: Vec<String> = conn.prepare("SELECT name FROM pragma_table_info('metrics')")?
+            .query_map([], |row| row.get(0))?
+            .collect::<Result<_, _>>()?;
+        for (name, kind) in METRIC_COLUMNS.iter().filter(|(name, _)| !existing.iter().any(|e| e == name)) {
+            conn.execute_batch(&format!("ALTER TABLE metrics ADD COLUMN {} {}", name, kind))?;
+        }
+        Ok(Self { conn, include_sequences })
+    }
+
+    // One transaction per cycle: either every product of the cycle is stored or none is
+    pub fn insert_cycle(&mut self, captured_at: u64, windows: usize, results: &[AnalysisResult]) -> Result<usize, Box<dyn Error>> {
+        let names: Vec<&str> = METRIC_COLUMNS.iter().map(|(name, _)| *name).collect();
+        let insert = format!(
+            "INSERT INTO metrics (product_id, captured_at, windows, {}, detection_method) VALUES ({})",
+            names.join(", "),
+            vec!["?"; names.len() + 4].join(", "),
+        );
+
+        let tx = self.conn.transaction()?;
+        {
+            let mut metrics = tx.prepare(&insert)?;
+            let mut sequences = tx.prepare("INSERT INTO delta_sequences (metric_id, sequences) VALUES (?, ?)")?;
+            for result in results {
+                let row = serde_json::to_value(result)?;
+                let mut values = vec![
+                    to_sql(&row["product_id"]),
+                    SqlValue::Integer(captured_at as i64),
+                    SqlValue::Integer(windows as i64),
+                ];
+                values.extend(names.iter().map(|name| to_sql(&row[*name])));
+                values.push(to_sql(&row["pattern_details"]["detection_method"]));
+                metrics.execute(params_from_iter(values))?;
+
+                if self.include_sequences && !row["delta_sequences"].is_null() {
+                    sequences.execute((tx.last_insert_rowid(), row["delta_sequences"].to_string()))?;
+                }
+            }
+        }
+        tx.commit()?;
+        Ok(results.len())
+    }
+}
+
+fn to_sql(value: &Value) -> SqlValue {
+    match value {
+        Value::Bool(b) => SqlValue::Integer(*b as i64),
+        Value::Number(n) => n.as_i64().map(SqlValue::Integer).unwrap_or_else(|| SqlValue::Real(n.as_f64().unwrap_or_default())),
+        Value::String(s) => SqlValue::Text(s.clone()),
+        Value::Null | Value::Array(_) | Value::Object(_) => SqlValue::Null,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::replay::replay_cycle;
+
+    #[tokio::test]
+    async fn cycles_are_queryable_by_product_and_time() {
+        let results = replay_cycle().await;
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("metrics.db");
+
+        let mut sink = SqliteExporter::open(&path, true).unwrap();
+        sink.insert_cycle(1_735_693_200, 180, &results).unwrap();
+        let mut sink = SqliteExporter::open(&path, false).unwrap();
+        sink.insert_cycle(1_735_696_800, 180, &results).unwrap();
+
+        let (count, price, activity): (i64, f64, String) = sink.conn.query_row(
+            "SELECT COUNT(*), MAX(instabuy_price_average), MAX(market_activity) FROM metrics WHERE product_id = 'ENCHANTED_DIAMOND'",
+            [], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+        ).unwrap();
+        let diamond = results.iter().find(|r| r.product_id == "ENCHANTED_DIAMOND").unwrap();
+        assert_eq!((count, price), (2, diamond.instabuy_price_average));
+        assert_eq!(activity, serde_json::to_value(diamond.market_activity).unwrap());
+        let stored: i64 = sink.conn.query_row("SELECT COUNT(*) FROM delta_sequences", [], |row| row.get(0)).unwrap();
+        assert_eq!(stored, results.len() as i64);
+    }
+
+    #[test]
+    fn every_scalar_result_field_has_a_column() {
+        let corpus = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/replay_golden.json");
+        let golden: Value = serde_json::from_slice(&std::fs::read(corpus).unwrap()).unwrap();
+        let fields = golden[0].as_object().unwrap().keys()
+            .filter(|key| !matches!(key.as_str(), "product_id" | "delta_sequences" | "pattern_details"));
+        for field in fields {
+            assert!(METRIC_COLUMNS.iter().any(|(name, _)| name == field), "no column for {}", field);
+        }
+    }
+}
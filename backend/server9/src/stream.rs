@@ -0,0 +1,174 @@
+// Optional NATS sink (NATS_URL): every exported cycle is also published for event-driven
+// consumers, one message per product on `<NATS_SUBJECT>.<product_id>` carrying that product's
+// `AnalysisResult` as JSON. Dots and whitespace in product ids become `_` so each id stays a
+// single subject token.
+//
+// A cycle that can't be delivered (broker unreachable, or the publishes not flushed within the
+// timeout) is queued whole as `stream_pending/<stamp>.json` next to the exports and re-published,
+// oldest first, ahead of the next cycle. A cycle that failed half-way is re-sent whole, so
+// consumers should expect the occasional duplicate.
+use crate::atomic::write_atomic;
+use crate::AnalysisResult;
+use async_nats::ConnectOptions;
+use serde::Serialize;
+use serde_json::Value;
+use std::error::Error;
+use std::fs;
+use std::path::PathBuf;
+use std::time::Duration;
+
+pub struct NatsSink {
+    pub url: String,
+    pub subject: String,
+    pub token: Option<String>,
+    pub pending_dir: PathBuf,
+    pub timeout: Duration,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct StreamReport {
+    pub published: usize,
+    // Earlier cycles delivered from the pending queue on this attempt, and how many still wait
+    pub replayed: usize,
+    pub pending: usize,
+    pub error: Option<String>,
+}
+
+impl NatsSink {
+    pub async fn publish_cycle(&self, stamp: &str, results: &[AnalysisResult]) -> StreamReport {
+        let mut report = StreamReport::default();
+        let messages: Vec<Value> = results.iter().filter_map(|result| serde_json::to_value(result).ok()).collect();
+        if let Err(e) = self.deliver(&messages, &mut report).await {
+            report.error = Some(e.to_string());
+            if let Err(e) = self.queue(stamp, &messages) {
+                report.error = Some(format!("{}; cycle lost, could not queue it: {}", report.error.unwrap_or_default(), e));
+            }
+        }
+        report.pending = self.pending().map(|p| p.len()).unwrap_or(0);
+        report
+    }
+
+    async fn deliver(&self, messages: &[Value], report: &mut StreamReport) -> Result<(), Box<dyn Error>> {
+        let options = match &self.token {
+            Some(token) => ConnectOptions::with_token(token.clone()),
+            None => ConnectOptions::new(),
+        };
+        let client = options.connection_timeout(self.timeout).connect(self.url.as_str()).await?;
+
+        for path in self.pending()? {
+            let queued: Vec<Value> = serde_json::from_slice(&fs::read(&path)?)?;
+            self.publish(&client, &queued).await?;
+            fs::remove_file(&path)?;
+            report.replayed += 1;
+        }
+        self.publish(&client, messages).await?;
+        report.published = messages.len();
+        Ok(())
+    }
+
+    async fn publish(&self, client: &async_nats::Client, messages: &[Value]) -> Result<(), Box<dyn Error>> {
+        for message in messages {
+            let product_id = message["product_id"].as_str().unwrap_or_default();
+            let subject = format!("{}.{}", self.subject, product_id.replace(|c: char| c == '.' || c.is_whitespace(), "_"));
+            client.publish(subject, serde_json::to_vec(message)?.into()).await?;
+        }
+        tokio::time::timeout(self.timeout, client.flush()).await.map_err(|_| "flush timed out")??;
+        Ok(())
+    }
+
+    fn queue(&self, stamp: &str, messages: &[Value]) -> Result<(), Box<dyn Error>> {
+        fs::create_dir_all(&self.pending_dir)?;
+        write_atomic(&self.pending_dir.join(format!("{}.json", stamp)), &serde_json::to_vec(messages)?)?;
+        Ok(())
+    }
+
+    fn pending(&self) -> std::io::Result<Vec<PathBuf>> {
+        if !self.pending_dir.exists() {
+            return Ok(Vec::new());
+        }
+        let mut queued: Vec<PathBuf> = fs::read_dir(&self.pending_dir)?
+            .filter_map(|entry| entry.ok().map(|e| e.path()))
+            .filter(|path| path.extension().is_some_and(|ext| ext == "json"))
+            .collect();
+        queued.sort();
+        Ok(queued)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::replay::replay_cycle;
+    use std::sync::{Arc, Mutex};
+    use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+    use tokio::net::TcpListener;
+
+    // Just enough of the NATS server protocol for a client to connect and publish
+    async fn mock_broker(listener: TcpListener, received: Arc<Mutex<Vec<(String, Value)>>>) {
+        while let Ok((socket, _)) = listener.accept().await {
+            let received = received.clone();
+            tokio::spawn(async move {
+                let (read, mut write) = socket.into_split();
+                let mut read = BufReader::new(read);
+                write.write_all(b"INFO {\"server_id\":\"mock\",\"version\":\"2.10.0\",\"proto\":1,\"max_payload\":1048576}\r\n").await.unwrap();
+                let mut line = String::new();
+                while read.read_line(&mut line).await.unwrap_or(0) > 0 {
+                    let words: Vec<&str> = line.split_whitespace().collect();
+                    match words.first().copied() {
+                        Some("PING") => write.write_all(b"PONG\r\n").await.unwrap(),
+                        Some("PUB") => {
+                            let len: usize = words.last().unwrap().parse().unwrap();
+                            let mut payload = vec![0; len + 2];
+                            read.read_exact(&mut payload).await.unwrap();
+                            let message = serde_json::from_slice(&payload[..len]).unwrap();
+                            received.lock().unwrap().push((words[1].to_string(), message));
+                        }
+                        _ => {}
+                    }
+                    line.clear();
+                }
+            });
+        }
+    }
+
+    #[tokio::test]
+    async fn unreachable_broker_queues_the_cycle_until_it_comes_back() {
+        let results = replay_cycle().await;
+        let dir = tempfile::tempdir().unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        drop(listener);
+
+        let sink = NatsSink {
+            url: format!("nats://{}", addr),
+            subject: "wiz.metrics".into(),
+            token: None,
+            pending_dir: dir.path().join("stream_pending"),
+            timeout: Duration::from_secs(2),
+        };
+        let down = sink.publish_cycle("20250101000000", &results).await;
+        assert_eq!((down.published, down.pending), (0, 1));
+        assert!(down.error.is_some());
+
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let sink = NatsSink { url: format!("nats://{}", listener.local_addr().unwrap()), ..sink };
+        tokio::spawn(mock_broker(listener, received.clone()));
+        let up = sink.publish_cycle("20250101010000", &results).await;
+        assert_eq!((up.published, up.replayed, up.pending, up.error), (results.len(), 1, 0, None));
+
+        // The queued cycle goes out first, each product on its own subject. A flush only means
+        // the bytes left the client, so give the broker a moment to read them.
+        for _ in 0..100 {
+            if received.lock().unwrap().len() >= 2 * results.len() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        let received = received.lock().unwrap();
+        assert_eq!(received.len(), 2 * results.len());
+        assert_eq!(received[0].0, format!("wiz.metrics.{}", results[0].product_id));
+        assert_eq!(received[0].1["product_id"], results[0].product_id.as_str());
+        assert_eq!(received[0].1, received[results.len()].1);
+    }
+}
@@ -0,0 +1,229 @@
+// Synthetic replay corpora with known embedded behaviors, written in the `--capture` layout so
+// `--replay` and `--calibrate` run on them unchanged. The same config and seed always produce the
+// same bytes. A `truth.json` of expected modal sizes is written alongside for `--calibrate`.
+use crate::atomic::write_atomic;
+use crate::capture::{meta_path, CaptureMeta};
+use crate::rng::{SplitMix64, DEFAULT_SEED};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Map, Value};
+use std::error::Error;
+use std::fs;
+use std::path::Path;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Side {
+    Buy,
+    Sell,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "behavior", rename_all = "snake_case")]
+pub enum Behavior {
+    // Both sides fill exactly `size` units every `every` windows and nothing in between
+    Periodic { size: i64, every: usize },
+    // Both sides fill a uniform random 0..=2*mean units every window
+    Noise { mean: i64 },
+    // Only `side` trades, `size` units give or take 25% every window
+    OneSided { side: Side, size: i64 },
+    // Both sides fill `size` every window, and the moving-week counters restart from zero at window `at`
+    CounterReset { size: i64, at: usize },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SyntheticProduct {
+    pub id: String,
+    #[serde(flatten)]
+    pub behavior: Behavior,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SyntheticConfig {
+    pub seed: u64,
+    pub snapshots: usize,
+    pub interval_secs: u64,
+    pub start: u64,
+    pub products: Vec<SyntheticProduct>,
+}
+
+impl Default for SyntheticConfig {
+    fn default() -> Self {
+        let product = |id: &str, behavior| SyntheticProduct { id: id.to_string(), behavior };
+        Self {
+            seed: DEFAULT_SEED,
+            snapshots: 181,
+            interval_secs: 20,
+            start: 1_735_689_600,
+            products: vec![
+                product("SYNTH_PERIODIC", Behavior::Periodic { size: 160, every: 3 }),
+                product("SYNTH_NOISE", Behavior::Noise { mean: 40 }),
+                product("SYNTH_BUY_ONLY", Behavior::OneSided { side: Side::Buy, size: 64 }),
+                product("SYNTH_COUNTER_RESET", Behavior::CounterReset { size: 32, at: 90 }),
+            ],
+        }
+    }
+}
+
+const STARTING_BOOK: i64 = 10_000_000;
+
+struct ProductState {
+    buy_book: i64,
+    sell_book: i64,
+    buy_moving_week: i64,
+    sell_moving_week: i64,
+}
+
+// (buy fill, sell fill) for window `window` (1-based: window 1 is between snapshots 0 and 1)
+fn fills(behavior: &Behavior, window: usize, rng: &mut SplitMix64) -> (i64, i64) {
+    match *behavior {
+        Behavior::Periodic { size, every } => {
+            if every > 0 && window.is_multiple_of(every) { (size, size) } else { (0, 0) }
+        }
+        Behavior::Noise { mean } => (rng.range(0, 2 * mean), rng.range(0, 2 * mean)),
+        Behavior::OneSided { side, size } => {
+            let fill = rng.range(size - size / 4, size + size / 4);
+            match side {
+                Side::Buy => (fill, 0),
+                Side::Sell => (0, fill),
+            }
+        }
+        Behavior::CounterReset { size, .. } => (size, size),
+    }
+}
+
+fn product_json(id: &str, state: &ProductState) -> Value {
+    let level = |amount: i64, price: f64| json!([{ "amount": amount, "pricePerUnit": price, "orders": 1 }]);
+    json!({
+        "product_id": id,
+        "sell_summary": level(state.sell_book, 95.0),
+        "buy_summary": level(state.buy_book, 100.0),
+        "quick_status": {
+            "productId": id,
+            "buyPrice": 100.0,
+            "sellPrice": 95.0,
+            "buyMovingWeek": state.buy_moving_week,
+            "sellMovingWeek": state.sell_moving_week,
+        },
+    })
+}
+
+// Expected modal sizes for `--calibrate`; behaviors without a deliberate pattern expect none
+pub fn truth(config: &SyntheticConfig) -> Value {
+    let labels: Map<String, Value> = config.products.iter()
+        .map(|product| {
+            let label = match product.behavior {
+                Behavior::Periodic { size, .. } | Behavior::CounterReset { size, .. } => {
+                    json!({ "instabuy_modal_size": size, "instasell_modal_size": size })
+                }
+                Behavior::Noise { .. } | Behavior::OneSided { .. } => json!({}),
+            };
+            (product.id.clone(), label)
+        })
+        .collect();
+    Value::Object(labels)
+}
+
+pub struct SyntheticSnapshot {
+    pub captured_at: u64,
+    // The bazaar response body
+    pub body: Value,
+}
+
+// The corpus in memory, oldest first; `dump_fixtures` writes it out and `--self-test` feeds it
+// straight to the update path
+pub fn snapshots(config: &SyntheticConfig) -> Vec<SyntheticSnapshot> {
+    let mut rng = SplitMix64(config.seed);
+    let mut states: Vec<ProductState> = config.products.iter()
+        .map(|_| ProductState { buy_book: STARTING_BOOK, sell_book: STARTING_BOOK, buy_moving_week: 500_000, sell_moving_week: 500_000 })
+        .collect();
+
+    (0..config.snapshots)
+        .map(|snapshot| {
+            let captured_at = config.start + snapshot as u64 * config.interval_secs;
+            let mut products = Map::new();
+            for (product, state) in config.products.iter().zip(states.iter_mut()) {
+                if snapshot > 0 {
+                    let (buy_fill, sell_fill) = fills(&product.behavior, snapshot, &mut rng);
+                    if let Behavior::CounterReset { at, .. } = product.behavior {
+                        if snapshot == at {
+                            state.buy_moving_week = 0;
+                            state.sell_moving_week = 0;
+                        }
+                    }
+                    state.buy_book -= buy_fill;
+                    state.sell_book -= sell_fill;
+                    state.buy_moving_week += buy_fill;
+                    state.sell_moving_week += sell_fill;
+                }
+                products.insert(product.id.clone(), product_json(&product.id, state));
+            }
+            let body = json!({ "success": true, "lastUpdated": captured_at * 1000, "products": products });
+            SyntheticSnapshot { captured_at, body }
+        })
+        .collect()
+}
+
+pub fn dump_fixtures(dir: &Path, config: &SyntheticConfig) -> Result<usize, Box<dyn Error>> {
+    fs::create_dir_all(dir)?;
+    for snapshot in snapshots(config) {
+        let time = DateTime::<Utc>::from_timestamp(snapshot.captured_at as i64, 0).ok_or("start time out of range")?;
+        let path = dir.join(format!("snapshot_{}.json", time.format("%Y%m%d%H%M%S%3f")));
+        let meta = CaptureMeta {
+            captured_at: snapshot.captured_at,
+            last_modified: Some(time.format("%a, %d %b %Y %H:%M:%S GMT").to_string()),
+        };
+        write_atomic(&meta_path(&path), &serde_json::to_vec_pretty(&meta)?)?;
+        write_atomic(&path, &serde_json::to_vec_pretty(&snapshot.body)?)?;
+    }
+
+    write_atomic(&dir.join("truth.json"), &serde_json::to_vec_pretty(&truth(config))?)?;
+    Ok(config.snapshots)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::capture::list_captures;
+    use crate::config::AnalysisConfig;
+    use crate::replay::replay_dir;
+    use crate::{finalize_all, MarketActivity};
+
+    #[tokio::test]
+    async fn default_corpus_embeds_its_behaviors() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = SyntheticConfig::default();
+        assert_eq!(dump_fixtures(dir.path(), &config).unwrap(), 181);
+        assert_eq!(list_captures(dir.path()).unwrap().len(), 181);
+
+        let states = replay_dir(dir.path(), 0.0, &AnalysisConfig::default()).await.unwrap();
+        let results = finalize_all(&states, &AnalysisConfig::default());
+        let result = |id: &str| results.iter().find(|r| r.product_id == id).unwrap();
+
+        assert_eq!(result("SYNTH_PERIODIC").instabuy_modal_size, 160.0);
+        assert_eq!(result("SYNTH_PERIODIC").instabuy_pattern_per_hour, 60.0);
+        assert_eq!(result("SYNTH_BUY_ONLY").market_activity, MarketActivity::BuyOnly);
+        assert_eq!(result("SYNTH_NOISE").market_activity, MarketActivity::TwoSided);
+        let reset = result("SYNTH_COUNTER_RESET");
+        let deltas = &reset.delta_sequences.as_ref().unwrap().buy_moving_week;
+        assert_eq!(deltas[89], 0);
+        assert!(deltas.iter().all(|&d| d >= 0));
+        assert_eq!((reset.buy_counter_resets, reset.sell_counter_resets), (1, 1));
+    }
+
+    #[test]
+    fn same_seed_produces_identical_corpus() {
+        let (a, b, c) = (tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap());
+        let config = SyntheticConfig { snapshots: 5, ..Default::default() };
+        dump_fixtures(a.path(), &config).unwrap();
+        dump_fixtures(b.path(), &config).unwrap();
+        dump_fixtures(c.path(), &SyntheticConfig { seed: 7, ..config }).unwrap();
+
+        let last = |dir: &Path| fs::read(list_captures(dir).unwrap().pop().unwrap()).unwrap();
+        assert_eq!(last(a.path()), last(b.path()));
+        assert_ne!(last(a.path()), last(c.path()));
+        let truth: Value = serde_json::from_slice(&fs::read(a.path().join("truth.json")).unwrap()).unwrap();
+        assert_eq!(truth["SYNTH_PERIODIC"]["instabuy_modal_size"], 160);
+    }
+}
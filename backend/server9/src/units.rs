@@ -0,0 +1,33 @@
+// Unit convention for `AnalysisResult`: every rate is events per hour of observed time and is
+// named `*_per_hour`. Detectors work in minutes between occurrences internally (`interval_minutes`)
+// and convert on the way out, so no exported field mixes "per window" and "minutes per event".
+
+// `count` events over `span_secs` seconds, as events per hour; 0 over an empty span
+pub fn per_hour(count: f64, span_secs: u64) -> f64 {
+    if span_secs == 0 {
+        return 0.0;
+    }
+    count * 3600.0 / span_secs as f64
+}
+
+// An average gap of `minutes` between occurrences, as occurrences per hour; 0 for no gap
+pub fn interval_minutes_to_per_hour(minutes: f64) -> f64 {
+    if minutes > 0.0 { 60.0 / minutes } else { 0.0 }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rates_are_events_per_hour() {
+        // 30 events over 180 windows of 20s is 30 per hour, not 1/6 per window
+        assert_eq!(per_hour(30.0, 180 * 20), 30.0);
+        assert_eq!(per_hour(5.0, 600), 30.0);
+        assert_eq!(per_hour(5.0, 0), 0.0);
+        // Every 3 windows of 20s is every minute, 60 times an hour
+        assert_eq!(interval_minutes_to_per_hour(1.0), 60.0);
+        assert_eq!(interval_minutes_to_per_hour(7.5), 8.0);
+        assert_eq!(interval_minutes_to_per_hour(0.0), 0.0);
+    }
+}
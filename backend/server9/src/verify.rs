@@ -0,0 +1,154 @@
+// `--verify <metrics.json>`: checks an export before it is ingested, listing every problem found.
+// Accepts a bare array of products, the OUTPUT_META document, or a delta export (whose `changed`
+// rows are checked). Checks:
+//   - the file is JSON (a NaN or Infinity written by another tool makes it invalid)
+//   - every schema_version is this build's SCHEMA_VERSION
+//   - every row deserializes as this build's `AnalysisResult`; NaN and infinite floats are written
+//     as null, so one shows up here as a null in a field that can't be null
+//   - `delta_sequences` has one more timestamp than deltas (unless downsampled), its six delta
+//     arrays and the inferred volumes are equally long, and `sequence_numbers` matches `timestamps`
+//   - every confidence is within [0, 100]
+//   - product ids are unique
+use crate::{AnalysisResult, SCHEMA_VERSION};
+use serde_json::Value;
+use std::collections::BTreeSet;
+
+pub fn verify(bytes: &[u8]) -> Vec<String> {
+    let export: Value = match serde_json::from_slice(bytes) {
+        Ok(export) => export,
+        Err(e) => return vec![format!("not valid JSON: {}", e)],
+    };
+    let mut problems = Vec::new();
+    if let Some(version) = export.get("schema_version") {
+        check_version(version, "document", &mut problems);
+    }
+    let rows = match &export {
+        Value::Array(rows) => rows,
+        Value::Object(document) if document.get("kind").is_some_and(|kind| kind == "delta") => match document.get("changed") {
+            Some(Value::Array(rows)) => rows,
+            _ => return vec!["delta export without a `changed` array".to_string()],
+        },
+        Value::Object(document) => match document.get("results") {
+            Some(Value::Array(rows)) => rows,
+            _ => return vec!["neither an array of products nor a document with `results`".to_string()],
+        },
+        _ => return vec!["neither an array of products nor a document with `results`".to_string()],
+    };
+
+    let mut seen = BTreeSet::new();
+    for (i, row) in rows.iter().enumerate() {
+        let id = row["product_id"].as_str().map_or_else(|| format!("row {}", i), str::to_string);
+        if !seen.insert(id.clone()) {
+            problems.push(format!("{}: duplicate product id", id));
+        }
+        match row.get("schema_version") {
+            Some(version) => check_version(version, &id, &mut problems),
+            None => problems.push(format!("{}: no schema_version", id)),
+        }
+        if let Err(e) = serde_path_to_error::deserialize::<_, AnalysisResult>(row) {
+            problems.push(format!("{}: {}: {}", id, e.path(), e.inner()));
+        }
+        if let Some(sequences) = row.get("delta_sequences").filter(|s| !s.is_null()) {
+            check_sequences(sequences, &id, &mut problems);
+        }
+        check_confidences(row, &id, &mut problems);
+    }
+    problems
+}
+
+fn check_version(version: &Value, at: &str, problems: &mut Vec<String>) {
+    if version.as_u64() != Some(SCHEMA_VERSION as u64) {
+        problems.push(format!("{}: schema_version {} is not supported (this build reads {})", at, version, SCHEMA_VERSION));
+    }
+}
+
+fn check_sequences(sequences: &Value, id: &str, problems: &mut Vec<String>) {
+    let len = |name: &str| sequences.get(name).and_then(Value::as_array).map(Vec::len);
+    let deltas = ["buy_moving_week", "sell_moving_week", "buy_orders", "sell_orders", "buy_amount", "sell_amount"];
+    let lengths: Vec<(&str, Option<usize>)> = deltas.iter().map(|&name| (name, len(name))).collect();
+    if let Some((name, _)) = lengths.iter().find(|(_, n)| n.is_none()) {
+        problems.push(format!("{}: delta_sequences.{} is missing", id, name));
+        return;
+    }
+    let expected = lengths[0].1;
+    for &(name, n) in &lengths[1..] {
+        if n != expected {
+            problems.push(format!("{}: delta_sequences.{} has {} entries, buy_moving_week {}", id, name, n.unwrap_or(0), expected.unwrap_or(0)));
+        }
+    }
+    // Exports made before --recompute existed have no inferred volumes
+    for name in ["buy_inferred_volume", "sell_inferred_volume"] {
+        if let Some(n) = len(name).filter(|&n| n > 0 && Some(n) != expected) {
+            problems.push(format!("{}: delta_sequences.{} has {} entries, buy_moving_week {}", id, name, n, expected.unwrap_or(0)));
+        }
+    }
+    let timestamps = len("timestamps").unwrap_or(0);
+    let downsampled = sequences.get("downsampled_every").is_some_and(|k| !k.is_null());
+    if !downsampled && Some(timestamps) != expected.map(|n| n + 1) {
+        problems.push(format!("{}: delta_sequences has {} timestamps for {} deltas", id, timestamps, expected.unwrap_or(0)));
+    }
+    if let Some(n) = len("sequence_numbers").filter(|&n| n > 0 && n != timestamps) {
+        problems.push(format!("{}: delta_sequences has {} sequence numbers for {} timestamps", id, n, timestamps));
+    }
+}
+
+fn check_confidences(value: &Value, id: &str, problems: &mut Vec<String>) {
+    let Value::Object(fields) = value else { return };
+    for (name, field) in fields {
+        match field {
+            Value::Number(n) if name.contains("confidence") => {
+                let confidence = n.as_f64().unwrap_or(f64::NAN);
+                if !(0.0..=100.0).contains(&confidence) {
+                    problems.push(format!("{}: {} is {}, outside [0, 100]", id, name, confidence));
+                }
+            }
+            Value::Object(_) => check_confidences(field, id, problems),
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+
+    fn golden() -> Value {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/replay_golden.json");
+        serde_json::from_slice(&std::fs::read(path).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn a_clean_export_passes() {
+        let export = golden();
+        assert_eq!(verify(&serde_json::to_vec(&export).unwrap()), Vec::<String>::new());
+        let wrapped = serde_json::json!({ "schema_version": SCHEMA_VERSION, "results": export });
+        assert_eq!(verify(&serde_json::to_vec(&wrapped).unwrap()), Vec::<String>::new());
+    }
+
+    #[test]
+    fn a_corrupted_export_lists_every_problem() {
+        let mut export = golden();
+        let rows = export.as_array_mut().unwrap();
+        rows[0]["schema_version"] = 99.into();
+        rows[1]["instabuy_price_average"] = Value::Null;
+        rows[1]["delta_sequences"]["buy_orders"].as_array_mut().unwrap().pop();
+        rows[1]["delta_sequences"]["timestamps"].as_array_mut().unwrap().pop();
+        rows[2]["pattern_detection_confidence"] = 140.0.into();
+        rows[3]["product_id"] = rows[2]["product_id"].clone();
+        let (first, second, third) = (rows[0]["product_id"].clone(), rows[1]["product_id"].clone(), rows[2]["product_id"].clone());
+
+        let problems = verify(&serde_json::to_vec(&export).unwrap());
+        let mentions = |id: &Value, text: &str| problems.iter().any(|p| p.starts_with(id.as_str().unwrap()) && p.contains(text));
+        assert!(mentions(&first, "schema_version 99 is not supported"));
+        assert!(mentions(&second, "instabuy_price_average: invalid type: null"));
+        assert!(mentions(&second, "delta_sequences.buy_orders has"));
+        assert!(mentions(&second, "29 timestamps for 29 deltas"));
+        assert!(mentions(&second, "30 sequence numbers for 29 timestamps"));
+        assert!(mentions(&third, "pattern_detection_confidence is 140"));
+        assert!(mentions(&third, "duplicate product id"));
+        assert_eq!(problems.len(), 7, "{:#?}", problems);
+
+        assert!(verify(b"[{\"product_id\": \"WHEAT\", \"instabuy_price_average\": NaN}]")[0].starts_with("not valid JSON"));
+    }
+}
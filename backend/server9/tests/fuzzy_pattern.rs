@@ -0,0 +1,37 @@
+use timestamp_generator::activity::ActivityIndex;
+use timestamp_generator::config::DetectorConfig;
+use timestamp_generator::ProductMetricsState;
+
+// A fill of about 1,000 every third 20-second window, with the inferred volume seeing most of it
+fn repeating_fills(windows: usize) -> (Vec<i64>, Vec<i64>, Vec<u64>) {
+    let deltas: Vec<i64> = (0..windows).map(|i| if i % 3 == 0 { 1_000 + (i % 7) as i64 * 5 } else { 0 }).collect();
+    let inferred = deltas.iter().map(|&d| d * 4 / 5).collect();
+    let timestamps = (0..=windows as u64).map(|i| 1_700_000_000 + i * 20).collect();
+    (deltas, inferred, timestamps)
+}
+
+#[test]
+fn repeating_fills_are_detected_through_the_public_api() {
+    let (deltas, inferred, timestamps) = repeating_fills(180);
+    let activity = ActivityIndex::from_deltas(&deltas, &timestamps);
+    let (pattern, details) = ProductMetricsState::detect_fuzzy_modal_pattern(&activity, &deltas, &inferred, &timestamps, &DetectorConfig::default());
+
+    let pattern = pattern.expect("a pattern in 60 near-identical fills");
+    assert!(ProductMetricsState::relative_diff(1_000.0, pattern.size) < 0.05, "size {}", pattern.size);
+    assert_eq!(pattern.ratio, Some(1.25));
+    assert!(pattern.confidence > 0.0 && pattern.confidence <= 1.0);
+    assert_eq!(details.fuzzy_confidence, pattern.confidence);
+    assert!(details.velocity_patterns_found + details.rhythm_patterns_found > 0);
+}
+
+#[test]
+fn a_flat_market_has_no_pattern() {
+    let deltas = vec![0; 180];
+    let timestamps: Vec<u64> = (0..=180).map(|i| 1_700_000_000 + i * 20).collect();
+    let activity = ActivityIndex::from_deltas(&deltas, &timestamps);
+    let (pattern, details) = ProductMetricsState::detect_fuzzy_modal_pattern(&activity, &deltas, &deltas, &timestamps, &DetectorConfig::default());
+
+    assert!(pattern.is_none());
+    assert_eq!(details.detection_method, "fuzzy_combined");
+    assert_eq!(details.velocity_patterns_found + details.rhythm_patterns_found + details.sequence_patterns_found, 0);
+}
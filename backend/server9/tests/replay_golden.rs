@@ -0,0 +1,61 @@
+use serde_json::Value;
+use std::fs;
+use std::path::{Path, PathBuf};
+use timestamp_generator::config::AnalysisConfig;
+use timestamp_generator::{finalize_all, replay_dir};
+
+fn fixtures_dir() -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures")
+}
+
+// Numbers are compared with a relative tolerance; everything else must match exactly
+fn assert_json_close(expected: &Value, actual: &Value, path: &str) {
+    match (expected, actual) {
+        (Value::Number(e), Value::Number(a)) => {
+            let (e, a) = (e.as_f64().unwrap(), a.as_f64().unwrap());
+            let tolerance = 1e-9 * e.abs().max(1.0);
+            assert!((e - a).abs() <= tolerance, "{}: expected {}, got {}", path, e, a);
+        }
+        (Value::Array(e), Value::Array(a)) => {
+            assert_eq!(e.len(), a.len(), "{}: array length differs", path);
+            for (i, (e, a)) in e.iter().zip(a).enumerate() {
+                assert_json_close(e, a, &format!("{}[{}]", path, i));
+            }
+        }
+        (Value::Object(e), Value::Object(a)) => {
+            let mut e_keys: Vec<_> = e.keys().collect();
+            let mut a_keys: Vec<_> = a.keys().collect();
+            e_keys.sort();
+            a_keys.sort();
+            assert_eq!(e_keys, a_keys, "{}: fields differ", path);
+            for (key, e) in e {
+                assert_json_close(e, &a[key], &format!("{}.{}", path, key));
+            }
+        }
+        _ => assert_eq!(expected, actual, "{}", path),
+    }
+}
+
+// Regenerate after an intended output change with:
+//   UPDATE_GOLDEN=1 cargo test --test replay_golden
+#[tokio::test]
+async fn full_cycle_matches_golden() {
+    let dir = fixtures_dir().join("replay");
+    let captures = fs::read_dir(&dir).unwrap()
+        .filter(|entry| entry.as_ref().unwrap().file_name().to_str().is_some_and(|n| n.starts_with("snapshot_") && !n.ends_with(".meta.json")))
+        .count();
+    assert_eq!(captures, 30);
+
+    let states = replay_dir(&dir, 0.0, &AnalysisConfig::default()).await.unwrap();
+    let results = finalize_all(&states, &AnalysisConfig::default());
+    let golden_path = fixtures_dir().join("replay_golden.json");
+
+    if std::env::var("UPDATE_GOLDEN").is_ok() {
+        fs::write(&golden_path, serde_json::to_string_pretty(&results).unwrap() + "\n").unwrap();
+        return;
+    }
+
+    let actual = serde_json::to_value(&results).unwrap();
+    let expected: Value = serde_json::from_slice(&fs::read(&golden_path).unwrap()).unwrap();
+    assert_json_close(&expected, &actual, "results");
+}